@@ -437,7 +437,8 @@ char *mpc_channel_setup(const char *channel_name,
                         int64_t bal_min_cust,
                         int64_t bal_min_merch,
                         int64_t val_cpfp,
-                        uint32_t third_party_support);
+                        uint32_t third_party_support,
+                        const char *ser_network);
 
 void mpc_free_string(char *pointer);
 
@@ -447,7 +448,8 @@ char *mpc_get_initial_state(char *ser_cust_state);
 
 char *mpc_get_masked_tx_inputs(char *ser_session_id, uint32_t mpc_result, char *ser_merch_state);
 
-char *mpc_init_customer(char *ser_merch_pk,
+char *mpc_init_customer(char *ser_channel_state,
+                        char *ser_merch_pk,
                         int64_t cust_bal,
                         int64_t merch_bal,
                         char *ser_tx_fee_info,
@@ -466,6 +468,12 @@ char *mpc_load_merchant_wallet(char *ser_merch_state,
                                char *ser_payout_sk,
                                char *ser_dispute_sk);
 
+char *mpc_merchant_expire_sessions(char *ser_merch_state);
+
+char *mpc_pay_rollback_customer(char *ser_cust_state);
+
+char *mpc_pay_resume_customer(char *ser_cust_state);
+
 char *mpc_pay_unmask_pay_token_customer(char *ser_pt_mask_bytes,
                                         char *ser_pt_mask_r,
                                         char *ser_cust_state);
@@ -7,7 +7,6 @@ import "C"
 import (
 	"crypto/rand"
 	"encoding/json"
-	"errors"
 	"strings"
 	"unsafe"
 )
@@ -22,6 +21,7 @@ type setupResp struct {
 	State           string `json:"state"`
 	RevState        string `json:"rev_state"`
 	SessionId       string `json:"session_id"`
+	Step            string `json:"step"`
 	FoundRevSecret  string `json:"found_rev_secret"`
 	PayTokenMaskCom string `json:"pay_token_mask_com"`
 	RevLockCom      string `json:"rev_lock_com"`
@@ -41,7 +41,33 @@ type setupResp struct {
 	InitCustState   string `json:"init_state"`
 	InitHash        string `json:"init_hash"`
 	SelfDelayBE     string `json:"self_delay_be"`
+	ExpiredSessions string `json:"expired_sessions"`
 	Error           string `json:"error"`
+	ErrorCode       int    `json:"error_code"`
+}
+
+// Stable error codes reported by libzkchannels (see ZkChannelError::code())
+const (
+	ErrInvalidStatusTransition  = 1
+	ErrDoubleSpentNonce         = 2
+	ErrReusedSessionId          = 3
+	ErrInvalidCommitmentOpening = 4
+	ErrDatabaseUnavailable      = 5
+	ErrMpcFailure               = 6
+	ErrTransactionError         = 7
+	ErrInvalidInput             = 8
+	ErrNotFound                 = 9
+	ErrSerializationError       = 10
+	ErrInternalError            = 11
+)
+
+type ZkChannelError struct {
+	Code int
+	Msg  string
+}
+
+func (e *ZkChannelError) Error() string {
+	return e.Msg
 }
 
 type ChannelState struct {
@@ -54,24 +80,25 @@ type ChannelState struct {
 	MerchPayOutPk  *string `json:"merch_payout_pk"`
 	MerchDisputePk *string `json:"merch_dispute_pk"`
 	SelfDelay      uint16  `json:"self_delay"`
+	Network        string  `json:"network"`
 }
 
 type MerchState struct {
-	Id               *string                 `json:"id"`
-	PkM              *string                 `json:"pk_m"`
-	SkM              *string                 `json:"sk_m"`
-	HmacKey          string                  `json:"hmac_key"`
-	HmacKeyR         string                  `json:"hmac_key_r"`
-	PayoutSk         *string                 `json:"payout_sk"`
-	PayoutPk         *string                 `json:"payout_pk"`
-	DisputeSk        *string                 `json:"dispute_sk"`
-	DisputePk        *string                 `json:"dispute_pk"`
-	ChannelStatusMap *map[string]interface{} `json:"channel_status_map"`
-	ActivateMap      *map[string]interface{} `json:"activate_map"`
-	CloseTxMap       *map[string]interface{} `json:"close_tx"`
-	NetConfig        *map[string]interface{} `json:"net_config"`
-	DbUrl            string                  `json:"db_url"`
-	RefundPolicy     string                  `json:"refund_policy"`
+	Id                *string                 `json:"id"`
+	PkM               *string                 `json:"pk_m"`
+	SkM               *string                 `json:"sk_m"`
+	HmacKey           string                  `json:"hmac_key"`
+	HmacKeyR          string                  `json:"hmac_key_r"`
+	PayoutSk          *string                 `json:"payout_sk"`
+	PayoutPk          *string                 `json:"payout_pk"`
+	DisputeSk         *string                 `json:"dispute_sk"`
+	DisputePk         *string                 `json:"dispute_pk"`
+	NetConfig         *map[string]interface{} `json:"net_config"`
+	DbUrl             string                  `json:"db_url"`
+	RefundPolicy      string                  `json:"refund_policy"`
+	RefundAuthorityPk *string                 `json:"refund_authority_pk"`
+	Network           string                  `json:"network"`
+	SessionTimeout    uint64                  `json:"session_timeout"`
 }
 
 type CustState struct {
@@ -96,6 +123,10 @@ type CustState struct {
 	ProtocolStatus  string                  `json:"protocol_status"`
 	ChannelStatus   string                  `json:"channel_status"`
 	NetConfig       *map[string]interface{} `json:"net_config"`
+	Network         string                  `json:"network"`
+	PaymentJournal  *map[string]interface{} `json:"payment_journal"`
+	MerchFunding    int64                   `json:"merch_funding"`
+	PendingTopUp    *map[string]interface{} `json:"pending_top_up"`
 }
 
 type State struct {
@@ -119,6 +150,7 @@ type ChannelToken struct {
 	PkM        string `json:"pk_m"`
 	EscrowTxId string `json:"escrow_txid"`
 	MerchTxId  string `json:"merch_txid"`
+	Network    string `json:"network"`
 }
 
 type MaskedTxInputs struct {
@@ -151,18 +183,22 @@ type FundingTxInfo struct {
 	MerchPrevout  string `json:"merch_prevout"`
 	InitCustBal   int64  `json:"init_cust_bal"`
 	InitMerchBal  int64  `json:"init_merch_bal"`
+	CustFunding   int64  `json:"cust_funding"`
+	MerchFunding  int64  `json:"merch_funding"`
 }
 
 type InitCustState struct {
-	PkC      *string `json:"pk_c"`
-	ClosePk  *string `json:"close_pk"`
-	Nonce    string  `json:"nonce"`
-	RevLock  string  `json:"rev_lock"`
-	CustBal  int64   `json:"cust_bal"`
-	MerchBal int64   `json:"merch_bal"`
-	MinFee   int64   `json:"min_fee"`
-	MaxFee   int64   `json:"max_fee"`
-	FeeMC    int64   `json:"fee_mc"`
+	PkC          *string `json:"pk_c"`
+	ClosePk      *string `json:"close_pk"`
+	Nonce        string  `json:"nonce"`
+	RevLock      string  `json:"rev_lock"`
+	CustBal      int64   `json:"cust_bal"`
+	MerchBal     int64   `json:"merch_bal"`
+	MinFee       int64   `json:"min_fee"`
+	MaxFee       int64   `json:"max_fee"`
+	FeeMC        int64   `json:"fee_mc"`
+	CustFunding  int64   `json:"cust_funding"`
+	MerchFunding int64   `json:"merch_funding"`
 }
 
 func GenerateRandomBytes(n int) ([]byte, error) {
@@ -188,8 +224,8 @@ func GetSelfDelayBE(channelState ChannelState) (string, error) {
 	return r.SelfDelayBE, err
 }
 
-func ChannelSetup(name string, selfDelay int16, balMinCust int64, balMinMerch int64, valCpfp int64, channelThirdPartySupport bool) (ChannelState, error) {
-	resp := C.GoString(C.mpc_channel_setup(C.CString(name), C.uint16_t(selfDelay), C.int64_t(balMinCust), C.int64_t(balMinMerch), C.int64_t(valCpfp), C.uint(btoi(channelThirdPartySupport))))
+func ChannelSetup(name string, selfDelay int16, balMinCust int64, balMinMerch int64, valCpfp int64, channelThirdPartySupport bool, network string) (ChannelState, error) {
+	resp := C.GoString(C.mpc_channel_setup(C.CString(name), C.uint16_t(selfDelay), C.int64_t(balMinCust), C.int64_t(balMinMerch), C.int64_t(valCpfp), C.uint(btoi(channelThirdPartySupport)), C.CString(network)))
 	r, err := processCResponse(resp)
 	if err != nil {
 		return ChannelState{}, err
@@ -199,6 +235,8 @@ func ChannelSetup(name string, selfDelay int16, balMinCust int64, balMinMerch in
 	return channelState, err
 }
 
+// States are returned as plain json and rev secrets are stored in the db at dbUrl unsealed:
+// libzkchannels only encrypts secrets at rest through its rust api
 func InitMerchant(dbUrl string, channelState ChannelState, name string) (ChannelState, MerchState, error) {
 	serChannelState, err := json.Marshal(channelState)
 	if err != nil {
@@ -246,13 +284,18 @@ func LoadMerchantWallet(merchState MerchState, channelState ChannelState, skC st
 	return channelState, merchState, err
 }
 
-func InitCustomer(pkM string, custBal int64, merchBal int64, txFeeInfo TransactionFeeInfo, name string) (ChannelToken, CustState, error) {
+func InitCustomer(channelState ChannelState, pkM string, custBal int64, merchBal int64, txFeeInfo TransactionFeeInfo, name string) (ChannelToken, CustState, error) {
+	serChannelState, err := json.Marshal(channelState)
+	if err != nil {
+		return ChannelToken{}, CustState{}, err
+	}
+
 	serTxFeeInfo, err := json.Marshal(txFeeInfo)
 	if err != nil {
 		return ChannelToken{}, CustState{}, err
 	}
 
-	resp := C.GoString(C.mpc_init_customer(C.CString(pkM), C.int64_t(custBal),
+	resp := C.GoString(C.mpc_init_customer(C.CString(string(serChannelState)), C.CString(pkM), C.int64_t(custBal),
 		C.int64_t(merchBal), C.CString(string(serTxFeeInfo)), C.CString(name)))
 	r, err := processCResponse(resp)
 	if err != nil {
@@ -726,6 +769,64 @@ func PayConfirmMPCResult(sessionId string, mpcResult bool, merchState MerchState
 	return maskedTxInputs, err
 }
 
+func MerchantExpireSessions(merchState MerchState) ([]string, error) {
+	serMerchState, err := json.Marshal(merchState)
+	if err != nil {
+		return nil, err
+	}
+
+	resp := C.GoString(C.mpc_merchant_expire_sessions(C.CString(string(serMerchState))))
+	r, err := processCResponse(resp)
+	if err != nil {
+		return nil, err
+	}
+
+	expiredSessions := []string{}
+	err = json.Unmarshal([]byte(r.ExpiredSessions), &expiredSessions)
+	return expiredSessions, err
+}
+
+func PayRollbackCustomer(custState CustState) (State, CustState, error) {
+	serCustState, err := json.Marshal(custState)
+	if err != nil {
+		return State{}, CustState{}, err
+	}
+	resp := C.GoString(C.mpc_pay_rollback_customer(C.CString(string(serCustState))))
+	r, err := processCResponse(resp)
+	if err != nil {
+		return State{}, CustState{}, err
+	}
+	state := State{}
+	err = json.Unmarshal([]byte(r.State), &state)
+	if err != nil {
+		return State{}, CustState{}, err
+	}
+
+	err = json.Unmarshal([]byte(r.CustState), &custState)
+	return state, custState, err
+}
+
+func PayResumeCustomer(custState CustState) (string, string, *RevokedState, error) {
+	serCustState, err := json.Marshal(custState)
+	if err != nil {
+		return "", "", nil, err
+	}
+	resp := C.GoString(C.mpc_pay_resume_customer(C.CString(string(serCustState))))
+	r, err := processCResponse(resp)
+	if err != nil {
+		return "", "", nil, err
+	}
+	step := ""
+	err = json.Unmarshal([]byte(r.Step), &step)
+	if err != nil {
+		return "", "", nil, err
+	}
+
+	var revState *RevokedState
+	err = json.Unmarshal([]byte(r.RevState), &revState)
+	return step, r.SessionId, revState, err
+}
+
 func PayUnmaskSigsCustomer(channelState ChannelState, channelToken ChannelToken, maskedTxInputs MaskedTxInputs, custState CustState) (bool, CustState, error) {
 	serChannelToken, err := json.Marshal(channelToken)
 	if err != nil {
@@ -1028,7 +1129,7 @@ func processCResponse(resp string) (*setupResp, error) {
 		return nil, err
 	}
 	if r.Error != "" {
-		return nil, errors.New(r.Error)
+		return nil, &ZkChannelError{Code: r.ErrorCode, Msg: r.Error}
 	}
 	return r, err
 }
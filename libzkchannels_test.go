@@ -69,7 +69,7 @@ func Test_fullProtocolWithValidUTXO(t *testing.T) {
 	feeCC := txFeeInfo.FeeCC
 	feeMC := txFeeInfo.FeeMC
 
-	channelState, err := ChannelSetup("channel", selfDelay, txFeeInfo.BalMinCust, txFeeInfo.BalMinMerch, txFeeInfo.ValCpFp, false)
+	channelState, err := ChannelSetup("channel", selfDelay, txFeeInfo.BalMinCust, txFeeInfo.BalMinMerch, txFeeInfo.ValCpFp, false, "testnet")
 	assert.Nil(t, err)
 
 	channelState, merchState, err := InitMerchant(dbUrl, channelState, "merch")
@@ -86,7 +86,7 @@ func Test_fullProtocolWithValidUTXO(t *testing.T) {
 
 	merchPKM := fmt.Sprintf("%v", *merchState.PkM)
 
-	channelToken, custState, err := InitCustomer(merchPKM, custBal, merchBal, txFeeInfo, "cust")
+	channelToken, custState, err := InitCustomer(channelState, merchPKM, custBal, merchBal, txFeeInfo, "cust")
 	assert.Nil(t, err)
 
 	fix_customer_wallet := os.Getenv("FIX_CUSTOMER_WALLET")
@@ -270,6 +270,7 @@ func Test_fullProtocolWithValidUTXO(t *testing.T) {
 	fmt.Println("initial close transactions validated: ", isOk)
 	_, err = CustomerChangeChannelStatusToPendingClose(custState)
 	assert.Equal(t, "transition not allowed for channel: PendingOpen => PendingClose", err.Error())
+	assert.Equal(t, ErrInvalidStatusTransition, err.(*ZkChannelError).Code)
 
 	_, err = CustomerChangeChannelStatusToConfirmedClose(custState)
 	assert.Equal(t, "transition not allowed for channel: PendingOpen => ConfirmedClose", err.Error())
@@ -316,7 +317,8 @@ func Test_fullProtocolWithValidUTXO(t *testing.T) {
 	}
 	/////////////////////////////////////////////////////////
 	fmt.Println("Proceed with channel activation...")
-	(*merchState.ChannelStatusMap)[escrowTxid_BE] = "Open"
+	merchState, err = MerchantChangeChannelStatusToOpen(escrowTxid_LE, merchState)
+	assert.Nil(t, err)
 
 	channelId, err := GetChannelId(channelToken)
 	assert.Nil(t, err)
@@ -453,8 +455,6 @@ func Test_fullProtocolWithValidUTXO(t *testing.T) {
 	claimAmount = disputedInAmt - feeCC - feeMC
 	claimOutAmount := claimAmount - txFee
 	{
-		(*merchState.ChannelStatusMap)[escrowTxid_BE] = "CustomerInitClose"
-
 		disputeTx, merchState, err := MerchantSignDisputeTx(escrowTxid_LE, CloseEscrowTxId_TX3, index, claimAmount, claimOutAmount, toSelfDelay, outputPk, revState.RevLock, FoundRevSecret, custClosePk, merchState)
 		assert.Nil(t, err)
 		fmt.Println("========================================")
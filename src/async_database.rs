@@ -0,0 +1,249 @@
+use super::*;
+use channels_mpc::{ChannelMPCState, ChannelMPCToken, ChannelStatus, MerchantMPCState};
+use database::{DbBatch, MaskedMPCInputs, SessionState, StateDatabase};
+use errors::ZkChannelError;
+use event_log::{EventKey, EventRecord};
+use pool::{BlockingPool, DatabasePool, ZkFuture};
+use std::sync::Arc;
+
+///
+/// AsyncStateDatabase - StateDatabase for async callers. Every method returns a future that owns
+/// its arguments, so it can be awaited on any executor (e.g., tokio) without blocking it. run()
+/// is the only required method; the others are run() over the matching StateDatabase method.
+/// The backends themselves stay blocking (there is no async redis client here): the calls run on
+/// a pool with a thread for every connection, so the executor never waits on the database
+///
+pub trait AsyncStateDatabase: Send + Sync {
+    ///
+    /// run() - runs a (blocking) call on a connection of the database, off the async executor
+    /// output: future that resolves to the result of the call
+    ///
+    fn run<F, T>(&self, call: F) -> ZkFuture<T>
+    where
+        F: FnOnce(&mut dyn StateDatabase) -> Result<T, ZkChannelError> + Send + 'static,
+        T: Send + 'static;
+
+    fn check_session_id(&self, session_id_hex: String) -> ZkFuture<bool> {
+        self.run(move |db| db.check_session_id(&session_id_hex))
+    }
+
+    fn save_new_session_state(
+        &self,
+        session_id_hex: String,
+        session_state: SessionState,
+    ) -> ZkFuture<bool> {
+        self.run(move |db| Ok(db.save_new_session_state(&session_id_hex, &session_state)))
+    }
+
+    fn load_session_state(&self, session_id_hex: String) -> ZkFuture<SessionState> {
+        self.run(move |db| db.load_session_state(&session_id_hex))
+    }
+
+    fn update_session_state(
+        &self,
+        session_id_hex: String,
+        session_state: SessionState,
+    ) -> ZkFuture<bool> {
+        self.run(move |db| Ok(db.update_session_state(&session_id_hex, &session_state)))
+    }
+
+    fn get_session_ids(&self) -> ZkFuture<Vec<String>> {
+        self.run(|db| db.get_session_ids())
+    }
+
+    fn get_masked_mpc_inputs(&self, session_id_hex: String) -> ZkFuture<MaskedMPCInputs> {
+        self.run(move |db| db.get_masked_mpc_inputs(&session_id_hex))
+    }
+
+    fn get_mask_map_from_nonce(&self, nonce_hex: String) -> ZkFuture<([u8; 32], [u8; 16])> {
+        self.run(move |db| db.get_mask_map_from_nonce(&nonce_hex))
+    }
+
+    fn get_rev_secret(&self, rev_lock_hex: String) -> ZkFuture<String> {
+        self.run(move |db| db.get_rev_secret(&rev_lock_hex))
+    }
+
+    // see StateDatabase::apply_batch(): false if a check of the batch failed
+    fn apply_batch(&self, batch: DbBatch) -> ZkFuture<bool> {
+        self.run(move |db| db.apply_batch(&batch))
+    }
+
+    fn check_spent_map(&self, nonce_hex: String) -> ZkFuture<bool> {
+        self.run(move |db| Ok(db.check_spent_map(&nonce_hex)))
+    }
+
+    fn check_rev_lock_map(&self, rev_lock_hex: String) -> ZkFuture<bool> {
+        self.run(move |db| Ok(db.check_rev_lock_map(&rev_lock_hex)))
+    }
+
+    fn update_unlink_set(&self, nonce_hex: String) -> ZkFuture<bool> {
+        self.run(move |db| db.update_unlink_set(&nonce_hex))
+    }
+
+    fn get_unlink_set(&self) -> ZkFuture<HashSet<String>> {
+        self.run(|db| db.get_unlink_set())
+    }
+
+    fn update_channel_status(
+        &self,
+        escrow_txid_hex: String,
+        status: ChannelStatus,
+    ) -> ZkFuture<bool> {
+        self.run(move |db| db.update_channel_status(&escrow_txid_hex, &status))
+    }
+
+    fn get_channel_status(&self, escrow_txid_hex: String) -> ZkFuture<ChannelStatus> {
+        self.run(move |db| db.get_channel_status(&escrow_txid_hex))
+    }
+
+    fn append_event(&self, record: EventRecord) -> ZkFuture<bool> {
+        self.run(move |db| db.append_event(&record))
+    }
+
+    fn get_events(&self, key: EventKey) -> ZkFuture<Vec<EventRecord>> {
+        self.run(move |db| db.get_events(&key))
+    }
+
+    fn save_merchant_state(
+        &self,
+        channel_state: ChannelMPCState,
+        merch_state: MerchantMPCState,
+    ) -> ZkFuture<()> {
+        self.run(move |db| db.save_merchant_state(&channel_state, &merch_state))
+    }
+
+    fn load_merchant_state(&self) -> ZkFuture<(ChannelMPCState, MerchantMPCState)> {
+        self.run(|db| db.load_merchant_state())
+    }
+
+    fn save_merchant_channel(&self, channel_token: ChannelMPCToken) -> ZkFuture<String> {
+        self.run(move |db| db.save_merchant_channel(&channel_token))
+    }
+
+    fn load_merchant_channel(&self, channel_id: String) -> ZkFuture<ChannelMPCToken> {
+        self.run(move |db| db.load_merchant_channel(&channel_id))
+    }
+
+    fn list_merchant_channels(&self) -> ZkFuture<Vec<String>> {
+        self.run(|db| db.list_merchant_channels())
+    }
+}
+
+///
+/// AsyncDatabase - runs the calls of a database pool on a blocking pool with a thread for every
+/// connection, so a call only waits for a free thread and never for a connection
+///
+pub struct AsyncDatabase<D: StateDatabase + Send> {
+    db_pool: Arc<DatabasePool<D>>,
+    io_pool: Arc<BlockingPool>,
+}
+
+// clones share the same pools
+impl<D: StateDatabase + Send> Clone for AsyncDatabase<D> {
+    fn clone(&self) -> Self {
+        AsyncDatabase {
+            db_pool: self.db_pool.clone(),
+            io_pool: self.io_pool.clone(),
+        }
+    }
+}
+
+impl<D: StateDatabase + Send> AsyncDatabase<D> {
+    pub fn new(db_pool: DatabasePool<D>) -> Result<Self, ZkChannelError> {
+        AsyncDatabase::from_shared_pool(Arc::new(db_pool))
+    }
+
+    // shares the pool with sync callers (e.g., a MerchantServer)
+    pub fn from_shared_pool(db_pool: Arc<DatabasePool<D>>) -> Result<Self, ZkChannelError> {
+        let io_pool = BlockingPool::new(db_pool.size())?;
+        Ok(AsyncDatabase {
+            db_pool: db_pool,
+            io_pool: Arc::new(io_pool),
+        })
+    }
+
+    pub fn get_db_pool(&self) -> &DatabasePool<D> {
+        &self.db_pool
+    }
+}
+
+impl<D: StateDatabase + Send + 'static> AsyncStateDatabase for AsyncDatabase<D> {
+    fn run<F, T>(&self, call: F) -> ZkFuture<T>
+    where
+        F: FnOnce(&mut dyn StateDatabase) -> Result<T, ZkChannelError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let db_pool = self.db_pool.clone();
+        self.io_pool.run(move || {
+            let mut db = db_pool.get();
+            call(&mut *db as &mut dyn StateDatabase)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::{BatchCheck, BatchWrite, HashMapDatabase};
+    use futures::future;
+
+    #[test]
+    fn test_async_database_calls_share_the_pool() {
+        let db_pool: DatabasePool<HashMapDatabase> =
+            DatabasePool::connect("asynctest", String::new(), 1).unwrap();
+        let db = AsyncDatabase::new(db_pool).unwrap();
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let calls = (0..8u8)
+            .map(|i| db.update_unlink_set(hex::encode([i; 16])))
+            .collect::<Vec<_>>();
+        let results = rt.block_on(future::join_all(calls));
+        assert!(results.into_iter().all(|r| r.unwrap()));
+
+        // sync callers see the writes of async callers
+        assert_eq!(db.get_db_pool().get().get_unlink_set().unwrap().len(), 8);
+        let escrow_txid = hex::encode([3u8; 32]);
+        assert!(rt
+            .block_on(db.get_channel_status(escrow_txid.clone()))
+            .is_err());
+        rt.block_on(db.update_channel_status(escrow_txid.clone(), ChannelStatus::Open))
+            .unwrap();
+        assert_eq!(
+            rt.block_on(db.get_channel_status(escrow_txid)).unwrap(),
+            ChannelStatus::Open
+        );
+    }
+
+    #[test]
+    fn test_async_apply_batch_spends_nonce_once() {
+        let db_pool: DatabasePool<HashMapDatabase> =
+            DatabasePool::connect("asyncbatchtest", String::new(), 1).unwrap();
+        let db = AsyncDatabase::new(db_pool).unwrap();
+
+        let nonce_hex = hex::encode([5u8; 16]);
+        let rev_lock_hex = hex::encode([6u8; 32]);
+        let mut batch = DbBatch::new();
+        batch.check(BatchCheck::NonceNotSpent(nonce_hex.clone()));
+        batch.write(BatchWrite::UpdateSpentMap(
+            nonce_hex.clone(),
+            rev_lock_hex.clone(),
+        ));
+        batch.write(BatchWrite::UpdateRevLockMap(
+            rev_lock_hex.clone(),
+            hex::encode([7u8; 32]),
+        ));
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let results = rt.block_on(future::join_all(vec![
+            db.apply_batch(batch.clone()),
+            db.apply_batch(batch),
+        ]));
+        let applied = results.into_iter().filter(|r| *r.as_ref().unwrap()).count();
+        assert_eq!(applied, 1);
+        assert!(rt.block_on(db.check_spent_map(nonce_hex)).unwrap());
+        assert_eq!(
+            rt.block_on(db.get_rev_secret(rev_lock_hex)).unwrap(),
+            hex::encode([7u8; 32])
+        );
+    }
+}
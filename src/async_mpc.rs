@@ -0,0 +1,204 @@
+use super::*;
+use async_database::AsyncDatabase;
+use channels_mpc::{ChannelMPCState, ChannelMPCToken, CustomerMPCState, RevokedState};
+use database::{MaskedTxMPCInputs, StateDatabase};
+use errors::ZkChannelError;
+use merchant_server::MerchantServer;
+use pool::{BlockingPool, ZkFuture};
+use std::ptr;
+use std::sync::Arc;
+use wallet::{State, NONCE_LEN};
+
+///
+/// AsyncMerchantServer - async facade of a MerchantServer. The MPC of a session (the EMP calls)
+/// runs on a dedicated pool of mpc_threads threads, and all other steps run on a pool with a thread
+/// for every database connection, so a long MPC never holds up the other steps of other sessions.
+/// The futures own a clone of the server and can be spawned on any executor (e.g., tokio)
+///
+pub struct AsyncMerchantServer<D: StateDatabase + Send> {
+    server: MerchantServer<D>,
+    io_pool: Arc<BlockingPool>,
+    mpc_pool: Arc<BlockingPool>,
+}
+
+// clones share the same server and pools
+impl<D: StateDatabase + Send> Clone for AsyncMerchantServer<D> {
+    fn clone(&self) -> Self {
+        AsyncMerchantServer {
+            server: self.server.clone(),
+            io_pool: self.io_pool.clone(),
+            mpc_pool: self.mpc_pool.clone(),
+        }
+    }
+}
+
+impl<D: StateDatabase + Send + 'static> AsyncMerchantServer<D> {
+    ///
+    /// new() - creates the pools of the facade. Every MPC holds a database connection, so the
+    /// database pool of the server should have at least mpc_threads connections
+    /// output: async merchant server
+    ///
+    pub fn new(server: MerchantServer<D>, mpc_threads: usize) -> Result<Self, ZkChannelError> {
+        let io_pool = BlockingPool::new(server.get_db_pool().size())?;
+        let mpc_pool = BlockingPool::new(mpc_threads)?;
+        Ok(AsyncMerchantServer {
+            server: server,
+            io_pool: Arc::new(io_pool),
+            mpc_pool: Arc::new(mpc_pool),
+        })
+    }
+
+    pub fn get_server(&self) -> &MerchantServer<D> {
+        &self.server
+    }
+
+    ///
+    /// database() - async access to the database pool of the server (e.g., to check the status of
+    /// a channel between payments)
+    /// output: async database that shares the pool of the server
+    ///
+    pub fn database(&self) -> Result<AsyncDatabase<D>, ZkChannelError> {
+        AsyncDatabase::from_shared_pool(self.server.share_db_pool())
+    }
+
+    // see MerchantServer::prepare()
+    pub fn prepare(
+        &self,
+        session_id: [u8; 16],
+        nonce: [u8; NONCE_LEN],
+        rev_lock_com: [u8; 32],
+        amount: i64,
+        justification: Option<String>,
+    ) -> ZkFuture<[u8; 32]> {
+        let server = self.server.clone();
+        self.io_pool
+            .run(move || server.prepare(session_id, nonce, rev_lock_com, amount, justification))
+    }
+
+    // see MerchantServer::update()
+    pub fn update(
+        &self,
+        session_id: [u8; 16],
+        pay_token_mask_com: [u8; 32],
+        dest_ip: String,
+        mpc_port: i32,
+    ) -> ZkFuture<bool> {
+        let server = self.server.clone();
+        self.mpc_pool
+            .run(move || server.update(session_id, pay_token_mask_com, &dest_ip, mpc_port))
+    }
+
+    // see MerchantServer::confirm()
+    pub fn confirm(&self, session_id: [u8; 16], mpc_result: bool) -> ZkFuture<MaskedTxMPCInputs> {
+        let server = self.server.clone();
+        self.io_pool
+            .run(move || server.confirm(session_id, mpc_result))
+    }
+
+    // see MerchantServer::validate_rev_lock()
+    pub fn validate_rev_lock(
+        &self,
+        session_id: [u8; 16],
+        rev_state: RevokedState,
+    ) -> ZkFuture<([u8; 32], [u8; 16])> {
+        let server = self.server.clone();
+        self.io_pool
+            .run(move || server.validate_rev_lock(session_id, rev_state))
+    }
+}
+
+///
+/// pay_update_customer() - runs the customer side of the MPC (see mpc::pay_update_customer()) on
+/// the given pool. The future owns the customer state while the MPC runs and hands it back with
+/// the result of the MPC, since the state is updated even if the MPC fails. This is the only
+/// customer step with an async variant: the others (e.g., pay_prepare_customer() or
+/// pay_unmask_sigs_customer()) only update the customer state in memory and never block
+/// output: future that resolves to the customer state and the result of the MPC
+///
+pub fn pay_update_customer(
+    mpc_pool: &BlockingPool,
+    channel_state: ChannelMPCState,
+    channel_token: ChannelMPCToken,
+    s0: State,
+    s1: State,
+    pay_token_mask_com: [u8; 32],
+    rev_lock_com: [u8; 32],
+    amount: i64,
+    mut cust_state: CustomerMPCState,
+) -> ZkFuture<(CustomerMPCState, Result<bool, ZkChannelError>)> {
+    mpc_pool.run(move || {
+        let result = mpc::pay_update_customer(
+            &channel_state,
+            &channel_token,
+            s0,
+            s1,
+            pay_token_mask_com,
+            rev_lock_com,
+            amount,
+            &mut cust_state,
+            ptr::null_mut(),
+            None,
+            None,
+        );
+        Ok((cust_state, result))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_database::AsyncStateDatabase;
+    use database::RedisDatabase;
+    use futures::future;
+    use pool::DatabasePool;
+    use rand::RngCore;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_async_prepare_with_same_nonce() {
+        assert_send_sync::<AsyncMerchantServer<RedisDatabase>>();
+
+        let rng = &mut rand::thread_rng();
+        let db_url = "redis://127.0.0.1/".to_string();
+        let mut channel_state = mpc::ChannelMPCState::new(
+            String::from("Channel A -> B"),
+            1487,
+            546,
+            546,
+            1000,
+            false,
+            mpc::BitcoinNetworkType::Testnet,
+        );
+        let merch_state = mpc::init_merchant(rng, db_url.clone(), &mut channel_state, "Bob");
+        let pool = DatabasePool::<RedisDatabase>::connect("asyncservertest", db_url, 4).unwrap();
+        pool.get().clear_state();
+        let server = MerchantServer::new(pool, channel_state, merch_state);
+        let server = AsyncMerchantServer::new(server, 2).unwrap();
+        let db = server.database().unwrap();
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        // the unlink payment (amount 0) is only allowed with a nonce in the unlink set
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+        assert!(rt
+            .block_on(db.update_unlink_set(hex::encode(nonce)))
+            .unwrap());
+
+        // a single payment can start with a nonce, however many sessions try at once
+        let sessions = (0..8)
+            .map(|_| {
+                let mut session_id = [0u8; 16];
+                rng.fill_bytes(&mut session_id);
+                rt.spawn(server.prepare(session_id, nonce, [1u8; 32], 0, None))
+            })
+            .collect::<Vec<_>>();
+        let started = rt
+            .block_on(future::join_all(sessions))
+            .into_iter()
+            .filter(|r| r.as_ref().unwrap().is_ok())
+            .count();
+        assert_eq!(started, 1);
+        server.get_server().get_db_pool().get().clear_state();
+    }
+}
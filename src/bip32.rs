@@ -0,0 +1,284 @@
+use super::*;
+use channels_mpc::BitcoinNetworkType;
+use errors::ZkChannelError;
+use hmac::{Hmac, Mac};
+use secp256k1::{PublicKey, SecretKey};
+use sha2::Sha512;
+use util::hash_to_slice;
+
+type HmacSha512 = Hmac<Sha512>;
+
+pub const HARDENED_INDEX: u32 = 0x8000_0000;
+// purpose of the zkchannels key hierarchy: m/purpose'/coin_type'/role'/index'/key'
+pub const KEY_PURPOSE: u32 = 1000;
+pub const CUSTOMER_ROLE: u32 = 0;
+pub const MERCHANT_ROLE: u32 = 1;
+
+const CUST_ESCROW_KEY: u32 = 0;
+const CUST_PAYOUT_KEY: u32 = 1;
+const MERCH_ESCROW_KEY: u32 = 0;
+const MERCH_PAYOUT_KEY: u32 = 1;
+const MERCH_DISPUTE_KEY: u32 = 2;
+const MERCH_HMAC_KEY: u32 = 3;
+const MERCH_HMAC_KEY_R: u32 = 4;
+
+///
+/// ExtendedSecretKey - BIP32 extended private key. Only hardened derivation is supported,
+/// so leaking a derived key never exposes its parent or siblings.
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExtendedSecretKey {
+    pub secret_key: [u8; 32],
+    pub chain_code: [u8; 32],
+}
+
+impl ExtendedSecretKey {
+    fn from_hmac(key: &[u8], data: &[u8]) -> Self {
+        let mut mac = HmacSha512::new_varkey(key).expect("HMAC can take key of any size");
+        mac.input(data);
+        let i = mac.result().code();
+        let mut secret_key = [0u8; 32];
+        let mut chain_code = [0u8; 32];
+        secret_key.copy_from_slice(&i[..32]);
+        chain_code.copy_from_slice(&i[32..]);
+        ExtendedSecretKey {
+            secret_key,
+            chain_code,
+        }
+    }
+
+    pub fn new_master(seed: &[u8]) -> Result<Self, ZkChannelError> {
+        if seed.len() < 16 || seed.len() > 64 {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "seed must be between 16 and 64 bytes: {}",
+                seed.len()
+            )));
+        }
+        let master = ExtendedSecretKey::from_hmac(b"Bitcoin seed", seed);
+        handle_error_util!(
+            SecretKey::from_slice(&master.secret_key),
+            ZkChannelError::InvalidInput
+        );
+        Ok(master)
+    }
+
+    ///
+    /// derive_hardened_child() - derives the hardened child at index (the hardened bit is added here)
+    /// output: child extended secret key
+    ///
+    pub fn derive_hardened_child(&self, index: u32) -> Result<Self, ZkChannelError> {
+        if index >= HARDENED_INDEX {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "child index out of range: {}",
+                index
+            )));
+        }
+        let mut data = vec![0x00];
+        data.extend_from_slice(&self.secret_key);
+        data.extend_from_slice(&(index | HARDENED_INDEX).to_be_bytes());
+        let i = ExtendedSecretKey::from_hmac(&self.chain_code, &data);
+
+        // k_child = IL + k_par (mod n), invalid if IL >= n or the sum is zero
+        let mut sk = handle_error_util!(
+            SecretKey::from_slice(&i.secret_key),
+            ZkChannelError::InvalidInput
+        );
+        handle_error_util!(
+            sk.add_assign(&self.secret_key),
+            ZkChannelError::InvalidInput
+        );
+        let mut secret_key = [0u8; 32];
+        secret_key.copy_from_slice(&sk[..]);
+        Ok(ExtendedSecretKey {
+            secret_key,
+            chain_code: i.chain_code,
+        })
+    }
+
+    pub fn derive_path(&self, path: &[u32]) -> Result<Self, ZkChannelError> {
+        let mut key = self.clone();
+        for index in path.iter() {
+            key = key.derive_hardened_child(*index)?;
+        }
+        Ok(key)
+    }
+
+    pub fn get_public_key(&self) -> PublicKey {
+        get_public_key(&self.secret_key)
+    }
+}
+
+// derived keys are always valid secret keys
+fn get_public_key(sk: &[u8; 32]) -> PublicKey {
+    let secp = secp256k1::Secp256k1::new();
+    PublicKey::from_secret_key(&secp, &SecretKey::from_slice(sk).unwrap())
+}
+
+fn get_coin_type(network: BitcoinNetworkType) -> u32 {
+    match network {
+        BitcoinNetworkType::Mainnet => 0,
+        BitcoinNetworkType::Testnet | BitcoinNetworkType::Regtest => 1,
+    }
+}
+
+///
+/// get_derivation_path() - path of the keys of a customer channel or merchant (all hardened)
+/// output: m/purpose'/coin_type'/role'/index'
+///
+pub fn get_derivation_path(network: BitcoinNetworkType, role: u32, index: u32) -> Vec<u32> {
+    vec![KEY_PURPOSE, get_coin_type(network), role, index]
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CustomerKeys {
+    pub sk_c: [u8; 32],
+    pub payout_sk: [u8; 32],
+}
+
+impl CustomerKeys {
+    pub fn get_pk_c(&self) -> PublicKey {
+        get_public_key(&self.sk_c)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MerchantKeys {
+    pub sk_m: [u8; 32],
+    pub payout_sk: [u8; 32],
+    pub dispute_sk: [u8; 32],
+    pub hmac_key: [u8; 64],
+    pub hmac_key_r: [u8; 16],
+}
+
+impl MerchantKeys {
+    pub fn get_pk_m(&self) -> PublicKey {
+        get_public_key(&self.sk_m)
+    }
+
+    pub fn get_payout_pk(&self) -> PublicKey {
+        get_public_key(&self.payout_sk)
+    }
+
+    pub fn get_dispute_pk(&self) -> PublicKey {
+        get_public_key(&self.dispute_sk)
+    }
+
+    // commitment to the hmac key that is published in the channel state
+    pub fn get_key_com(&self) -> [u8; 32] {
+        let mut key_com_buf = self.hmac_key.to_vec();
+        key_com_buf.extend_from_slice(&self.hmac_key_r);
+        hash_to_slice(&key_com_buf)
+    }
+}
+
+///
+/// derive_customer_keys() - derives the escrow and payout keys of the customer's channel_index-th channel
+/// output: customer keys
+///
+pub fn derive_customer_keys(
+    seed: &[u8],
+    network: BitcoinNetworkType,
+    channel_index: u32,
+) -> Result<CustomerKeys, ZkChannelError> {
+    let channel_key = ExtendedSecretKey::new_master(seed)?.derive_path(&get_derivation_path(
+        network,
+        CUSTOMER_ROLE,
+        channel_index,
+    ))?;
+    Ok(CustomerKeys {
+        sk_c: channel_key
+            .derive_hardened_child(CUST_ESCROW_KEY)?
+            .secret_key,
+        payout_sk: channel_key
+            .derive_hardened_child(CUST_PAYOUT_KEY)?
+            .secret_key,
+    })
+}
+
+///
+/// derive_merchant_keys() - derives the escrow, payout and dispute keys and the hmac key of a merchant
+/// output: merchant keys
+///
+pub fn derive_merchant_keys(
+    seed: &[u8],
+    network: BitcoinNetworkType,
+    merch_index: u32,
+) -> Result<MerchantKeys, ZkChannelError> {
+    let merch_key = ExtendedSecretKey::new_master(seed)?.derive_path(&get_derivation_path(
+        network,
+        MERCHANT_ROLE,
+        merch_index,
+    ))?;
+    // the 512-bit hmac key is the full extended key (secret key and chain code)
+    let hmac_node = merch_key.derive_hardened_child(MERCH_HMAC_KEY)?;
+    let mut hmac_key = [0u8; 64];
+    hmac_key[..32].copy_from_slice(&hmac_node.secret_key);
+    hmac_key[32..].copy_from_slice(&hmac_node.chain_code);
+    let mut hmac_key_r = [0u8; 16];
+    hmac_key_r.copy_from_slice(
+        &merch_key
+            .derive_hardened_child(MERCH_HMAC_KEY_R)?
+            .secret_key[..16],
+    );
+    Ok(MerchantKeys {
+        sk_m: merch_key
+            .derive_hardened_child(MERCH_ESCROW_KEY)?
+            .secret_key,
+        payout_sk: merch_key
+            .derive_hardened_child(MERCH_PAYOUT_KEY)?
+            .secret_key,
+        dispute_sk: merch_key
+            .derive_hardened_child(MERCH_DISPUTE_KEY)?
+            .secret_key,
+        hmac_key,
+        hmac_key_r,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bip32_vector_hardened_derivation() {
+        // BIP32 test vector 1: m and m/0'
+        let seed = hex::decode("000102030405060708090a0b0c0d0e0f").unwrap();
+        let master = ExtendedSecretKey::new_master(&seed).unwrap();
+        assert_eq!(
+            hex::encode(master.secret_key),
+            "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35"
+        );
+        assert_eq!(
+            hex::encode(master.chain_code),
+            "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508"
+        );
+        let child = master.derive_path(&[0]).unwrap();
+        assert_eq!(
+            hex::encode(child.secret_key),
+            "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea"
+        );
+        assert_eq!(
+            hex::encode(child.chain_code),
+            "47fdacbd0f1097043b78c63c20c34ef4ed9a111d980047ad16282c7ae6236141"
+        );
+
+        assert!(master.derive_hardened_child(HARDENED_INDEX).is_err());
+        assert!(ExtendedSecretKey::new_master(&[0u8; 8]).is_err());
+
+        // keys are separated by network and channel index
+        let keys = derive_customer_keys(&seed, BitcoinNetworkType::Testnet, 0).unwrap();
+        assert_eq!(
+            keys,
+            derive_customer_keys(&seed, BitcoinNetworkType::Regtest, 0).unwrap()
+        );
+        assert_ne!(
+            keys,
+            derive_customer_keys(&seed, BitcoinNetworkType::Mainnet, 0).unwrap()
+        );
+        assert_ne!(
+            keys,
+            derive_customer_keys(&seed, BitcoinNetworkType::Testnet, 1).unwrap()
+        );
+        assert_ne!(keys.sk_c, keys.payout_sk);
+    }
+}
@@ -0,0 +1,913 @@
+use super::*;
+use channels_mpc::{
+    parse_cust_close_transaction, parse_transaction, ChannelMPCState, ChannelMPCToken,
+    ChannelStatus, CustomerMPCState, MerchantMPCState,
+};
+use database::StateDatabase;
+use errors::ZkChannelError;
+use splice::EscrowSpliceTx;
+use util::hash_to_slice;
+use watchtower::MockChain;
+use zkchan_tx::fixed_size_array::FixedSizeArray32;
+
+// number of confirmations after which a transaction is considered final
+pub const DEFAULT_MIN_DEPTH: u64 = 6;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BlockHeader {
+    pub height: u64,
+    pub block_hash: FixedSizeArray32,
+    pub prev_block_hash: FixedSizeArray32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutPoint {
+    pub txid_be: FixedSizeArray32,
+    pub index: u32,
+}
+
+impl OutPoint {
+    pub fn new(txid_be: [u8; 32], index: u32) -> Self {
+        OutPoint {
+            txid_be: FixedSizeArray32(txid_be),
+            index,
+        }
+    }
+}
+
+/// A confirmed transaction that spends a watched outpoint.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpendingTx {
+    pub txid_be: [u8; 32],
+    pub raw_tx: Vec<u8>,
+    pub height: u64,
+}
+
+///
+/// ChainSource - view of the best chain that the monitor needs: the tip, the confirmation height
+/// of a transaction and the confirmed transaction (if any) that spends an outpoint
+///
+pub trait ChainSource {
+    fn get_best_block_header(&mut self) -> Result<BlockHeader, ZkChannelError>;
+    fn get_block_header(&mut self, height: u64) -> Result<BlockHeader, ZkChannelError>;
+    fn get_transaction_height(&mut self, txid_be: &[u8; 32])
+        -> Result<Option<u64>, ZkChannelError>;
+    fn get_spending_transaction(
+        &mut self,
+        outpoint: &OutPoint,
+    ) -> Result<Option<SpendingTx>, ZkChannelError>;
+}
+
+impl MockChain {
+    ///
+    /// get_block_header() - the mock block hash commits to the previous block hash and the txids of the block
+    /// output: block header at the given height
+    ///
+    pub fn get_block_header(&self, height: u64) -> Result<BlockHeader, ZkChannelError> {
+        if height >= self.blocks.len() as u64 {
+            return Err(ZkChannelError::NotFound(format!(
+                "no block at height: {}",
+                height
+            )));
+        }
+        let mut prev_block_hash = [0u8; 32];
+        let mut block_hash = [0u8; 32];
+        for txs in self.blocks[..=height as usize].iter() {
+            prev_block_hash = block_hash;
+            let mut preimage = prev_block_hash.to_vec();
+            for raw_tx in txs.iter() {
+                preimage.extend_from_slice(&hash_to_slice(raw_tx));
+            }
+            block_hash = hash_to_slice(&preimage);
+        }
+        Ok(BlockHeader {
+            height,
+            block_hash: FixedSizeArray32(block_hash),
+            prev_block_hash: FixedSizeArray32(prev_block_hash),
+        })
+    }
+}
+
+impl ChainSource for MockChain {
+    fn get_best_block_header(&mut self) -> Result<BlockHeader, ZkChannelError> {
+        if self.blocks.is_empty() {
+            return Err(ZkChannelError::NotFound(String::from(
+                "mock chain does not have any blocks",
+            )));
+        }
+        MockChain::get_block_header(self, self.blocks.len() as u64 - 1)
+    }
+
+    fn get_block_header(&mut self, height: u64) -> Result<BlockHeader, ZkChannelError> {
+        MockChain::get_block_header(self, height)
+    }
+
+    fn get_transaction_height(
+        &mut self,
+        txid_be: &[u8; 32],
+    ) -> Result<Option<u64>, ZkChannelError> {
+        for (height, txs) in self.blocks.iter().enumerate() {
+            for raw_tx in txs.iter() {
+                match parse_transaction(raw_tx) {
+                    Ok(tx) if tx.txid_be == *txid_be => return Ok(Some(height as u64)),
+                    _ => {}
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    fn get_spending_transaction(
+        &mut self,
+        outpoint: &OutPoint,
+    ) -> Result<Option<SpendingTx>, ZkChannelError> {
+        for (height, txs) in self.blocks.iter().enumerate() {
+            for raw_tx in txs.iter() {
+                let tx = match parse_transaction(raw_tx) {
+                    Ok(t) => t,
+                    Err(_) => continue,
+                };
+                if tx.outpoints.contains(&(outpoint.txid_be.0, outpoint.index)) {
+                    return Ok(Some(SpendingTx {
+                        txid_be: tx.txid_be,
+                        raw_tx: raw_tx.clone(),
+                        height: height as u64,
+                    }));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+///
+/// MonitoredChannel - the outpoints of a channel that can be spent by a closing transaction
+/// (output 0 of the escrow-tx and of the merch-close-tx) and the timelock of its close outputs.
+/// During a top-up, also the txid of the splice-tx that moves the escrow
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MonitoredChannel {
+    pub escrow_txid_be: FixedSizeArray32,
+    pub merch_txid_be: FixedSizeArray32,
+    pub self_delay: u16,
+    #[serde(default)]
+    pub splice_txid_be: Option<FixedSizeArray32>,
+}
+
+impl MonitoredChannel {
+    pub fn new(channel_state: &ChannelMPCState, channel_token: &ChannelMPCToken) -> Self {
+        let mut escrow_txid_be = channel_token.escrow_txid.0.clone();
+        escrow_txid_be.reverse();
+        let mut merch_txid_be = channel_token.merch_txid.0.clone();
+        merch_txid_be.reverse();
+        MonitoredChannel {
+            escrow_txid_be: FixedSizeArray32(escrow_txid_be),
+            merch_txid_be: FixedSizeArray32(merch_txid_be),
+            self_delay: channel_state.self_delay,
+            splice_txid_be: None,
+        }
+    }
+
+    // the channel is spliced (instead of mutually closed) once this splice-tx confirms
+    pub fn with_splice_tx(mut self, splice_tx: &EscrowSpliceTx) -> Self {
+        self.splice_txid_be = Some(FixedSizeArray32(splice_tx.get_txid().0));
+        self
+    }
+}
+
+fn get_depth(tip_height: u64, height: u64) -> u64 {
+    if height > tip_height {
+        return 0;
+    }
+    tip_height - height + 1
+}
+
+// the statuses that still have to be applied to reach the end of the history. The close
+// statuses a party sets locally (before its close tx is seen on chain, including a signed
+// mutual close) are skipped if the other party's close tx confirmed instead.
+fn get_pending_transitions(
+    cur_status: &ChannelStatus,
+    history: &Vec<ChannelStatus>,
+) -> Vec<ChannelStatus> {
+    if let Some(i) = history.iter().position(|s| s == cur_status) {
+        return history[i + 1..].to_vec();
+    }
+    match cur_status {
+        ChannelStatus::None => history.clone(),
+        ChannelStatus::CustomerInitClose
+        | ChannelStatus::MerchantInitClose
+        | ChannelStatus::MutualClose => {
+            match history
+                .iter()
+                .position(|s| *s == ChannelStatus::PendingClose)
+            {
+                Some(i) => history[i..].to_vec(),
+                None => Vec::new(),
+            }
+        }
+        _ => Vec::new(),
+    }
+}
+
+// after a reorg, a status that was derived from the chain and is no longer part of the
+// history goes back to the last status the best chain still supports
+fn get_rollback_status(
+    cur_status: &ChannelStatus,
+    history: &Vec<ChannelStatus>,
+) -> Option<ChannelStatus> {
+    if history.contains(cur_status) {
+        return None;
+    }
+    match cur_status {
+        ChannelStatus::Open
+        | ChannelStatus::PendingClose
+        | ChannelStatus::ConfirmedClose
+        | ChannelStatus::Disputed
+        | ChannelStatus::Spliced => history.last().cloned(),
+        _ => None,
+    }
+}
+
+///
+/// ChainMonitor - derives the status of a channel from the chain and applies the transitions to
+/// the customer or merchant state. Keeps the tip each channel was last synced at to detect reorgs
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChainMonitor {
+    min_depth: u64,
+    #[serde(default)]
+    synced_tips: HashMap<String, BlockHeader>,
+}
+
+impl ChainMonitor {
+    pub fn new(min_depth: u64) -> Result<Self, ZkChannelError> {
+        if min_depth == 0 {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "min_depth must be at least 1",
+            )));
+        }
+        Ok(ChainMonitor {
+            min_depth,
+            synced_tips: HashMap::new(),
+        })
+    }
+
+    pub fn get_min_depth(&self) -> u64 {
+        self.min_depth
+    }
+
+    ///
+    /// is_reorged() - checks that the tip the channel was last synced at is still on the best chain
+    /// output: true if the block at that height changed or the best chain got shorter
+    ///
+    pub fn is_reorged(
+        &self,
+        source: &mut dyn ChainSource,
+        channel: &MonitoredChannel,
+        best: &BlockHeader,
+    ) -> Result<bool, ZkChannelError> {
+        let synced_tip = match self
+            .synced_tips
+            .get(&hex::encode(&channel.escrow_txid_be.0))
+        {
+            Some(h) => h,
+            None => return Ok(false),
+        };
+        if best.height < synced_tip.height {
+            return Ok(true);
+        }
+        let block_hash = if best.height == synced_tip.height + 1 {
+            best.prev_block_hash.clone()
+        } else {
+            source.get_block_header(synced_tip.height)?.block_hash
+        };
+        Ok(block_hash != synced_tip.block_hash)
+    }
+
+    fn set_synced_tip(&mut self, channel: &MonitoredChannel, best: BlockHeader) {
+        self.synced_tips
+            .insert(hex::encode(&channel.escrow_txid_be.0), best);
+    }
+
+    // outcome of a confirmed cust-close-tx: disputed if the to_customer output was spent before
+    // the timelock expired (only possible with the rev_secret), closed once the timelock expired
+    fn get_cust_close_history(
+        &self,
+        source: &mut dyn ChainSource,
+        close_tx: &SpendingTx,
+        self_delay: u16,
+        tip_height: u64,
+    ) -> Result<Vec<ChannelStatus>, ZkChannelError> {
+        let close_tx_info = parse_cust_close_transaction(&close_tx.raw_tx)?;
+        let to_customer = OutPoint::new(close_tx.txid_be, close_tx_info.to_customer_index);
+        let timelock_height = close_tx.height + self_delay as u64;
+        match source.get_spending_transaction(&to_customer)? {
+            Some(claim_tx) if claim_tx.height < timelock_height => {
+                Ok(vec![ChannelStatus::Disputed])
+            }
+            Some(_) => Ok(vec![ChannelStatus::ConfirmedClose]),
+            None if tip_height >= timelock_height => Ok(vec![ChannelStatus::ConfirmedClose]),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    ///
+    /// get_status_history() - replays the transactions of the channel that are on chain
+    /// output: the statuses the channel went through (starting at PendingOpen)
+    ///
+    pub fn get_status_history(
+        &self,
+        source: &mut dyn ChainSource,
+        channel: &MonitoredChannel,
+    ) -> Result<Vec<ChannelStatus>, ZkChannelError> {
+        let tip_height = source.get_best_block_header()?.height;
+        let mut history = vec![ChannelStatus::PendingOpen];
+
+        let escrow_height = match source.get_transaction_height(&channel.escrow_txid_be.0)? {
+            Some(h) => h,
+            None => return Ok(history),
+        };
+        if get_depth(tip_height, escrow_height) < self.min_depth {
+            return Ok(history);
+        }
+        history.push(ChannelStatus::Open);
+
+        let close_tx =
+            match source.get_spending_transaction(&OutPoint::new(channel.escrow_txid_be.0, 0))? {
+                Some(t) => t,
+                None => return Ok(history),
+            };
+        let is_confirmed = get_depth(tip_height, close_tx.height) >= self.min_depth;
+        if close_tx.txid_be == channel.merch_txid_be.0 {
+            history.push(ChannelStatus::MerchantInitClose);
+            if !is_confirmed {
+                return Ok(history);
+            }
+            history.push(ChannelStatus::PendingClose);
+            // the customer can respond with a cust-close-tx until the merchant claims after self_delay
+            let merch_outpoint = OutPoint::new(close_tx.txid_be, 0);
+            match source.get_spending_transaction(&merch_outpoint)? {
+                Some(cust_close_tx) => {
+                    if parse_cust_close_transaction(&cust_close_tx.raw_tx).is_ok() {
+                        if get_depth(tip_height, cust_close_tx.height) >= self.min_depth {
+                            history.extend(self.get_cust_close_history(
+                                source,
+                                &cust_close_tx,
+                                channel.self_delay,
+                                tip_height,
+                            )?);
+                        }
+                    } else {
+                        // merchant claimed the merch-close-tx after the timelock
+                        history.push(ChannelStatus::ConfirmedClose);
+                    }
+                }
+                None => {
+                    if tip_height >= close_tx.height + channel.self_delay as u64 {
+                        history.push(ChannelStatus::ConfirmedClose);
+                    }
+                }
+            }
+        } else if parse_cust_close_transaction(&close_tx.raw_tx).is_ok() {
+            history.push(ChannelStatus::CustomerInitClose);
+            if !is_confirmed {
+                return Ok(history);
+            }
+            history.push(ChannelStatus::PendingClose);
+            history.extend(self.get_cust_close_history(
+                source,
+                &close_tx,
+                channel.self_delay,
+                tip_height,
+            )?);
+        } else if Some(close_tx.txid_be) == channel.splice_txid_be.map(|t| t.0) {
+            // the escrow moved to the new escrow of a top-up
+            if is_confirmed {
+                history.push(ChannelStatus::Spliced);
+            }
+        } else {
+            // any other spend of the escrow is signed by both parties
+            history.push(ChannelStatus::MutualClose);
+            if is_confirmed {
+                history.push(ChannelStatus::ConfirmedClose);
+            }
+        }
+        Ok(history)
+    }
+
+    ///
+    /// update_customer() - applies the transitions observed on chain to the customer state
+    /// (after a reorg, the statuses derived from blocks that were reorged out are rolled back first)
+    /// output: the new channel status
+    ///
+    pub fn update_customer(
+        &mut self,
+        source: &mut dyn ChainSource,
+        channel: &MonitoredChannel,
+        cust_state: &mut CustomerMPCState,
+    ) -> Result<ChannelStatus, ZkChannelError> {
+        let best = source.get_best_block_header()?;
+        let is_reorged = self.is_reorged(source, channel, &best)?;
+        let history = self.get_status_history(source, channel)?;
+        if is_reorged {
+            if let Some(status) = get_rollback_status(&cust_state.get_channel_status(), &history) {
+                cust_state.rollback_channel_status(status)?;
+            }
+        }
+        for status in get_pending_transitions(&cust_state.get_channel_status(), &history) {
+            cust_state.change_channel_status(status)?;
+        }
+        self.set_synced_tip(channel, best);
+        Ok(cust_state.get_channel_status())
+    }
+
+    ///
+    /// update_merchant() - applies the transitions observed on chain to the channel status in the merchant db
+    /// (after a reorg, the statuses derived from blocks that were reorged out are rolled back first)
+    /// output: the new channel status
+    ///
+    pub fn update_merchant(
+        &mut self,
+        source: &mut dyn ChainSource,
+        db: &mut dyn StateDatabase,
+        channel: &MonitoredChannel,
+        merch_state: &MerchantMPCState,
+    ) -> Result<ChannelStatus, ZkChannelError> {
+        let escrow_txid_be = channel.escrow_txid_be.0;
+        let best = source.get_best_block_header()?;
+        let is_reorged = self.is_reorged(source, channel, &best)?;
+        let history = self.get_status_history(source, channel)?;
+        if is_reorged {
+            let cur_status = merch_state.get_channel_status(db, escrow_txid_be)?;
+            if let Some(status) = get_rollback_status(&cur_status, &history) {
+                merch_state.rollback_channel_status(db, escrow_txid_be, status)?;
+            }
+        }
+        let cur_status = merch_state.get_channel_status(db, escrow_txid_be)?;
+        for status in get_pending_transitions(&cur_status, &history) {
+            merch_state.change_channel_status(db, escrow_txid_be, status)?;
+        }
+        self.set_synced_tip(channel, best);
+        merch_state.get_channel_status(db, escrow_txid_be)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channels_mpc::BitcoinNetworkType;
+    use database::HashMapDatabase;
+    use rand::SeedableRng;
+    use rand_xorshift::XorShiftRng;
+
+    // legacy (non-segwit) tx with the given inputs and outputs
+    fn build_tx(inputs: Vec<([u8; 32], u32)>, outputs: Vec<(i64, Vec<u8>)>) -> Vec<u8> {
+        let mut tx = vec![0x02, 0x00, 0x00, 0x00];
+        tx.push(inputs.len() as u8);
+        for (txid_be, index) in inputs.iter() {
+            tx.extend_from_slice(txid_be);
+            tx.extend_from_slice(&index.to_le_bytes());
+            tx.push(0x00);
+            tx.extend_from_slice(&[0xff; 4]);
+        }
+        tx.push(outputs.len() as u8);
+        for (amount, script) in outputs.iter() {
+            tx.extend_from_slice(&amount.to_le_bytes());
+            tx.push(script.len() as u8);
+            tx.extend_from_slice(script);
+        }
+        tx.extend_from_slice(&[0x00; 4]);
+        tx
+    }
+
+    fn build_cust_close_tx(prevout_txid_be: [u8; 32]) -> Vec<u8> {
+        let mut to_customer = vec![0x00, 0x20];
+        to_customer.extend_from_slice(&[0x41; 32]);
+        let mut op_return = vec![0x6a, 0x41];
+        op_return.extend_from_slice(&[0x42; 65]);
+        build_tx(
+            vec![(prevout_txid_be, 0)],
+            vec![(9000, to_customer), (0, op_return)],
+        )
+    }
+
+    fn txid(raw_tx: &Vec<u8>) -> [u8; 32] {
+        parse_transaction(raw_tx).unwrap().txid_be
+    }
+
+    #[test]
+    fn test_chain_monitor_status_transitions() {
+        let mut rng = XorShiftRng::seed_from_u64(0x2f61a9c3d07e5b48);
+        let self_delay = 3;
+        let escrow_tx = build_tx(vec![([0x01; 32], 0)], vec![(10000, vec![0x51])]);
+        let merch_close_tx = build_tx(vec![(txid(&escrow_tx), 0)], vec![(9000, vec![0x52])]);
+        let channel = MonitoredChannel {
+            escrow_txid_be: FixedSizeArray32(txid(&escrow_tx)),
+            merch_txid_be: FixedSizeArray32(txid(&merch_close_tx)),
+            self_delay,
+            splice_txid_be: None,
+        };
+        let mut monitor = ChainMonitor::new(2).unwrap();
+        let mut merch_monitor = monitor.clone();
+        assert!(ChainMonitor::new(0).is_err());
+
+        let mut channel_state = ChannelMPCState::new(
+            String::from("Channel A <-> B"),
+            self_delay,
+            546,
+            546,
+            1000,
+            false,
+            BitcoinNetworkType::Testnet,
+        );
+        let mut db = HashMapDatabase::new("", "".to_string()).unwrap();
+        let merch_state = MerchantMPCState::new(
+            &mut rng,
+            "".to_string(),
+            &mut channel_state,
+            String::from("Merchant"),
+        );
+        db.update_channel_status(
+            &hex::encode(&channel.escrow_txid_be.0),
+            &ChannelStatus::PendingOpen,
+        )
+        .unwrap();
+        let mut cust_state = CustomerMPCState::new(
+            &mut rng,
+            10000,
+            0,
+            1000,
+            String::from("Customer"),
+            BitcoinNetworkType::Testnet,
+        );
+        cust_state
+            .change_channel_status(ChannelStatus::PendingOpen)
+            .unwrap();
+
+        // escrow-tx needs min_depth confirmations
+        let mut chain = MockChain::new();
+        chain.mine_block(vec![escrow_tx.clone()]);
+        assert_eq!(
+            monitor
+                .update_customer(&mut chain, &channel, &mut cust_state)
+                .unwrap(),
+            ChannelStatus::PendingOpen
+        );
+        chain.mine_block(Vec::new());
+        assert_eq!(
+            monitor
+                .update_customer(&mut chain, &channel, &mut cust_state)
+                .unwrap(),
+            ChannelStatus::Open
+        );
+
+        // merch-close-tx followed by a cust-close-tx whose to_customer output is spent before the timelock
+        let cust_close_tx = build_cust_close_tx(txid(&merch_close_tx));
+        let dispute_tx = build_tx(vec![(txid(&cust_close_tx), 0)], vec![(8000, vec![0x53])]);
+        let mut disputed_chain = chain.clone();
+        disputed_chain.mine_block(vec![merch_close_tx.clone()]);
+        assert_eq!(
+            merch_monitor
+                .update_merchant(&mut disputed_chain, &mut db, &channel, &merch_state)
+                .unwrap(),
+            ChannelStatus::MerchantInitClose
+        );
+        disputed_chain.mine_block(vec![cust_close_tx]);
+        disputed_chain.mine_block(vec![dispute_tx]);
+        assert_eq!(
+            monitor
+                .get_status_history(&mut disputed_chain, &channel)
+                .unwrap(),
+            vec![
+                ChannelStatus::PendingOpen,
+                ChannelStatus::Open,
+                ChannelStatus::MerchantInitClose,
+                ChannelStatus::PendingClose,
+                ChannelStatus::Disputed
+            ]
+        );
+        assert_eq!(
+            merch_monitor
+                .update_merchant(&mut disputed_chain, &mut db, &channel, &merch_state)
+                .unwrap(),
+            ChannelStatus::Disputed
+        );
+
+        // cust-close-tx from escrow: closed once self_delay expired (the local close status is skipped)
+        cust_state
+            .change_channel_status(ChannelStatus::MerchantInitClose)
+            .unwrap();
+        chain.mine_block(vec![build_cust_close_tx(txid(&escrow_tx))]);
+        chain.mine_block(Vec::new());
+        assert_eq!(
+            monitor
+                .update_customer(&mut chain, &channel, &mut cust_state)
+                .unwrap(),
+            ChannelStatus::PendingClose
+        );
+        chain.mine_block(Vec::new());
+        assert_eq!(
+            monitor
+                .update_customer(&mut chain, &channel, &mut cust_state)
+                .unwrap(),
+            ChannelStatus::PendingClose
+        );
+        chain.mine_block(Vec::new());
+        assert_eq!(
+            monitor
+                .update_customer(&mut chain, &channel, &mut cust_state)
+                .unwrap(),
+            ChannelStatus::ConfirmedClose
+        );
+
+        let header = chain.get_best_block_header().unwrap();
+        assert_eq!(header.height, 5);
+        assert_eq!(
+            header.prev_block_hash,
+            chain.get_block_header(4).unwrap().block_hash
+        );
+    }
+
+    fn setup_channel(
+        rng: &mut XorShiftRng,
+        self_delay: u16,
+    ) -> (
+        Vec<u8>,
+        MonitoredChannel,
+        HashMapDatabase,
+        MerchantMPCState,
+        CustomerMPCState,
+    ) {
+        let escrow_tx = build_tx(vec![([0x01; 32], 0)], vec![(10000, vec![0x51])]);
+        let merch_close_tx = build_tx(vec![(txid(&escrow_tx), 0)], vec![(9000, vec![0x52])]);
+        let channel = MonitoredChannel {
+            escrow_txid_be: FixedSizeArray32(txid(&escrow_tx)),
+            merch_txid_be: FixedSizeArray32(txid(&merch_close_tx)),
+            self_delay,
+            splice_txid_be: None,
+        };
+        let mut channel_state = ChannelMPCState::new(
+            String::from("Channel A <-> B"),
+            self_delay,
+            546,
+            546,
+            1000,
+            false,
+            BitcoinNetworkType::Testnet,
+        );
+        let mut db = HashMapDatabase::new("", "".to_string()).unwrap();
+        let merch_state = MerchantMPCState::new(
+            rng,
+            "".to_string(),
+            &mut channel_state,
+            String::from("Merchant"),
+        );
+        db.update_channel_status(
+            &hex::encode(&channel.escrow_txid_be.0),
+            &ChannelStatus::PendingOpen,
+        )
+        .unwrap();
+        let mut cust_state = CustomerMPCState::new(
+            rng,
+            10000,
+            0,
+            1000,
+            String::from("Customer"),
+            BitcoinNetworkType::Testnet,
+        );
+        cust_state
+            .change_channel_status(ChannelStatus::PendingOpen)
+            .unwrap();
+        (escrow_tx, channel, db, merch_state, cust_state)
+    }
+
+    #[test]
+    fn test_chain_monitor_mutual_close_then_unilateral_close() {
+        let mut rng = XorShiftRng::seed_from_u64(0x5c0e8a1f3b7d2469);
+        let self_delay = 2;
+        let (escrow_tx, channel, mut db, merch_state, mut cust_state) =
+            setup_channel(&mut rng, self_delay);
+        let escrow_txid_be = channel.escrow_txid_be.0;
+        let mut cust_monitor = ChainMonitor::new(1).unwrap();
+        let mut merch_monitor = ChainMonitor::new(1).unwrap();
+
+        let mut chain = MockChain::new();
+        chain.mine_block(vec![escrow_tx.clone()]);
+        assert_eq!(
+            cust_monitor
+                .update_customer(&mut chain, &channel, &mut cust_state)
+                .unwrap(),
+            ChannelStatus::Open
+        );
+        assert_eq!(
+            merch_monitor
+                .update_merchant(&mut chain, &mut db, &channel, &merch_state)
+                .unwrap(),
+            ChannelStatus::Open
+        );
+
+        // both parties signed the mutual close tx, but the customer's close tx confirms first
+        cust_state
+            .change_channel_status(ChannelStatus::MutualClose)
+            .unwrap();
+        merch_state
+            .change_channel_status(&mut db, escrow_txid_be, ChannelStatus::MutualClose)
+            .unwrap();
+        chain.mine_block(vec![build_cust_close_tx(txid(&escrow_tx))]);
+        assert_eq!(
+            cust_monitor
+                .update_customer(&mut chain, &channel, &mut cust_state)
+                .unwrap(),
+            ChannelStatus::PendingClose
+        );
+        assert_eq!(
+            merch_monitor
+                .update_merchant(&mut chain, &mut db, &channel, &merch_state)
+                .unwrap(),
+            ChannelStatus::PendingClose
+        );
+        chain.mine_block(Vec::new());
+        chain.mine_block(Vec::new());
+        assert_eq!(
+            cust_monitor
+                .update_customer(&mut chain, &channel, &mut cust_state)
+                .unwrap(),
+            ChannelStatus::ConfirmedClose
+        );
+        assert_eq!(
+            merch_monitor
+                .update_merchant(&mut chain, &mut db, &channel, &merch_state)
+                .unwrap(),
+            ChannelStatus::ConfirmedClose
+        );
+    }
+
+    #[test]
+    fn test_chain_monitor_detects_reorgs() {
+        let mut rng = XorShiftRng::seed_from_u64(0x91d4b6e03a5f7c28);
+        let self_delay = 3;
+        let (escrow_tx, channel, mut db, merch_state, mut cust_state) =
+            setup_channel(&mut rng, self_delay);
+        let escrow_txid_be = channel.escrow_txid_be.0;
+        let mut cust_monitor = ChainMonitor::new(2).unwrap();
+        let mut merch_monitor = ChainMonitor::new(2).unwrap();
+
+        // escrow-tx is reorged out after the customer saw it confirm
+        let mut chain = MockChain::new();
+        chain.mine_block(vec![escrow_tx.clone()]);
+        chain.mine_block(Vec::new());
+        assert_eq!(
+            cust_monitor
+                .update_customer(&mut chain, &channel, &mut cust_state)
+                .unwrap(),
+            ChannelStatus::Open
+        );
+        let mut reorged_chain = MockChain::new();
+        reorged_chain.mine_block(vec![build_tx(
+            vec![([0x02; 32], 0)],
+            vec![(5000, vec![0x51])],
+        )]);
+        reorged_chain.mine_block(Vec::new());
+        reorged_chain.mine_block(Vec::new());
+        let best = reorged_chain.get_best_block_header().unwrap();
+        assert!(cust_monitor
+            .is_reorged(&mut reorged_chain, &channel, &best)
+            .unwrap());
+        assert_eq!(
+            cust_monitor
+                .update_customer(&mut reorged_chain, &channel, &mut cust_state)
+                .unwrap(),
+            ChannelStatus::PendingOpen
+        );
+        assert!(!cust_monitor
+            .is_reorged(&mut reorged_chain, &channel, &best)
+            .unwrap());
+        reorged_chain.mine_block(vec![escrow_tx.clone()]);
+        reorged_chain.mine_block(Vec::new());
+        assert_eq!(
+            cust_monitor
+                .update_customer(&mut reorged_chain, &channel, &mut cust_state)
+                .unwrap(),
+            ChannelStatus::Open
+        );
+
+        // the merchant activated the channel before the escrow-tx was deep enough: no reorg, no rollback
+        merch_state
+            .change_channel_status(&mut db, escrow_txid_be, ChannelStatus::Open)
+            .unwrap();
+        let mut chain = MockChain::new();
+        chain.mine_block(vec![escrow_tx.clone()]);
+        assert_eq!(
+            merch_monitor
+                .update_merchant(&mut chain, &mut db, &channel, &merch_state)
+                .unwrap(),
+            ChannelStatus::Open
+        );
+
+        // a confirmed cust-close-tx is reorged out
+        let base_chain = chain.clone();
+        chain.mine_block(vec![build_cust_close_tx(txid(&escrow_tx))]);
+        chain.mine_block(Vec::new());
+        assert_eq!(
+            merch_monitor
+                .update_merchant(&mut chain, &mut db, &channel, &merch_state)
+                .unwrap(),
+            ChannelStatus::PendingClose
+        );
+        let mut reorged_chain = base_chain.clone();
+        reorged_chain.mine_block(Vec::new());
+        reorged_chain.mine_block(Vec::new());
+        reorged_chain.mine_block(Vec::new());
+        assert_eq!(
+            merch_monitor
+                .update_merchant(&mut reorged_chain, &mut db, &channel, &merch_state)
+                .unwrap(),
+            ChannelStatus::Open
+        );
+    }
+
+    #[test]
+    fn test_chain_monitor_splice_tx() {
+        let mut rng = XorShiftRng::seed_from_u64(0x3c6ef372fe94f82b);
+        let (escrow_tx, channel, mut db, merch_state, mut cust_state) = setup_channel(&mut rng, 3);
+        let escrow_txid_be = channel.escrow_txid_be.0;
+        let splice_tx = build_tx(
+            vec![(escrow_txid_be, 0), ([0x03; 32], 0)],
+            vec![(30000, vec![0x51])],
+        );
+        let spliced_channel = MonitoredChannel {
+            splice_txid_be: Some(FixedSizeArray32(txid(&splice_tx))),
+            ..channel.clone()
+        };
+        let mut cust_monitor = ChainMonitor::new(2).unwrap();
+        let mut merch_monitor = ChainMonitor::new(2).unwrap();
+
+        let mut chain = MockChain::new();
+        chain.mine_block(vec![escrow_tx.clone()]);
+        chain.mine_block(Vec::new());
+        let base_chain = chain.clone();
+
+        // the splice-tx is not confirmed until it has min_depth confirmations
+        chain.mine_block(vec![splice_tx.clone()]);
+        assert_eq!(
+            cust_monitor
+                .update_customer(&mut chain, &spliced_channel, &mut cust_state)
+                .unwrap(),
+            ChannelStatus::Open
+        );
+        assert_eq!(
+            merch_monitor
+                .update_merchant(&mut chain, &mut db, &spliced_channel, &merch_state)
+                .unwrap(),
+            ChannelStatus::Open
+        );
+        // without the txid of the splice-tx, the spend looks like a mutual close
+        assert_eq!(
+            cust_monitor
+                .get_status_history(&mut chain, &channel)
+                .unwrap()
+                .last(),
+            Some(&ChannelStatus::MutualClose)
+        );
+
+        chain.mine_block(Vec::new());
+        assert_eq!(
+            cust_monitor
+                .update_customer(&mut chain, &spliced_channel, &mut cust_state)
+                .unwrap(),
+            ChannelStatus::Spliced
+        );
+        assert_eq!(
+            merch_monitor
+                .update_merchant(&mut chain, &mut db, &spliced_channel, &merch_state)
+                .unwrap(),
+            ChannelStatus::Spliced
+        );
+
+        // the splice-tx is reorged out and never confirms again
+        let mut reorged_chain = base_chain.clone();
+        reorged_chain.mine_block(Vec::new());
+        reorged_chain.mine_block(Vec::new());
+        reorged_chain.mine_block(Vec::new());
+        assert_eq!(
+            cust_monitor
+                .update_customer(&mut reorged_chain, &spliced_channel, &mut cust_state)
+                .unwrap(),
+            ChannelStatus::Open
+        );
+        assert_eq!(
+            merch_monitor
+                .update_merchant(&mut reorged_chain, &mut db, &spliced_channel, &merch_state)
+                .unwrap(),
+            ChannelStatus::Open
+        );
+
+        // the old escrow of a spliced channel can still be closed on
+        merch_state
+            .change_channel_status(&mut db, escrow_txid_be, ChannelStatus::Spliced)
+            .unwrap();
+        merch_state
+            .change_channel_status(&mut db, escrow_txid_be, ChannelStatus::MerchantInitClose)
+            .unwrap();
+    }
+}
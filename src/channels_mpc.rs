@@ -1,23 +1,40 @@
 use super::*;
-use util::{compute_hash160, hash_to_slice, hmac_sign};
+use util::{compute_hash160, get_current_time, hash_to_slice, hmac_sign};
 
 use bindings::{cb_receive, cb_send, load_circuit_file, ConnType};
-use database::{MaskedMPCInputs, MaskedTxMPCInputs, SessionState, StateDatabase};
+use bip32::MerchantKeys;
+use cpfp::{p2wpkh_script, sign_cpfp_child_tx, DUST_LIMIT};
+use database::{
+    BatchCheck, BatchWrite, DbBatch, MaskedMPCInputs, MaskedTxMPCInputs, SessionState,
+    StateDatabase,
+};
+use dual_funding::{
+    compute_escrow_prevout, DualFundedEscrowTx, EscrowContribution, EscrowFundingInput,
+    FundingParty,
+};
+use errors::ZkChannelError;
+use event_log::{record_event, EventKey, EventParty, EventRecord, ProtocolEvent};
 use mpcwrapper::{mpc_build_masked_tokens_cust, mpc_build_masked_tokens_merch, CIRCUIT_FILE};
 use rand::Rng;
 use sha2::{Digest, Sha256};
+use splice::EscrowSpliceTx;
 use std::ffi::{c_void, CString};
 use std::fmt::Debug;
 use std::fmt::Display;
-use std::{env, ptr};
+use std::str::FromStr;
+use std::{cmp, env, ptr};
 use wallet::{State, NONCE_LEN};
+use watchtower::{DisputeAuthorization, DisputeMaterial, WatchedChannel};
 use zkchan_tx::fixed_size_array::{FixedSizeArray16, FixedSizeArray32, FixedSizeArray64};
 use zkchan_tx::transactions::btc::{
     completely_sign_multi_sig_transaction, create_cust_close_transaction, create_utxo_input,
     generate_customer_close_tx_helper, generate_signature_for_multi_sig_transaction,
     get_private_key, merchant_form_close_transaction,
 };
-use zkchan_tx::transactions::ClosePublicKeys;
+use zkchan_tx::transactions::{ClosePublicKeys, UtxoInput};
+use zkchan_tx::txutil::{
+    customer_sign_mutual_close_transaction, merchant_sign_mutual_close_transaction,
+};
 use zkchan_tx::{BitcoinNetwork, BitcoinTransactionParameters, Transaction};
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct NetworkConfig {
@@ -45,6 +62,9 @@ pub enum ChannelStatus {
     Disputed,
     PendingClose,
     ConfirmedClose,
+    MutualClose,
+    // the escrow was spent into a new escrow by a top-up
+    Spliced,
 }
 
 #[derive(Clone, Debug, PartialEq, Display, Serialize, Deserialize)]
@@ -54,12 +74,159 @@ pub enum PaymentStatus {
     Error,
 }
 
+/// Bitcoin network a channel was opened on. Regtest reuses the testnet
+/// transaction parameters since the two only differ in their address prefixes.
+#[derive(Clone, Copy, Debug, PartialEq, Display, Serialize, Deserialize)]
+pub enum BitcoinNetworkType {
+    Mainnet,
+    Testnet,
+    Regtest,
+}
+
+impl Default for BitcoinNetworkType {
+    // states serialized before the network was recorded were all testnet
+    fn default() -> Self {
+        BitcoinNetworkType::Testnet
+    }
+}
+
+impl FromStr for BitcoinNetworkType {
+    type Err = ZkChannelError;
+    fn from_str(s: &str) -> Result<BitcoinNetworkType, ZkChannelError> {
+        match s.to_lowercase().as_str() {
+            "mainnet" => Ok(BitcoinNetworkType::Mainnet),
+            "testnet" => Ok(BitcoinNetworkType::Testnet),
+            "regtest" => Ok(BitcoinNetworkType::Regtest),
+            _ => Err(ZkChannelError::InvalidInput(format!(
+                "unknown bitcoin network: {}",
+                s
+            ))),
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Display, Serialize, Deserialize)]
 pub enum NegativePaymentPolicy {
     REJECT,              // only positive payments are allowed in this mode
     CHECK_JUSTIFICATION, // allow negative payments, if authorization/justification presented
 }
 
+///
+/// JustificationVerifier - decides whether a justification authorizes a negative payment (refund)
+/// of amount on the state with the given nonce (used with NegativePaymentPolicy::CHECK_JUSTIFICATION)
+///
+pub trait JustificationVerifier {
+    fn verify(
+        &self,
+        db: &mut dyn StateDatabase,
+        nonce_hex: &String,
+        amount: i64,
+        justification: &String,
+    ) -> Result<bool, ZkChannelError>;
+}
+
+///
+/// RefundJustification - a refund of amount (a negative payment amount) signed by the refund authority.
+/// The refund id is chosen by the authority and is independent of the channel.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct RefundJustification {
+    pub refund_id: FixedSizeArray32,
+    pub amount: i64,
+    // unix time (in seconds) after which the refund can no longer be claimed
+    pub expiry: u64,
+    // compact signature (hex) of the refund authority
+    pub sig: String,
+}
+
+impl RefundJustification {
+    fn compute_message(refund_id: &[u8; 32], amount: i64, expiry: u64) -> secp256k1::Message {
+        let mut input = refund_id.to_vec();
+        input.extend_from_slice(&amount.to_be_bytes());
+        input.extend_from_slice(&expiry.to_be_bytes());
+        secp256k1::Message::from_slice(&hash_to_slice(&input)).unwrap()
+    }
+
+    pub fn sign(
+        authority_sk: &secp256k1::SecretKey,
+        refund_id: [u8; 32],
+        amount: i64,
+        expiry: u64,
+    ) -> Self {
+        let secp = secp256k1::Secp256k1::signing_only();
+        let msg = RefundJustification::compute_message(&refund_id, amount, expiry);
+        let sig = secp.sign(&msg, authority_sk);
+        RefundJustification {
+            refund_id: FixedSizeArray32(refund_id),
+            amount,
+            expiry,
+            sig: hex::encode(&sig.serialize_compact().to_vec()),
+        }
+    }
+
+    pub fn verify_sig(&self, authority_pk: &secp256k1::PublicKey) -> bool {
+        let sig = match hex::decode(&self.sig) {
+            Ok(s) => match secp256k1::Signature::from_compact(&s) {
+                Ok(sig) => sig,
+                Err(_) => return false,
+            },
+            Err(_) => return false,
+        };
+        let msg = RefundJustification::compute_message(&self.refund_id.0, self.amount, self.expiry);
+        let secp = secp256k1::Secp256k1::verification_only();
+        secp.verify(&msg, &sig, authority_pk).is_ok()
+    }
+}
+
+///
+/// RefundAuthorityVerifier - default verifier: the justification is a serialized RefundJustification
+/// signed by the refund authority. Each refund id can only be applied to a single state (nonce).
+///
+pub struct RefundAuthorityVerifier {
+    pub authority_pk: secp256k1::PublicKey,
+}
+
+impl JustificationVerifier for RefundAuthorityVerifier {
+    fn verify(
+        &self,
+        db: &mut dyn StateDatabase,
+        nonce_hex: &String,
+        amount: i64,
+        justification: &String,
+    ) -> Result<bool, ZkChannelError> {
+        let refund: RefundJustification = serde_json::from_str(justification)?;
+        if refund.amount != amount {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "refund justification is for amount {} but payment is {}",
+                refund.amount, amount
+            )));
+        }
+        if get_current_time() > refund.expiry {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "refund justification has expired",
+            )));
+        }
+        if !refund.verify_sig(&self.authority_pk) {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "invalid refund authority signature on justification",
+            )));
+        }
+
+        // a restarted session for the same nonce may present the refund again
+        let refund_id_hex = hex::encode(refund.refund_id.0);
+        if !db.update_refund_id_map(&refund_id_hex, nonce_hex)? {
+            let used_nonce_hex = db.get_nonce_for_refund_id(&refund_id_hex)?;
+            if !used_nonce_hex.eq_ignore_ascii_case(nonce_hex) {
+                return Err(ZkChannelError::InvalidInput(format!(
+                    "refund id has already been used: {}",
+                    refund_id_hex
+                )));
+            }
+        }
+        Ok(true)
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct ChannelMPCToken {
     pub pk_c: Option<secp256k1::PublicKey>,
@@ -68,6 +235,8 @@ pub struct ChannelMPCToken {
     // pk_m
     pub escrow_txid: FixedSizeArray32,
     pub merch_txid: FixedSizeArray32,
+    #[serde(default)]
+    pub network: BitcoinNetworkType,
 }
 
 impl ChannelMPCToken {
@@ -79,9 +248,11 @@ impl ChannelMPCToken {
         return !self.pk_c.is_none();
     }
 
-    pub fn compute_channel_id(&self) -> Result<[u8; 32], String> {
+    pub fn compute_channel_id(&self) -> Result<[u8; 32], ZkChannelError> {
         if self.pk_c.is_none() {
-            return Err(String::from("pk_c is not initialized yet"));
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "pk_c is not initialized yet",
+            )));
         }
 
         // check txids are set
@@ -131,6 +302,8 @@ pub struct ChannelMPCState {
     pub merch_payout_pk: Option<secp256k1::PublicKey>,
     pub merch_dispute_pk: Option<secp256k1::PublicKey>,
     pub self_delay: u16,
+    #[serde(default)]
+    network: BitcoinNetworkType,
 }
 
 impl ChannelMPCState {
@@ -141,6 +314,7 @@ impl ChannelMPCState {
         bal_min_merch: i64,
         val_cpfp: i64,
         third_party_support: bool,
+        network: BitcoinNetworkType,
     ) -> ChannelMPCState {
         ChannelMPCState {
             bal_min_cust,  // dust limit (546)
@@ -152,7 +326,22 @@ impl ChannelMPCState {
             merch_payout_pk: None,
             merch_dispute_pk: None,
             self_delay,
+            network,
+        }
+    }
+
+    pub fn get_network(&self) -> BitcoinNetworkType {
+        return self.network;
+    }
+
+    pub fn check_network(&self, network: BitcoinNetworkType) -> Result<(), ZkChannelError> {
+        if self.network != network {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "network mismatch: channel is on {} but got {}",
+                self.network, network
+            )));
         }
+        Ok(())
     }
 
     pub fn get_bal_min_cust(&self) -> i64 {
@@ -200,6 +389,61 @@ pub struct MaskedMPCOutputs {
     merch_masked: FixedSizeArray32,
 }
 
+#[derive(Clone, Debug, PartialEq, Display, Serialize, Deserialize)]
+pub enum PaymentStep {
+    // new state generated: only the previous state has valid close signatures
+    Prepared,
+    // masked close signatures and pay token received from the mpc
+    MpcCompleted,
+    // close signatures on the new state verified: the previous state may be revoked from here on
+    SigsUnmasked,
+}
+
+///
+/// PaymentJournal - write-ahead record of a pending payment. It is written before the customer
+/// replaces its current state and keeps everything needed to roll back to the previous state
+/// (which still has valid close signatures) or to resume the payment.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PaymentJournal {
+    pub step: PaymentStep,
+    pub amount: i64,
+    pub prev_state: State,
+    // opening of the previous state's rev_lock (sent to the merchant to complete the payment)
+    pub prev_rev_state: RevokedState,
+    prev_cust_balance: i64,
+    prev_merch_balance: i64,
+    prev_close_escrow_signature: Option<String>,
+    prev_close_merch_signature: Option<String>,
+    // session of the payment with the merchant (needed to resume it)
+    #[serde(default)]
+    pub session_id: Option<FixedSizeArray16>,
+}
+
+///
+/// PendingTopUp - the state of the topped up channel that the customer switches to once the
+/// splice-tx confirmed, along with the close signatures on that state
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PendingTopUp {
+    pub splice_tx: EscrowSpliceTx,
+    pub state: State,
+    rev_lock: FixedSizeArray32,
+    rev_secret: FixedSizeArray32,
+    close_escrow_signature: Option<String>,
+    close_merch_signature: Option<String>,
+    cust_sigs: Option<TopUpSignatures>,
+    // set once the merchant signed the splice-tx as well
+    #[serde(default)]
+    merch_escrow_sig: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TopUpSignatures {
+    pub escrow_sig: Vec<u8>,
+    pub top_up_sig: Vec<u8>,
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct CustomerMPCState {
     pub name: String,
@@ -225,6 +469,18 @@ pub struct CustomerMPCState {
     pub protocol_status: ProtocolStatus,
     channel_status: ChannelStatus,
     pub net_config: Option<NetworkConfig>,
+    #[serde(default)]
+    network: BitcoinNetworkType,
+    #[serde(default)]
+    payment_journal: Option<PaymentJournal>,
+    // amount the merchant contributes to the escrow (0 if the customer funds the channel alone)
+    #[serde(default)]
+    merch_funding: i64,
+    #[serde(default)]
+    pending_top_up: Option<PendingTopUp>,
+    // protocol events that have not been appended to an event log yet
+    #[serde(default)]
+    pending_events: Vec<EventRecord>,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
@@ -238,6 +494,10 @@ pub struct InitCustState {
     pub min_fee: i64,
     pub max_fee: i64,
     pub fee_mc: i64,
+    #[serde(default)]
+    pub cust_funding: i64,
+    #[serde(default)]
+    pub merch_funding: i64,
 }
 
 impl fmt::Display for InitCustState {
@@ -266,6 +526,7 @@ impl CustomerMPCState {
         merch_bal: i64,
         fee_cc: i64,
         name: String,
+        network: BitcoinNetworkType,
     ) -> Self {
         let secp = secp256k1::Secp256k1::new();
 
@@ -315,6 +576,11 @@ impl CustomerMPCState {
             protocol_status: ProtocolStatus::New,
             channel_status: ChannelStatus::None,
             net_config: None,
+            network: network,
+            payment_journal: None,
+            merch_funding: 0,
+            pending_top_up: None,
+            pending_events: Vec::new(),
         };
     }
 
@@ -323,7 +589,7 @@ impl CustomerMPCState {
         channel_token: &mut ChannelMPCToken,
         cust_sk: [u8; 32],
         pay_sk: [u8; 32],
-    ) -> Result<(), String> {
+    ) -> Result<(), ZkChannelError> {
         let secp = secp256k1::Secp256k1::new();
 
         let sk_c = handle_error_util!(secp256k1::SecretKey::from_slice(&cust_sk));
@@ -362,6 +628,41 @@ impl CustomerMPCState {
         return self.channel_status.clone();
     }
 
+    pub fn get_network(&self) -> BitcoinNetworkType {
+        return self.network;
+    }
+
+    ///
+    /// set_merch_funding() - records that the merchant funds its initial balance with its own inputs
+    /// (dual-funded escrow). Must be called before the initial state is generated.
+    ///
+    pub fn set_merch_funding(&mut self, merch_funding: i64) -> Result<(), ZkChannelError> {
+        if self.protocol_status != ProtocolStatus::New || self.state.is_some() {
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
+                "cannot change the funding contributions in status: {}",
+                self.protocol_status
+            )));
+        }
+        if merch_funding < 0 || merch_funding > self.merch_balance {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "merchant contribution {} exceeds the merchant balance {}",
+                merch_funding, self.merch_balance
+            )));
+        }
+        self.merch_funding = merch_funding;
+        Ok(())
+    }
+
+    ///
+    /// get_funding_contributions() - amounts the customer and merchant put into the escrow
+    /// output: (customer contribution, merchant contribution)
+    ///
+    pub fn get_funding_contributions(&self) -> (i64, i64) {
+        // payments only move funds between the balances, so their sum is the escrow amount
+        let escrow_sats = self.cust_balance + self.merch_balance;
+        (escrow_sats - self.merch_funding, self.merch_funding)
+    }
+
     pub fn generate_init_state<R: Rng>(
         &mut self,
         csprng: &mut R,
@@ -395,6 +696,7 @@ impl CustomerMPCState {
             pk_m: pk_m.clone(),
             escrow_txid: FixedSizeArray32([0u8; 32]),
             merch_txid: FixedSizeArray32([0u8; 32]),
+            network: self.network,
         };
     }
 
@@ -422,12 +724,12 @@ impl CustomerMPCState {
         return self.state.unwrap();
     }
 
-    pub fn store_initial_pay_token(&mut self, pay_token: [u8; 32]) -> Result<(), String> {
+    pub fn store_initial_pay_token(&mut self, pay_token: [u8; 32]) -> Result<(), ZkChannelError> {
         if self.protocol_status != ProtocolStatus::Initialized {
-            return Err(format!(
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
                 "Invalid channel status for store_initial_pay_token(): {}",
                 self.protocol_status
-            ));
+            )));
         }
 
         self.pay_tokens.insert(0, FixedSizeArray32(pay_token));
@@ -440,9 +742,26 @@ impl CustomerMPCState {
         channel_token: &mut ChannelMPCToken,
         tx: &FundingTxInfo,
         tx_fee_info: &TransactionFeeInfo,
-    ) -> Result<(), String> {
+    ) -> Result<(), ZkChannelError> {
         if self.state.is_none() {
-            return Err(String::from("Customer state has not been initialized!"));
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "Customer state has not been initialized!",
+            )));
+        }
+
+        // a dual-funded channel must carry the merchant's contribution agreed in init
+        if tx.merch_funding != self.merch_funding {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "funding tx merchant contribution does not match: {} != {}",
+                tx.merch_funding, self.merch_funding
+            )));
+        }
+        if tx.merch_funding != 0
+            && tx.cust_funding + tx.merch_funding != tx.init_cust_bal + tx.init_merch_bal
+        {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "funding tx contributions do not add up to the initial balances",
+            )));
         }
 
         let mut s = self.state.unwrap();
@@ -463,7 +782,7 @@ impl CustomerMPCState {
         Ok(())
     }
 
-    pub fn get_initial_cust_state(&self) -> Result<InitCustState, String> {
+    pub fn get_initial_cust_state(&self) -> Result<InitCustState, ZkChannelError> {
         assert!(self.state.is_some());
 
         let s = self.state.unwrap();
@@ -477,12 +796,390 @@ impl CustomerMPCState {
             min_fee: self.state.unwrap().min_fee,
             max_fee: self.state.unwrap().max_fee,
             fee_mc: self.state.unwrap().fee_mc,
+            cust_funding: self.get_funding_contributions().0,
+            merch_funding: self.merch_funding,
+        })
+    }
+
+    ///
+    /// verify_dual_funded_escrow() - checks that the escrow-tx pays both contributions into the escrow
+    /// of this channel. Only once the initial closing txs have been signed (PendingOpen) can the
+    /// customer safely sign its escrow input.
+    ///
+    pub fn verify_dual_funded_escrow(
+        &self,
+        channel_token: &ChannelMPCToken,
+        escrow_tx: &DualFundedEscrowTx,
+    ) -> Result<(), ZkChannelError> {
+        if self.channel_status != ChannelStatus::PendingOpen {
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
+                "initial closing txs must be signed before the escrow-tx: {}",
+                self.channel_status
+            )));
+        }
+        if escrow_tx.cust_pk != self.pk_c || escrow_tx.merch_pk != channel_token.pk_m {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "escrow-tx does not pay to the channel keys",
+            )));
+        }
+        let (cust_funding, merch_funding) = self.get_funding_contributions();
+        if escrow_tx.cust.escrow_sats != cust_funding
+            || escrow_tx.merch.escrow_sats != merch_funding
+        {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "escrow-tx contributions {}/{} do not match the channel: {}/{}",
+                escrow_tx.cust.escrow_sats,
+                escrow_tx.merch.escrow_sats,
+                cust_funding,
+                merch_funding
+            )));
+        }
+        let s = self.get_current_state();
+        let (escrow_txid_be, _) = escrow_tx.get_txid();
+        if s.escrow_txid.0 != escrow_txid_be || s.escrow_prevout.0 != escrow_tx.get_escrow_prevout()
+        {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "escrow-tx does not match the escrow txid of the channel",
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn has_pending_top_up(&self) -> bool {
+        return self.pending_top_up.is_some();
+    }
+
+    ///
+    /// record_event() - adds a protocol event of the channel (keyed by its escrow txid) to the outbox.
+    /// There is no channel to log to before the initial state exists
+    ///
+    pub fn record_event(&mut self, event: ProtocolEvent) {
+        if let Some(s) = self.state {
+            self.pending_events.push(EventRecord::new(
+                EventKey::escrow(&s.escrow_txid.0),
+                EventParty::Customer,
+                event,
+            ));
+        }
+    }
+
+    ///
+    /// record_result() - adds the event of a step to the outbox if it succeeded or a StepFailed event otherwise
+    /// output: the result of the step
+    ///
+    pub fn record_result<T>(
+        &mut self,
+        step: &str,
+        event: ProtocolEvent,
+        result: Result<T, ZkChannelError>,
+    ) -> Result<T, ZkChannelError> {
+        let event = match &result {
+            Ok(_) => event,
+            Err(e) => ProtocolEvent::StepFailed {
+                step: step.to_string(),
+                error_code: e.code(),
+            },
+        };
+        self.record_event(event);
+        result
+    }
+
+    ///
+    /// take_events() - removes the recorded protocol events from the outbox. The customer state has no
+    /// database, so the caller appends them to its event log (see StateDatabase::append_event())
+    /// output: events in the order they were recorded
+    ///
+    pub fn take_events(&mut self) -> Vec<EventRecord> {
+        self.pending_events.drain(..).collect()
+    }
+
+    ///
+    /// prepare_top_up() - generates the initial state of the topped up channel: the balances of the current
+    /// state with the top-up added to the customer balance, a fresh nonce/rev_lock and the txids of the new escrow.
+    /// The current state stays in use until the splice-tx is complete.
+    /// output: top-up request for the merchant
+    ///
+    pub fn prepare_top_up<R: Rng>(
+        &mut self,
+        csprng: &mut R,
+        channel_token: &ChannelMPCToken,
+        splice_tx: &EscrowSpliceTx,
+        merch_txid_be: [u8; 32],
+        merch_prevout: [u8; 32],
+    ) -> Result<TopUpRequest, ZkChannelError> {
+        if self.channel_status != ChannelStatus::Open {
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
+                "Invalid channel status for top-up: {}",
+                self.channel_status
+            )));
+        }
+        match self.protocol_status {
+            ProtocolStatus::Activated | ProtocolStatus::Established => (),
+            _ => {
+                return Err(ZkChannelError::InvalidStatusTransition(format!(
+                    "Invalid protocol status for top-up: {}",
+                    self.protocol_status
+                )))
+            }
+        }
+        if self.has_pending_payment() || self.has_pending_top_up() {
+            return Err(ZkChannelError::InvalidStatusTransition(String::from(
+                "cannot top up while a payment or another top-up is pending",
+            )));
+        }
+        let pay_token = match self.pay_tokens.get(&self.index) {
+            Some(pt) => pt.clone(),
+            None => {
+                return Err(ZkChannelError::NotFound(String::from(
+                    "you do not have a pay token for the current state",
+                )));
+            }
+        };
+
+        let s = self.get_current_state();
+        if splice_tx.cust_pk != self.pk_c
+            || splice_tx.merch_pk != channel_token.pk_m
+            || splice_tx.escrow_txid_be != s.escrow_txid
+            || splice_tx.escrow_sats != s.bc + s.bm
+        {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "splice-tx does not spend the escrow of the channel",
+            )));
+        }
+
+        // same as a payment: fresh nonce and rev_lock/rev_secret pair for the new state
+        let mut nonce: [u8; NONCE_LEN] = [0; NONCE_LEN];
+        csprng.fill_bytes(&mut nonce);
+        let mut rev_secret = [0u8; 32];
+        csprng.fill_bytes(&mut rev_secret);
+        let rev_lock = hash_to_slice(&rev_secret.to_vec());
+
+        let (escrow_txid_be, _) = splice_tx.get_txid();
+        let mut new_state = s.clone();
+        new_state.nonce = FixedSizeArray16(nonce);
+        new_state.rev_lock = FixedSizeArray32(rev_lock);
+        new_state.bc += splice_tx.top_up.escrow_sats;
+        new_state.escrow_txid = FixedSizeArray32(escrow_txid_be);
+        new_state.escrow_prevout = FixedSizeArray32(splice_tx.get_escrow_prevout());
+        new_state.merch_txid = FixedSizeArray32(merch_txid_be);
+        new_state.merch_prevout = FixedSizeArray32(merch_prevout);
+
+        let (cust_funding, merch_funding) = self.get_funding_contributions();
+        let funding_tx = FundingTxInfo {
+            init_cust_bal: new_state.bc,
+            init_merch_bal: new_state.bm,
+            escrow_txid: new_state.escrow_txid,
+            escrow_prevout: new_state.escrow_prevout,
+            merch_txid: new_state.merch_txid,
+            merch_prevout: new_state.merch_prevout,
+            cust_funding: cust_funding + splice_tx.top_up.escrow_sats,
+            merch_funding: merch_funding,
+        };
+        let init_state = InitCustState {
+            pk_c: self.pk_c.clone(),
+            close_pk: self.payout_pk.clone(),
+            nonce: new_state.nonce,
+            rev_lock: new_state.rev_lock,
+            cust_bal: new_state.bc,
+            merch_bal: new_state.bm,
+            min_fee: new_state.min_fee,
+            max_fee: new_state.max_fee,
+            fee_mc: new_state.fee_mc,
+            cust_funding: funding_tx.cust_funding,
+            merch_funding: funding_tx.merch_funding,
+        };
+
+        self.pending_top_up = Some(PendingTopUp {
+            splice_tx: splice_tx.clone(),
+            state: new_state,
+            rev_lock: FixedSizeArray32(rev_lock),
+            rev_secret: FixedSizeArray32(rev_secret),
+            close_escrow_signature: None,
+            close_merch_signature: None,
+            cust_sigs: None,
+            merch_escrow_sig: None,
+        });
+        Ok(TopUpRequest {
+            state: s,
+            pay_token,
+            splice_tx: splice_tx.clone(),
+            funding_tx,
+            init_state,
+            init_hash: FixedSizeArray32(new_state.compute_hash()),
         })
     }
 
+    // customer state and channel token of the topped up channel (before its initial closing txs are signed)
+    fn get_top_up_state(
+        &self,
+        channel_token: &ChannelMPCToken,
+        pending: &PendingTopUp,
+    ) -> (CustomerMPCState, ChannelMPCToken) {
+        let mut next = self.clone();
+        next.state = Some(pending.state);
+        next.rev_lock = pending.rev_lock;
+        next.rev_secret = pending.rev_secret;
+        next.t = FixedSizeArray16([0u8; 16]);
+        next.cust_balance = pending.state.bc;
+        next.merch_balance = pending.state.bm;
+        next.masked_outputs = HashMap::new();
+        next.pay_tokens = HashMap::new();
+        next.pay_token_mask_com = FixedSizeArray32([0u8; 32]);
+        next.close_escrow_signature = pending.close_escrow_signature.clone();
+        next.close_merch_signature = pending.close_merch_signature.clone();
+        next.protocol_status = ProtocolStatus::New;
+        next.channel_status = ChannelStatus::None;
+        next.payment_journal = None;
+        next.pending_top_up = None;
+
+        let mut next_token = channel_token.clone();
+        next_token.escrow_txid = convert_to_little_endian(&pending.state.escrow_txid);
+        next_token.merch_txid = convert_to_little_endian(&pending.state.merch_txid);
+        (next, next_token)
+    }
+
+    ///
+    /// sign_top_up() - verifies the merchant's signatures on the initial closing txs of the topped up channel
+    /// and only then signs the escrow input (with sk_c) and the top-up input (with funding_sk) of the splice-tx.
+    /// The current state is not revoked until the splice-tx confirmed (see complete_top_up())
+    /// output: customer signatures on the splice-tx
+    ///
+    pub fn sign_top_up<N: BitcoinNetwork>(
+        &mut self,
+        channel_state: &ChannelMPCState,
+        channel_token: &ChannelMPCToken,
+        escrow_sig: &Vec<u8>,
+        merch_sig: &Vec<u8>,
+        funding_sk: &[u8; 32],
+    ) -> Result<TopUpSignatures, ZkChannelError> {
+        let mut pending = match self.pending_top_up.clone() {
+            Some(p) => p,
+            None => {
+                return Err(ZkChannelError::NotFound(String::from(
+                    "no pending top-up to sign",
+                )))
+            }
+        };
+        if self.channel_status != ChannelStatus::Open {
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
+                "Invalid channel status for top-up: {}",
+                self.channel_status
+            )));
+        }
+
+        let (mut next, next_token) = self.get_top_up_state(channel_token, &pending);
+        next.sign_initial_closing_transaction::<N>(
+            channel_state,
+            &next_token,
+            escrow_sig,
+            merch_sig,
+        )?;
+
+        let cust_sigs = TopUpSignatures {
+            escrow_sig: pending
+                .splice_tx
+                .sign_escrow(FundingParty::Customer, &self.sk_c.0)?,
+            top_up_sig: pending.splice_tx.sign_top_up(funding_sk)?,
+        };
+        pending.close_escrow_signature = next.close_escrow_signature;
+        pending.close_merch_signature = next.close_merch_signature;
+        pending.cust_sigs = Some(cust_sigs.clone());
+        self.pending_top_up = Some(pending);
+        Ok(cust_sigs)
+    }
+
+    ///
+    /// finalize_top_up() - verifies the merchant's escrow signature and completes the splice-tx. The customer
+    /// keeps the current state (and can close on it) until the splice-tx confirmed, see complete_top_up()
+    /// output: signed splice-tx
+    ///
+    pub fn finalize_top_up(
+        &mut self,
+        merch_escrow_sig: &Vec<u8>,
+    ) -> Result<Vec<u8>, ZkChannelError> {
+        let mut pending = match self.pending_top_up.clone() {
+            Some(p) => p,
+            None => {
+                return Err(ZkChannelError::NotFound(String::from(
+                    "no pending top-up to finalize",
+                )))
+            }
+        };
+        let cust_sigs = match pending.cust_sigs.clone() {
+            Some(s) => s,
+            None => {
+                return Err(ZkChannelError::InvalidStatusTransition(String::from(
+                    "customer has not signed the splice-tx yet",
+                )))
+            }
+        };
+        let signed_tx = pending.splice_tx.finalize(
+            &cust_sigs.escrow_sig,
+            merch_escrow_sig,
+            &cust_sigs.top_up_sig,
+        )?;
+
+        pending.merch_escrow_sig = Some(merch_escrow_sig.clone());
+        self.pending_top_up = Some(pending);
+        Ok(signed_tx)
+    }
+
+    ///
+    /// complete_top_up() - switches the customer state and channel token over to the new escrow once the chain
+    /// monitor saw the splice-tx confirm (the channel status is Spliced). Only then is the current state revoked.
+    /// The topped up channel is pending open and has to be activated again.
+    /// output: rev_secret of the replaced state (for the merchant)
+    ///
+    pub fn complete_top_up(
+        &mut self,
+        channel_token: &mut ChannelMPCToken,
+    ) -> Result<FixedSizeArray32, ZkChannelError> {
+        let pending = match self.pending_top_up.clone() {
+            Some(p) => p,
+            None => {
+                return Err(ZkChannelError::NotFound(String::from(
+                    "no pending top-up to complete",
+                )))
+            }
+        };
+        if pending.merch_escrow_sig.is_none() {
+            return Err(ZkChannelError::InvalidStatusTransition(String::from(
+                "splice-tx has not been signed by both parties yet",
+            )));
+        }
+        if self.channel_status != ChannelStatus::Spliced {
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
+                "splice-tx has not confirmed yet, channel status: {}",
+                self.channel_status
+            )));
+        }
+
+        let rev_secret = self.rev_secret;
+        let (mut next, next_token) = self.get_top_up_state(channel_token, &pending);
+        next.protocol_status = ProtocolStatus::Initialized;
+        next.change_channel_status(ChannelStatus::PendingOpen)?;
+        // state and token are replaced together so that they always refer to the same escrow
+        *self = next;
+        *channel_token = next_token;
+        Ok(rev_secret)
+    }
+
     pub fn generate_new_state<R: Rng>(&mut self, csprng: &mut R, amount: i64) {
         assert!(!self.state.is_none());
 
+        // journal the current state before it is replaced
+        self.payment_journal = Some(PaymentJournal {
+            step: PaymentStep::Prepared,
+            amount: amount,
+            prev_state: self.state.unwrap(),
+            prev_rev_state: RevokedState::new(self.rev_lock.0, self.rev_secret.0, self.t.0),
+            prev_cust_balance: self.cust_balance,
+            prev_merch_balance: self.merch_balance,
+            prev_close_escrow_signature: self.close_escrow_signature.clone(),
+            prev_close_merch_signature: self.close_merch_signature.clone(),
+            session_id: None,
+        });
+
         let mut new_state = self.state.unwrap().clone();
 
         // generate a new nonce
@@ -513,6 +1210,115 @@ impl CustomerMPCState {
         self.state = Some(new_state);
     }
 
+    pub fn get_payment_journal(&self) -> Option<PaymentJournal> {
+        return self.payment_journal.clone();
+    }
+
+    pub fn has_pending_payment(&self) -> bool {
+        return self.payment_journal.is_some();
+    }
+
+    fn update_payment_step(&mut self, step: PaymentStep) {
+        match self.payment_journal.as_mut() {
+            Some(j) => j.step = step,
+            None => (),
+        }
+    }
+
+    pub fn set_payment_session_id(&mut self, session_id: [u8; 16]) {
+        match self.payment_journal.as_mut() {
+            Some(j) => j.session_id = Some(FixedSizeArray16(session_id)),
+            None => (),
+        }
+    }
+
+    ///
+    /// resume_payment() - what the customer needs to complete an interrupted payment with the merchant.
+    /// After the mpc, the masked close signatures of the session are requested again. Once they are unmasked,
+    /// the revoked previous state is sent to the merchant again to get the pay token mask.
+    /// output: the step of the pending payment, its session id and (only once the close signatures on the
+    /// new state are unmasked) the revoked previous state
+    ///
+    pub fn resume_payment(
+        &self,
+    ) -> Result<(PaymentStep, [u8; 16], Option<RevokedState>), ZkChannelError> {
+        let journal = match &self.payment_journal {
+            Some(j) => j,
+            None => {
+                return Err(ZkChannelError::NotFound(String::from(
+                    "no pending payment to resume",
+                )))
+            }
+        };
+        let session_id = match &journal.session_id {
+            Some(s) => s.0,
+            None => {
+                return Err(ZkChannelError::NotFound(String::from(
+                    "pending payment does not have a session id",
+                )))
+            }
+        };
+        match journal.step {
+            // the mpc has to run again from the start, which needs a new state
+            PaymentStep::Prepared => Err(ZkChannelError::InvalidStatusTransition(String::from(
+                "the mpc of the pending payment did not complete: roll it back instead",
+            ))),
+            PaymentStep::MpcCompleted => Ok((journal.step.clone(), session_id, None)),
+            PaymentStep::SigsUnmasked => Ok((
+                journal.step.clone(),
+                session_id,
+                Some(journal.prev_rev_state.clone()),
+            )),
+        }
+    }
+
+    ///
+    /// get_close_state() - the latest state that the customer holds valid close signatures for
+    /// output: the current state, or the previous state if a pending payment has not unmasked
+    /// the close signatures on the new state yet
+    ///
+    pub fn get_close_state(&self) -> State {
+        match &self.payment_journal {
+            Some(j) if j.step != PaymentStep::SigsUnmasked => j.prev_state,
+            _ => self.get_current_state(),
+        }
+    }
+
+    ///
+    /// rollback_payment() - restores the state (and its close signatures) from before the pending payment.
+    /// Only possible until the close signatures on the new state are unmasked, since the customer may
+    /// reveal the previous rev_secret after that (the payment must be resumed instead).
+    /// output: the restored state
+    ///
+    pub fn rollback_payment(&mut self) -> Result<State, ZkChannelError> {
+        let journal = match self.payment_journal.clone() {
+            Some(j) => j,
+            None => {
+                return Err(ZkChannelError::NotFound(String::from(
+                    "no pending payment to roll back",
+                )))
+            }
+        };
+        if journal.step == PaymentStep::SigsUnmasked {
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
+                "cannot roll back a payment in step: {}",
+                journal.step
+            )));
+        }
+
+        self.state = Some(journal.prev_state);
+        self.rev_lock = journal.prev_rev_state.rev_lock;
+        self.rev_secret = journal.prev_rev_state.rev_secret;
+        self.t = journal.prev_rev_state.t;
+        self.cust_balance = journal.prev_cust_balance;
+        self.merch_balance = journal.prev_merch_balance;
+        self.close_escrow_signature = journal.prev_close_escrow_signature;
+        self.close_merch_signature = journal.prev_close_merch_signature;
+        self.masked_outputs.remove(&self.index);
+        self.payment_journal = None;
+        Ok(journal.prev_state)
+    }
+
     pub fn has_tokens(&self) -> bool {
         let index = self.index;
         let is_pt = self.pay_tokens.get(&index).is_some();
@@ -592,12 +1398,12 @@ impl CustomerMPCState {
         p_ptr: *mut c_void,
         send_cb: cb_send,
         receive_cb: cb_receive,
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
         let min_cust_bal = channel_state.bal_min_cust + self.fee_cc + channel_state.val_cpfp;
         if new_state.bc <= min_cust_bal {
-            return Err(format!(
+            return Err(ZkChannelError::InvalidInput(format!(
                 "customer::execute_mpc_context - customer balance below min balance allowed after payment: {}", min_cust_bal
-            ));
+            )));
         }
 
         // add channel_status check:
@@ -605,10 +1411,10 @@ impl CustomerMPCState {
             // executing unlnik and can proceed
             ()
         } else if self.protocol_status != ProtocolStatus::Established && amount > 0 {
-            return Err(format!(
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
                 "customer::execute_mpc_context - channel not established yet: {}",
                 self.protocol_status
-            ));
+            )));
         }
 
         // load the key_com from channel state
@@ -629,9 +1435,9 @@ impl CustomerMPCState {
         let old_paytoken = match self.has_tokens() {
             true => self.pay_tokens.get(&self.index).unwrap(),
             false => {
-                return Err(String::from(
+                return Err(ZkChannelError::NotFound(String::from(
                     "you do not have a pay token for previous state",
-                ));
+                )));
             }
         };
 
@@ -639,9 +1445,9 @@ impl CustomerMPCState {
         let net_conn = match self.net_config.clone() {
             Some(nc) => nc,
             None => {
-                return Err(String::from(
+                return Err(ZkChannelError::InvalidInput(String::from(
                     "customer::execute_mpc_context - net config not specified",
-                ));
+                )));
             }
         };
 
@@ -685,7 +1491,7 @@ impl CustomerMPCState {
                 cust_public_key_hash,
             ) {
                 Ok(c) => (c.0, c.1, c.2),
-                Err(e) => return Err(e.to_string()),
+                Err(e) => return Err(ZkChannelError::MpcFailure(e.to_string())),
             };
 
         let masked_output = MaskedMPCOutputs {
@@ -697,6 +1503,7 @@ impl CustomerMPCState {
         // save the masked outputs (will unmask later)
         self.masked_outputs
             .insert(self.index, masked_output.clone());
+        self.update_payment_step(PaymentStep::MpcCompleted);
         Ok(true)
     }
 
@@ -738,7 +1545,7 @@ impl CustomerMPCState {
             BitcoinTransactionParameters<N>,
             BitcoinTransactionParameters<N>,
         ),
-        String,
+        ZkChannelError,
     > {
         let fee_mc = self.get_current_state().fee_mc;
         let escrow_init_balance = self.cust_balance + self.merch_balance;
@@ -756,29 +1563,35 @@ impl CustomerMPCState {
         let merch_input =
             create_utxo_input(&channel_token.merch_txid.0, merch_index, merch_init_balance);
 
-        let (escrow_tx_preimage, escrow_tx_params, _) = create_cust_close_transaction::<N>(
-            &escrow_input,
-            &pubkeys,
-            &to_self_delay_be,
-            self.cust_balance,
-            self.merch_balance,
-            self.fee_cc,
-            self.get_current_state().fee_mc,
-            channel_state.get_val_cpfp(),
-            true,
-        )?;
+        let (escrow_tx_preimage, escrow_tx_params, _) = handle_error_util!(
+            create_cust_close_transaction::<N>(
+                &escrow_input,
+                &pubkeys,
+                &to_self_delay_be,
+                self.cust_balance,
+                self.merch_balance,
+                self.fee_cc,
+                self.get_current_state().fee_mc,
+                channel_state.get_val_cpfp(),
+                true,
+            ),
+            ZkChannelError::TransactionError
+        );
 
-        let (merch_tx_preimage, merch_tx_params, _) = create_cust_close_transaction::<N>(
-            &merch_input,
-            &pubkeys,
-            &to_self_delay_be,
-            self.cust_balance,
-            self.merch_balance,
-            self.fee_cc,
-            self.get_current_state().fee_mc,
-            channel_state.get_val_cpfp(),
-            false,
-        )?;
+        let (merch_tx_preimage, merch_tx_params, _) = handle_error_util!(
+            create_cust_close_transaction::<N>(
+                &merch_input,
+                &pubkeys,
+                &to_self_delay_be,
+                self.cust_balance,
+                self.merch_balance,
+                self.fee_cc,
+                self.get_current_state().fee_mc,
+                channel_state.get_val_cpfp(),
+                false,
+            ),
+            ZkChannelError::TransactionError
+        );
 
         return Ok((
             escrow_tx_preimage,
@@ -795,7 +1608,7 @@ impl CustomerMPCState {
         channel_token: &ChannelMPCToken,
         orig_escrow_sig: &Vec<u8>,
         orig_merch_sig: &Vec<u8>,
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
         let (escrow_tx_preimage, merch_tx_preimage, _, _) =
             self.construct_close_transaction_preimage::<N>(channel_state, channel_token)?;
 
@@ -807,23 +1620,27 @@ impl CustomerMPCState {
         let sig_len = orig_escrow_sig[0] as usize;
         let mut new_escrow_sig = orig_escrow_sig[1..].to_vec();
         if sig_len != new_escrow_sig.len() {
-            return Err(String::from("Invalid escrow_sig len!"));
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "Invalid escrow_sig len!",
+            )));
         }
         new_escrow_sig.pop(); // remove last byte for sighash flag
         let escrow_sig = match secp256k1::Signature::from_der(&new_escrow_sig.as_slice()) {
             Ok(n) => n,
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(ZkChannelError::InvalidInput(e.to_string())),
         };
         // new merch signature
         let sig_len = orig_merch_sig[0] as usize;
         let mut new_merch_sig = orig_merch_sig[1..].to_vec();
         if sig_len != new_merch_sig.len() {
-            return Err(String::from("Invalid merch_sig len!"));
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "Invalid merch_sig len!",
+            )));
         }
         new_merch_sig.pop(); // remove last byte for sighash flag
         let merch_sig = match secp256k1::Signature::from_der(&new_merch_sig.as_slice()) {
             Ok(n) => n,
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(ZkChannelError::InvalidInput(e.to_string())),
         };
 
         // println!("Tx hash: {}", hex::encode(&escrow_tx_hash));
@@ -846,7 +1663,7 @@ impl CustomerMPCState {
             let s = String::from(
                 "Could not verify the merchant signature on the initial closing transactions!",
             );
-            Err(s)
+            Err(ZkChannelError::TransactionError(s))
         }
     }
 
@@ -855,12 +1672,14 @@ impl CustomerMPCState {
         channel_state: &ChannelMPCState,
         channel_token: &ChannelMPCToken,
         mask_bytes: MaskedTxMPCInputs,
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
         let mut escrow_mask_bytes = mask_bytes.get_escrow_mask();
         let mut merch_mask_bytes = mask_bytes.get_merch_mask();
 
         if self.masked_outputs.get(&self.index).is_none() {
-            return Err(String::from("could not find masked output"));
+            return Err(ZkChannelError::NotFound(String::from(
+                "could not find masked output",
+            )));
         }
 
         let mpc_out = self.masked_outputs.get(&self.index).unwrap();
@@ -877,7 +1696,9 @@ impl CustomerMPCState {
         escrow_sig_vec.append(&mut escrow_mask_bytes.to_vec());
         let escrow_sig_res = secp256k1::Signature::from_compact(&escrow_sig_vec.as_slice());
         if escrow_sig_res.is_err() {
-            return Err(escrow_sig_res.err().unwrap().to_string());
+            return Err(ZkChannelError::MpcFailure(
+                escrow_sig_res.err().unwrap().to_string(),
+            ));
         }
         let escrow_sig = escrow_sig_res.unwrap();
         // println!("Close from Escrow Signature: {}", &escrow_sig);
@@ -886,7 +1707,9 @@ impl CustomerMPCState {
         merch_sig_vec.append(&mut merch_mask_bytes.to_vec());
         let merch_sig_res = secp256k1::Signature::from_compact(&merch_sig_vec.as_slice());
         if merch_sig_res.is_err() {
-            return Err(merch_sig_res.err().unwrap().to_string());
+            return Err(ZkChannelError::MpcFailure(
+                merch_sig_res.err().unwrap().to_string(),
+            ));
         }
         let merch_sig = merch_sig_res.unwrap();
         // println!("Close from Merch Signature: {}", &merch_sig);
@@ -901,22 +1724,23 @@ impl CustomerMPCState {
         let secp = secp256k1::Secp256k1::verification_only();
         let ver_escrow = secp.verify(&msg1, &escrow_sig, &channel_token.pk_m);
         if ver_escrow.is_err() {
-            return Err(format!(
+            return Err(ZkChannelError::MpcFailure(format!(
                 "Escrow tx signature failed: {}",
                 ver_escrow.err().unwrap().to_string()
-            ));
+            )));
         }
         let ver_merch = secp.verify(&msg2, &merch_sig, &channel_token.pk_m);
         if ver_merch.is_err() {
-            return Err(format!(
+            return Err(ZkChannelError::MpcFailure(format!(
                 "Merch tx signature failed: {}",
                 ver_merch.err().unwrap().to_string()
-            ));
+            )));
         }
         let escrow_sig_hex = hex::encode(&escrow_sig.serialize_compact().to_vec());
         let merch_sig_hex = hex::encode(&merch_sig.serialize_compact().to_vec());
         self.close_escrow_signature = Some(escrow_sig_hex);
         self.close_merch_signature = Some(merch_sig_hex);
+        self.update_payment_step(PaymentStep::SigsUnmasked);
         Ok(true)
     }
 
@@ -947,6 +1771,8 @@ impl CustomerMPCState {
 
         self.pay_tokens
             .insert(self.index, FixedSizeArray32(pt_mask_bytes));
+        // payment is complete
+        self.payment_journal = None;
 
         if self.protocol_status == ProtocolStatus::Activated {
             self.protocol_status = ProtocolStatus::Established;
@@ -954,40 +1780,253 @@ impl CustomerMPCState {
         return true;
     }
 
-    pub fn customer_close<N: BitcoinNetwork>(
+    pub fn sign_mutual_close_transaction(
         &mut self,
         channel_state: &ChannelMPCState,
         channel_token: &ChannelMPCToken,
-        from_escrow: bool,
-    ) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), String> {
-        let (escrow_tx_preimage, merch_tx_preimage, escrow_tx_params, merch_tx_params) =
-            self.construct_close_transaction_preimage::<N>(channel_state, channel_token)?;
-        let merch_pk = channel_token.pk_m.serialize().to_vec();
-        let cust_sk = self.sk_c.0.to_vec();
-        let close_tx = generate_customer_close_tx_helper::<N>(
-            &self.close_escrow_signature,
-            &escrow_tx_preimage,
-            &escrow_tx_params,
-            &self.close_merch_signature,
-            &merch_tx_preimage,
-            &merch_tx_params,
-            from_escrow,
-            &merch_pk,
-            &cust_sk,
-        );
-        if close_tx.is_ok() {
-            self.channel_status = match from_escrow {
-                true => ChannelStatus::CustomerInitClose,
-                false => ChannelStatus::MerchantInitClose,
-            };
+    ) -> Result<MutualCloseRequest, ZkChannelError> {
+        if self.channel_status != ChannelStatus::Open {
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
+                "Invalid channel status for mutual close: {}",
+                self.channel_status
+            )));
+        }
+        let pay_token = match self.pay_tokens.get(&self.index) {
+            Some(pt) => pt.clone(),
+            None => {
+                return Err(ZkChannelError::NotFound(String::from(
+                    "you do not have a pay token for the current state",
+                )));
+            }
+        };
+        let merch_close_pk = match channel_state.merch_payout_pk {
+            Some(pk) => pk.serialize().to_vec(),
+            None => {
+                return Err(ZkChannelError::InvalidInput(String::from(
+                    "merch_payout_pk is not set in channel state",
+                )))
+            }
+        };
+
+        // the customer pays the fee for the mutual close tx
+        let s = self.get_current_state();
+        let cust_bal = s.bc - self.fee_cc;
+        let merch_bal = s.bm;
+        check_mutual_close_cust_bal(channel_state, cust_bal)?;
+        let escrow_input = UtxoInput {
+            address_format: String::from("p2wsh"),
+            transaction_id: channel_token.escrow_txid.0.to_vec(),
+            index: 0,
+            redeem_script: None,
+            script_pub_key: None,
+            utxo_amount: Some(s.bc + s.bm),
+            sequence: Some([0xff, 0xff, 0xff, 0xff]),
+        };
+        let cust_sig = handle_error_util!(
+            customer_sign_mutual_close_transaction(
+                &escrow_input,
+                &self.pk_c.serialize().to_vec(),
+                &channel_token.pk_m.serialize().to_vec(),
+                &self.payout_pk.serialize().to_vec(),
+                &merch_close_pk,
+                cust_bal,
+                merch_bal,
+                &self.sk_c.0.to_vec()
+            ),
+            ZkChannelError::TransactionError
+        );
+
+        // the channel stays open until the merchant's signature is verified (see verify_mutual_close_transaction)
+        Ok(MutualCloseRequest {
+            state: s,
+            pay_token,
+            cust_close_pk: self.payout_pk.clone(),
+            cust_bal,
+            merch_bal,
+            cust_sig,
+        })
+    }
+
+    ///
+    /// verify_mutual_close_transaction() - checks that the mutual close tx returned by the merchant spends the
+    /// escrow and carries valid signatures of both parties over the split of the request
+    /// output: error if the tx is invalid (the channel status is set to MutualClose otherwise)
+    ///
+    pub fn verify_mutual_close_transaction(
+        &mut self,
+        channel_token: &ChannelMPCToken,
+        request: &MutualCloseRequest,
+        signed_tx: &Vec<u8>,
+    ) -> Result<(), ZkChannelError> {
+        if self.channel_status != ChannelStatus::Open {
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
+                "Invalid channel status for mutual close: {}",
+                self.channel_status
+            )));
+        }
+        if request.state != self.get_current_state() {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "mutual close request is not for the current state",
+            )));
+        }
+        let s = &request.state;
+        let tx_info = parse_mutual_close_transaction(signed_tx, s.bc + s.bm)?;
+        if tx_info.outpoint != (s.escrow_txid.0, 0) {
+            return Err(ZkChannelError::TransactionError(String::from(
+                "mutual close tx does not spend the escrow",
+            )));
+        }
+        let amounts: Vec<i64> = tx_info.outputs.iter().map(|o| o.0).collect();
+        if !amounts.contains(&request.cust_bal) || !amounts.contains(&request.merch_bal) {
+            return Err(ZkChannelError::TransactionError(String::from(
+                "mutual close tx does not pay the split of the request",
+            )));
+        }
+
+        // the customer's signature commits to the outputs, the merchant's has to be over the same tx
+        let msg = secp256k1::Message::from_slice(&tx_info.sighash).unwrap();
+        let secp = secp256k1::Secp256k1::verification_only();
+        let mut cust_sig_valid = false;
+        let mut merch_sig_valid = false;
+        for sig in tx_info.sigs.iter() {
+            let sig = match secp256k1::Signature::from_der(sig.as_slice()) {
+                Ok(n) => n,
+                Err(e) => return Err(ZkChannelError::TransactionError(e.to_string())),
+            };
+            cust_sig_valid |= secp.verify(&msg, &sig, &self.pk_c).is_ok();
+            merch_sig_valid |= secp.verify(&msg, &sig, &channel_token.pk_m).is_ok();
+        }
+        if !cust_sig_valid || !merch_sig_valid {
+            return Err(ZkChannelError::TransactionError(String::from(
+                "Could not verify the signatures on the mutual close transaction!",
+            )));
         }
-        return close_tx;
-    }
+
+        self.change_channel_status(ChannelStatus::MutualClose)
+    }
+
+    pub fn customer_close<N: BitcoinNetwork>(
+        &mut self,
+        channel_state: &ChannelMPCState,
+        channel_token: &ChannelMPCToken,
+        from_escrow: bool,
+    ) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), ZkChannelError> {
+        // only close on a state with valid close signatures
+        if self.get_close_state() != self.get_current_state() {
+            self.rollback_payment()?;
+        }
+        let (escrow_tx_preimage, merch_tx_preimage, escrow_tx_params, merch_tx_params) =
+            self.construct_close_transaction_preimage::<N>(channel_state, channel_token)?;
+        let merch_pk = channel_token.pk_m.serialize().to_vec();
+        let cust_sk = self.sk_c.0.to_vec();
+        let close_tx = generate_customer_close_tx_helper::<N>(
+            &self.close_escrow_signature,
+            &escrow_tx_preimage,
+            &escrow_tx_params,
+            &self.close_merch_signature,
+            &merch_tx_preimage,
+            &merch_tx_params,
+            from_escrow,
+            &merch_pk,
+            &cust_sk,
+        );
+        if close_tx.is_ok() {
+            let cur_channel_status = self.channel_status.clone();
+            self.channel_status = match from_escrow {
+                true => ChannelStatus::CustomerInitClose,
+                false => ChannelStatus::MerchantInitClose,
+            };
+            let channel_status = self.channel_status.clone();
+            self.record_event(ProtocolEvent::StatusChanged {
+                from: cur_channel_status,
+                to: channel_status,
+            });
+        }
+        return close_tx.map_err(|e| ZkChannelError::TransactionError(e.to_string()));
+    }
+
+    ///
+    /// sign_cpfp_child_tx() - fee-bumps a broadcast cust-close-tx (from escrow or merch-close) with a child tx
+    /// that spends its anchor output (and the optional funding input) to the customer's payout-pk
+    /// output: signed child tx and its txid (little-endian)
+    ///
+    pub fn sign_cpfp_child_tx(
+        &self,
+        cust_close_tx: &Vec<u8>,
+        fee_rate: i64,
+        funding: Option<(EscrowFundingInput, [u8; 32])>,
+    ) -> Result<(Vec<u8>, [u8; 32]), ZkChannelError> {
+        let anchor_keys = vec![(self.pk_c, self.sk_c.0), (self.payout_pk, self.payout_sk.0)];
+        sign_cpfp_child_tx(
+            cust_close_tx,
+            self.fee_cc,
+            &anchor_keys,
+            fee_rate,
+            funding,
+            self.payout_pk,
+        )
+    }
+
+    ///
+    /// claim_close_output() - claims the to_customer output of a confirmed cust-close-tx (from escrow or
+    /// merch-close) after the timelock expired. The output is paid (minus the fee) to the customer's payout-pk
+    /// and the channel is marked as closed
+    /// output: signed claim tx and its txid (little-endian)
+    ///
+    pub fn claim_close_output(
+        &mut self,
+        channel_state: &ChannelMPCState,
+        cust_close_tx: &Vec<u8>,
+        close_height: u64,
+        cur_height: u64,
+        fee: i64,
+    ) -> Result<(Vec<u8>, [u8; 32]), ZkChannelError> {
+        let close_tx = parse_cust_close_transaction(cust_close_tx)?;
+        if close_tx.cust_close_pk != self.payout_pk.serialize().to_vec() {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "cust-close-tx does not pay to the customer's payout-pk",
+            )));
+        }
+        let merch_disp_pk = match channel_state.merch_dispute_pk {
+            Some(pk) => pk.serialize().to_vec(),
+            None => {
+                return Err(ZkChannelError::InvalidInput(String::from(
+                    "channel state does not have merch_dispute_pk set",
+                )))
+            }
+        };
+        check_timelock_expired(channel_state.self_delay, close_height, cur_height)?;
+        let output_amount = get_claim_output_amount(close_tx.to_customer_amount, fee)?;
+
+        let signed_tx = handle_error_util!(
+            zkchan_tx::txutil::customer_sign_cust_close_claim_transaction(
+                close_tx.txid_le.to_vec(),
+                close_tx.to_customer_index,
+                close_tx.to_customer_amount,
+                output_amount,
+                channel_state.get_self_delay_be(),
+                self.payout_pk.serialize().to_vec(),
+                close_tx.rev_lock.to_vec(),
+                close_tx.cust_close_pk.clone(),
+                merch_disp_pk,
+                self.payout_sk.0.to_vec()
+            ),
+            ZkChannelError::TransactionError
+        );
+        let txid_le = get_claim_txid_le(&signed_tx)?;
+
+        // the close tx confirmed and could not be disputed before the timelock expired
+        if self.channel_status != ChannelStatus::ConfirmedClose {
+            self.change_channel_status(ChannelStatus::PendingClose)?;
+            self.change_channel_status(ChannelStatus::ConfirmedClose)?;
+        }
+        Ok((signed_tx, txid_le))
+    }
 
     pub fn change_channel_status(
         &mut self,
         new_channel_status: ChannelStatus,
-    ) -> Result<(), String> {
+    ) -> Result<(), ZkChannelError> {
         let cur_channel_status = self.channel_status.clone();
         if cur_channel_status == new_channel_status {
             return Ok(());
@@ -1010,15 +2049,68 @@ impl CustomerMPCState {
             (ChannelStatus::PendingClose, ChannelStatus::ConfirmedClose) => new_channel_status,
             (ChannelStatus::ConfirmedClose, ChannelStatus::None) => new_channel_status,
             (ChannelStatus::PendingClose, ChannelStatus::Disputed) => new_channel_status,
+            // can be set once both parties signed the mutual close tx and
+            // set again once it confirmed on chain
+            (ChannelStatus::Open, ChannelStatus::MutualClose) => new_channel_status,
+            (ChannelStatus::MutualClose, ChannelStatus::ConfirmedClose) => new_channel_status,
+            // can be set if a unilateral close confirmed before the mutual close tx
+            (ChannelStatus::MutualClose, ChannelStatus::PendingClose) => new_channel_status,
+            // can be set once the splice-tx of a top-up confirmed on chain
+            (ChannelStatus::Open, ChannelStatus::Spliced) => new_channel_status,
+            // the old escrow can still be closed on or disputed (e.g., if the splice-tx is reorged out)
+            (ChannelStatus::Spliced, ChannelStatus::MerchantInitClose) => new_channel_status,
+            (ChannelStatus::Spliced, ChannelStatus::Disputed) => new_channel_status,
             (_, _) => {
-                return Err(format!(
+                return Err(ZkChannelError::InvalidStatusTransition(format!(
                     "transition not allowed for channel: {} => {}",
                     cur_channel_status, new_channel_status
-                ))
+                )))
             }
         };
+        let channel_status = self.channel_status.clone();
+        self.record_event(ProtocolEvent::StatusChanged {
+            from: cur_channel_status,
+            to: channel_status,
+        });
         Ok(())
     }
+
+    ///
+    /// rollback_channel_status() - reverts a status that was derived from a block that is no longer
+    /// on the best chain (only used by the chain monitor after a reorg)
+    /// output: error if the current status was not set from the chain
+    ///
+    pub fn rollback_channel_status(
+        &mut self,
+        channel_status: ChannelStatus,
+    ) -> Result<(), ZkChannelError> {
+        let cur_channel_status = self.channel_status.clone();
+        check_rollback(&cur_channel_status, &channel_status)?;
+        self.channel_status = channel_status.clone();
+        self.record_event(ProtocolEvent::StatusChanged {
+            from: cur_channel_status,
+            to: channel_status,
+        });
+        Ok(())
+    }
+}
+
+// only the statuses that are set once a tx is deep enough on chain can be reverted by a reorg
+fn check_rollback(
+    cur_channel_status: &ChannelStatus,
+    new_channel_status: &ChannelStatus,
+) -> Result<(), ZkChannelError> {
+    match cur_channel_status {
+        ChannelStatus::Open
+        | ChannelStatus::PendingClose
+        | ChannelStatus::ConfirmedClose
+        | ChannelStatus::Disputed
+        | ChannelStatus::Spliced => Ok(()),
+        _ => Err(ZkChannelError::InvalidStatusTransition(format!(
+            "rollback not allowed for channel: {} => {}",
+            cur_channel_status, new_channel_status
+        ))),
+    }
 }
 
 fn compute_rev_lock_commitment(input: &[u8; 32], r: &[u8; 16]) -> [u8; 32] {
@@ -1028,6 +2120,430 @@ fn compute_rev_lock_commitment(input: &[u8; 32], r: &[u8; 16]) -> [u8; 32] {
     return hash_to_slice(&input_buf);
 }
 
+/// Fields of a broadcast cust-close-tx that are needed to dispute it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct CustCloseTxInfo {
+    pub txid_le: [u8; 32],
+    pub prevout_txid_be: [u8; 32],
+    pub to_customer_index: u32,
+    pub to_customer_amount: i64,
+    pub rev_lock: [u8; 32],
+    pub cust_close_pk: Vec<u8>,
+}
+
+struct RawTxReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> RawTxReader<'a> {
+    // lengths come from the (untrusted) raw tx, so they must not overflow
+    fn read(&mut self, len: usize) -> Result<&'a [u8], ZkChannelError> {
+        let end = match self.pos.checked_add(len) {
+            Some(end) if end <= self.buf.len() => end,
+            _ => {
+                return Err(ZkChannelError::TransactionError(String::from(
+                    "raw transaction is truncated",
+                )))
+            }
+        };
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn read_varint(&mut self) -> Result<usize, ZkChannelError> {
+        let prefix = self.read(1)?[0];
+        let len = match prefix {
+            0xfd => 2,
+            0xfe => 4,
+            0xff => 8,
+            n => return Ok(n as usize),
+        };
+        let bytes = self.read(len)?;
+        let mut n = 0u64;
+        for (i, b) in bytes.iter().enumerate() {
+            n |= (*b as u64) << (8 * i);
+        }
+        if n > usize::max_value() as u64 {
+            return Err(ZkChannelError::TransactionError(format!(
+                "invalid length in raw transaction: {}",
+                n
+            )));
+        }
+        Ok(n as usize)
+    }
+
+    // script_sig and sequence of an input
+    fn skip_script_sig(&mut self) -> Result<(), ZkChannelError> {
+        let script_len = self.read_varint()?;
+        match script_len.checked_add(4) {
+            Some(len) => self.read(len).map(|_| ()),
+            None => Err(ZkChannelError::TransactionError(format!(
+                "invalid script_sig length in raw transaction: {}",
+                script_len
+            ))),
+        }
+    }
+}
+
+// the customer's output of the mutual close tx must be above the dust limit and the minimum balance
+fn check_mutual_close_cust_bal(
+    channel_state: &ChannelMPCState,
+    cust_bal: i64,
+) -> Result<(), ZkChannelError> {
+    if cust_bal < DUST_LIMIT || cust_bal < channel_state.bal_min_cust {
+        return Err(ZkChannelError::InvalidInput(format!(
+            "customer balance of the mutual close tx is below the minimum ({}): {}",
+            cmp::max(DUST_LIMIT, channel_state.bal_min_cust),
+            cust_bal
+        )));
+    }
+    Ok(())
+}
+
+// a claim tx pays the claimed output (minus the fee) to a single output, which must not be dust
+fn get_claim_output_amount(input_amount: i64, fee: i64) -> Result<i64, ZkChannelError> {
+    if fee < 0 || input_amount - fee < DUST_LIMIT {
+        return Err(ZkChannelError::InvalidInput(format!(
+            "invalid fee for claiming {} sats: {}",
+            input_amount, fee
+        )));
+    }
+    Ok(input_amount - fee)
+}
+
+///
+/// check_timelock_expired() - checks that an output with a relative timelock of self_delay blocks
+/// (in a close tx confirmed at close_height) can be spent at the current height
+/// output: success or error
+///
+pub fn check_timelock_expired(
+    self_delay: u16,
+    close_height: u64,
+    cur_height: u64,
+) -> Result<(), ZkChannelError> {
+    let timelock_height = close_height + self_delay as u64;
+    if cur_height < timelock_height {
+        return Err(ZkChannelError::InvalidInput(format!(
+            "timelock has not expired: current height {} < {}",
+            cur_height, timelock_height
+        )));
+    }
+    Ok(())
+}
+
+fn get_claim_txid_le(signed_tx: &Vec<u8>) -> Result<[u8; 32], ZkChannelError> {
+    let mut txid_le = parse_transaction(signed_tx)?.txid_be;
+    txid_le.reverse();
+    Ok(txid_le)
+}
+
+/// Parses a cust-close-tx (from escrow or merch-close) and extracts the to_customer output
+/// along with the rev_lock and cust-close-pk committed to in its OP_RETURN output.
+pub fn parse_cust_close_transaction(raw_tx: &Vec<u8>) -> Result<CustCloseTxInfo, ZkChannelError> {
+    let mut r = RawTxReader {
+        buf: raw_tx.as_slice(),
+        pos: 0,
+    };
+    let mut stripped_tx = Vec::new();
+    stripped_tx.extend_from_slice(r.read(4)?);
+    let is_segwit = raw_tx.len() > 6 && raw_tx[4] == 0x00 && raw_tx[5] == 0x01;
+    if is_segwit {
+        r.read(2)?;
+    }
+
+    let start = r.pos;
+    let num_inputs = r.read_varint()?;
+    if num_inputs != 1 {
+        return Err(ZkChannelError::TransactionError(format!(
+            "expected a cust-close-tx with one input, but got {}",
+            num_inputs
+        )));
+    }
+    // outpoints are serialized with the txid in big-endian (the order of the hash digest)
+    let mut prevout_txid_be = [0u8; 32];
+    prevout_txid_be.copy_from_slice(r.read(32)?);
+    r.read(4)?; // prevout index
+    r.skip_script_sig()?;
+
+    let mut to_customer = None;
+    let mut op_return = None;
+    let num_outputs = r.read_varint()?;
+    for i in 0..num_outputs {
+        let mut amount = 0i64;
+        for (j, b) in r.read(8)?.iter().enumerate() {
+            amount |= (*b as i64) << (8 * j);
+        }
+        let script_len = r.read_varint()?;
+        let script = r.read(script_len)?;
+        if script.len() == 34 && script[0] == 0x00 && script[1] == 0x20 {
+            // P2WSH output that pays to the customer after the timelock (or the merchant w/ the rev_secret)
+            to_customer = Some((i as u32, amount));
+        } else if script.len() == 67 && script[0] == 0x6a && script[1] == 0x41 {
+            // OP_RETURN output with the rev_lock and the cust-close-pk
+            op_return = Some(script[2..].to_vec());
+        }
+    }
+    stripped_tx.extend_from_slice(&raw_tx[start..r.pos]);
+
+    if is_segwit {
+        for _ in 0..num_inputs {
+            let num_items = r.read_varint()?;
+            for _ in 0..num_items {
+                let item_len = r.read_varint()?;
+                r.read(item_len)?;
+            }
+        }
+    }
+    stripped_tx.extend_from_slice(r.read(4)?);
+    if r.pos != raw_tx.len() {
+        return Err(ZkChannelError::TransactionError(String::from(
+            "raw transaction has trailing bytes",
+        )));
+    }
+
+    let (to_customer_index, to_customer_amount) = match to_customer {
+        Some(n) => n,
+        None => {
+            return Err(ZkChannelError::TransactionError(String::from(
+                "cust-close-tx does not have a to_customer output",
+            )))
+        }
+    };
+    let op_return = match op_return {
+        Some(n) => n,
+        None => {
+            return Err(ZkChannelError::TransactionError(String::from(
+                "cust-close-tx does not have an OP_RETURN output",
+            )))
+        }
+    };
+    let mut rev_lock = [0u8; 32];
+    rev_lock.copy_from_slice(&op_return[0..32]);
+
+    // txid is the double sha256 of the tx without the witness data
+    let mut txid_le = hash_to_slice(&hash_to_slice(&stripped_tx).to_vec());
+    txid_le.reverse();
+
+    Ok(CustCloseTxInfo {
+        txid_le,
+        prevout_txid_be,
+        to_customer_index,
+        to_customer_amount,
+        rev_lock,
+        cust_close_pk: op_return[32..].to_vec(),
+    })
+}
+
+/// Fields of any raw transaction. All txids are big-endian.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TxInfo {
+    pub txid_be: [u8; 32],
+    // outpoints (txid, index) spent by the inputs
+    pub outpoints: Vec<([u8; 32], u32)>,
+    // amount and scriptPubKey of each output
+    pub outputs: Vec<(i64, Vec<u8>)>,
+    pub weight: usize,
+}
+
+impl TxInfo {
+    pub fn get_vsize(&self) -> usize {
+        (self.weight + 3) / 4
+    }
+}
+
+/// Parses any raw transaction (segwit or not).
+pub fn parse_transaction(raw_tx: &Vec<u8>) -> Result<TxInfo, ZkChannelError> {
+    let mut r = RawTxReader {
+        buf: raw_tx.as_slice(),
+        pos: 0,
+    };
+    let mut stripped_tx = Vec::new();
+    stripped_tx.extend_from_slice(r.read(4)?);
+    let is_segwit = raw_tx.len() > 6 && raw_tx[4] == 0x00 && raw_tx[5] == 0x01;
+    if is_segwit {
+        r.read(2)?;
+    }
+
+    let start = r.pos;
+    let num_inputs = r.read_varint()?;
+    let mut outpoints = Vec::new();
+    for _ in 0..num_inputs {
+        let mut txid_be = [0u8; 32];
+        txid_be.copy_from_slice(r.read(32)?);
+        let mut index = 0u32;
+        for (i, b) in r.read(4)?.iter().enumerate() {
+            index |= (*b as u32) << (8 * i);
+        }
+        outpoints.push((txid_be, index));
+        r.skip_script_sig()?;
+    }
+    let num_outputs = r.read_varint()?;
+    let mut outputs = Vec::new();
+    for _ in 0..num_outputs {
+        let mut amount = 0i64;
+        for (j, b) in r.read(8)?.iter().enumerate() {
+            amount |= (*b as i64) << (8 * j);
+        }
+        let script_len = r.read_varint()?;
+        outputs.push((amount, r.read(script_len)?.to_vec()));
+    }
+    stripped_tx.extend_from_slice(&raw_tx[start..r.pos]);
+
+    if is_segwit {
+        for _ in 0..num_inputs {
+            let num_items = r.read_varint()?;
+            for _ in 0..num_items {
+                let item_len = r.read_varint()?;
+                r.read(item_len)?;
+            }
+        }
+    }
+    stripped_tx.extend_from_slice(r.read(4)?);
+    if r.pos != raw_tx.len() {
+        return Err(ZkChannelError::TransactionError(String::from(
+            "raw transaction has trailing bytes",
+        )));
+    }
+
+    Ok(TxInfo {
+        txid_be: hash_to_slice(&hash_to_slice(&stripped_tx).to_vec()),
+        outpoints,
+        outputs,
+        // witness data counts 1 weight unit per byte, everything else 4
+        weight: stripped_tx.len() * 3 + raw_tx.len(),
+    })
+}
+
+/// Fields of a signed mutual close tx that are needed to verify its signatures.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MutualCloseTxInfo {
+    // outpoint (txid, index) spent by the only input
+    pub outpoint: ([u8; 32], u32),
+    pub outputs: Vec<(i64, Vec<u8>)>,
+    // DER signatures of the 2-of-2 witness (without the sighash flag)
+    pub sigs: Vec<Vec<u8>>,
+    // BIP143 sighash (SIGHASH_ALL) of the input
+    pub sighash: [u8; 32],
+}
+
+fn write_varint(buf: &mut Vec<u8>, n: usize) {
+    if n < 0xfd {
+        buf.push(n as u8);
+    } else if n <= 0xffff {
+        buf.push(0xfd);
+        buf.extend_from_slice(&(n as u16).to_le_bytes());
+    } else {
+        buf.push(0xfe);
+        buf.extend_from_slice(&(n as u32).to_le_bytes());
+    }
+}
+
+fn hash256(input: &[u8]) -> [u8; 32] {
+    hash_to_slice(&hash_to_slice(&input.to_vec()).to_vec())
+}
+
+///
+/// parse_mutual_close_transaction() - parses a mutual close tx that spends a 2-of-2 multisig escrow output
+/// of the given amount (witness: empty, sig, sig, redeem script)
+/// output: the outpoint, outputs, signatures and sighash of the tx
+///
+pub fn parse_mutual_close_transaction(
+    raw_tx: &Vec<u8>,
+    utxo_amount: i64,
+) -> Result<MutualCloseTxInfo, ZkChannelError> {
+    let mut r = RawTxReader {
+        buf: raw_tx.as_slice(),
+        pos: 0,
+    };
+    let version = r.read(4)?;
+    if raw_tx.len() <= 6 || raw_tx[4] != 0x00 || raw_tx[5] != 0x01 {
+        return Err(ZkChannelError::TransactionError(String::from(
+            "expected a segwit mutual close tx",
+        )));
+    }
+    r.read(2)?;
+    if r.read_varint()? != 1 {
+        return Err(ZkChannelError::TransactionError(String::from(
+            "expected a mutual close tx with one input",
+        )));
+    }
+    let outpoint_bytes = r.read(36)?;
+    let mut txid_be = [0u8; 32];
+    txid_be.copy_from_slice(&outpoint_bytes[..32]);
+    let mut index = 0u32;
+    for (i, b) in outpoint_bytes[32..].iter().enumerate() {
+        index |= (*b as u32) << (8 * i);
+    }
+    if r.read_varint()? != 0 {
+        return Err(ZkChannelError::TransactionError(String::from(
+            "mutual close tx must have an empty script_sig",
+        )));
+    }
+    let sequence = r.read(4)?;
+
+    let num_outputs = r.read_varint()?;
+    let outputs_start = r.pos;
+    let mut outputs = Vec::new();
+    for _ in 0..num_outputs {
+        let mut amount = 0i64;
+        for (j, b) in r.read(8)?.iter().enumerate() {
+            amount |= (*b as i64) << (8 * j);
+        }
+        let script_len = r.read_varint()?;
+        outputs.push((amount, r.read(script_len)?.to_vec()));
+    }
+    let outputs_bytes = &raw_tx[outputs_start..r.pos];
+
+    let mut witness = Vec::new();
+    for _ in 0..r.read_varint()? {
+        let item_len = r.read_varint()?;
+        witness.push(r.read(item_len)?.to_vec());
+    }
+    let locktime = r.read(4)?;
+    if r.pos != raw_tx.len() {
+        return Err(ZkChannelError::TransactionError(String::from(
+            "raw transaction has trailing bytes",
+        )));
+    }
+    if witness.len() != 4 || !witness[0].is_empty() {
+        return Err(ZkChannelError::TransactionError(String::from(
+            "expected a 2-of-2 multisig witness in the mutual close tx",
+        )));
+    }
+    let mut sigs = Vec::new();
+    for sig in witness[1..3].iter() {
+        match sig.split_last() {
+            Some((&0x01, der_sig)) => sigs.push(der_sig.to_vec()),
+            _ => {
+                return Err(ZkChannelError::TransactionError(String::from(
+                    "expected SIGHASH_ALL signatures in the mutual close tx",
+                )))
+            }
+        }
+    }
+
+    let mut preimage = version.to_vec();
+    preimage.extend_from_slice(&hash256(outpoint_bytes));
+    preimage.extend_from_slice(&hash256(sequence));
+    preimage.extend_from_slice(outpoint_bytes);
+    write_varint(&mut preimage, witness[3].len());
+    preimage.extend_from_slice(&witness[3]);
+    preimage.extend_from_slice(&utxo_amount.to_le_bytes());
+    preimage.extend_from_slice(sequence);
+    preimage.extend_from_slice(&hash256(outputs_bytes));
+    preimage.extend_from_slice(locktime);
+    preimage.extend_from_slice(&[0x01, 0x00, 0x00, 0x00]);
+
+    Ok(MutualCloseTxInfo {
+        outpoint: (txid_be, index),
+        outputs,
+        sigs,
+        sighash: hash256(&preimage),
+    })
+}
+
 fn xor_in_place(a: &mut [u8], b: &[u8]) {
     for (b1, b2) in a.iter_mut().zip(b.iter()) {
         *b1 ^= *b2;
@@ -1063,6 +2579,31 @@ impl RevokedState {
     }
 }
 
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MutualCloseRequest {
+    pub state: State,
+    pub pay_token: FixedSizeArray32,
+    pub cust_close_pk: secp256k1::PublicKey,
+    pub cust_bal: i64,
+    pub merch_bal: i64,
+    pub cust_sig: Vec<u8>,
+}
+
+///
+/// TopUpRequest - sent by the customer to top up the channel. Reveals the current state and its pay token
+/// (like a mutual close) so that the merchant can check that the balances are carried forward to the
+/// initial state of the new escrow.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TopUpRequest {
+    pub state: State,
+    pub pay_token: FixedSizeArray32,
+    pub splice_tx: EscrowSpliceTx,
+    pub funding_tx: FundingTxInfo,
+    pub init_state: InitCustState,
+    pub init_hash: FixedSizeArray32,
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PayMaskMap {
     pub mask: FixedSizeArray32,
@@ -1079,6 +2620,21 @@ pub struct MerchCloseTx {
     self_delay: String,
 }
 
+///
+/// LegacyChannelMaps - the per-channel maps that merchant states kept before they moved to the
+/// StateDatabase (schema version 1), keyed by escrow txid (status, merch-close-tx) or channel id
+/// (initial state). A migrated merchant state carries them until they are written to the db
+///
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct LegacyChannelMaps {
+    #[serde(default)]
+    pub channel_status_map: HashMap<String, ChannelStatus>,
+    #[serde(default)]
+    pub activate_map: HashMap<String, State>,
+    #[serde(default)]
+    pub close_tx: HashMap<String, MerchCloseTx>,
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct MerchantMPCState {
     id: String,
@@ -1095,13 +2651,26 @@ pub struct MerchantMPCState {
     dispute_sk: FixedSizeArray32,
     // for dispute pub key
     pub dispute_pk: secp256k1::PublicKey,
-    // replace the following with a fast in-memory key-value DB
-    channel_status_map: HashMap<FixedSizeArray32, ChannelStatus>,
-    pub activate_map: HashMap<String, State>,
-    pub close_tx: HashMap<FixedSizeArray32, MerchCloseTx>,
+    // per-channel state (status, initial state, merch-close-tx) lives in the StateDatabase
+    #[serde(default)]
+    legacy_channel_maps: Option<LegacyChannelMaps>,
     pub net_config: Option<NetworkConfig>,
     pub db_url: String,
     refund_policy: NegativePaymentPolicy,
+    // signs the justifications for negative payments (see RefundAuthorityVerifier)
+    #[serde(default)]
+    refund_authority_pk: Option<secp256k1::PublicKey>,
+    #[serde(default)]
+    network: BitcoinNetworkType,
+    // seconds after which an unfinished payment session can be expired
+    #[serde(default = "default_session_timeout")]
+    session_timeout: u64,
+}
+
+pub const DEFAULT_SESSION_TIMEOUT: u64 = 600;
+
+fn default_session_timeout() -> u64 {
+    DEFAULT_SESSION_TIMEOUT
 }
 
 impl MerchantMPCState {
@@ -1153,22 +2722,69 @@ impl MerchantMPCState {
             payout_pk: payout_pub_key,
             dispute_sk: FixedSizeArray32(_dispute_sk),
             dispute_pk: dispute_pub_key,
-            channel_status_map: HashMap::new(),
-            activate_map: HashMap::new(),
-            close_tx: HashMap::new(),
+            legacy_channel_maps: None,
             net_config: None,
             db_url: db_url,
             refund_policy: NegativePaymentPolicy::REJECT,
+            refund_authority_pk: None,
+            network: channel.get_network(),
+            session_timeout: DEFAULT_SESSION_TIMEOUT,
         }
     }
 
+    pub fn has_legacy_channel_maps(&self) -> bool {
+        self.legacy_channel_maps.is_some()
+    }
+
+    ///
+    /// migrate_legacy_channel_maps() - writes the per-channel maps of a merchant state from schema
+    /// version 1 to the db. Entries that the db already has are kept, so the migration can be repeated
+    /// output: true if the state changed (and has to be saved)
+    ///
+    pub fn migrate_legacy_channel_maps(
+        &mut self,
+        db: &mut dyn StateDatabase,
+    ) -> Result<bool, ZkChannelError> {
+        let legacy = match self.legacy_channel_maps.clone() {
+            Some(m) => m,
+            None => return Ok(false),
+        };
+        let mut statuses = Vec::new();
+        for (escrow_txid_hex, status) in legacy.channel_status_map.iter() {
+            if db.get_channel_status(escrow_txid_hex).is_err() {
+                statuses.push((escrow_txid_hex.clone(), status.clone()));
+            }
+        }
+        if !statuses.is_empty() {
+            db.update_channel_statuses(&statuses)?;
+        }
+        for (channel_id_hex, s0) in legacy.activate_map.iter() {
+            if db.get_activate_state(channel_id_hex).is_err() {
+                db.update_activate_map(channel_id_hex, s0)?;
+            }
+            // older states did not map the merch-close-tx back to the channel (see
+            // validate_initial_state()), which a cust-close-tx spending from it needs
+            let merch_txid_hex = hex::encode(&s0.merch_txid.0);
+            if db.get_escrow_txid(&merch_txid_hex).is_err() {
+                db.update_merch_txid_map(&merch_txid_hex, &hex::encode(&s0.escrow_txid.0))?;
+            }
+        }
+        for (escrow_txid_hex, merch_close_tx) in legacy.close_tx.iter() {
+            if db.get_merch_close_tx(escrow_txid_hex).is_err() {
+                db.update_close_tx_map(escrow_txid_hex, merch_close_tx)?;
+            }
+        }
+        self.legacy_channel_maps = None;
+        Ok(true)
+    }
+
     pub fn load_external_wallet(
         &mut self,
         channel: &mut ChannelMPCState,
         merch_sk: [u8; 32],
         pay_sk: [u8; 32],
         disp_sk: [u8; 32],
-    ) -> Result<(), String> {
+    ) -> Result<(), ZkChannelError> {
         let secp = secp256k1::Secp256k1::new();
 
         let sk_m = handle_error_util!(secp256k1::SecretKey::from_slice(&merch_sk));
@@ -1195,10 +2811,132 @@ impl MerchantMPCState {
         Ok(())
     }
 
+    ///
+    /// load_derived_keys() - replaces all merchant secrets (including the hmac key) with keys derived
+    /// from a seed and updates the key commitment and merchant public keys in the channel state
+    ///
+    pub fn load_derived_keys(
+        &mut self,
+        channel: &mut ChannelMPCState,
+        keys: &MerchantKeys,
+    ) -> Result<(), ZkChannelError> {
+        self.load_external_wallet(channel, keys.sk_m, keys.payout_sk, keys.dispute_sk)?;
+
+        channel.set_key_com(keys.get_key_com());
+        self.hmac_key = FixedSizeArray64::new(keys.hmac_key);
+        self.hmac_key_r = FixedSizeArray16(keys.hmac_key_r);
+        Ok(())
+    }
+
     pub fn set_refund_policy(&mut self, policy: NegativePaymentPolicy) {
         self.refund_policy = policy;
     }
 
+    pub fn set_refund_authority(&mut self, authority_pk: secp256k1::PublicKey) {
+        self.refund_authority_pk = Some(authority_pk);
+    }
+
+    pub fn set_session_timeout(&mut self, timeout_secs: u64) {
+        self.session_timeout = timeout_secs;
+    }
+
+    pub fn get_session_timeout(&self) -> u64 {
+        return self.session_timeout;
+    }
+
+    // the name of the merchant (e.g., the id of the merchant in a MerchantRegistry)
+    pub fn get_id(&self) -> &String {
+        return &self.id;
+    }
+
+    fn is_session_expired(&self, session_state: &SessionState, now: u64) -> bool {
+        now >= session_state
+            .created_at
+            .saturating_add(self.session_timeout)
+    }
+
+    // removes the session state, its masked inputs and (if still owned by the session) the nonce entries
+    fn remove_session(
+        &self,
+        db: &mut dyn StateDatabase,
+        session_id_hex: &String,
+        nonce_hex: &String,
+    ) {
+        db.clear_session_state(session_id_hex);
+        db.clear_masked_mpc_inputs(session_id_hex);
+        match db.get_session_id_for_nonce(nonce_hex) {
+            Ok(s) => {
+                if s.eq_ignore_ascii_case(session_id_hex) {
+                    db.clear_nonce_to_session_id(nonce_hex);
+                    db.clear_nonce_mask_map(nonce_hex);
+                }
+            }
+            Err(_) => (),
+        }
+    }
+
+    // a session can be restarted if it failed or expired in the prepare step, and only as long
+    // as its pay masks were not released (the customer could otherwise close on both states)
+    fn is_session_restartable(&self, session_state: &SessionState, now: u64) -> bool {
+        if session_state.masks_released {
+            return false;
+        }
+        match session_state.status {
+            PaymentStatus::Error => true,
+            PaymentStatus::Prepare => self.is_session_expired(session_state, now),
+            _ => false,
+        }
+    }
+
+    // a nonce can be reused by a new session if its previous session can be restarted
+    fn release_nonce_session(
+        &self,
+        db: &mut dyn StateDatabase,
+        nonce_hex: &String,
+        now: u64,
+    ) -> Result<bool, ZkChannelError> {
+        let old_session_id_hex = db.get_session_id_for_nonce(nonce_hex)?;
+        let can_restart = match db.load_session_state(&old_session_id_hex) {
+            Ok(s) => self.is_session_restartable(&s, now),
+            // session state was already cleared, only the nonce mapping is left
+            Err(ZkChannelError::NotFound(_)) => true,
+            Err(e) => return Err(e),
+        };
+        if can_restart {
+            self.remove_session(db, &old_session_id_hex, nonce_hex);
+        }
+        Ok(can_restart)
+    }
+
+    ///
+    /// expire_sessions() - removes all payment sessions (and their pay masks) that were created
+    /// more than session_timeout seconds before now. Sessions whose pay masks were released are
+    /// kept, so their nonce stays bound until the customer reveals the rev lock
+    /// output: the expired session ids (hex)
+    ///
+    pub fn expire_sessions(
+        &self,
+        db: &mut dyn StateDatabase,
+        now: u64,
+    ) -> Result<Vec<String>, ZkChannelError> {
+        db.is_connected()?;
+
+        let mut expired = Vec::new();
+        for session_id_hex in db.get_session_ids()? {
+            let session_state = match db.load_session_state(&session_id_hex) {
+                Ok(s) => s,
+                Err(ZkChannelError::NotFound(_)) => continue,
+                Err(e) => return Err(e),
+            };
+            if self.is_session_expired(&session_state, now) && !session_state.masks_released {
+                let nonce_hex = hex::encode(session_state.nonce.0);
+                self.remove_session(db, &session_id_hex, &nonce_hex);
+                expired.push(session_id_hex);
+            }
+        }
+        Ok(expired)
+    }
+
     pub fn get_secret_key(&self) -> Vec<u8> {
         return self.sk_m.0.to_vec();
     }
@@ -1211,12 +2949,16 @@ impl MerchantMPCState {
         return self.dispute_sk.0.to_vec();
     }
 
+    pub fn get_network(&self) -> BitcoinNetworkType {
+        return self.network;
+    }
+
     pub fn activate_channel(
         &self,
         db: &mut dyn StateDatabase,
         channel_token: &ChannelMPCToken,
         s0: &State,
-    ) -> Result<[u8; 32], String> {
+    ) -> Result<[u8; 32], ZkChannelError> {
         // check db is connected
         db.is_connected()?;
 
@@ -1226,19 +2968,19 @@ impl MerchantMPCState {
 
         // check that s_0 is consistent with init phase before signing
         let s0_hash = s0.compute_hash();
-        let init_state_hash = match self.activate_map.get(&channel_id_str) {
-            Some(n) => n.compute_hash(),
-            None => {
-                return Err(String::from(
+        let init_state_hash = match db.get_activate_state(&channel_id_str) {
+            Ok(n) => n.compute_hash(),
+            Err(_) => {
+                return Err(ZkChannelError::NotFound(String::from(
                     "activate_channel: could not find initial state given channel token",
-                ));
+                )));
             }
         };
 
         if s0_hash != init_state_hash {
-            return Err(String::from(
+            return Err(ZkChannelError::InvalidInput(String::from(
                 "activate_channel: initial state on activation does not match stored state",
-            ));
+            )));
         }
 
         // proceed to sign the initial state
@@ -1255,7 +2997,7 @@ impl MerchantMPCState {
         channel_token: &ChannelMPCToken,
         init_state: &InitCustState,
         init_state_hash: [u8; 32],
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
         // check db is connected
         db.is_connected()?;
 
@@ -1265,28 +3007,46 @@ impl MerchantMPCState {
         let mut escrow_txid_be = channel_token.escrow_txid.0.clone();
         escrow_txid_be.reverse();
 
-        // initialize the channel status map for the given escrow txid
-        self.channel_status_map.insert(
-            FixedSizeArray32(escrow_txid_be.clone()),
-            ChannelStatus::None,
-        );
+        // initialize the channel status for the given escrow txid
+        db.update_channel_status(&hex::encode(&escrow_txid_be), &ChannelStatus::None)?;
 
         // check if pk_c
         let pk_c = match channel_token.pk_c {
             Some(pk) => pk,
-            None => return Err(String::from("cannot validate channel token: pk_c not set")),
+            None => {
+                return Err(ZkChannelError::InvalidInput(String::from(
+                    "cannot validate channel token: pk_c not set",
+                )))
+            }
         };
 
         if pk_c != init_state.pk_c {
-            return Err(String::from(
+            return Err(ZkChannelError::InvalidInput(String::from(
                 "init state pk_c does not match channel token pk_c",
-            ));
+            )));
         }
 
         if channel_token.pk_m != self.pk_m {
-            return Err(String::from(
+            return Err(ZkChannelError::InvalidInput(String::from(
                 "channel token pk_m does not match merch state pk_m",
-            ));
+            )));
+        }
+
+        if channel_token.network != self.network {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "channel token network does not match merch state network: {} != {}",
+                channel_token.network, self.network
+            )));
+        }
+
+        // in a dual-funded channel the initial balances are exactly what each party put in
+        if init_state.merch_funding != 0
+            && (init_state.merch_bal != init_state.merch_funding
+                || init_state.cust_bal != init_state.cust_funding)
+        {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "init state balances do not match the funding contributions",
+            )));
         }
 
         // cache prevout from escrow_txid and escrow_prevout
@@ -1324,39 +3084,83 @@ impl MerchantMPCState {
 
         if init_state_hash != s0.compute_hash() {
             println!("state: {}", s0);
-            return Err(String::from("initial state not well-formed"));
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "initial state not well-formed",
+            )));
         }
 
         let nonce_hex_str = hex::encode(s0.get_nonce());
 
-        self.activate_map.insert(channel_id_str, s0);
+        db.update_activate_map(&channel_id_str, &s0)?;
         db.update_unlink_set(&nonce_hex_str)?;
+        // so that a cust-close-tx spending from the merch-close-tx can be traced back to the channel
+        db.update_merch_txid_map(&hex::encode(&merch_txid_be), &hex::encode(&escrow_txid_be))?;
 
-        let res = self.change_channel_status(escrow_txid_be, ChannelStatus::PendingOpen);
+        let res = self.change_channel_status(db, escrow_txid_be, ChannelStatus::PendingOpen);
         if res.is_err() {
-            return Err(format!(
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
                 "could not change channel status to: {}",
                 ChannelStatus::PendingOpen
-            ));
+            )));
         }
 
         Ok(true)
     }
 
+    // uses the given verifier or, if none, the configured refund authority
     pub fn process_justification(
         &self,
+        db: &mut dyn StateDatabase,
+        nonce_hex: &String,
         amount: i64,
-        signature: Option<String>,
-    ) -> Result<bool, String> {
-        // TODO: ZKC-17 verify justification for negative payments
-        if signature.is_some() {
-            println!("Verify justification for negative payment: {}", amount);
-            return Ok(true);
+        justification: Option<String>,
+        verifier: Option<&dyn JustificationVerifier>,
+    ) -> Result<bool, ZkChannelError> {
+        let justification = match justification {
+            Some(j) => j,
+            None => return Ok(false),
+        };
+        match verifier {
+            Some(v) => v.verify(db, nonce_hex, amount, &justification),
+            None => match self.refund_authority_pk {
+                Some(pk) => RefundAuthorityVerifier { authority_pk: pk }.verify(
+                    db,
+                    nonce_hex,
+                    amount,
+                    &justification,
+                ),
+                None => Err(ZkChannelError::InvalidInput(String::from(
+                    "no refund authority configured to check justifications",
+                ))),
+            },
         }
-        return Ok(false);
     }
 
     pub fn generate_pay_mask_commitment<R: Rng>(
+        &mut self,
+        csprng: &mut R,
+        db: &mut dyn StateDatabase,
+        channel_state: &ChannelMPCState,
+        session_id: [u8; 16],
+        nonce: [u8; NONCE_LEN],
+        rev_lock_com: [u8; 32],
+        amount: i64,
+        justification: Option<String>,
+    ) -> Result<[u8; 32], ZkChannelError> {
+        self.generate_pay_mask_commitment_with_verifier(
+            csprng,
+            db,
+            channel_state,
+            session_id,
+            nonce,
+            rev_lock_com,
+            amount,
+            justification,
+            None,
+        )
+    }
+
+    pub fn generate_pay_mask_commitment_with_verifier<R: Rng>(
         &mut self,
         csprng: &mut R,
         db: &mut dyn StateDatabase,
@@ -1366,7 +3170,8 @@ impl MerchantMPCState {
         rev_lock_com: [u8; 32],
         amount: i64,
         justification: Option<String>,
-    ) -> Result<[u8; 32], String> {
+        verifier: Option<&dyn JustificationVerifier>,
+    ) -> Result<[u8; 32], ZkChannelError> {
         // check db is connected
         db.is_connected()?;
 
@@ -1375,52 +3180,67 @@ impl MerchantMPCState {
         // check if there's an existing active session with the same session id
         let is_existing_session = match db.check_session_id(&session_id_hex) {
             Ok(s) => s,
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(e),
         };
 
         if is_existing_session {
-            return Err(format!(
+            return Err(ZkChannelError::ReusedSessionId(format!(
                 "Specified an existing session id: {}",
                 session_id_hex
-            ));
+            )));
         }
 
-        // if specified nonce is part of an existing active session
-        if db.check_dup_nonce_to_session_id(&nonce_hex, &session_id_hex) {
-            return Err(format!(
+        // if specified nonce is part of an existing active session. A customer restarts a
+        // payment with the same nonce (and a new session id) once the previous session failed or expired
+        if db.check_dup_nonce_to_session_id(&nonce_hex, &session_id_hex)
+            && !self.release_nonce_session(db, &nonce_hex, get_current_time())?
+        {
+            return Err(ZkChannelError::DoubleSpentNonce(format!(
                 "Cannot reuse nonce with a different session id: {} {}",
                 session_id_hex, nonce_hex
-            ));
+            )));
         }
 
         // check if n_i in S_unlink and amount == 0. if so, proceed since this is the unlink protocol
         if amount == 0 && !db.is_member_unlink_set(&nonce_hex) {
-            return Err(String::from(
+            return Err(ZkChannelError::InvalidInput(String::from(
                 "Can only run unlink with previously known nonce",
-            ));
+            )));
+        }
+
+        // check if n_i not in S_spent
+        if db.check_spent_map(&nonce_hex) {
+            return Err(ZkChannelError::DoubleSpentNonce(format!(
+                "nonce {} has been spent already.",
+                &nonce_hex
+            )));
         }
 
+        // checked after the nonce so that a refund id is not used up by a spent nonce
         if amount < 0 {
             let payment_result = match self.refund_policy {
                 NegativePaymentPolicy::REJECT => false,
                 NegativePaymentPolicy::CHECK_JUSTIFICATION => {
-                    match self.process_justification(amount, justification) {
+                    match self.process_justification(
+                        db,
+                        &nonce_hex,
+                        amount,
+                        justification,
+                        verifier,
+                    ) {
                         Ok(s) => s,
-                        Err(e) => return Err(e.to_string()),
+                        Err(e) => return Err(e),
                     }
                 }
             };
 
             if !payment_result {
-                return Err(format!("Sorry, refunds are not allowed for this channel"));
+                return Err(ZkChannelError::InvalidInput(format!(
+                    "Sorry, refunds are not allowed for this channel"
+                )));
             }
         }
 
-        // check if n_i not in S_spent
-        if db.check_spent_map(&nonce_hex) {
-            return Err(format!("nonce {} has been spent already.", &nonce_hex));
-        }
-
         // pick mask_pay and form commitment to it
         let mut pay_mask = [0u8; 32];
         let mut pay_mask_r = [0u8; 16];
@@ -1432,26 +3252,44 @@ impl MerchantMPCState {
         pay_mask_buf.extend_from_slice(&pay_mask_r);
         let paytoken_mask_com = hash_to_slice(&pay_mask_buf);
 
-        // store pay_mask for use in mpc protocol later
-        db.update_nonce_mask_map(&nonce_hex, pay_mask, pay_mask_r)?;
-
-        // save session state
         let sess_state = SessionState {
             nonce: FixedSizeArray16(nonce),
             rev_lock_com: FixedSizeArray32(rev_lock_com),
             amount: amount,
             status: PaymentStatus::Prepare,
+            created_at: get_current_time(),
+            masks_released: false,
         };
-        if !db.save_new_session_state(&session_id_hex, &sess_state) {
-            println!(
-                "Save-new-session-state failed: Could not cache new session state for id: {}",
-                &session_id_hex
-            );
-        }
 
-        let is_ok = db.update_nonce_to_session_id(&nonce_hex, &session_id_hex);
-        if (is_ok.is_err()) {
-            println!("Could not completely save state: {}", is_ok.unwrap_err());
+        // the checks above are repeated atomically with the writes, so that only one of two
+        // concurrent sessions for the same nonce (or session id) can start
+        let mut batch = DbBatch::new();
+        batch.check(BatchCheck::NewSessionId(session_id_hex.clone()));
+        batch.check(BatchCheck::NonceNotSpent(nonce_hex.clone()));
+        batch.check(BatchCheck::NonceFreeForSession(
+            nonce_hex.clone(),
+            session_id_hex.clone(),
+        ));
+        // store pay_mask for use in mpc protocol later
+        batch.write(BatchWrite::UpdateNonceMaskMap(
+            nonce_hex.clone(),
+            pay_mask,
+            pay_mask_r,
+        ));
+        // save session state
+        batch.write(BatchWrite::SaveSessionState(
+            session_id_hex.clone(),
+            sess_state,
+        ));
+        batch.write(BatchWrite::UpdateNonceToSessionId(
+            nonce_hex.clone(),
+            session_id_hex.clone(),
+        ));
+        if !db.apply_batch(&batch)? {
+            return Err(ZkChannelError::DoubleSpentNonce(format!(
+                "nonce {} was spent or used by a concurrent session (session id: {})",
+                nonce_hex, session_id_hex
+            )));
         }
 
         Ok(paytoken_mask_com)
@@ -1478,7 +3316,7 @@ impl MerchantMPCState {
         fee_cc: i64,
         fee_mc: i64,
         val_cpfp: i64,
-    ) -> Result<(Vec<u8>, Vec<u8>), String> {
+    ) -> Result<(Vec<u8>, Vec<u8>), ZkChannelError> {
         let escrow_init_balance = funding_tx.init_cust_bal + funding_tx.init_merch_bal;
         let merch_init_balance = escrow_init_balance - val_cpfp - fee_mc;
         let escrow_index = 0;
@@ -1500,29 +3338,35 @@ impl MerchantMPCState {
             rev_lock: FixedSizeArray32(rev_lock),
         };
 
-        let (escrow_tx_preimage, _, _) = create_cust_close_transaction::<N>(
-            &escrow_input,
-            &pubkeys,
-            &to_self_delay_be,
-            funding_tx.init_cust_bal,
-            funding_tx.init_merch_bal,
-            fee_cc,
-            fee_mc,
-            val_cpfp,
-            true,
-        )?;
+        let (escrow_tx_preimage, _, _) = handle_error_util!(
+            create_cust_close_transaction::<N>(
+                &escrow_input,
+                &pubkeys,
+                &to_self_delay_be,
+                funding_tx.init_cust_bal,
+                funding_tx.init_merch_bal,
+                fee_cc,
+                fee_mc,
+                val_cpfp,
+                true,
+            ),
+            ZkChannelError::TransactionError
+        );
 
-        let (merch_tx_preimage, _, _) = create_cust_close_transaction::<N>(
-            &merch_input,
-            &pubkeys,
-            &to_self_delay_be,
-            funding_tx.init_cust_bal,
-            funding_tx.init_merch_bal,
-            fee_cc,
-            fee_mc,
-            val_cpfp,
-            false,
-        )?;
+        let (merch_tx_preimage, _, _) = handle_error_util!(
+            create_cust_close_transaction::<N>(
+                &merch_input,
+                &pubkeys,
+                &to_self_delay_be,
+                funding_tx.init_cust_bal,
+                funding_tx.init_merch_bal,
+                fee_cc,
+                fee_mc,
+                val_cpfp,
+                false,
+            ),
+            ZkChannelError::TransactionError
+        );
 
         // merchant generates signatures
         let sk_m = self.sk_m.0.to_vec();
@@ -1538,7 +3382,8 @@ impl MerchantMPCState {
     }
 
     pub fn store_merch_close_tx(
-        &mut self,
+        &self,
+        db: &mut dyn StateDatabase,
         escrow_txid_be: &Vec<u8>,
         cust_pk: &Vec<u8>,
         cust_bal_sats: i64,
@@ -1546,7 +3391,7 @@ impl MerchantMPCState {
         fee_mc: i64,
         to_self_delay_be: [u8; 2],
         cust_sig: &Vec<u8>,
-    ) {
+    ) -> Result<(), ZkChannelError> {
         let merch_close = MerchCloseTx {
             cust_pk: hex::encode(cust_pk),
             bc: cust_bal_sats,
@@ -1556,26 +3401,349 @@ impl MerchantMPCState {
             self_delay: hex::encode(to_self_delay_be),
         };
 
-        let mut escrow_txid = [0u8; 32];
-        escrow_txid.copy_from_slice(escrow_txid_be.as_slice());
-        self.close_tx
-            .insert(FixedSizeArray32(escrow_txid), merch_close);
-        let _res = self.change_channel_status(escrow_txid.clone(), ChannelStatus::None);
+        if escrow_txid_be.len() != 32 {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "escrow-txid does not have expected length: {}",
+                escrow_txid_be.len()
+            )));
+        }
+        let mut escrow_txid = [0u8; 32];
+        escrow_txid.copy_from_slice(escrow_txid_be.as_slice());
+        db.update_close_tx_map(&hex::encode(&escrow_txid), &merch_close)?;
+        let _res = self.change_channel_status(db, escrow_txid.clone(), ChannelStatus::None);
+        Ok(())
+    }
+
+    ///
+    /// verify_dual_funded_escrow() - checks that the escrow-tx spends the merchant's contribution
+    /// unmodified and funds a channel that has been validated (PendingOpen) with an initial merchant
+    /// balance that covers the contribution.
+    ///
+    pub fn verify_dual_funded_escrow(
+        &self,
+        db: &mut dyn StateDatabase,
+        escrow_tx: &DualFundedEscrowTx,
+        merch_contribution: &EscrowContribution,
+    ) -> Result<(), ZkChannelError> {
+        if escrow_tx.merch_pk != self.pk_m {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "escrow-tx does not pay to the merchant pk",
+            )));
+        }
+        if escrow_tx.merch != *merch_contribution {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "escrow-tx does not contain the merchant contribution as proposed",
+            )));
+        }
+
+        let (escrow_txid_be, _) = escrow_tx.get_txid();
+        let channel_status = self.get_channel_status(db, escrow_txid_be)?;
+        if channel_status != ChannelStatus::PendingOpen {
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
+                "channel params must be validated before signing the escrow-tx: {}",
+                channel_status
+            )));
+        }
+
+        let escrow_txid_hex = hex::encode(&escrow_txid_be);
+        let m = db.get_merch_close_tx(&escrow_txid_hex)?;
+        if m.cust_pk != hex::encode(&escrow_tx.cust_pk.serialize().to_vec())
+            || m.bc + m.bm != escrow_tx.get_escrow_sats()
+            || m.bm < merch_contribution.escrow_sats
+        {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "escrow-tx does not match the merch-close-tx for escrow_txid: {}",
+                escrow_txid_hex
+            )));
+        }
+        Ok(())
+    }
+
+    // checks that the top-up spends the escrow of an open channel on its latest state and carries the
+    // balances of that state forward to the new initial state (which is returned)
+    fn verify_top_up_request(
+        &self,
+        db: &mut dyn StateDatabase,
+        channel_token: &ChannelMPCToken,
+        request: &TopUpRequest,
+    ) -> Result<State, ZkChannelError> {
+        // check db is connected
+        db.is_connected()?;
+
+        let mut escrow_txid_be = channel_token.escrow_txid.0.clone();
+        escrow_txid_be.reverse();
+        let channel_status = self.get_channel_status(db, escrow_txid_be)?;
+        if channel_status != ChannelStatus::Open {
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
+                "Invalid channel status for top-up: {}",
+                channel_status
+            )));
+        }
+        let cust_pk = match channel_token.pk_c {
+            Some(pk) => pk,
+            None => {
+                return Err(ZkChannelError::InvalidInput(String::from(
+                    "pk_c is not set in channel token",
+                )))
+            }
+        };
+        if channel_token.pk_m != self.pk_m {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "channel token pk_m does not match merch state pk_m",
+            )));
+        }
+
+        // the current state must be one that the merchant signed and that is neither spent nor revoked
+        let s = &request.state;
+        if s.escrow_txid.0 != escrow_txid_be {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "top-up state does not belong to the channel",
+            )));
+        }
+        let key = self.hmac_key.get_bytes();
+        if hmac_sign(key, &s.serialize_compact()) != request.pay_token.0 {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "could not verify the pay token on the top-up state",
+            )));
+        }
+        if db.check_spent_map(&hex::encode(s.get_nonce()))
+            || db.check_rev_lock_map(&hex::encode(s.get_rev_lock()))
+        {
+            return Err(ZkChannelError::DoubleSpentNonce(String::from(
+                "top-up state has already been revoked",
+            )));
+        }
+
+        let splice_tx = &request.splice_tx;
+        if splice_tx.cust_pk != cust_pk
+            || splice_tx.merch_pk != self.pk_m
+            || splice_tx.escrow_txid_be.0 != escrow_txid_be
+            || splice_tx.escrow_sats != s.bc + s.bm
+        {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "splice-tx does not spend the escrow of the channel",
+            )));
+        }
+
+        let f = &request.funding_tx;
+        let (new_escrow_txid_be, _) = splice_tx.get_txid();
+        if f.escrow_txid.0 != new_escrow_txid_be
+            || f.escrow_prevout.0 != splice_tx.get_escrow_prevout()
+            || f.merch_prevout.0 != compute_escrow_prevout(&f.merch_txid.0)
+        {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "funding tx does not match the splice-tx",
+            )));
+        }
+        if f.init_cust_bal != s.bc + splice_tx.top_up.escrow_sats || f.init_merch_bal != s.bm {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "balances ({}, {}) are not carried forward from the state ({}, {})",
+                f.init_cust_bal, f.init_merch_bal, s.bc, s.bm
+            )));
+        }
+
+        let init_state = &request.init_state;
+        if init_state.pk_c != cust_pk
+            || init_state.cust_bal != f.init_cust_bal
+            || init_state.merch_bal != f.init_merch_bal
+            || init_state.min_fee != s.min_fee
+            || init_state.max_fee != s.max_fee
+            || init_state.fee_mc != s.fee_mc
+        {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "init state does not match the top-up",
+            )));
+        }
+        let s0 = State {
+            bc: init_state.cust_bal,
+            bm: init_state.merch_bal,
+            nonce: init_state.nonce.clone(),
+            rev_lock: init_state.rev_lock.clone(),
+            escrow_txid: f.escrow_txid.clone(),
+            escrow_prevout: f.escrow_prevout.clone(),
+            merch_txid: f.merch_txid.clone(),
+            merch_prevout: f.merch_prevout.clone(),
+            min_fee: init_state.min_fee,
+            max_fee: init_state.max_fee,
+            fee_mc: init_state.fee_mc,
+        };
+        if request.init_hash.0 != s0.compute_hash() {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "initial state not well-formed",
+            )));
+        }
+        if s0.get_nonce() == s.get_nonce() || db.check_spent_map(&hex::encode(s0.get_nonce())) {
+            return Err(ZkChannelError::DoubleSpentNonce(String::from(
+                "nonce of the topped up state has already been used",
+            )));
+        }
+
+        // the merch-close-tx of the new escrow has to be stored before the top-up
+        let new_escrow_txid_hex = hex::encode(&new_escrow_txid_be);
+        let m = db.get_merch_close_tx(&new_escrow_txid_hex)?;
+        if m.cust_pk != hex::encode(&cust_pk.serialize().to_vec())
+            || m.bc != s0.bc
+            || m.bm != s0.bm
+            || m.fee_mc != s0.fee_mc
+        {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "merch-close-tx does not match the topped up channel for escrow_txid: {}",
+                new_escrow_txid_hex
+            )));
+        }
+        Ok(s0)
+    }
+
+    ///
+    /// sign_top_up() - verifies the top-up request and signs the initial closing txs on the new escrow
+    /// output: escrow and merch signatures for the customer (as in establish)
+    ///
+    pub fn sign_top_up<N: BitcoinNetwork>(
+        &self,
+        db: &mut dyn StateDatabase,
+        channel_state: &ChannelMPCState,
+        channel_token: &ChannelMPCToken,
+        request: &TopUpRequest,
+        fee_cc: i64,
+    ) -> Result<(Vec<u8>, Vec<u8>), ZkChannelError> {
+        let s0 = self.verify_top_up_request(db, channel_token, request)?;
+        self.sign_initial_closing_transaction::<N>(
+            request.funding_tx.clone(),
+            s0.get_rev_lock(),
+            request.init_state.pk_c.serialize().to_vec(),
+            request.init_state.close_pk.serialize().to_vec(),
+            channel_state.get_self_delay_be(),
+            fee_cc,
+            s0.fee_mc,
+            channel_state.get_val_cpfp(),
+        )
+    }
+
+    ///
+    /// finalize_top_up() - verifies the customer signatures on the splice-tx and signs the escrow input.
+    /// The current state is spent and the new escrow registered (all in one batch). The old escrow stays
+    /// open until the chain monitor sees the splice-tx confirm, and the customer only revokes the current
+    /// state after that (see verify_top_up_revocation())
+    /// output: signed splice-tx and the merchant's escrow signature (for the customer)
+    ///
+    pub fn finalize_top_up(
+        &self,
+        db: &mut dyn StateDatabase,
+        channel_token: &ChannelMPCToken,
+        request: &TopUpRequest,
+        cust_sigs: &TopUpSignatures,
+    ) -> Result<(Vec<u8>, Vec<u8>), ZkChannelError> {
+        let s0 = self.verify_top_up_request(db, channel_token, request)?;
+        let s = &request.state;
+        let splice_tx = &request.splice_tx;
+        let merch_escrow_sig = splice_tx.sign_escrow(FundingParty::Merchant, &self.sk_m.0)?;
+        let signed_tx = splice_tx.finalize(
+            &cust_sigs.escrow_sig,
+            &merch_escrow_sig,
+            &cust_sigs.top_up_sig,
+        )?;
+
+        // register the new escrow like in validate_channel_params
+        let mut new_channel_token = channel_token.clone();
+        new_channel_token.escrow_txid = convert_to_little_endian(&s0.escrow_txid);
+        new_channel_token.merch_txid = convert_to_little_endian(&s0.merch_txid);
+        let channel_id_str = hex::encode(new_channel_token.compute_channel_id()?);
+        let new_escrow_txid_hex = hex::encode(&s0.escrow_txid.0);
+        let nonce_hex = hex::encode(s.get_nonce());
+        let new_nonce_hex = hex::encode(s0.get_nonce());
+        let rev_lock_hex = hex::encode(s.get_rev_lock());
+
+        // the current state is spent together with the switch to the new escrow, so a crash can not
+        // leave the channel half moved and no payment can be made on the state the top-up carries forward
+        let mut batch = DbBatch::new();
+        batch.check(BatchCheck::NonceNotSpent(nonce_hex.clone()));
+        batch.check(BatchCheck::NonceNotSpent(new_nonce_hex.clone()));
+        batch.check(BatchCheck::RevLockNotRevoked(rev_lock_hex.clone()));
+        batch.write(BatchWrite::UpdateActivateMap(channel_id_str, s0));
+        batch.write(BatchWrite::AddToUnlinkSet(new_nonce_hex));
+        batch.write(BatchWrite::UpdateMerchTxidMap(
+            hex::encode(&s0.merch_txid.0),
+            new_escrow_txid_hex.clone(),
+        ));
+        batch.write(BatchWrite::UpdateSpentMap(nonce_hex.clone(), rev_lock_hex));
+        batch.write(BatchWrite::UpdateChannelStatus(
+            new_escrow_txid_hex,
+            ChannelStatus::PendingOpen,
+        ));
+        if !db.apply_batch(&batch)? {
+            return Err(ZkChannelError::DoubleSpentNonce(format!(
+                "nonce {} has been spent already or the rev_lock was revoked",
+                &nonce_hex
+            )));
+        }
+
+        Ok((signed_tx, merch_escrow_sig))
+    }
+
+    ///
+    /// verify_top_up_revocation() - checks that the rev_secret opens the rev_lock of the state that a top-up
+    /// spent and stores it, so that a cust-close-tx on that state can be disputed. The customer only reveals
+    /// it once the splice-tx confirmed (the old escrow is Spliced)
+    ///
+    pub fn verify_top_up_revocation(
+        &self,
+        db: &mut dyn StateDatabase,
+        request: &TopUpRequest,
+        rev_secret: &FixedSizeArray32,
+    ) -> Result<(), ZkChannelError> {
+        // check db is connected
+        db.is_connected()?;
+
+        let s = &request.state;
+        let channel_status = self.get_channel_status(db, s.escrow_txid.0)?;
+        if channel_status != ChannelStatus::Spliced {
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
+                "splice-tx of the top-up has not confirmed, channel status: {}",
+                channel_status
+            )));
+        }
+        let nonce_hex = hex::encode(s.get_nonce());
+        let rev_lock_hex = hex::encode(s.get_rev_lock());
+        if db.get_spent_rev_lock(&nonce_hex).ok() != Some(rev_lock_hex.clone()) {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "state was not spent by the top-up",
+            )));
+        }
+        if hash_to_slice(&rev_secret.0.to_vec()) != s.get_rev_lock() {
+            return Err(ZkChannelError::InvalidCommitmentOpening(String::from(
+                "rev_secret does not open the rev_lock of the spent state",
+            )));
+        }
+
+        let mut batch = DbBatch::new();
+        batch.check(BatchCheck::RevLockNotRevoked(rev_lock_hex.clone()));
+        batch.write(BatchWrite::UpdateRevLockMap(
+            rev_lock_hex,
+            hex::encode(&rev_secret.0),
+        ));
+        if !db.apply_batch(&batch)? {
+            return Err(ZkChannelError::DoubleSpentNonce(format!(
+                "rev_lock of the state with nonce {} was revoked already",
+                &nonce_hex
+            )));
+        }
+        Ok(())
     }
 
     pub fn get_closing_tx<N: BitcoinNetwork>(
-        &mut self,
+        &self,
+        db: &mut dyn StateDatabase,
         escrow_txid: [u8; 32],
         val_cpfp: i64,
-    ) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), String> {
+    ) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), ZkChannelError> {
         let escrow_txid2 = FixedSizeArray32(escrow_txid);
-        let m = match self.close_tx.get(&escrow_txid2) {
-            Some(t) => t,
-            None => {
-                return Err(format!(
+        let m = match db.get_merch_close_tx(&hex::encode(&escrow_txid2.0)) {
+            Ok(t) => t,
+            Err(_) => {
+                return Err(ZkChannelError::NotFound(format!(
                     "could not find merch_close_tx for escrow_txid: {}",
                     hex::encode(escrow_txid2.0)
-                ));
+                )));
             }
         };
         let cust_pk = handle_error_util!(hex::decode(&m.cust_pk));
@@ -1587,17 +3755,20 @@ impl MerchantMPCState {
         let cust_sig_and_len_byte = handle_error_util!(hex::decode(&m.cust_sig));
 
         // move forward to sign the transaction
-        let (_, tx_params) = handle_error_util!(merchant_form_close_transaction(
-            escrow_txid2.0.to_vec(),
-            cust_pk,
-            merch_pk,
-            merch_close_pk,
-            m.bc,
-            m.bm,
-            m.fee_mc,
-            val_cpfp,
-            to_self_delay
-        ));
+        let (_, tx_params) = handle_error_util!(
+            merchant_form_close_transaction(
+                escrow_txid2.0.to_vec(),
+                cust_pk,
+                merch_pk,
+                merch_close_pk,
+                m.bc,
+                m.bm,
+                m.fee_mc,
+                val_cpfp,
+                to_self_delay
+            ),
+            ZkChannelError::TransactionError
+        );
 
         let sk = get_private_key(&self.sk_m.0.to_vec()).unwrap();
         let (signed_merch_close_tx, txid_be, _) = completely_sign_multi_sig_transaction::<N>(
@@ -1612,53 +3783,483 @@ impl MerchantMPCState {
         let signed_merch_close_tx = signed_merch_close_tx.to_transaction_bytes().unwrap();
 
         // let's update the close status
-        let res = self.change_channel_status(escrow_txid.clone(), ChannelStatus::MerchantInitClose);
+        let res =
+            self.change_channel_status(db, escrow_txid.clone(), ChannelStatus::MerchantInitClose);
         if res.is_err() {
-            return Err(format!(
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
                 "could not change channel status to: {}",
                 ChannelStatus::MerchantInitClose
-            ));
+            )));
         }
 
         Ok((signed_merch_close_tx, txid_be.to_vec(), txid_le))
     }
 
-    pub fn get_channel_status(&self, escrow_txid_be: [u8; 32]) -> Result<ChannelStatus, String> {
-        let escrow_txid = FixedSizeArray32(escrow_txid_be);
-        let channel_status = match self.channel_status_map.get(&escrow_txid) {
-            Some(t) => t,
+    pub fn sign_mutual_close_transaction(
+        &self,
+        db: &mut dyn StateDatabase,
+        channel_state: &ChannelMPCState,
+        channel_token: &ChannelMPCToken,
+        request: &MutualCloseRequest,
+    ) -> Result<(Vec<u8>, Vec<u8>), ZkChannelError> {
+        // check db is connected
+        db.is_connected()?;
+
+        let mut escrow_txid_be = channel_token.escrow_txid.0.clone();
+        escrow_txid_be.reverse();
+        let channel_status = self.get_channel_status(db, escrow_txid_be)?;
+        if channel_status != ChannelStatus::Open {
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
+                "Invalid channel status for mutual close: {}",
+                channel_status
+            )));
+        }
+
+        // the state must be one that the merchant signed for this channel
+        let s = &request.state;
+        if s.escrow_txid.0 != escrow_txid_be {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "mutual close state does not belong to the channel",
+            )));
+        }
+        let key = self.hmac_key.get_bytes();
+        if hmac_sign(key, &s.serialize_compact()) != request.pay_token.0 {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "could not verify the pay token on the mutual close state",
+            )));
+        }
+
+        // and it must be the state after the last revocation: neither spent nor revoked
+        let nonce_hex = hex::encode(s.get_nonce());
+        let rev_lock_hex = hex::encode(s.get_rev_lock());
+        if db.check_spent_map(&nonce_hex) || db.check_rev_lock_map(&rev_lock_hex) {
+            return Err(ZkChannelError::DoubleSpentNonce(String::from(
+                "mutual close state has already been revoked",
+            )));
+        }
+
+        // the split must match the balances of that state (customer pays the fee)
+        let fee = s.bc - request.cust_bal;
+        if request.merch_bal != s.bm || fee < 0 || fee > s.max_fee {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "proposed split ({}, {}) does not match the state balances ({}, {})",
+                request.cust_bal, request.merch_bal, s.bc, s.bm
+            )));
+        }
+        check_mutual_close_cust_bal(channel_state, request.cust_bal)?;
+
+        let escrow_input = UtxoInput {
+            address_format: String::from("p2wsh"),
+            transaction_id: channel_token.escrow_txid.0.to_vec(),
+            index: 0,
+            redeem_script: None,
+            script_pub_key: None,
+            utxo_amount: Some(s.bc + s.bm),
+            sequence: Some([0xff, 0xff, 0xff, 0xff]),
+        };
+        let cust_pk = match channel_token.pk_c {
+            Some(pk) => pk.serialize().to_vec(),
             None => {
-                let mut txid_le = escrow_txid.0.clone();
-                txid_le.reverse();
-                return Err(format!(
-                    "could not find <channel_status> for input <escrow_txid>: {}",
-                    hex::encode(txid_le)
-                ));
+                return Err(ZkChannelError::InvalidInput(String::from(
+                    "pk_c is not set in channel token",
+                )))
             }
         };
+        let merch_close_pk = match channel_state.merch_payout_pk {
+            Some(pk) => pk.serialize().to_vec(),
+            None => self.payout_pk.serialize().to_vec(),
+        };
+        let (signed_tx, txid_le) = handle_error_util!(
+            merchant_sign_mutual_close_transaction(
+                &escrow_input,
+                &cust_pk,
+                &self.pk_m.serialize().to_vec(),
+                &request.cust_close_pk.serialize().to_vec(),
+                &merch_close_pk,
+                request.cust_bal,
+                request.merch_bal,
+                &request.cust_sig,
+                &self.sk_m.0.to_vec()
+            ),
+            ZkChannelError::TransactionError
+        );
+
+        // no further payments can be made on this state (unless it was revoked in the meantime)
+        let mut batch = DbBatch::new();
+        batch.check(BatchCheck::NonceNotSpent(nonce_hex.clone()));
+        batch.check(BatchCheck::RevLockNotRevoked(rev_lock_hex.clone()));
+        batch.write(BatchWrite::UpdateSpentMap(nonce_hex, rev_lock_hex));
+        if !db.apply_batch(&batch)? {
+            return Err(ZkChannelError::DoubleSpentNonce(String::from(
+                "mutual close state has already been revoked",
+            )));
+        }
+        self.change_channel_status(db, escrow_txid_be, ChannelStatus::MutualClose)?;
 
-        Ok(channel_status.clone())
+        Ok((signed_tx, txid_le.to_vec()))
     }
 
-    pub fn change_channel_status(
-        &mut self,
-        escrow_txid_be: [u8; 32],
-        new_channel_status: ChannelStatus,
-    ) -> Result<(), String> {
-        let escrow_txid = FixedSizeArray32(escrow_txid_be);
-        let m = match self.channel_status_map.get(&escrow_txid) {
-            Some(t) => t,
+    // finds the escrow-txid of the channel that a cust-close-tx spends from (either the
+    // escrow-tx or the merch-close-tx of the channel)
+    fn find_escrow_txid_for_close_tx(
+        &self,
+        db: &mut dyn StateDatabase,
+        prevout_txid_be: [u8; 32],
+    ) -> Result<[u8; 32], ZkChannelError> {
+        let prevout_txid_hex = hex::encode(&prevout_txid_be);
+        if db.get_merch_close_tx(&prevout_txid_hex).is_ok() {
+            return Ok(prevout_txid_be);
+        }
+        let escrow_txid_hex = match db.get_escrow_txid(&prevout_txid_hex) {
+            Ok(t) => t,
+            Err(_) => {
+                return Err(ZkChannelError::NotFound(format!(
+                    "could not find a channel for cust-close-tx that spends: {}",
+                    prevout_txid_hex
+                )))
+            }
+        };
+        let escrow_txid_vec = handle_error_util!(hex::decode(&escrow_txid_hex));
+        if escrow_txid_vec.len() != 32 {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "invalid escrow txid: {}",
+                escrow_txid_hex
+            )));
+        }
+        let mut escrow_txid = [0u8; 32];
+        escrow_txid.copy_from_slice(escrow_txid_vec.as_slice());
+        Ok(escrow_txid)
+    }
+
+    ///
+    /// sign_cpfp_child_tx() - fee-bumps a broadcast merch-close-tx with a child tx that spends its anchor output
+    /// (and the optional funding input) to the merchant's payout-pk
+    /// output: signed child tx and its txid (little-endian)
+    ///
+    pub fn sign_cpfp_child_tx(
+        &self,
+        db: &mut dyn StateDatabase,
+        merch_close_tx: &Vec<u8>,
+        fee_rate: i64,
+        funding: Option<(EscrowFundingInput, [u8; 32])>,
+    ) -> Result<(Vec<u8>, [u8; 32]), ZkChannelError> {
+        // the merch-close-tx spends the escrow of the channel
+        let escrow_txid = match parse_transaction(merch_close_tx)?.outpoints.first() {
+            Some((txid_be, _)) => txid_be.clone(),
+            None => {
+                return Err(ZkChannelError::TransactionError(String::from(
+                    "merch-close-tx does not have any inputs",
+                )))
+            }
+        };
+        let m = match db.get_merch_close_tx(&hex::encode(&escrow_txid)) {
+            Ok(t) => t,
+            Err(_) => {
+                return Err(ZkChannelError::NotFound(format!(
+                    "could not find merch_close_tx for escrow_txid: {}",
+                    hex::encode(escrow_txid)
+                )));
+            }
+        };
+        let anchor_keys = vec![(self.pk_m, self.sk_m.0), (self.payout_pk, self.payout_sk.0)];
+        sign_cpfp_child_tx(
+            merch_close_tx,
+            m.fee_mc,
+            &anchor_keys,
+            fee_rate,
+            funding,
+            self.payout_pk,
+        )
+    }
+
+    pub fn sign_dispute_transaction(
+        &self,
+        db: &mut dyn StateDatabase,
+        raw_close_tx: &Vec<u8>,
+    ) -> Result<Vec<u8>, ZkChannelError> {
+        // check db is connected
+        db.is_connected()?;
+
+        let close_tx = parse_cust_close_transaction(raw_close_tx)?;
+        let escrow_txid = self.find_escrow_txid_for_close_tx(db, close_tx.prevout_txid_be)?;
+
+        // the merchant can only dispute if the customer revealed the rev_secret for this rev_lock
+        let material = self.get_dispute_material(db, close_tx.rev_lock)?;
+        let (to_self_delay, fee_mc) = self.get_dispute_params(db, escrow_txid)?;
+        let signed_tx = self.get_dispute_authorization()?.sign_dispute_transaction(
+            &close_tx,
+            &material.rev_secret.0,
+            to_self_delay,
+            fee_mc,
+        )?;
+
+        // the cust-close-tx was detected on chain, so the channel is closing and now disputed
+        self.mark_channel_disputed(db, escrow_txid)?;
+
+        Ok(signed_tx)
+    }
+
+    ///
+    /// claim_close_output() - claims the merchant's output of a confirmed close tx and pays it (minus the fee)
+    /// to the merchant's payout-pk. For a merch-close-tx, the timelock has to be expired and the channel is
+    /// marked as closed. The to_merchant output of a cust-close-tx can be claimed right away, but the channel
+    /// is only marked as closed once the cust-close-tx can no longer be disputed
+    /// output: signed claim tx and its txid (little-endian)
+    ///
+    pub fn claim_close_output(
+        &self,
+        db: &mut dyn StateDatabase,
+        close_tx: &Vec<u8>,
+        close_height: u64,
+        cur_height: u64,
+        fee: i64,
+    ) -> Result<(Vec<u8>, [u8; 32]), ZkChannelError> {
+        // check db is connected
+        db.is_connected()?;
+
+        let tx = parse_transaction(close_tx)?;
+        let prevout_txid_be = match tx.outpoints.first() {
+            Some((txid_be, _)) => txid_be.clone(),
             None => {
-                let mut txid_le = escrow_txid.0.clone();
+                return Err(ZkChannelError::TransactionError(String::from(
+                    "close tx does not have any inputs",
+                )))
+            }
+        };
+        let mut txid_le = tx.txid_be.clone();
+        txid_le.reverse();
+
+        // the merch-close-tx of a channel is the one stored (by its txid) for the escrow it spends
+        let is_merch_close = match db.get_escrow_txid(&hex::encode(&tx.txid_be)) {
+            Ok(escrow_txid_hex) => {
+                escrow_txid_hex.eq_ignore_ascii_case(&hex::encode(&prevout_txid_be))
+            }
+            Err(_) => false,
+        };
+
+        // only a cust-close-tx has an OP_RETURN output with the rev_lock
+        if !is_merch_close && parse_cust_close_transaction(close_tx).is_ok() {
+            let escrow_txid = self.find_escrow_txid_for_close_tx(db, prevout_txid_be)?;
+            let (to_self_delay, _) = self.get_dispute_params(db, escrow_txid)?;
+            let script = p2wpkh_script(&self.payout_pk);
+            let (index, input_amount) = match tx.outputs.iter().position(|o| o.1 == script) {
+                Some(i) => (i as u32, tx.outputs[i].0),
+                None => {
+                    return Err(ZkChannelError::TransactionError(String::from(
+                        "cust-close-tx does not have a to_merchant output",
+                    )))
+                }
+            };
+            let output_amount = get_claim_output_amount(input_amount, fee)?;
+            let signed_tx = handle_error_util!(
+                zkchan_tx::txutil::merchant_sign_cust_close_claim_transaction(
+                    txid_le.to_vec(),
+                    index,
+                    input_amount,
+                    output_amount,
+                    self.payout_pk.serialize().to_vec(),
+                    self.payout_sk.0.to_vec()
+                ),
+                ZkChannelError::TransactionError
+            );
+            let claim_txid_le = get_claim_txid_le(&signed_tx)?;
+
+            // a revoked cust-close-tx can be disputed until the to_customer output is spendable
+            let self_delay = u16::from_be_bytes(to_self_delay);
+            let dispute_expired =
+                check_timelock_expired(self_delay, close_height, cur_height).is_ok();
+            let channel_status = self.get_channel_status(db, escrow_txid)?;
+            if channel_status != ChannelStatus::Disputed
+                && channel_status != ChannelStatus::ConfirmedClose
+            {
+                self.change_channel_status(db, escrow_txid, ChannelStatus::PendingClose)?;
+                if dispute_expired {
+                    self.change_channel_status(db, escrow_txid, ChannelStatus::ConfirmedClose)?;
+                }
+            }
+            return Ok((signed_tx, claim_txid_le));
+        }
+
+        if !is_merch_close {
+            return Err(ZkChannelError::NotFound(format!(
+                "close tx {} is neither a cust-close-tx nor the merch-close-tx of a channel",
+                hex::encode(&txid_le)
+            )));
+        }
+        let escrow_txid = prevout_txid_be;
+        let m = match db.get_merch_close_tx(&hex::encode(&escrow_txid)) {
+            Ok(t) => t,
+            Err(_) => {
+                return Err(ZkChannelError::NotFound(format!(
+                    "could not find merch_close_tx for escrow_txid: {}",
+                    hex::encode(escrow_txid)
+                )));
+            }
+        };
+        let (to_self_delay, _) = self.get_dispute_params(db, escrow_txid)?;
+        check_timelock_expired(u16::from_be_bytes(to_self_delay), close_height, cur_height)?;
+        let input_amount = match tx.outputs.first() {
+            Some((amount, script)) if script.len() == 34 && script[0] == 0x00 => *amount,
+            _ => {
+                return Err(ZkChannelError::TransactionError(String::from(
+                    "merch-close-tx does not have a to_merchant output",
+                )))
+            }
+        };
+        let output_amount = get_claim_output_amount(input_amount, fee)?;
+        let cust_pk = handle_error_util!(hex::decode(&m.cust_pk));
+        let signed_tx = handle_error_util!(
+            zkchan_tx::txutil::merchant_sign_merch_close_claim_transaction(
+                txid_le.to_vec(),
+                0,
+                input_amount,
+                output_amount,
+                self.payout_pk.serialize().to_vec(),
+                to_self_delay,
+                cust_pk,
+                self.pk_m.serialize().to_vec(),
+                self.payout_pk.serialize().to_vec(),
+                self.payout_sk.0.to_vec()
+            ),
+            ZkChannelError::TransactionError
+        );
+        let claim_txid_le = get_claim_txid_le(&signed_tx)?;
+
+        // the customer did not respond with a cust-close-tx before the timelock expired
+        if self.get_channel_status(db, escrow_txid)? != ChannelStatus::ConfirmedClose {
+            self.change_channel_status(db, escrow_txid, ChannelStatus::PendingClose)?;
+            self.change_channel_status(db, escrow_txid, ChannelStatus::ConfirmedClose)?;
+        }
+        Ok((signed_tx, claim_txid_le))
+    }
+
+    // to_self_delay and fee of the merch-close-tx of the channel (also used for the dispute tx)
+    fn get_dispute_params(
+        &self,
+        db: &mut dyn StateDatabase,
+        escrow_txid_be: [u8; 32],
+    ) -> Result<([u8; 2], i64), ZkChannelError> {
+        let m = match db.get_merch_close_tx(&hex::encode(&escrow_txid_be)) {
+            Ok(t) => t,
+            Err(_) => {
+                return Err(ZkChannelError::NotFound(format!(
+                    "could not find merch_close_tx for escrow_txid: {}",
+                    hex::encode(escrow_txid_be)
+                )));
+            }
+        };
+        let t = handle_error_util!(hex::decode(&m.self_delay));
+        if t.len() != 2 {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "invalid self_delay for escrow_txid: {}",
+                hex::encode(escrow_txid_be)
+            )));
+        }
+        let mut to_self_delay = [0u8; 2];
+        to_self_delay.copy_from_slice(t.as_slice());
+        Ok((to_self_delay, m.fee_mc))
+    }
+
+    ///
+    /// mark_channel_disputed() - records that a revoked cust-close-tx of the channel was disputed
+    /// (by the merchant or its watchtower)
+    /// output: success or error
+    ///
+    pub fn mark_channel_disputed(
+        &self,
+        db: &mut dyn StateDatabase,
+        escrow_txid_be: [u8; 32],
+    ) -> Result<(), ZkChannelError> {
+        if self.get_channel_status(db, escrow_txid_be)? != ChannelStatus::Disputed {
+            self.change_channel_status(db, escrow_txid_be, ChannelStatus::PendingClose)?;
+            self.change_channel_status(db, escrow_txid_be, ChannelStatus::Disputed)?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// get_dispute_authorization() - pre-authorizes a watchtower to sign dispute txs that pay to the merchant
+    /// output: dispute authorization (includes the dispute secret key)
+    ///
+    pub fn get_dispute_authorization(&self) -> Result<DisputeAuthorization, ZkChannelError> {
+        DisputeAuthorization::new(self.payout_pk, self.dispute_sk.0)
+    }
+
+    ///
+    /// get_watched_channel() - outpoints and merch-close-tx parameters of a channel for the watchtower
+    /// output: watched channel
+    ///
+    pub fn get_watched_channel(
+        &self,
+        db: &mut dyn StateDatabase,
+        channel_token: &ChannelMPCToken,
+    ) -> Result<WatchedChannel, ZkChannelError> {
+        let mut escrow_txid_be = channel_token.escrow_txid.0.clone();
+        escrow_txid_be.reverse();
+        let mut merch_txid_be = channel_token.merch_txid.0.clone();
+        merch_txid_be.reverse();
+        let (to_self_delay, fee_mc) = self.get_dispute_params(db, escrow_txid_be)?;
+        Ok(WatchedChannel {
+            escrow_txid_be: FixedSizeArray32(escrow_txid_be),
+            merch_txid_be: FixedSizeArray32(merch_txid_be),
+            to_self_delay,
+            fee_mc,
+        })
+    }
+
+    ///
+    /// get_dispute_material() - looks up the rev_secret of a revoked state in the rev_lock map
+    /// output: dispute material for the watchtower
+    ///
+    pub fn get_dispute_material(
+        &self,
+        db: &mut dyn StateDatabase,
+        rev_lock: [u8; 32],
+    ) -> Result<DisputeMaterial, ZkChannelError> {
+        let rev_lock_hex = hex::encode(&rev_lock);
+        if !db.check_rev_lock_map(&rev_lock_hex) {
+            return Err(ZkChannelError::NotFound(format!(
+                "state is not revoked: no rev_secret for rev_lock {}",
+                rev_lock_hex
+            )));
+        }
+        let rev_secret = handle_error_util!(hex::decode(db.get_rev_secret(&rev_lock_hex)?));
+        if rev_secret.len() != 32 {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "stored rev_secret does not have expected length: {}",
+                rev_secret.len()
+            )));
+        }
+        let mut rev_secret_buf = [0u8; 32];
+        rev_secret_buf.copy_from_slice(rev_secret.as_slice());
+        DisputeMaterial::new(rev_lock, rev_secret_buf)
+    }
+
+    pub fn get_channel_status(
+        &self,
+        db: &mut dyn StateDatabase,
+        escrow_txid_be: [u8; 32],
+    ) -> Result<ChannelStatus, ZkChannelError> {
+        match db.get_channel_status(&hex::encode(&escrow_txid_be)) {
+            Ok(t) => Ok(t),
+            Err(_) => {
+                let mut txid_le = escrow_txid_be.clone();
                 txid_le.reverse();
-                return Err(format!(
+                return Err(ZkChannelError::NotFound(format!(
                     "could not find <channel_status> for input <escrow_txid>: {}",
                     hex::encode(txid_le)
-                ));
+                )));
             }
-        };
+        }
+    }
 
-        let cur_channel_status = m.clone();
+    pub fn change_channel_status(
+        &self,
+        db: &mut dyn StateDatabase,
+        escrow_txid_be: [u8; 32],
+        new_channel_status: ChannelStatus,
+    ) -> Result<(), ZkChannelError> {
+        let cur_channel_status = self.get_channel_status(db, escrow_txid_be)?;
         if cur_channel_status == new_channel_status {
             return Ok(());
         }
@@ -1674,18 +4275,63 @@ impl MerchantMPCState {
             (ChannelStatus::PendingClose, ChannelStatus::ConfirmedClose) => new_channel_status,
             (ChannelStatus::ConfirmedClose, ChannelStatus::None) => new_channel_status,
             (ChannelStatus::PendingClose, ChannelStatus::Disputed) => new_channel_status,
+            (ChannelStatus::Open, ChannelStatus::MutualClose) => new_channel_status,
+            (ChannelStatus::MutualClose, ChannelStatus::ConfirmedClose) => new_channel_status,
+            (ChannelStatus::MutualClose, ChannelStatus::PendingClose) => new_channel_status,
+            (ChannelStatus::Open, ChannelStatus::Spliced) => new_channel_status,
+            (ChannelStatus::Spliced, ChannelStatus::MerchantInitClose) => new_channel_status,
+            (ChannelStatus::Spliced, ChannelStatus::Disputed) => new_channel_status,
             (_, _) => {
-                return Err(format!(
+                return Err(ZkChannelError::InvalidStatusTransition(format!(
                     "transition not allowed for channel identified by <escrow-txid>: {} => {}",
                     cur_channel_status, new_channel_status
-                ))
+                )))
             }
         };
-        self.channel_status_map.insert(escrow_txid, channel_status);
+        db.update_channel_status(&hex::encode(&escrow_txid_be), &channel_status)?;
+        record_event(
+            db,
+            EventRecord::new(
+                EventKey::escrow(&escrow_txid_be),
+                EventParty::Merchant,
+                ProtocolEvent::StatusChanged {
+                    from: cur_channel_status,
+                    to: channel_status,
+                },
+            ),
+        );
 
         Ok(())
     }
 
+    ///
+    /// rollback_channel_status() - reverts a status in the db that was derived from a block that is
+    /// no longer on the best chain (only used by the chain monitor after a reorg)
+    /// output: error if the current status was not set from the chain
+    ///
+    pub fn rollback_channel_status(
+        &self,
+        db: &mut dyn StateDatabase,
+        escrow_txid_be: [u8; 32],
+        channel_status: ChannelStatus,
+    ) -> Result<(), ZkChannelError> {
+        let cur_channel_status = self.get_channel_status(db, escrow_txid_be)?;
+        check_rollback(&cur_channel_status, &channel_status)?;
+        db.update_channel_status(&hex::encode(&escrow_txid_be), &channel_status)?;
+        record_event(
+            db,
+            EventRecord::new(
+                EventKey::escrow(&escrow_txid_be),
+                EventParty::Merchant,
+                ProtocolEvent::StatusChanged {
+                    from: cur_channel_status,
+                    to: channel_status,
+                },
+            ),
+        );
+        Ok(())
+    }
+
     pub fn get_circuit_file(&self) -> *mut c_void {
         // Box<Circuit>
         let using_ag2pc = match env::var("AG2PC") {
@@ -1726,7 +4372,7 @@ impl MerchantMPCState {
         p_ptr: *mut c_void,
         send_cb: cb_send,
         receive_cb: cb_receive,
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
         // // if epsilon > 0, check if acceptable (above dust limit).
         // if amount > 0 && amount < channel_state.get_min_threshold() {
         //     // if check fails, abort and output an error
@@ -1739,7 +4385,7 @@ impl MerchantMPCState {
         let session_id_hex = hex::encode(&session_id);
         let session_state = match db.load_session_state(&session_id_hex) {
             Ok(s) => s,
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(e),
         };
 
         let amount = session_state.amount;
@@ -1749,23 +4395,23 @@ impl MerchantMPCState {
         // check if n_i not in S_spent
         let nonce_hex = hex::encode(nonce);
         if db.check_spent_map(&nonce_hex) {
-            return Err(format!(
+            return Err(ZkChannelError::DoubleSpentNonce(format!(
                 "merch::execute_mpc_context - nonce {} has been spent already.",
                 &nonce_hex
-            ));
+            )));
         }
 
         // retrieve the paytoken_mask & randomness (based on the given nonce)
         let (pay_mask_bytes, pay_mask_r) = match db.get_mask_map_from_nonce(&nonce_hex) {
             Ok(n) => (n.0, n.1),
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(e),
         };
 
         let pay_mask_com = self.recompute_commitmment(&pay_mask_bytes, &pay_mask_r);
         if pay_mask_com != paytoken_mask_com {
-            return Err(String::from(
+            return Err(ZkChannelError::InvalidCommitmentOpening(String::from(
                 "merch::execute_mpc_context - specified invalid pay mask commitment",
-            ));
+            )));
         }
 
         // generate masks for close-escrow and close-merch txs
@@ -1791,9 +4437,9 @@ impl MerchantMPCState {
         let net_conn = match self.net_config.clone() {
             Some(nc) => nc,
             None => {
-                return Err(String::from(
+                return Err(ZkChannelError::InvalidInput(String::from(
                     "merch::execute_mpc_context - net config not specified",
-                ));
+                )));
             }
         };
 
@@ -1859,7 +4505,7 @@ impl MerchantMPCState {
         rev_lock: [u8; 32],
         rev_sec: [u8; 32],
         t: [u8; 16],
-    ) -> Result<([u8; 32], [u8; 16]), String> {
+    ) -> Result<([u8; 32], [u8; 16]), ZkChannelError> {
         // check db is connected
         db.is_connected()?;
 
@@ -1867,7 +4513,7 @@ impl MerchantMPCState {
         // retrieve session_state
         let session_state = match db.load_session_state(&session_id_hex) {
             Ok(s) => s,
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(e),
         };
 
         let nonce_hex = hex::encode(session_state.nonce.0);
@@ -1878,38 +4524,56 @@ impl MerchantMPCState {
         if compute_rev_lock_commitment(&rev_lock, &t) != rev_lock_com
             || hash_to_slice(&rev_sec.to_vec()) != rev_lock
         {
-            return Err(String::from(
+            return Err(ZkChannelError::InvalidCommitmentOpening(String::from(
                 "rev_lock_com commitment did not open to specified rev_lock",
-            ));
+            )));
         }
 
         // retrieve masked bytes from rev_lock_com (output error, if not)
         let (pt_mask, pt_mask_r) = match db.get_masked_mpc_inputs(&session_id_hex) {
             Ok(n) => (n.pt_mask.0, n.pt_mask_r.0),
             _ => {
-                return Err(String::from(
+                return Err(ZkChannelError::NotFound(String::from(
                     "could not retrieve pt_mask for specified rev_lock_com commitment",
-                ));
+                )));
             }
         };
 
         // verify that RL_i not in the S_spent
         let rev_lock_r = hex::encode(&rev_lock);
         if db.check_rev_lock_map(&rev_lock_r) {
-            return Err(String::from(
+            // a customer that resumes an interrupted payment sends the revoked state of the
+            // session again and gets the same pay token mask (as long as it is the rev_lock
+            // the nonce was spent with)
+            let is_resumed = db.get_spent_rev_lock(&nonce_hex).ok() == Some(rev_lock_r.clone())
+                && db.get_session_id_for_nonce(&nonce_hex).ok() == Some(session_id_hex.clone());
+            if is_resumed {
+                return Ok((pt_mask, pt_mask_r));
+            }
+            return Err(ZkChannelError::InvalidInput(String::from(
                 "attempting to revoke with a rev_lock that is already revoked",
-            ));
+            )));
         }
 
         let rev_sec_r = hex::encode(&rev_sec);
+        // the nonce is spent and the rev_lock revoked all-or-nothing, and only once
+        let mut batch = DbBatch::new();
+        batch.check(BatchCheck::NonceNotSpent(nonce_hex.clone()));
+        batch.check(BatchCheck::RevLockNotRevoked(rev_lock_r.clone()));
         // add (n_i, RL_i) to S_spent map
-        db.update_spent_map(&nonce_hex, &rev_lock_r)?;
+        batch.write(BatchWrite::UpdateSpentMap(
+            nonce_hex.clone(),
+            rev_lock_r.clone(),
+        ));
         // add (RL_i, RS_i) to RL map
-        db.update_rev_lock_map(&rev_lock_r, &rev_sec_r)?;
-        // check if n_i in the unlink map. if so, remove it
-        if db.is_member_unlink_set(&nonce_hex) {
-            // remove from unlink set
-            assert!(db.remove_from_unlink_set(&nonce_hex));
+        batch.write(BatchWrite::UpdateRevLockMap(rev_lock_r, rev_sec_r));
+        // remove n_i from the unlink set (if present)
+        batch.write(BatchWrite::RemoveFromUnlinkSet(nonce_hex.clone()));
+        if !db.apply_batch(&batch)? {
+            return Err(ZkChannelError::DoubleSpentNonce(format!(
+                "nonce {} has been spent already or the rev_lock was revoked",
+                &nonce_hex
+            )));
         }
 
         Ok((pt_mask, pt_mask_r))
@@ -1956,13 +4620,15 @@ mod tests {
             escrow_prevout: FixedSizeArray32(escrow_prevout),
             merch_txid: FixedSizeArray32(merch_txid),
             merch_prevout: FixedSizeArray32(merch_prevout),
+            cust_funding: b0_cust + b0_merch,
+            merch_funding: 0,
         };
     }
 
     rusty_fork_test! {
     #[test]
     fn mpc_channel_util_customer_works() {
-        let mut channel_state = ChannelMPCState::new(String::from("Channel A <-> B"), 1487, 546, 546, 1000, false);
+        let mut channel_state = ChannelMPCState::new(String::from("Channel A <-> B"), 1487, 546, 546, 1000, false, BitcoinNetworkType::Testnet);
         // let rng = &mut rand::thread_rng();
         let mut rng = XorShiftRng::seed_from_u64(0x5dbe62598d313d86);
 
@@ -1988,7 +4654,7 @@ mod tests {
         db.clear_state();
 
         // initialize on the customer side with balance: b0_cust
-        let mut cust_state = CustomerMPCState::new(&mut rng, b0_cust, b0_merch, tx_fee_info.fee_cc, String::from("Customer"));
+        let mut cust_state = CustomerMPCState::new(&mut rng, b0_cust, b0_merch, tx_fee_info.fee_cc, String::from("Customer"), channel_state.get_network());
 
         // initialize the channel token on with pks
         // generate and send initial state to the merchant
@@ -2135,7 +4801,7 @@ mod tests {
     rusty_fork_test! {
     #[test]
     fn mpc_channel_util_merchant_works() {
-        let mut channel = ChannelMPCState::new(String::from("Channel A <-> B"), 1487, 546, 546, 1000, false);
+        let mut channel = ChannelMPCState::new(String::from("Channel A <-> B"), 1487, 546, 546, 1000, false, BitcoinNetworkType::Testnet);
         // let rng = &mut rand::thread_rng();
         let mut rng = XorShiftRng::seed_from_u64(0x5dbe62598d313d86);
         let db_url = "redis://127.0.0.1/".to_string();
@@ -2169,7 +4835,8 @@ mod tests {
             b0_cust,
             b0_merch,
             tx_fee_info.fee_cc,
-            String::from("Customer")
+            String::from("Customer"),
+            channel.get_network()
         );
 
         // initialize the channel token on with pks
@@ -2282,6 +4949,37 @@ mod tests {
                 t,
             )
             .unwrap();
+        // the customer resumes the payment after a crash: same pay token mask
+        assert_eq!(
+            merch_state
+                .verify_revoked_state(
+                    &mut db as &mut dyn StateDatabase,
+                    session_id,
+                    rev_lock,
+                    rev_secret,
+                    t,
+                )
+                .unwrap(),
+            (pt_mask, pt_mask_r)
+        );
+        // a retry is rejected if the nonce was spent with another rev_lock
+        let nonce_hex = hex::encode(
+            db.load_session_state(&hex::encode(session_id))
+                .unwrap()
+                .nonce
+                .0,
+        );
+        db.update_spent_map(&nonce_hex, &hex::encode([0u8; 32]))
+            .unwrap();
+        assert!(merch_state
+            .verify_revoked_state(
+                &mut db as &mut dyn StateDatabase,
+                session_id,
+                rev_lock,
+                rev_secret,
+                t,
+            )
+            .is_err());
         println!("pt_masked: {:?}", hex::encode(&pt_mask));
         println!("pt_mask_r: {:?}", hex::encode(&pt_mask_r));
     }
@@ -2306,6 +5004,7 @@ mod tests {
             tx_fee_info.bal_min_merch,
             tx_fee_info.val_cpfp,
             false,
+            BitcoinNetworkType::Testnet,
         );
         let mut rng = XorShiftRng::seed_from_u64(0x8d863e545dbe6259);
         let db_url = "redis://127.0.0.1/".to_string();
@@ -2348,6 +5047,7 @@ mod tests {
             b0_merch,
             tx_fee_info.fee_cc,
             String::from("Customer"),
+            channel_state.get_network(),
         );
 
         // initialize the channel token on with pks
@@ -2386,4 +5086,127 @@ mod tests {
         let orig_channel_token: ChannelMPCToken = serde_json::from_str(&ser_channel_token).unwrap();
         assert_eq!(channel_token, orig_channel_token);
     }
+
+    #[test]
+    fn test_customer_payment_journal_rollback() {
+        let mut rng = XorShiftRng::seed_from_u64(0x5dbe62598d313d87);
+        let b0_cust = 100000;
+        let b0_merch = 10000;
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+        let mut channel_state = ChannelMPCState::new(
+            String::from("Channel A <-> B"),
+            1487,
+            546,
+            546,
+            1000,
+            false,
+            BitcoinNetworkType::Testnet,
+        );
+        let merch_state = MerchantMPCState::new(
+            &mut rng,
+            String::new(),
+            &mut channel_state,
+            String::from("Merchant"),
+        );
+        let mut cust_state = CustomerMPCState::new(
+            &mut rng,
+            b0_cust,
+            b0_merch,
+            tx_fee_info.fee_cc,
+            String::from("Customer"),
+            channel_state.get_network(),
+        );
+        let mut channel_token = cust_state.generate_init_state(
+            &mut rng,
+            &merch_state.pk_m,
+            tx_fee_info.min_fee,
+            tx_fee_info.max_fee,
+            tx_fee_info.fee_mc,
+        );
+        let funding_tx_info = generate_test_txs(&mut rng, b0_cust, b0_merch);
+        cust_state
+            .set_initial_cust_state(&mut channel_token, &funding_tx_info, &tx_fee_info)
+            .unwrap();
+        cust_state.close_escrow_signature = Some(String::from("escrow-sig-s0"));
+        cust_state.close_merch_signature = Some(String::from("merch-sig-s0"));
+
+        let s0 = cust_state.get_current_state();
+        let _r_com = cust_state.generate_rev_lock_commitment(&mut rng);
+        let (rev_lock, rev_secret) = cust_state.get_rev_pair();
+        assert!(!cust_state.has_pending_payment());
+
+        // crash after the mpc completed: the reloaded state can only close on s0
+        let session_id = [7u8; 16];
+        cust_state.generate_new_state(&mut rng, 1000);
+        cust_state.set_payment_session_id(session_id);
+        assert!(cust_state.resume_payment().is_err());
+        cust_state.update_payment_step(PaymentStep::MpcCompleted);
+        let ser_cust_state = serde_json::to_string(&cust_state).unwrap();
+        let mut cust_state: CustomerMPCState = serde_json::from_str(&ser_cust_state).unwrap();
+        assert_eq!(
+            cust_state.get_payment_journal().unwrap().step,
+            PaymentStep::MpcCompleted
+        );
+        assert_ne!(cust_state.get_current_state(), s0);
+        assert_eq!(cust_state.get_close_state(), s0);
+        // the masked close signatures of the session can be requested again, but the
+        // previous state must not be revoked before they are unmasked
+        assert_eq!(
+            cust_state.resume_payment().unwrap(),
+            (PaymentStep::MpcCompleted, session_id, None)
+        );
+
+        assert_eq!(cust_state.rollback_payment().unwrap(), s0);
+        assert_eq!(cust_state.get_current_state(), s0);
+        assert_eq!(cust_state.get_rev_pair(), (rev_lock, rev_secret));
+        assert_eq!(cust_state.cust_balance, b0_cust);
+        assert_eq!(
+            cust_state.close_escrow_signature,
+            Some(String::from("escrow-sig-s0"))
+        );
+        assert!(!cust_state.has_pending_payment());
+        assert!(cust_state.rollback_payment().is_err());
+
+        // once the new close signatures are unmasked, the payment can only be resumed
+        cust_state.generate_new_state(&mut rng, 1000);
+        cust_state.set_payment_session_id(session_id);
+        cust_state.update_payment_step(PaymentStep::SigsUnmasked);
+        assert_eq!(cust_state.get_close_state(), cust_state.get_current_state());
+        assert!(cust_state.rollback_payment().is_err());
+        let (step, resumed_session_id, rev_state) = cust_state.resume_payment().unwrap();
+        assert_eq!(step, PaymentStep::SigsUnmasked);
+        assert_eq!(resumed_session_id, session_id);
+        assert_eq!(rev_state.unwrap().get_rev_secret(), rev_secret);
+        let journal = cust_state.get_payment_journal().unwrap();
+        assert_eq!(journal.prev_state, s0);
+        assert_eq!(journal.prev_rev_state.get_rev_secret(), rev_secret);
+    }
+
+    #[test]
+    fn test_parse_transaction_rejects_oversized_lengths() {
+        // version, one input with a script_sig length of u64::MAX
+        let mut raw_tx = vec![0x02, 0x00, 0x00, 0x00, 0x01];
+        raw_tx.extend_from_slice(&[0u8; 36]);
+        raw_tx.push(0xff);
+        raw_tx.extend_from_slice(&[0xff; 8]);
+        assert!(parse_transaction(&raw_tx).is_err());
+        assert!(parse_cust_close_transaction(&raw_tx).is_err());
+
+        // one output with a script length that points past the end of the tx
+        let mut raw_tx = vec![0x02, 0x00, 0x00, 0x00, 0x01];
+        raw_tx.extend_from_slice(&[0u8; 41]);
+        raw_tx.push(0x01);
+        raw_tx.extend_from_slice(&[0u8; 8]);
+        raw_tx.push(0xfe);
+        raw_tx.extend_from_slice(&[0xff; 4]);
+        assert!(parse_transaction(&raw_tx).is_err());
+    }
 }
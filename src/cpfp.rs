@@ -0,0 +1,411 @@
+use super::*;
+use channels_mpc::parse_transaction;
+use dual_funding::{
+    compute_sighash, double_sha256, sign_sighash, EscrowFundingInput, TX_LOCKTIME, TX_SEQUENCE,
+    TX_VERSION,
+};
+use errors::ZkChannelError;
+use secp256k1::PublicKey;
+use util::compute_hash160;
+use zkchan_tx::fixed_size_array::FixedSizeArray32;
+
+pub const DUST_LIMIT: i64 = 546;
+// upper bound of a P2WPKH witness: item count, 72-byte signature and compressed pk
+const MAX_P2WPKH_WITNESS_LEN: usize = 1 + 1 + 72 + 1 + 33;
+
+pub fn p2wpkh_script(pk: &PublicKey) -> Vec<u8> {
+    let mut script = vec![0x00, 0x14];
+    script.extend_from_slice(&compute_hash160(&pk.serialize().to_vec()));
+    script
+}
+
+///
+/// AnchorOutput - the P2WPKH output (of value val_cpfp) of a cust-close-tx or merch-close-tx
+/// that its owner spends to fee-bump the close tx
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AnchorOutput {
+    pub txid_be: FixedSizeArray32,
+    pub index: u32,
+    pub sats: i64,
+    pub pk: PublicKey,
+}
+
+impl AnchorOutput {
+    ///
+    /// find() - looks for an output of the parent tx that pays to pk
+    /// output: the anchor output (None if the parent does not pay to pk)
+    ///
+    pub fn find(parent_tx: &Vec<u8>, pk: &PublicKey) -> Result<Option<Self>, ZkChannelError> {
+        let parent = parse_transaction(parent_tx)?;
+        let script = p2wpkh_script(pk);
+        for (index, (sats, output_script)) in parent.outputs.iter().enumerate() {
+            if *output_script == script {
+                return Ok(Some(AnchorOutput {
+                    txid_be: FixedSizeArray32(parent.txid_be),
+                    index: index as u32,
+                    sats: *sats,
+                    pk: pk.clone(),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    fn serialize_outpoint(&self) -> Vec<u8> {
+        let mut outpoint = self.txid_be.0.to_vec();
+        outpoint.extend_from_slice(&self.index.to_le_bytes());
+        outpoint
+    }
+
+    fn script_code(&self) -> Vec<u8> {
+        let mut script = vec![0x19, 0x76, 0xa9, 0x14];
+        script.extend_from_slice(&compute_hash160(&self.pk.serialize().to_vec()));
+        script.extend_from_slice(&[0x88, 0xac]);
+        script
+    }
+
+    // native segwit input, so the scriptSig is empty
+    fn serialize_input(&self) -> Vec<u8> {
+        let mut input = self.serialize_outpoint();
+        input.push(0x00);
+        input.extend_from_slice(&TX_SEQUENCE);
+        input
+    }
+
+    fn serialize_witness(&self, sig: &Vec<u8>) -> Vec<u8> {
+        let pk = self.pk.serialize();
+        let mut witness = vec![0x02, sig.len() as u8];
+        witness.extend_from_slice(sig);
+        witness.push(pk.len() as u8);
+        witness.extend_from_slice(&pk);
+        witness
+    }
+}
+
+///
+/// CpfpChildTx - child tx that spends the anchor output of a close tx (first input) and optionally an
+/// extra funding input (second input) into a single P2WPKH output. Its fee is chosen such that the
+/// package of parent and child reaches the target fee rate.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CpfpChildTx {
+    pub anchor: AnchorOutput,
+    pub funding: Option<EscrowFundingInput>,
+    pub output_sats: i64,
+    pub output_pk: PublicKey,
+}
+
+impl CpfpChildTx {
+    ///
+    /// new() - builds the child of parent_tx given the fee the parent pays and the target fee rate (sat/vbyte)
+    /// output: cpfp child tx
+    ///
+    pub fn new(
+        parent_tx: &Vec<u8>,
+        parent_fee: i64,
+        anchor_pk: &PublicKey,
+        fee_rate: i64,
+        funding: Option<EscrowFundingInput>,
+        output_pk: PublicKey,
+    ) -> Result<Self, ZkChannelError> {
+        if fee_rate <= 0 {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "invalid fee rate: {}",
+                fee_rate
+            )));
+        }
+        let anchor = match AnchorOutput::find(parent_tx, anchor_pk)? {
+            Some(a) => a,
+            None => {
+                return Err(ZkChannelError::TransactionError(String::from(
+                    "close tx does not have an anchor output for the given key",
+                )))
+            }
+        };
+        if let Some(f) = &funding {
+            if f.input_sats <= 0 {
+                return Err(ZkChannelError::InvalidInput(format!(
+                    "invalid amount for the funding input: {}",
+                    f.input_sats
+                )));
+            }
+        }
+        let mut child_tx = CpfpChildTx {
+            anchor,
+            funding,
+            output_sats: 0,
+            output_pk,
+        };
+
+        let parent_vsize = parse_transaction(parent_tx)?.get_vsize() as i64;
+        let child_vsize = child_tx.get_max_vsize() as i64;
+        let package_fee = fee_rate * (parent_vsize + child_vsize);
+        // the child has to pay at least for itself, even if the parent fee already covers the package
+        let child_fee = std::cmp::max(package_fee - parent_fee, fee_rate * child_vsize);
+        child_tx.output_sats = child_tx.get_input_sats() - child_fee;
+        if child_tx.output_sats < DUST_LIMIT {
+            return Err(ZkChannelError::TransactionError(format!(
+                "inputs of the child tx ({}) do not cover the fee ({}): add a funding input",
+                child_tx.get_input_sats(),
+                child_fee
+            )));
+        }
+        Ok(child_tx)
+    }
+
+    pub fn get_input_sats(&self) -> i64 {
+        let funding_sats = match &self.funding {
+            Some(f) => f.input_sats,
+            None => 0,
+        };
+        self.anchor.sats + funding_sats
+    }
+
+    pub fn get_fee(&self) -> i64 {
+        self.get_input_sats() - self.output_sats
+    }
+
+    fn get_outpoints(&self) -> Vec<Vec<u8>> {
+        let mut outpoints = vec![self.anchor.serialize_outpoint()];
+        if let Some(f) = &self.funding {
+            outpoints.push(f.serialize_outpoint());
+        }
+        outpoints
+    }
+
+    fn serialize_inputs(&self) -> Vec<u8> {
+        let mut inputs = vec![self.get_outpoints().len() as u8];
+        inputs.extend(self.anchor.serialize_input());
+        if let Some(f) = &self.funding {
+            inputs.extend(f.serialize_input());
+        }
+        inputs
+    }
+
+    fn serialize_outputs(&self) -> Vec<u8> {
+        let script = p2wpkh_script(&self.output_pk);
+        let mut outputs = vec![0x01];
+        outputs.extend_from_slice(&self.output_sats.to_le_bytes());
+        outputs.push(script.len() as u8);
+        outputs.extend(script);
+        outputs
+    }
+
+    fn serialize_unsigned_tx(&self) -> Vec<u8> {
+        let mut tx = TX_VERSION.to_vec();
+        tx.extend(self.serialize_inputs());
+        tx.extend(self.serialize_outputs());
+        tx.extend_from_slice(&TX_LOCKTIME);
+        tx
+    }
+
+    ///
+    /// get_max_vsize() - virtual size of the signed child tx assuming signatures of maximal length
+    /// output: vsize
+    ///
+    pub fn get_max_vsize(&self) -> usize {
+        let witness_len = 2 + MAX_P2WPKH_WITNESS_LEN * self.get_outpoints().len();
+        let weight = self.serialize_unsigned_tx().len() * 4 + witness_len;
+        (weight + 3) / 4
+    }
+
+    pub fn get_anchor_sighash(&self) -> [u8; 32] {
+        compute_sighash(
+            &self.get_outpoints(),
+            &self.anchor.serialize_outpoint(),
+            &self.anchor.script_code(),
+            self.anchor.sats,
+            &self.serialize_outputs()[1..].to_vec(),
+        )
+    }
+
+    pub fn get_funding_sighash(&self) -> Option<[u8; 32]> {
+        match &self.funding {
+            Some(f) => Some(compute_sighash(
+                &self.get_outpoints(),
+                &f.serialize_outpoint(),
+                &f.script_code(),
+                f.input_sats,
+                &self.serialize_outputs()[1..].to_vec(),
+            )),
+            None => None,
+        }
+    }
+
+    pub fn get_txid(&self) -> ([u8; 32], [u8; 32]) {
+        let txid_be = double_sha256(&self.serialize_unsigned_tx());
+        let mut txid_le = txid_be.clone();
+        txid_le.reverse();
+        (txid_be, txid_le)
+    }
+
+    ///
+    /// sign() - signs the anchor input (and the funding input, if any)
+    /// output: signed child tx that can be broadcast along with the close tx
+    ///
+    pub fn sign(
+        &self,
+        anchor_sk: &[u8; 32],
+        funding_sk: Option<&[u8; 32]>,
+    ) -> Result<Vec<u8>, ZkChannelError> {
+        let anchor_sig = sign_sighash(&self.get_anchor_sighash(), anchor_sk, &self.anchor.pk)?;
+        let mut witness = self.anchor.serialize_witness(&anchor_sig);
+        match (&self.funding, funding_sk) {
+            (Some(f), Some(sk)) => {
+                let funding_sig = sign_sighash(&self.get_funding_sighash().unwrap(), sk, &f.pk)?;
+                witness.extend(f.serialize_witness(&funding_sig));
+            }
+            (None, None) => {}
+            (_, _) => {
+                return Err(ZkChannelError::InvalidInput(String::from(
+                    "the funding input and its secret key have to be given together",
+                )))
+            }
+        }
+
+        let mut tx = TX_VERSION.to_vec();
+        tx.extend_from_slice(&[0x00, 0x01]); // segwit marker and flag
+        tx.extend(self.serialize_inputs());
+        tx.extend(self.serialize_outputs());
+        tx.extend(witness);
+        tx.extend_from_slice(&TX_LOCKTIME);
+        Ok(tx)
+    }
+}
+
+///
+/// sign_cpfp_child_tx() - builds and signs a child of the close tx with the first of the keys that owns
+/// an anchor output of the close tx. The child pays to output_pk.
+/// output: signed child tx and its txid (little-endian)
+///
+pub fn sign_cpfp_child_tx(
+    parent_tx: &Vec<u8>,
+    parent_fee: i64,
+    anchor_keys: &Vec<(PublicKey, [u8; 32])>,
+    fee_rate: i64,
+    funding: Option<(EscrowFundingInput, [u8; 32])>,
+    output_pk: PublicKey,
+) -> Result<(Vec<u8>, [u8; 32]), ZkChannelError> {
+    let mut anchor_key = None;
+    for (pk, sk) in anchor_keys.iter() {
+        if AnchorOutput::find(parent_tx, pk)?.is_some() {
+            anchor_key = Some((pk, sk));
+            break;
+        }
+    }
+    let (anchor_pk, anchor_sk) = match anchor_key {
+        Some(k) => k,
+        None => {
+            return Err(ZkChannelError::TransactionError(String::from(
+                "close tx does not have an anchor output that can be spent with our keys",
+            )))
+        }
+    };
+    let (funding_input, funding_sk) = match funding {
+        Some((input, sk)) => (Some(input), Some(sk)),
+        None => (None, None),
+    };
+    let child_tx = CpfpChildTx::new(
+        parent_tx,
+        parent_fee,
+        anchor_pk,
+        fee_rate,
+        funding_input,
+        output_pk,
+    )?;
+    let signed_tx = child_tx.sign(anchor_sk, funding_sk.as_ref())?;
+    let (_, txid_le) = child_tx.get_txid();
+    Ok((signed_tx, txid_le))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secp256k1::SecretKey;
+
+    fn keypair(byte: u8) -> ([u8; 32], PublicKey) {
+        let secp = secp256k1::Secp256k1::new();
+        let sk = [byte; 32];
+        let pk = PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&sk).unwrap());
+        (sk, pk)
+    }
+
+    // close tx with a P2WSH output and an anchor of 1000 sats
+    fn build_parent_tx(anchor_pk: &PublicKey) -> Vec<u8> {
+        let mut tx = TX_VERSION.to_vec();
+        tx.push(0x01);
+        tx.extend_from_slice(&[0x55; 36]);
+        tx.push(0x00);
+        tx.extend_from_slice(&TX_SEQUENCE);
+        tx.push(0x02);
+        tx.extend_from_slice(&9000i64.to_le_bytes());
+        tx.extend_from_slice(&[0x22, 0x00, 0x20]);
+        tx.extend_from_slice(&[0x66; 32]);
+        tx.extend_from_slice(&1000i64.to_le_bytes());
+        tx.push(0x16);
+        tx.extend(p2wpkh_script(anchor_pk));
+        tx.extend_from_slice(&TX_LOCKTIME);
+        tx
+    }
+
+    #[test]
+    fn test_cpfp_child_tx() {
+        let (anchor_sk, anchor_pk) = keypair(0x41);
+        let (funding_sk, funding_pk) = keypair(0x42);
+        let (other_sk, other_pk) = keypair(0x43);
+        let parent_tx = build_parent_tx(&anchor_pk);
+        let parent_vsize = parse_transaction(&parent_tx).unwrap().get_vsize() as i64;
+
+        let anchor = AnchorOutput::find(&parent_tx, &anchor_pk).unwrap().unwrap();
+        assert_eq!(anchor.index, 1);
+        assert_eq!(anchor.sats, 1000);
+        assert!(AnchorOutput::find(&parent_tx, &other_pk).unwrap().is_none());
+
+        // the anchor alone covers a low fee rate
+        let child_tx = CpfpChildTx::new(&parent_tx, 100, &anchor_pk, 2, None, other_pk).unwrap();
+        let child_vsize = child_tx.get_max_vsize() as i64;
+        assert_eq!(child_tx.get_fee(), 2 * (parent_vsize + child_vsize) - 100);
+        let signed_tx = child_tx.sign(&anchor_sk, None).unwrap();
+        let child = parse_transaction(&signed_tx).unwrap();
+        assert_eq!(child.txid_be, child_tx.get_txid().0);
+        assert_eq!(child.outpoints, vec![(anchor.txid_be.0, 1)]);
+        assert!(child.get_vsize() as i64 <= child_vsize);
+        assert!(child_tx.sign(&other_sk, None).is_err());
+
+        // a higher fee rate needs an extra funding input
+        assert!(CpfpChildTx::new(&parent_tx, 100, &anchor_pk, 20, None, other_pk).is_err());
+        let funding = EscrowFundingInput {
+            txid_le: FixedSizeArray32([0x77; 32]),
+            index: 0,
+            input_sats: 50000,
+            pk: funding_pk,
+        };
+        let (signed_tx, txid_le) = sign_cpfp_child_tx(
+            &parent_tx,
+            100,
+            &vec![(other_pk, other_sk), (anchor_pk, anchor_sk)],
+            20,
+            Some((funding.clone(), funding_sk)),
+            other_pk,
+        )
+        .unwrap();
+        let child = parse_transaction(&signed_tx).unwrap();
+        let mut txid_be = txid_le.clone();
+        txid_be.reverse();
+        assert_eq!(child.txid_be, txid_be);
+        assert_eq!(child.outpoints.len(), 2);
+        let fee = 51000 - child.outputs[0].0;
+        assert!(fee + 100 >= 20 * (parent_vsize + child.get_vsize() as i64));
+
+        assert!(sign_cpfp_child_tx(
+            &parent_tx,
+            100,
+            &vec![(other_pk, other_sk)],
+            2,
+            None,
+            other_pk
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,405 @@
+use super::*;
+use channels_mpc::{
+    ChannelMPCState, ChannelMPCToken, ChannelStatus, CustomerMPCState, ProtocolStatus,
+};
+use database::WalletDatabase;
+use errors::ZkChannelError;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ChannelBalance {
+    pub cust_bal: i64,
+    pub merch_bal: i64,
+}
+
+///
+/// CustomerChannel - everything the customer needs to run the protocol on a single channel
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CustomerChannel {
+    pub channel_state: ChannelMPCState,
+    pub channel_token: ChannelMPCToken,
+    pub cust_state: CustomerMPCState,
+}
+
+impl CustomerChannel {
+    pub fn get_balance(&self) -> ChannelBalance {
+        ChannelBalance {
+            cust_bal: self.cust_state.cust_balance,
+            merch_bal: self.cust_state.merch_balance,
+        }
+    }
+
+    // the escrow has been (or is being) split between the parties
+    pub fn is_closed(&self) -> bool {
+        match self.cust_state.get_channel_status() {
+            ChannelStatus::MerchantInitClose
+            | ChannelStatus::CustomerInitClose
+            | ChannelStatus::Disputed
+            | ChannelStatus::PendingClose
+            | ChannelStatus::ConfirmedClose
+            | ChannelStatus::MutualClose => true,
+            _ => false,
+        }
+    }
+
+    // same minimum the customer enforces before running the payment mpc
+    fn get_min_cust_balance(&self) -> i64 {
+        self.channel_state.get_bal_min_cust()
+            + self.cust_state.get_fee_cc()
+            + self.channel_state.get_val_cpfp()
+    }
+
+    ///
+    /// can_pay() - checks that the channel is open, established, has no pending payment and stays
+    /// above the minimum customer balance after paying the given amount
+    /// output: true if a payment of amount can be made on this channel
+    ///
+    pub fn can_pay(&self, amount: i64) -> bool {
+        if amount <= 0
+            || self.cust_state.get_channel_status() != ChannelStatus::Open
+            || self.cust_state.has_pending_payment()
+        {
+            return false;
+        }
+        match self.cust_state.protocol_status {
+            ProtocolStatus::Activated | ProtocolStatus::Established => (),
+            _ => return false,
+        }
+        self.cust_state.cust_balance - amount > self.get_min_cust_balance()
+    }
+}
+
+///
+/// CustomerWallet - a named set of customer channels keyed by channel id (hex).
+/// The wallet is persisted as a whole so that all channels are always saved atomically.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CustomerWallet {
+    pub name: String,
+    channels: HashMap<String, CustomerChannel>,
+}
+
+impl CustomerWallet {
+    pub fn new(name: String) -> Self {
+        CustomerWallet {
+            name: name,
+            channels: HashMap::new(),
+        }
+    }
+
+    ///
+    /// add_channel() - adds the channel to the wallet. The channel id is derived from the channel
+    /// token, so channels should be added once the token is final (i.e., after the funding txs are known)
+    /// output: channel id (hex)
+    ///
+    pub fn add_channel(
+        &mut self,
+        channel_state: ChannelMPCState,
+        channel_token: ChannelMPCToken,
+        cust_state: CustomerMPCState,
+    ) -> Result<String, ZkChannelError> {
+        channel_state.check_network(cust_state.get_network())?;
+        channel_state.check_network(channel_token.network)?;
+        let channel_id = hex::encode(channel_token.compute_channel_id()?);
+        if self.channels.contains_key(&channel_id) {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "channel already exists in wallet: {}",
+                channel_id
+            )));
+        }
+        self.channels.insert(
+            channel_id.clone(),
+            CustomerChannel {
+                channel_state,
+                channel_token,
+                cust_state,
+            },
+        );
+        Ok(channel_id)
+    }
+
+    pub fn remove_channel(
+        &mut self,
+        channel_id: &String,
+    ) -> Result<CustomerChannel, ZkChannelError> {
+        match self.channels.remove(channel_id) {
+            Some(c) => Ok(c),
+            None => Err(ZkChannelError::NotFound(format!(
+                "channel not found in wallet: {}",
+                channel_id
+            ))),
+        }
+    }
+
+    pub fn get_channel(&self, channel_id: &String) -> Result<&CustomerChannel, ZkChannelError> {
+        match self.channels.get(channel_id) {
+            Some(c) => Ok(c),
+            None => Err(ZkChannelError::NotFound(format!(
+                "channel not found in wallet: {}",
+                channel_id
+            ))),
+        }
+    }
+
+    pub fn get_channel_mut(
+        &mut self,
+        channel_id: &String,
+    ) -> Result<&mut CustomerChannel, ZkChannelError> {
+        match self.channels.get_mut(channel_id) {
+            Some(c) => Ok(c),
+            None => Err(ZkChannelError::NotFound(format!(
+                "channel not found in wallet: {}",
+                channel_id
+            ))),
+        }
+    }
+
+    ///
+    /// find_channel_by_name() - looks up a channel by the name the customer gave it (the name of its
+    /// customer state, see init_customer())
+    /// output: channel id (hex) if the name is known
+    ///
+    pub fn find_channel_by_name(&self, name: &String) -> Option<String> {
+        self.get_channel_ids()
+            .into_iter()
+            .find(|id| self.channels[id].cust_state.name == *name)
+    }
+
+    // all channels of the wallet (including closed ones) by id
+    pub fn get_channels(&self) -> &HashMap<String, CustomerChannel> {
+        &self.channels
+    }
+
+    // sorted so that iteration over the wallet is deterministic
+    fn get_channel_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.channels.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    ///
+    /// list_channels() - lists the channels that have not been closed yet (closed channels stay
+    /// in the wallet and can still be looked up by id or name)
+    /// output: sorted channel ids (hex)
+    ///
+    pub fn list_channels(&self) -> Vec<String> {
+        self.get_channel_ids()
+            .into_iter()
+            .filter(|id| !self.channels[id].is_closed())
+            .collect()
+    }
+
+    pub fn get_balance(&self, channel_id: &String) -> Result<ChannelBalance, ZkChannelError> {
+        Ok(self.get_channel(channel_id)?.get_balance())
+    }
+
+    pub fn get_balances(&self) -> HashMap<String, ChannelBalance> {
+        self.channels
+            .iter()
+            .map(|(id, c)| (id.clone(), c.get_balance()))
+            .collect()
+    }
+
+    ///
+    /// get_total_balance() - sums up the balances of all channels that have not been closed yet
+    /// output: aggregate customer and merchant balance
+    ///
+    pub fn get_total_balance(&self) -> ChannelBalance {
+        let mut total = ChannelBalance {
+            cust_bal: 0,
+            merch_bal: 0,
+        };
+        for c in self.channels.values().filter(|c| !c.is_closed()) {
+            let bal = c.get_balance();
+            total.cust_bal += bal.cust_bal;
+            total.merch_bal += bal.merch_bal;
+        }
+        total
+    }
+
+    ///
+    /// select_channel_for_payment() - picks a channel with the given merchant that can pay amount.
+    /// Among the candidates, the one with the smallest sufficient customer balance is chosen
+    /// to keep larger channels available for larger payments.
+    /// output: channel id (hex) or None if no channel can make the payment
+    ///
+    pub fn select_channel_for_payment(
+        &self,
+        pk_m: &secp256k1::PublicKey,
+        amount: i64,
+    ) -> Option<String> {
+        self.get_channel_ids()
+            .into_iter()
+            .filter(|id| {
+                let c = &self.channels[id];
+                c.channel_token.pk_m == *pk_m && c.can_pay(amount)
+            })
+            .min_by_key(|id| self.channels[id].cust_state.cust_balance)
+    }
+
+    pub fn save(&self, db: &mut dyn WalletDatabase) -> Result<(), ZkChannelError> {
+        db.save_cust_wallet(self)
+    }
+
+    pub fn load(db: &mut dyn WalletDatabase, name: &String) -> Result<Self, ZkChannelError> {
+        db.load_cust_wallet(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channels_mpc::{BitcoinNetworkType, MerchantMPCState, TransactionFeeInfo};
+    use database::{HashMapDatabase, RedisDatabase, StateDatabase};
+    use rand::{Rng, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+    use zkchan_tx::fixed_size_array::FixedSizeArray32;
+
+    fn new_channel_helper<R: Rng>(
+        rng: &mut R,
+        merch_state: &MerchantMPCState,
+        name: &str,
+        b0_cust: i64,
+        tx_fee_info: &TransactionFeeInfo,
+    ) -> (ChannelMPCState, ChannelMPCToken, CustomerMPCState) {
+        let channel_state = ChannelMPCState::new(
+            String::from(name),
+            1487,
+            tx_fee_info.bal_min_cust,
+            tx_fee_info.bal_min_merch,
+            tx_fee_info.val_cpfp,
+            false,
+            BitcoinNetworkType::Testnet,
+        );
+        let (mut channel_token, mut cust_state) = mpc::init_customer(
+            rng,
+            &channel_state,
+            &merch_state.pk_m,
+            b0_cust,
+            b0_cust,
+            tx_fee_info,
+            name,
+        );
+        let mut escrow_txid = [0u8; 32];
+        rng.fill_bytes(&mut escrow_txid);
+        channel_token.escrow_txid = FixedSizeArray32(escrow_txid);
+        cust_state
+            .change_channel_status(ChannelStatus::PendingOpen)
+            .unwrap();
+        cust_state
+            .change_channel_status(ChannelStatus::Open)
+            .unwrap();
+        cust_state.protocol_status = ProtocolStatus::Established;
+        (channel_state, channel_token, cust_state)
+    }
+
+    #[test]
+    fn test_customer_wallet_select_and_persist() {
+        let mut rng = XorShiftRng::seed_from_u64(0x5dbe62598d313d86);
+        let tx_fee_info = TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+        let mut merch_channel_state = ChannelMPCState::new(
+            String::from("Merchant"),
+            1487,
+            546,
+            546,
+            1000,
+            false,
+            BitcoinNetworkType::Testnet,
+        );
+        let merch_state =
+            mpc::init_merchant(&mut rng, "".to_string(), &mut merch_channel_state, "Bob");
+        let other_merch_state =
+            mpc::init_merchant(&mut rng, "".to_string(), &mut merch_channel_state, "Carol");
+
+        let mut wallet = CustomerWallet::new(String::from("Alice"));
+        let (s, t, c) = new_channel_helper(&mut rng, &merch_state, "small", 5000, &tx_fee_info);
+        let small_id = wallet.add_channel(s, t, c).unwrap();
+        let (s, t, c) = new_channel_helper(&mut rng, &merch_state, "large", 50000, &tx_fee_info);
+        let large_id = wallet.add_channel(s.clone(), t.clone(), c.clone()).unwrap();
+        assert!(wallet.add_channel(s, t, c).is_err());
+        let (s, t, c) =
+            new_channel_helper(&mut rng, &other_merch_state, "other", 90000, &tx_fee_info);
+        let other_id = wallet.add_channel(s, t, c).unwrap();
+        let (s, t, mut c) =
+            new_channel_helper(&mut rng, &merch_state, "closed", 70000, &tx_fee_info);
+        c.change_channel_status(ChannelStatus::MutualClose).unwrap();
+        c.change_channel_status(ChannelStatus::ConfirmedClose)
+            .unwrap();
+        let closed_id = wallet.add_channel(s, t, c).unwrap();
+
+        // closed channels are not listed, but can still be looked up
+        let ids = wallet.list_channels();
+        assert_eq!(ids.len(), 3);
+        assert!(!ids.contains(&closed_id));
+        assert!(ids.contains(&small_id));
+        assert!(ids.contains(&large_id));
+        assert!(ids.contains(&other_id));
+        assert!(wallet.get_channel(&closed_id).is_ok());
+        assert_eq!(
+            wallet.find_channel_by_name(&String::from("closed")),
+            Some(closed_id.clone())
+        );
+        assert_eq!(
+            wallet.find_channel_by_name(&String::from("large")),
+            Some(large_id.clone())
+        );
+        let total = wallet.get_total_balance();
+        assert_eq!(total.cust_bal, 5000 + 50000 + 90000);
+        assert_eq!(total.merch_bal, 5000 + 50000 + 90000);
+
+        // the smallest channel with the merchant that covers the amount is picked
+        assert_eq!(
+            wallet.select_channel_for_payment(&merch_state.pk_m, 1000),
+            Some(small_id.clone())
+        );
+        assert_eq!(
+            wallet.select_channel_for_payment(&merch_state.pk_m, 10000),
+            Some(large_id.clone())
+        );
+        // the closed channel would cover the amount but is not selected
+        assert_eq!(
+            wallet.select_channel_for_payment(&merch_state.pk_m, 60000),
+            None
+        );
+        assert_eq!(
+            wallet.select_channel_for_payment(&other_merch_state.pk_m, 60000),
+            Some(other_id.clone())
+        );
+
+        // closed channels are not used for payments or counted in the total
+        wallet
+            .get_channel_mut(&large_id)
+            .unwrap()
+            .cust_state
+            .change_channel_status(ChannelStatus::CustomerInitClose)
+            .unwrap();
+        assert_eq!(
+            wallet.select_channel_for_payment(&merch_state.pk_m, 10000),
+            None
+        );
+        assert!(!wallet.list_channels().contains(&large_id));
+        let total = wallet.get_total_balance();
+        assert_eq!(total.cust_bal, 5000 + 90000);
+
+        let mut db = RedisDatabase::new("wallet", "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
+        wallet.save(&mut db).unwrap();
+        let rec_wallet = CustomerWallet::load(&mut db, &String::from("Alice")).unwrap();
+        assert_eq!(wallet, rec_wallet);
+        assert!(CustomerWallet::load(&mut db, &String::from("Bob")).is_err());
+
+        let mut hm_db = HashMapDatabase::new("", "".to_string()).unwrap();
+        rec_wallet.save(&mut hm_db).unwrap();
+        let rec_wallet = CustomerWallet::load(&mut hm_db, &String::from("Alice")).unwrap();
+        assert_eq!(wallet, rec_wallet);
+    }
+}
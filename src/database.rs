@@ -1,7 +1,14 @@
 use super::*;
-use channels_mpc::PaymentStatus;
+use channels_mpc::{
+    ChannelMPCState, ChannelMPCToken, ChannelStatus, MerchCloseTx, MerchantMPCState, PaymentStatus,
+};
+use customer_wallet::{CustomerChannel, CustomerWallet};
+use encryption::{open_legacy_record, open_record, reseal_record, seal_record, StorageKey};
+use errors::ZkChannelError;
+use event_log::{EventKey, EventRecord};
 use redis::{Commands, Connection};
 use std::collections::hash_map::RandomState;
+use wallet::State;
 use zkchan_tx::fixed_size_array::{FixedSizeArray16, FixedSizeArray32};
 
 pub fn create_db_connection(url: String) -> redis::RedisResult<Connection> {
@@ -11,14 +18,49 @@ pub fn create_db_connection(url: String) -> redis::RedisResult<Connection> {
     Ok(con)
 }
 
-pub fn ensure_db_connected(con: &mut Connection) -> Result<bool, String> {
+pub fn ensure_db_connected(con: &mut Connection) -> Result<bool, ZkChannelError> {
     let ping = match redis::cmd("PING").query::<String>(con) {
         Ok(n) => n,
-        Err(e) => return Err(format!("Connection Error: {}", e.to_string())),
+        Err(e) => {
+            return Err(ZkChannelError::DatabaseUnavailable(format!(
+                "Connection Error: {}",
+                e.to_string()
+            )))
+        }
     };
     Ok(ping.eq("PONG"))
 }
 
+///
+/// get_namespace() - prefix of the keys of a merchant in a database shared by several merchants,
+/// so that each merchant has its own sessions, nonces, rev locks and states
+/// output: "{prefix}:{merchant_id}"
+///
+pub fn get_namespace(prefix: &str, merchant_id: &str) -> Result<String, ZkChannelError> {
+    // another merchant id must never map to the same (or a nested) namespace
+    if merchant_id.is_empty()
+        || merchant_id
+            .chars()
+            .any(|c| c == ':' || c.is_whitespace() || c.is_control())
+    {
+        return Err(ZkChannelError::InvalidInput(format!(
+            "invalid merchant id: '{}'",
+            merchant_id
+        )));
+    }
+    Ok(format!("{}:{}", prefix, merchant_id))
+}
+
+// redis reports a missing hash field as a failed (nil) type conversion
+fn lookup_error(context: &str, e: redis::RedisError) -> ZkChannelError {
+    match e.kind() {
+        redis::ErrorKind::TypeError => {
+            ZkChannelError::NotFound(format!("{}: {}", context, e.to_string()))
+        }
+        _ => ZkChannelError::DatabaseUnavailable(format!("{}: {}", context, e.to_string())),
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PayMaskMap {
     pub mask: FixedSizeArray32,
@@ -92,48 +134,118 @@ pub struct SessionState {
     pub rev_lock_com: FixedSizeArray32,
     pub amount: i64,
     pub status: PaymentStatus,
+    // unix time (in seconds) at which the session was created
+    #[serde(default)]
+    pub created_at: u64,
+    // the pay masks were sent to the customer, so the nonce can never be given to another session
+    #[serde(default)]
+    pub masks_released: bool,
+}
+
+// a condition that apply_batch() checks before any write of the batch is made
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchCheck {
+    // the session id is not in use
+    NewSessionId(String),
+    // the nonce is not in the spent map
+    NonceNotSpent(String),
+    // the nonce is not mapped to another session id: (nonce, session id)
+    NonceFreeForSession(String, String),
+    // the rev lock is not in the rev lock map
+    RevLockNotRevoked(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum BatchWrite {
+    SaveSessionState(String, SessionState),
+    UpdateNonceMaskMap(String, [u8; 32], [u8; 16]),
+    UpdateNonceToSessionId(String, String),
+    UpdateSpentMap(String, String),
+    UpdateRevLockMap(String, String),
+    RemoveFromUnlinkSet(String),
+    AddToUnlinkSet(String),
+    UpdateActivateMap(String, State),
+    UpdateMerchTxidMap(String, String),
+    UpdateChannelStatus(String, ChannelStatus),
+}
+
+///
+/// DbBatch - a set of writes that apply_batch() makes all-or-nothing, and only if all checks pass
+/// (e.g., spending a nonce or revoking a rev lock)
+///
+#[derive(Clone, Debug, Default)]
+pub struct DbBatch {
+    pub checks: Vec<BatchCheck>,
+    pub writes: Vec<BatchWrite>,
+}
+
+impl DbBatch {
+    pub fn new() -> Self {
+        DbBatch::default()
+    }
+
+    pub fn check(&mut self, check: BatchCheck) {
+        self.checks.push(check);
+    }
+
+    pub fn write(&mut self, write: BatchWrite) {
+        self.writes.push(write);
+    }
 }
 
 pub trait StateDatabase {
     // creating a new database
-    fn new(prefix: &'static str, url: String) -> Result<Self, String>
+    fn new(prefix: &str, url: String) -> Result<Self, ZkChannelError>
     where
         Self: Sized;
+    // whether every connection to the same prefix and url sees the same data (so that a pool
+    // can hold more than one connection)
+    fn is_shared() -> bool
+    where
+        Self: Sized,
+    {
+        false
+    }
     // check db connection
-    fn is_connected(&mut self) -> Result<bool, String>;
+    fn is_connected(&mut self) -> Result<bool, ZkChannelError>;
     // manage session state
-    fn check_session_id(&mut self, session_id_hex: &String) -> Result<bool, String>;
+    fn check_session_id(&mut self, session_id_hex: &String) -> Result<bool, ZkChannelError>;
     fn save_new_session_state(
         &mut self,
         session_id_hex: &String,
         session_state: &SessionState,
     ) -> bool;
-    fn load_session_state(&mut self, session_id_hex: &String) -> Result<SessionState, String>;
+    fn load_session_state(
+        &mut self,
+        session_id_hex: &String,
+    ) -> Result<SessionState, ZkChannelError>;
     fn update_session_state(
         &mut self,
         session_id_hex: &String,
         session_state: &SessionState,
     ) -> bool;
     fn clear_session_state(&mut self, session_id_hex: &String) -> bool;
+    fn get_session_ids(&mut self) -> Result<Vec<String>, ZkChannelError>;
 
     // spent rev_lock map methods
     fn update_spent_map(
         &mut self,
         nonce_hex: &String,
         rev_lock_hex: &String,
-    ) -> Result<bool, String>;
+    ) -> Result<bool, ZkChannelError>;
     fn check_spent_map(&mut self, nonce_hex: &String) -> bool;
+    fn get_spent_rev_lock(&mut self, nonce_hex: &String) -> Result<String, ZkChannelError>;
     // rev_lock map methods
     fn update_rev_lock_map(
         &mut self,
         rev_lock_hex: &String,
         rev_secret_hex: &String,
-    ) -> Result<bool, String>;
+    ) -> Result<bool, ZkChannelError>;
     fn check_rev_lock_map(&mut self, rev_lock_hex: &String) -> bool;
-    fn get_rev_secret(&mut self, rev_lock_hex: &String) -> Result<String, String>;
+    fn get_rev_secret(&mut self, rev_lock_hex: &String) -> Result<String, ZkChannelError>;
     // unlink set methods
-    fn update_unlink_set(&mut self, nonce: &String) -> Result<bool, String>;
-    fn get_unlink_set(&mut self) -> Result<HashSet<String>, String>;
+    fn update_unlink_set(&mut self, nonce: &String) -> Result<bool, ZkChannelError>;
+    fn get_unlink_set(&mut self) -> Result<HashSet<String>, ZkChannelError>;
     fn is_member_unlink_set(&mut self, nonce: &String) -> bool;
     fn remove_from_unlink_set(&mut self, nonce: &String) -> bool;
     // nonce to session ids
@@ -146,29 +258,137 @@ pub trait StateDatabase {
         &mut self,
         nonce_hex: &String,
         session_id_hex: &String,
-    ) -> Result<bool, String>;
+    ) -> Result<bool, ZkChannelError>;
+    fn get_session_id_for_nonce(&mut self, nonce_hex: &String) -> Result<String, ZkChannelError>;
+    fn clear_nonce_to_session_id(&mut self, nonce_hex: &String) -> bool;
     // nonce to pay mask methods
     fn update_nonce_mask_map(
         &mut self,
         nonce_hex: &String,
         mask: [u8; 32],
         mask_r: [u8; 16],
-    ) -> Result<bool, String>;
+    ) -> Result<bool, ZkChannelError>;
     fn get_mask_map_from_nonce(
         &mut self,
         nonce_hex: &String,
-    ) -> Result<([u8; 32], [u8; 16]), String>;
+    ) -> Result<([u8; 32], [u8; 16]), ZkChannelError>;
+    fn clear_nonce_mask_map(&mut self, nonce_hex: &String) -> bool;
     // masked mpc input methods
     fn update_masked_mpc_inputs(
         &mut self,
         session_id_hex: &String,
         mask_bytes: MaskedMPCInputs,
     ) -> bool;
-    fn get_masked_mpc_inputs(&mut self, nonce_hex: &String) -> Result<MaskedMPCInputs, String>;
+    fn get_masked_mpc_inputs(
+        &mut self,
+        nonce_hex: &String,
+    ) -> Result<MaskedMPCInputs, ZkChannelError>;
+    fn clear_masked_mpc_inputs(&mut self, session_id_hex: &String) -> bool;
+    // channel status methods (by escrow txid)
+    fn update_channel_status(
+        &mut self,
+        escrow_txid_hex: &String,
+        status: &ChannelStatus,
+    ) -> Result<bool, ZkChannelError>;
+    fn get_channel_status(
+        &mut self,
+        escrow_txid_hex: &String,
+    ) -> Result<ChannelStatus, ZkChannelError>;
+    // sets the status of several escrow txids in a single write
+    fn update_channel_statuses(
+        &mut self,
+        statuses: &Vec<(String, ChannelStatus)>,
+    ) -> Result<bool, ZkChannelError>;
+    // initial state for channel activation methods (by channel id)
+    fn update_activate_map(
+        &mut self,
+        channel_id_hex: &String,
+        s0: &State,
+    ) -> Result<bool, ZkChannelError>;
+    fn get_activate_state(&mut self, channel_id_hex: &String) -> Result<State, ZkChannelError>;
+    // merch-close-tx methods (by escrow txid)
+    fn update_close_tx_map(
+        &mut self,
+        escrow_txid_hex: &String,
+        merch_close_tx: &MerchCloseTx,
+    ) -> Result<bool, ZkChannelError>;
+    fn get_merch_close_tx(
+        &mut self,
+        escrow_txid_hex: &String,
+    ) -> Result<MerchCloseTx, ZkChannelError>;
+    // merch-close txid to escrow txid methods
+    fn update_merch_txid_map(
+        &mut self,
+        merch_txid_hex: &String,
+        escrow_txid_hex: &String,
+    ) -> Result<bool, ZkChannelError>;
+    fn get_escrow_txid(&mut self, merch_txid_hex: &String) -> Result<String, ZkChannelError>;
+    // used refund ids (to the nonce of the state they were applied to)
+    fn update_refund_id_map(
+        &mut self,
+        refund_id_hex: &String,
+        nonce_hex: &String,
+    ) -> Result<bool, ZkChannelError>;
+    fn get_nonce_for_refund_id(&mut self, refund_id_hex: &String)
+        -> Result<String, ZkChannelError>;
+    // applies the writes of the batch atomically if all of its checks pass.
+    // returns false (and writes nothing) if a check fails
+    fn apply_batch(&mut self, batch: &DbBatch) -> Result<bool, ZkChannelError>;
+    // protocol event log (append-only per key)
+    fn append_event(&mut self, record: &EventRecord) -> Result<bool, ZkChannelError>;
+    fn get_events(&mut self, key: &EventKey) -> Result<Vec<EventRecord>, ZkChannelError>;
+    // merchant state and the channel tokens of the merchant (by channel id)
+    fn save_merchant_state(
+        &mut self,
+        channel_state: &ChannelMPCState,
+        merch_state: &MerchantMPCState,
+    ) -> Result<(), ZkChannelError>;
+    fn load_merchant_state(
+        &mut self,
+    ) -> Result<(ChannelMPCState, MerchantMPCState), ZkChannelError>;
+    fn save_merchant_channel(
+        &mut self,
+        channel_token: &ChannelMPCToken,
+    ) -> Result<String, ZkChannelError>;
+    fn load_merchant_channel(
+        &mut self,
+        channel_id: &String,
+    ) -> Result<ChannelMPCToken, ZkChannelError>;
+    fn list_merchant_channels(&mut self) -> Result<Vec<String>, ZkChannelError>;
     // helper methods
     fn clear_state(&mut self) -> bool;
 }
 
+// customer wallets (and so customer channels, see CustomerWallet) are stored as a single object
+// so all channels are saved atomically
+pub trait WalletDatabase {
+    fn save_cust_wallet(&mut self, wallet: &CustomerWallet) -> Result<(), ZkChannelError>;
+    fn load_cust_wallet(&mut self, name: &String) -> Result<CustomerWallet, ZkChannelError>;
+}
+
+// KEYS[j] is the key of the j-th step and ARGV[3j - 1..3j + 1] its (op, field, value). ARGV[1]
+// is the number of checks, which come first and are all made before the first write. Redis runs
+// a script atomically, so no other client can change the checked fields in between
+const APPLY_BATCH_SCRIPT: &str = r"
+local checks = tonumber(ARGV[1])
+for j = 1, #KEYS do
+    local key, op, field, value = KEYS[j], ARGV[3 * j - 1], ARGV[3 * j], ARGV[3 * j + 1]
+    if j <= checks then
+        local current = redis.call('HGET', key, field)
+        if current and (op == 'absent' or current ~= value) then
+            return 0
+        end
+    elseif op == 'hset' then
+        redis.call('HSET', key, field, value)
+    elseif op == 'srem' then
+        redis.call('SREM', key, field)
+    elseif op == 'sadd' then
+        redis.call('SADD', key, field)
+    end
+end
+return 1
+";
+
 pub struct RedisDatabase {
     pub conn: redis::Connection,
     session_map_key: String,
@@ -178,13 +398,51 @@ pub struct RedisDatabase {
     rev_lock_map_key: String,
     nonce_mask_map_key: String,
     masked_bytes_key: String,
+    channel_status_key: String,
+    activate_map_key: String,
+    close_tx_map_key: String,
+    merch_txid_map_key: String,
+    refund_id_map_key: String,
+    cust_wallet_key: String,
+    merch_state_key: String,
+    merch_channel_key: String,
+    // keys of all event lists (so clear_state() can delete them)
+    event_keys_key: String,
+    event_list_prefix: String,
+    // seals rev secrets and customer wallets at rest (if set)
+    storage_key: Option<StorageKey>,
+}
+
+impl RedisDatabase {
+    pub fn set_storage_key(&mut self, storage_key: StorageKey) {
+        self.storage_key = Some(storage_key);
+    }
+
+    ///
+    /// rotate_storage_key() - re-encrypts the rev secrets, customer wallets and the merchant
+    /// state with the new storage key (plaintext records written before a storage key was set are encrypted as well).
+    /// All records are rewritten in one transaction, so a crash never leaves them sealed with different keys
+    /// output: number of re-encrypted records
+    ///
+    pub fn rotate_storage_key(&mut self, new_key: StorageKey) -> Result<usize, ZkChannelError> {
+        let keys = vec![
+            self.rev_lock_map_key.clone(),
+            self.cust_wallet_key.clone(),
+            self.merch_state_key.clone(),
+            self.merch_channel_key.clone(),
+        ];
+        let count =
+            reencrypt_files_in_db(&mut self.conn, &keys, self.storage_key.as_ref(), &new_key)?;
+        self.storage_key = Some(new_key);
+        Ok(count)
+    }
 }
 
 impl StateDatabase for RedisDatabase {
-    fn new(prefix: &'static str, url: String) -> Result<Self, String> {
+    fn new(prefix: &str, url: String) -> Result<Self, ZkChannelError> {
         let conn = match create_db_connection(url) {
             Ok(c) => c,
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(e.into()),
         };
         Ok(RedisDatabase {
             conn: conn,
@@ -195,20 +453,40 @@ impl StateDatabase for RedisDatabase {
             nonce_to_session_key: format!("{}:hashmap:nonce_session", prefix),
             nonce_mask_map_key: format!("{}:hashmap:nonce_paymasks", prefix),
             masked_bytes_key: format!("{}:hashmap:masked_bytes", prefix),
+            channel_status_key: format!("{}:hashmap:channel_status", prefix),
+            activate_map_key: format!("{}:hashmap:activate", prefix),
+            close_tx_map_key: format!("{}:hashmap:close_tx", prefix),
+            merch_txid_map_key: format!("{}:hashmap:merch_txid", prefix),
+            refund_id_map_key: format!("{}:hashmap:refund_ids", prefix),
+            cust_wallet_key: format!("{}:hashmap:cust_wallet", prefix),
+            merch_state_key: format!("{}:hashmap:merch_state", prefix),
+            merch_channel_key: format!("{}:hashmap:merch_channels", prefix),
+            event_keys_key: format!("{}:hashset:event_keys", prefix),
+            event_list_prefix: format!("{}:list:events", prefix),
+            storage_key: None,
         })
     }
 
-    fn is_connected(&mut self) -> Result<bool, String> {
+    fn is_shared() -> bool {
+        true
+    }
+
+    fn is_connected(&mut self) -> Result<bool, ZkChannelError> {
         ensure_db_connected(&mut self.conn)
     }
 
-    fn check_session_id(&mut self, session_id_hex: &String) -> Result<bool, String> {
+    fn check_session_id(&mut self, session_id_hex: &String) -> Result<bool, ZkChannelError> {
         match self
             .conn
             .hexists(self.session_map_key.clone(), session_id_hex.clone())
         {
             Ok(s) => Ok(s),
-            Err(e) => return Err(format!("check_session_id: {}", e.to_string())),
+            Err(e) => {
+                return Err(ZkChannelError::DatabaseUnavailable(format!(
+                    "check_session_id: {}",
+                    e.to_string()
+                )))
+            }
         }
     }
 
@@ -260,13 +538,16 @@ impl StateDatabase for RedisDatabase {
         }
     }
 
-    fn load_session_state(&mut self, session_id_hex: &String) -> Result<SessionState, String> {
+    fn load_session_state(
+        &mut self,
+        session_id_hex: &String,
+    ) -> Result<SessionState, ZkChannelError> {
         let ser_session_data = match self
             .conn
             .hget::<String, String, String>(self.session_map_key.clone(), session_id_hex.clone())
         {
             Ok(s) => s,
-            Err(e) => return Err(format!("load_session_state: {}", e.to_string())),
+            Err(e) => return Err(lookup_error("load_session_state", e)),
         };
 
         let t: SessionState = handle_error_util!(serde_json::from_str(&ser_session_data));
@@ -290,19 +571,29 @@ impl StateDatabase for RedisDatabase {
         }
     }
 
+    fn get_session_ids(&mut self) -> Result<Vec<String>, ZkChannelError> {
+        match self
+            .conn
+            .hkeys::<String, Vec<String>>(self.session_map_key.clone())
+        {
+            Ok(s) => Ok(s),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
     // spent map calls
     fn update_spent_map(
         &mut self,
         nonce_hex: &String,
         rev_lock_hex: &String,
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
         match self.conn.hset::<String, String, String, i32>(
             self.spent_map_key.clone(),
             nonce_hex.clone(),
             rev_lock_hex.clone(),
         ) {
             Ok(s) => Ok(s != 0),
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(e.into()),
         }
     }
 
@@ -319,19 +610,34 @@ impl StateDatabase for RedisDatabase {
         }
     }
 
+    fn get_spent_rev_lock(&mut self, nonce_hex: &String) -> Result<String, ZkChannelError> {
+        match self
+            .conn
+            .hget::<String, String, String>(self.spent_map_key.clone(), nonce_hex.clone())
+        {
+            Ok(s) => Ok(s),
+            Err(e) => return Err(lookup_error("get_spent_rev_lock", e)),
+        }
+    }
+
     // rev_lock map calls
     fn update_rev_lock_map(
         &mut self,
         rev_lock_hex: &String,
         rev_secret_hex: &String,
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
+        let record = seal_record(
+            self.storage_key.as_ref(),
+            &get_record_name(&self.rev_lock_map_key, rev_lock_hex),
+            rev_secret_hex,
+        )?;
         match self.conn.hset::<String, String, String, i32>(
             self.rev_lock_map_key.clone(),
             rev_lock_hex.clone(),
-            rev_secret_hex.clone(),
+            record,
         ) {
             Ok(s) => Ok(s != 0),
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(e.into()),
         }
     }
 
@@ -348,31 +654,38 @@ impl StateDatabase for RedisDatabase {
         }
     }
 
-    fn get_rev_secret(&mut self, rev_lock_hex: &String) -> Result<String, String> {
+    fn get_rev_secret(&mut self, rev_lock_hex: &String) -> Result<String, ZkChannelError> {
         match self
             .conn
             .hget::<String, String, String>(self.rev_lock_map_key.clone(), rev_lock_hex.clone())
         {
-            Ok(s) => Ok(s),
-            Err(e) => return Err(e.to_string()),
+            Ok(s) => open_record(
+                self.storage_key.as_ref(),
+                &get_record_name(&self.rev_lock_map_key, rev_lock_hex),
+                &s,
+            ),
+            Err(e) => return Err(lookup_error("get_rev_secret", e)),
         }
     }
 
     // unlink set calls
-    fn update_unlink_set(&mut self, nonce: &String) -> Result<bool, String> {
+    fn update_unlink_set(&mut self, nonce: &String) -> Result<bool, ZkChannelError> {
         match self
             .conn
             .sadd::<String, String, i32>(self.unlink_set_key.clone(), nonce.clone())
         {
             Ok(_) => Ok(true),
-            Err(e) => Err(format!("update_unlink_set: {}", e.to_string())),
+            Err(e) => Err(ZkChannelError::DatabaseUnavailable(format!(
+                "update_unlink_set: {}",
+                e.to_string()
+            ))),
         }
     }
 
-    fn get_unlink_set(&mut self) -> Result<HashSet<String>, String> {
+    fn get_unlink_set(&mut self) -> Result<HashSet<String>, ZkChannelError> {
         let hash_set: HashSet<String> = match self.conn.smembers(&self.unlink_set_key) {
             Ok(n) => n,
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(e.into()),
         };
         Ok(hash_set)
     }
@@ -458,22 +771,274 @@ impl StateDatabase for RedisDatabase {
                 return false;
             }
         }
+        match self.conn.del(self.channel_status_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("could not delete: {} => {}", self.channel_status_key, e);
+                return false;
+            }
+        }
+        match self.conn.del(self.activate_map_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("could not delete: {} => {}", self.activate_map_key, e);
+                return false;
+            }
+        }
+        match self.conn.del(self.close_tx_map_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("could not delete: {} => {}", self.close_tx_map_key, e);
+                return false;
+            }
+        }
+        match self.conn.del(self.merch_txid_map_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("could not delete: {} => {}", self.merch_txid_map_key, e);
+                return false;
+            }
+        }
+        match self.conn.del(self.refund_id_map_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("could not delete: {} => {}", self.refund_id_map_key, e);
+                return false;
+            }
+        }
+        match self.conn.del(self.cust_wallet_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("could not delete: {} => {}", self.cust_wallet_key, e);
+                return false;
+            }
+        }
+        match self.conn.del(self.merch_state_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("could not delete: {} => {}", self.merch_state_key, e);
+                return false;
+            }
+        }
+        match self.conn.del(self.merch_channel_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("could not delete: {} => {}", self.merch_channel_key, e);
+                return false;
+            }
+        }
+        let event_keys: Vec<String> = match self.conn.smembers(self.event_keys_key.clone()) {
+            Ok(k) => k,
+            Err(e) => {
+                eprintln!("could not read: {} => {}", self.event_keys_key, e);
+                return false;
+            }
+        };
+        for key in event_keys.iter() {
+            match self.conn.del(format!("{}:{}", self.event_list_prefix, key)) {
+                Ok(c) => c,
+                Err(e) => {
+                    eprintln!("could not delete event list: {} => {}", key, e);
+                    return false;
+                }
+            }
+        }
+        match self.conn.del(self.event_keys_key.clone()) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("could not delete: {} => {}", self.event_keys_key, e);
+                return false;
+            }
+        }
         return true;
     }
 
+    fn apply_batch(&mut self, batch: &DbBatch) -> Result<bool, ZkChannelError> {
+        let mut keys = Vec::new();
+        let mut args = vec![batch.checks.len().to_string()];
+        for check in batch.checks.iter() {
+            let (op, key, field, value) = match check {
+                BatchCheck::NewSessionId(s) => ("absent", &self.session_map_key, s, String::new()),
+                BatchCheck::NonceNotSpent(n) => ("absent", &self.spent_map_key, n, String::new()),
+                BatchCheck::NonceFreeForSession(n, s) => {
+                    ("absent_or_eq", &self.nonce_to_session_key, n, s.clone())
+                }
+                BatchCheck::RevLockNotRevoked(r) => {
+                    ("absent", &self.rev_lock_map_key, r, String::new())
+                }
+            };
+            keys.push(key.clone());
+            args.extend(vec![op.to_string(), field.clone(), value]);
+        }
+        for write in batch.writes.iter() {
+            let (op, key, field, value) = match write {
+                BatchWrite::SaveSessionState(s, session_state) => (
+                    "hset",
+                    &self.session_map_key,
+                    s,
+                    serde_json::to_string(session_state)?,
+                ),
+                BatchWrite::UpdateNonceMaskMap(n, mask, mask_r) => {
+                    let mut m = mask.to_vec();
+                    m.extend(mask_r.to_vec());
+                    ("hset", &self.nonce_mask_map_key, n, hex::encode(&m))
+                }
+                BatchWrite::UpdateNonceToSessionId(n, s) => {
+                    ("hset", &self.nonce_to_session_key, n, s.clone())
+                }
+                BatchWrite::UpdateSpentMap(n, r) => ("hset", &self.spent_map_key, n, r.clone()),
+                BatchWrite::UpdateRevLockMap(r, rev_secret) => (
+                    "hset",
+                    &self.rev_lock_map_key,
+                    r,
+                    seal_record(
+                        self.storage_key.as_ref(),
+                        &get_record_name(&self.rev_lock_map_key, r),
+                        rev_secret,
+                    )?,
+                ),
+                BatchWrite::RemoveFromUnlinkSet(n) => {
+                    ("srem", &self.unlink_set_key, n, String::new())
+                }
+                BatchWrite::AddToUnlinkSet(n) => ("sadd", &self.unlink_set_key, n, String::new()),
+                BatchWrite::UpdateActivateMap(c, s0) => (
+                    "hset",
+                    &self.activate_map_key,
+                    c,
+                    serde_json::to_string(s0)?,
+                ),
+                BatchWrite::UpdateMerchTxidMap(m, e) => {
+                    ("hset", &self.merch_txid_map_key, m, e.clone())
+                }
+                BatchWrite::UpdateChannelStatus(e, status) => (
+                    "hset",
+                    &self.channel_status_key,
+                    e,
+                    serde_json::to_string(status)?,
+                ),
+            };
+            keys.push(key.clone());
+            args.extend(vec![op.to_string(), field.clone(), value]);
+        }
+        let script = redis::Script::new(APPLY_BATCH_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        for key in keys.iter() {
+            invocation.key(key);
+        }
+        for arg in args.iter() {
+            invocation.arg(arg);
+        }
+        match invocation.invoke::<i32>(&mut self.conn) {
+            Ok(s) => Ok(s != 0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn append_event(&mut self, record: &EventRecord) -> Result<bool, ZkChannelError> {
+        let key = record.key.to_db_key();
+        let ser_record = serde_json::to_string(record)?;
+        // RPUSH only ever appends, so earlier events of the key are never changed
+        let _: (i32,) = redis::pipe()
+            .atomic()
+            .sadd(self.event_keys_key.clone(), key.clone())
+            .ignore()
+            .rpush(format!("{}:{}", self.event_list_prefix, key), ser_record)
+            .query(&mut self.conn)?;
+        Ok(true)
+    }
+
+    fn get_events(&mut self, key: &EventKey) -> Result<Vec<EventRecord>, ZkChannelError> {
+        let ser_records: Vec<String> = self.conn.lrange(
+            format!("{}:{}", self.event_list_prefix, key.to_db_key()),
+            0,
+            -1,
+        )?;
+        let mut records = Vec::new();
+        for ser_record in ser_records.iter() {
+            records.push(serde_json::from_str(ser_record)?);
+        }
+        Ok(records)
+    }
+
+    fn save_merchant_state(
+        &mut self,
+        channel_state: &ChannelMPCState,
+        merch_state: &MerchantMPCState,
+    ) -> Result<(), ZkChannelError> {
+        let record = encode_merchant_state(channel_state, merch_state)?;
+        store_encrypted_file_in_db(
+            &mut self.conn,
+            &self.merch_state_key,
+            &MERCH_STATE_FIELD.to_string(),
+            &record,
+            self.storage_key.as_ref(),
+        )?;
+        Ok(())
+    }
+
+    fn load_merchant_state(
+        &mut self,
+    ) -> Result<(ChannelMPCState, MerchantMPCState), ZkChannelError> {
+        let record = get_encrypted_file_from_db(
+            &mut self.conn,
+            &self.merch_state_key,
+            &MERCH_STATE_FIELD.to_string(),
+            self.storage_key.as_ref(),
+        )?;
+        let (channel_state, mut merch_state) = decode_merchant_state(&record)?;
+        if merch_state.migrate_legacy_channel_maps(self)? {
+            self.save_merchant_state(&channel_state, &merch_state)?;
+        }
+        Ok((channel_state, merch_state))
+    }
+
+    fn save_merchant_channel(
+        &mut self,
+        channel_token: &ChannelMPCToken,
+    ) -> Result<String, ZkChannelError> {
+        let channel_id = hex::encode(channel_token.compute_channel_id()?);
+        store_encrypted_file_in_db(
+            &mut self.conn,
+            &self.merch_channel_key,
+            &channel_id,
+            &persist::to_json_string(channel_token)?,
+            self.storage_key.as_ref(),
+        )?;
+        Ok(channel_id)
+    }
+
+    fn load_merchant_channel(
+        &mut self,
+        channel_id: &String,
+    ) -> Result<ChannelMPCToken, ZkChannelError> {
+        let record = get_encrypted_file_from_db(
+            &mut self.conn,
+            &self.merch_channel_key,
+            channel_id,
+            self.storage_key.as_ref(),
+        )?;
+        persist::from_json_str(&record)
+    }
+
+    fn list_merchant_channels(&mut self) -> Result<Vec<String>, ZkChannelError> {
+        let mut channel_ids: Vec<String> = self.conn.hkeys(self.merch_channel_key.clone())?;
+        channel_ids.sort();
+        Ok(channel_ids)
+    }
+
     // nonce -> session id
     fn update_nonce_to_session_id(
         &mut self,
         nonce_hex: &String,
         session_id_hex: &String,
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
         match self.conn.hset_nx::<String, String, String, i32>(
             self.nonce_to_session_key.clone(),
             nonce_hex.clone(),
             session_id_hex.clone(),
         ) {
             Ok(s) => Ok(s != 0),
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(e.into()),
         }
     }
 
@@ -491,13 +1056,39 @@ impl StateDatabase for RedisDatabase {
         }
     }
 
+    fn get_session_id_for_nonce(&mut self, nonce_hex: &String) -> Result<String, ZkChannelError> {
+        match self
+            .conn
+            .hget::<String, String, String>(self.nonce_to_session_key.clone(), nonce_hex.clone())
+        {
+            Ok(s) => Ok(s),
+            Err(e) => return Err(lookup_error("get_session_id_for_nonce", e)),
+        }
+    }
+
+    fn clear_nonce_to_session_id(&mut self, nonce_hex: &String) -> bool {
+        match self
+            .conn
+            .hdel::<String, String, i32>(self.nonce_to_session_key.clone(), nonce_hex.clone())
+        {
+            Ok(c) => c != 0,
+            Err(e) => {
+                eprintln!(
+                    "clear_nonce_to_session_id: failed to delete key: {} => {}",
+                    nonce_hex, e
+                );
+                return false;
+            }
+        }
+    }
+
     // nonce -> masks calls
     fn update_nonce_mask_map(
         &mut self,
         nonce_hex: &String,
         mask: [u8; 32],
         mask_r: [u8; 16],
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
         let mut m = mask.to_vec();
         m.extend(mask_r.to_vec());
         match self.conn.hset::<String, String, String, i32>(
@@ -506,14 +1097,14 @@ impl StateDatabase for RedisDatabase {
             hex::encode(&m),
         ) {
             Ok(s) => Ok(s != 0),
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(e.into()),
         }
     }
 
     fn get_mask_map_from_nonce(
         &mut self,
         nonce_hex: &String,
-    ) -> Result<([u8; 32], [u8; 16]), String> {
+    ) -> Result<([u8; 32], [u8; 16]), ZkChannelError> {
         let (mask, mask_r) = match self
             .conn
             .hget::<String, String, String>(self.nonce_mask_map_key.clone(), nonce_hex.clone())
@@ -527,22 +1118,40 @@ impl StateDatabase for RedisDatabase {
                         mask_r.copy_from_slice(&t[32..48]);
                         (mask, mask_r)
                     } else {
-                        return Err(format!("invalid length for mask: {}", t.len()));
+                        return Err(ZkChannelError::SerializationError(format!(
+                            "invalid length for mask: {}",
+                            t.len()
+                        )));
                     }
                 }
-                Err(e) => return Err(e.to_string()),
+                Err(e) => return Err(e.into()),
             },
             Err(e) => {
-                return Err(format!(
-                    "could not find mask for specified nonce: {}. reason: {}",
-                    nonce_hex,
-                    e.to_string()
+                return Err(lookup_error(
+                    &format!("could not find mask for specified nonce: {}", nonce_hex),
+                    e,
                 ))
             }
         };
         Ok((mask, mask_r))
     }
 
+    fn clear_nonce_mask_map(&mut self, nonce_hex: &String) -> bool {
+        match self
+            .conn
+            .hdel::<String, String, i32>(self.nonce_mask_map_key.clone(), nonce_hex.clone())
+        {
+            Ok(c) => c != 0,
+            Err(e) => {
+                eprintln!(
+                    "clear_nonce_mask_map: failed to delete key: {} => {}",
+                    nonce_hex, e
+                );
+                return false;
+            }
+        }
+    }
+
     // rev-lock -> masked inputs calls
     fn update_masked_mpc_inputs(
         &mut self,
@@ -567,18 +1176,19 @@ impl StateDatabase for RedisDatabase {
     fn get_masked_mpc_inputs(
         &mut self,
         session_id_hex: &String,
-    ) -> Result<MaskedMPCInputs, String> {
+    ) -> Result<MaskedMPCInputs, ZkChannelError> {
         let ser_masked_bytes = match self
             .conn
             .hget::<String, String, String>(self.masked_bytes_key.clone(), session_id_hex.clone())
         {
             Ok(s) => s,
             Err(e) => {
-                return Err(format!(
-                    "get_masked_mpc_inputs: key({}) field({}) => {}",
-                    &self.masked_bytes_key,
-                    session_id_hex,
-                    e.to_string()
+                return Err(lookup_error(
+                    &format!(
+                        "get_masked_mpc_inputs: key({}) field({})",
+                        &self.masked_bytes_key, session_id_hex
+                    ),
+                    e,
                 ))
             }
         };
@@ -587,70 +1197,307 @@ impl StateDatabase for RedisDatabase {
 
         Ok(t)
     }
-}
-
-#[derive(Debug)]
-pub struct HashMapDatabase {
-    pub session_state_map: HashMap<String, SessionState>,
-    pub nonce_session_map: HashMap<String, String>,
-    pub nonce_mask_map: HashMap<String, PayMaskMap>,
-    pub unlink_map: HashSet<String>,
-    pub spent_lock_map: HashMap<String, String>,
-    pub rev_lock_map: HashMap<String, String>,
-    pub mask_mpc_bytes: HashMap<String, MaskedMPCInputs>,
-}
 
-impl StateDatabase for HashMapDatabase {
-    fn new(_prefix: &'static str, _url: String) -> Result<Self, String> {
-        Ok(HashMapDatabase {
-            session_state_map: HashMap::new(),
-            nonce_session_map: HashMap::new(),
-            nonce_mask_map: HashMap::new(),
-            unlink_map: HashSet::new(),
-            spent_lock_map: HashMap::new(),
-            rev_lock_map: HashMap::new(),
-            mask_mpc_bytes: HashMap::new(),
-        })
+    fn clear_masked_mpc_inputs(&mut self, session_id_hex: &String) -> bool {
+        match self
+            .conn
+            .hdel::<String, String, i32>(self.masked_bytes_key.clone(), session_id_hex.clone())
+        {
+            Ok(c) => c != 0,
+            Err(e) => {
+                eprintln!(
+                    "clear_masked_mpc_inputs: failed to delete key: {} => {}",
+                    session_id_hex, e
+                );
+                return false;
+            }
+        }
     }
 
-    fn is_connected(&mut self) -> Result<bool, String> {
-        Ok(true)
+    // escrow txid -> channel status calls
+    fn update_channel_status(
+        &mut self,
+        escrow_txid_hex: &String,
+        status: &ChannelStatus,
+    ) -> Result<bool, ZkChannelError> {
+        let ser_status = handle_error_util!(serde_json::to_string(status));
+        match self.conn.hset::<String, String, String, i32>(
+            self.channel_status_key.clone(),
+            escrow_txid_hex.clone(),
+            ser_status,
+        ) {
+            Ok(s) => Ok(s != 0),
+            Err(e) => return Err(e.into()),
+        }
     }
 
-    fn check_session_id(&mut self, session_id_hex: &String) -> Result<bool, String> {
-        Ok(self.session_state_map.get(session_id_hex).is_some())
+    fn get_channel_status(
+        &mut self,
+        escrow_txid_hex: &String,
+    ) -> Result<ChannelStatus, ZkChannelError> {
+        let ser_status = match self.conn.hget::<String, String, String>(
+            self.channel_status_key.clone(),
+            escrow_txid_hex.clone(),
+        ) {
+            Ok(s) => s,
+            Err(e) => return Err(lookup_error("get_channel_status", e)),
+        };
+
+        let status: ChannelStatus = handle_error_util!(serde_json::from_str(&ser_status));
+
+        Ok(status)
     }
 
-    fn save_new_session_state(
+    fn update_channel_statuses(
         &mut self,
-        session_id_hex: &String,
-        session_state: &SessionState,
-    ) -> bool {
+        statuses: &Vec<(String, ChannelStatus)>,
+    ) -> Result<bool, ZkChannelError> {
+        let mut items = Vec::new();
+        for (escrow_txid_hex, status) in statuses.iter() {
+            items.push((
+                escrow_txid_hex.clone(),
+                handle_error_util!(serde_json::to_string(status)),
+            ));
+        }
+        // HMSET writes all fields at once
         match self
-            .session_state_map
-            .insert(session_id_hex.clone(), session_state.clone())
+            .conn
+            .hset_multiple::<String, String, String, ()>(self.channel_status_key.clone(), &items)
         {
-            Some(_) => true,
-            None => false,
+            Ok(_) => Ok(true),
+            Err(e) => return Err(e.into()),
         }
     }
 
-    fn update_session_state(
+    // channel id -> initial state calls
+    fn update_activate_map(
         &mut self,
-        session_id_hex: &String,
-        session_state: &SessionState,
-    ) -> bool {
-        return self.save_new_session_state(session_id_hex, session_state);
-    }
+        channel_id_hex: &String,
+        s0: &State,
+    ) -> Result<bool, ZkChannelError> {
+        let ser_state = handle_error_util!(serde_json::to_string(s0));
+        match self.conn.hset::<String, String, String, i32>(
+            self.activate_map_key.clone(),
+            channel_id_hex.clone(),
+            ser_state,
+        ) {
+            Ok(s) => Ok(s != 0),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    fn get_activate_state(&mut self, channel_id_hex: &String) -> Result<State, ZkChannelError> {
+        let ser_state = match self
+            .conn
+            .hget::<String, String, String>(self.activate_map_key.clone(), channel_id_hex.clone())
+        {
+            Ok(s) => s,
+            Err(e) => return Err(lookup_error("get_activate_state", e)),
+        };
+
+        let s0: State = handle_error_util!(serde_json::from_str(&ser_state));
+
+        Ok(s0)
+    }
+
+    // escrow txid -> merch-close-tx calls
+    fn update_close_tx_map(
+        &mut self,
+        escrow_txid_hex: &String,
+        merch_close_tx: &MerchCloseTx,
+    ) -> Result<bool, ZkChannelError> {
+        let ser_close_tx = handle_error_util!(serde_json::to_string(merch_close_tx));
+        match self.conn.hset::<String, String, String, i32>(
+            self.close_tx_map_key.clone(),
+            escrow_txid_hex.clone(),
+            ser_close_tx,
+        ) {
+            Ok(s) => Ok(s != 0),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    fn get_merch_close_tx(
+        &mut self,
+        escrow_txid_hex: &String,
+    ) -> Result<MerchCloseTx, ZkChannelError> {
+        let ser_close_tx = match self
+            .conn
+            .hget::<String, String, String>(self.close_tx_map_key.clone(), escrow_txid_hex.clone())
+        {
+            Ok(s) => s,
+            Err(e) => return Err(lookup_error("get_merch_close_tx", e)),
+        };
+
+        let merch_close_tx: MerchCloseTx = handle_error_util!(serde_json::from_str(&ser_close_tx));
+
+        Ok(merch_close_tx)
+    }
+
+    // merch-close txid -> escrow txid calls
+    fn update_merch_txid_map(
+        &mut self,
+        merch_txid_hex: &String,
+        escrow_txid_hex: &String,
+    ) -> Result<bool, ZkChannelError> {
+        match self.conn.hset::<String, String, String, i32>(
+            self.merch_txid_map_key.clone(),
+            merch_txid_hex.clone(),
+            escrow_txid_hex.clone(),
+        ) {
+            Ok(s) => Ok(s != 0),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    fn get_escrow_txid(&mut self, merch_txid_hex: &String) -> Result<String, ZkChannelError> {
+        match self
+            .conn
+            .hget::<String, String, String>(self.merch_txid_map_key.clone(), merch_txid_hex.clone())
+        {
+            Ok(s) => Ok(s),
+            Err(e) => return Err(lookup_error("get_escrow_txid", e)),
+        }
+    }
+
+    fn update_refund_id_map(
+        &mut self,
+        refund_id_hex: &String,
+        nonce_hex: &String,
+    ) -> Result<bool, ZkChannelError> {
+        // only set if the refund id has not been used yet
+        match self.conn.hset_nx::<String, String, String, i32>(
+            self.refund_id_map_key.clone(),
+            refund_id_hex.clone(),
+            nonce_hex.clone(),
+        ) {
+            Ok(s) => Ok(s != 0),
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    fn get_nonce_for_refund_id(
+        &mut self,
+        refund_id_hex: &String,
+    ) -> Result<String, ZkChannelError> {
+        match self
+            .conn
+            .hget::<String, String, String>(self.refund_id_map_key.clone(), refund_id_hex.clone())
+        {
+            Ok(s) => Ok(s),
+            Err(e) => return Err(lookup_error("get_nonce_for_refund_id", e)),
+        }
+    }
+}
+
+impl WalletDatabase for RedisDatabase {
+    fn save_cust_wallet(&mut self, wallet: &CustomerWallet) -> Result<(), ZkChannelError> {
+        let ser_wallet = encode_cust_wallet(wallet)?;
+        // a single HSET replaces the whole wallet, so a partially written wallet is never observed
+        store_encrypted_file_in_db(
+            &mut self.conn,
+            &self.cust_wallet_key,
+            &wallet.name,
+            &ser_wallet,
+            self.storage_key.as_ref(),
+        )?;
+        Ok(())
+    }
+
+    fn load_cust_wallet(&mut self, name: &String) -> Result<CustomerWallet, ZkChannelError> {
+        let ser_wallet = get_encrypted_file_from_db(
+            &mut self.conn,
+            &self.cust_wallet_key,
+            name,
+            self.storage_key.as_ref(),
+        )?;
+        decode_cust_wallet(&ser_wallet)
+    }
+}
+
+#[derive(Debug)]
+pub struct HashMapDatabase {
+    pub session_state_map: HashMap<String, SessionState>,
+    pub nonce_session_map: HashMap<String, String>,
+    pub nonce_mask_map: HashMap<String, PayMaskMap>,
+    pub unlink_map: HashSet<String>,
+    pub spent_lock_map: HashMap<String, String>,
+    pub rev_lock_map: HashMap<String, String>,
+    pub mask_mpc_bytes: HashMap<String, MaskedMPCInputs>,
+    pub channel_status_map: HashMap<String, ChannelStatus>,
+    pub activate_map: HashMap<String, State>,
+    pub close_tx_map: HashMap<String, MerchCloseTx>,
+    pub merch_txid_map: HashMap<String, String>,
+    pub refund_id_map: HashMap<String, String>,
+    pub cust_wallet_map: HashMap<String, CustomerWallet>,
+    pub merch_state: Option<(ChannelMPCState, MerchantMPCState)>,
+    pub merch_channel_map: HashMap<String, ChannelMPCToken>,
+    pub event_map: HashMap<String, Vec<EventRecord>>,
+}
+
+impl StateDatabase for HashMapDatabase {
+    fn new(_prefix: &str, _url: String) -> Result<Self, ZkChannelError> {
+        Ok(HashMapDatabase {
+            session_state_map: HashMap::new(),
+            nonce_session_map: HashMap::new(),
+            nonce_mask_map: HashMap::new(),
+            unlink_map: HashSet::new(),
+            spent_lock_map: HashMap::new(),
+            rev_lock_map: HashMap::new(),
+            mask_mpc_bytes: HashMap::new(),
+            channel_status_map: HashMap::new(),
+            activate_map: HashMap::new(),
+            close_tx_map: HashMap::new(),
+            merch_txid_map: HashMap::new(),
+            refund_id_map: HashMap::new(),
+            cust_wallet_map: HashMap::new(),
+            merch_state: None,
+            merch_channel_map: HashMap::new(),
+            event_map: HashMap::new(),
+        })
+    }
 
-    fn load_session_state(&mut self, session_id_hex: &String) -> Result<SessionState, String> {
+    fn is_connected(&mut self) -> Result<bool, ZkChannelError> {
+        Ok(true)
+    }
+
+    fn check_session_id(&mut self, session_id_hex: &String) -> Result<bool, ZkChannelError> {
+        Ok(self.session_state_map.get(session_id_hex).is_some())
+    }
+
+    fn save_new_session_state(
+        &mut self,
+        session_id_hex: &String,
+        session_state: &SessionState,
+    ) -> bool {
+        match self
+            .session_state_map
+            .insert(session_id_hex.clone(), session_state.clone())
+        {
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    fn update_session_state(
+        &mut self,
+        session_id_hex: &String,
+        session_state: &SessionState,
+    ) -> bool {
+        return self.save_new_session_state(session_id_hex, session_state);
+    }
+
+    fn load_session_state(
+        &mut self,
+        session_id_hex: &String,
+    ) -> Result<SessionState, ZkChannelError> {
         match self.session_state_map.get(session_id_hex) {
             Some(m) => Ok(m.clone()),
             None => {
-                return Err(format!(
+                return Err(ZkChannelError::NotFound(format!(
                     "could not find session state for session id: {}",
                     session_id_hex
-                ))
+                )))
             }
         }
     }
@@ -660,10 +1507,22 @@ impl StateDatabase for HashMapDatabase {
         return true;
     }
 
-    fn update_spent_map(&mut self, nonce: &String, rev_lock: &String) -> Result<bool, String> {
+    fn get_session_ids(&mut self) -> Result<Vec<String>, ZkChannelError> {
+        Ok(self.session_state_map.keys().cloned().collect())
+    }
+
+    fn update_spent_map(
+        &mut self,
+        nonce: &String,
+        rev_lock: &String,
+    ) -> Result<bool, ZkChannelError> {
         match self.spent_lock_map.insert(nonce.clone(), rev_lock.clone()) {
             Some(c) => c,
-            None => return Err(format!("could not update spent_map")),
+            None => {
+                return Err(ZkChannelError::DatabaseUnavailable(format!(
+                    "could not update spent_map"
+                )))
+            }
         };
         Ok(true)
     }
@@ -672,17 +1531,33 @@ impl StateDatabase for HashMapDatabase {
         return self.spent_lock_map.get(nonce).is_some();
     }
 
+    fn get_spent_rev_lock(&mut self, nonce: &String) -> Result<String, ZkChannelError> {
+        match self.spent_lock_map.get(nonce) {
+            Some(c) => Ok(c.clone()),
+            None => {
+                return Err(ZkChannelError::NotFound(format!(
+                    "could not find nonce: {}",
+                    nonce
+                )))
+            }
+        }
+    }
+
     fn update_rev_lock_map(
         &mut self,
         rev_lock_hex: &String,
         rev_secret_hex: &String,
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
         match self
             .spent_lock_map
             .insert(rev_lock_hex.clone(), rev_secret_hex.clone())
         {
             Some(c) => c,
-            None => return Err(format!("could not update spent_map")),
+            None => {
+                return Err(ZkChannelError::DatabaseUnavailable(format!(
+                    "could not update spent_map"
+                )))
+            }
         };
         Ok(true)
     }
@@ -691,18 +1566,23 @@ impl StateDatabase for HashMapDatabase {
         return self.rev_lock_map.get(rev_lock_hex).is_some();
     }
 
-    fn get_rev_secret(&mut self, rev_lock_hex: &String) -> Result<String, String> {
+    fn get_rev_secret(&mut self, rev_lock_hex: &String) -> Result<String, ZkChannelError> {
         match self.rev_lock_map.get(rev_lock_hex) {
             Some(c) => Ok(c.clone()),
-            None => return Err(format!("could not find rev_lock: {}", rev_lock_hex)),
+            None => {
+                return Err(ZkChannelError::NotFound(format!(
+                    "could not find rev_lock: {}",
+                    rev_lock_hex
+                )))
+            }
         }
     }
 
-    fn update_unlink_set(&mut self, nonce: &String) -> Result<bool, String> {
+    fn update_unlink_set(&mut self, nonce: &String) -> Result<bool, ZkChannelError> {
         Ok(self.unlink_map.insert(nonce.clone()))
     }
 
-    fn get_unlink_set(&mut self) -> Result<HashSet<String, RandomState>, String> {
+    fn get_unlink_set(&mut self) -> Result<HashSet<String, RandomState>, ZkChannelError> {
         Ok(self.unlink_map.clone())
     }
 
@@ -725,9 +1605,140 @@ impl StateDatabase for HashMapDatabase {
         self.nonce_session_map.clear();
         self.nonce_mask_map.clear();
         self.mask_mpc_bytes.clear();
+        self.channel_status_map.clear();
+        self.activate_map.clear();
+        self.close_tx_map.clear();
+        self.merch_txid_map.clear();
+        self.refund_id_map.clear();
+        self.cust_wallet_map.clear();
+        self.merch_state = None;
+        self.merch_channel_map.clear();
+        self.event_map.clear();
         return true;
     }
 
+    fn append_event(&mut self, record: &EventRecord) -> Result<bool, ZkChannelError> {
+        self.event_map
+            .entry(record.key.to_db_key())
+            .or_insert(Vec::new())
+            .push(record.clone());
+        Ok(true)
+    }
+
+    fn get_events(&mut self, key: &EventKey) -> Result<Vec<EventRecord>, ZkChannelError> {
+        match self.event_map.get(&key.to_db_key()) {
+            Some(records) => Ok(records.clone()),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn save_merchant_state(
+        &mut self,
+        channel_state: &ChannelMPCState,
+        merch_state: &MerchantMPCState,
+    ) -> Result<(), ZkChannelError> {
+        check_channel_maps_migrated(merch_state)?;
+        self.merch_state = Some((channel_state.clone(), merch_state.clone()));
+        Ok(())
+    }
+
+    fn load_merchant_state(
+        &mut self,
+    ) -> Result<(ChannelMPCState, MerchantMPCState), ZkChannelError> {
+        match &self.merch_state {
+            Some(s) => Ok(s.clone()),
+            None => Err(ZkChannelError::NotFound(String::from(
+                "could not find merchant state",
+            ))),
+        }
+    }
+
+    fn save_merchant_channel(
+        &mut self,
+        channel_token: &ChannelMPCToken,
+    ) -> Result<String, ZkChannelError> {
+        let channel_id = hex::encode(channel_token.compute_channel_id()?);
+        self.merch_channel_map
+            .insert(channel_id.clone(), channel_token.clone());
+        Ok(channel_id)
+    }
+
+    fn load_merchant_channel(
+        &mut self,
+        channel_id: &String,
+    ) -> Result<ChannelMPCToken, ZkChannelError> {
+        match self.merch_channel_map.get(channel_id) {
+            Some(t) => Ok(t.clone()),
+            None => Err(ZkChannelError::NotFound(format!(
+                "could not find channel: {}",
+                channel_id
+            ))),
+        }
+    }
+
+    fn list_merchant_channels(&mut self) -> Result<Vec<String>, ZkChannelError> {
+        let mut channel_ids: Vec<String> = self.merch_channel_map.keys().cloned().collect();
+        channel_ids.sort();
+        Ok(channel_ids)
+    }
+
+    fn apply_batch(&mut self, batch: &DbBatch) -> Result<bool, ZkChannelError> {
+        for check in batch.checks.iter() {
+            let passed = match check {
+                BatchCheck::NewSessionId(s) => !self.session_state_map.contains_key(s),
+                BatchCheck::NonceNotSpent(n) => !self.spent_lock_map.contains_key(n),
+                BatchCheck::NonceFreeForSession(n, s) => match self.nonce_session_map.get(n) {
+                    Some(t) => t == s,
+                    None => true,
+                },
+                BatchCheck::RevLockNotRevoked(r) => !self.rev_lock_map.contains_key(r),
+            };
+            if !passed {
+                return Ok(false);
+            }
+        }
+        for write in batch.writes.iter() {
+            match write {
+                BatchWrite::SaveSessionState(s, session_state) => {
+                    self.session_state_map
+                        .insert(s.clone(), session_state.clone());
+                }
+                BatchWrite::UpdateNonceMaskMap(n, mask, mask_r) => {
+                    let pay_mask_map = PayMaskMap {
+                        mask: FixedSizeArray32(*mask),
+                        r: FixedSizeArray16(*mask_r),
+                    };
+                    self.nonce_mask_map.insert(n.clone(), pay_mask_map);
+                }
+                BatchWrite::UpdateNonceToSessionId(n, s) => {
+                    self.nonce_session_map.insert(n.clone(), s.clone());
+                }
+                BatchWrite::UpdateSpentMap(n, r) => {
+                    self.spent_lock_map.insert(n.clone(), r.clone());
+                }
+                BatchWrite::UpdateRevLockMap(r, rev_secret) => {
+                    self.rev_lock_map.insert(r.clone(), rev_secret.clone());
+                }
+                BatchWrite::RemoveFromUnlinkSet(n) => {
+                    self.unlink_map.remove(n);
+                }
+                BatchWrite::AddToUnlinkSet(n) => {
+                    self.unlink_map.insert(n.clone());
+                }
+                BatchWrite::UpdateActivateMap(c, s0) => {
+                    self.activate_map.insert(c.clone(), s0.clone());
+                }
+                BatchWrite::UpdateMerchTxidMap(m, e) => {
+                    self.merch_txid_map.insert(m.clone(), e.clone());
+                }
+                BatchWrite::UpdateChannelStatus(e, status) => {
+                    self.channel_status_map.insert(e.clone(), status.clone());
+                }
+            }
+        }
+        Ok(true)
+    }
+
     fn check_dup_nonce_to_session_id(
         &mut self,
         nonce_hex: &String,
@@ -743,18 +1754,34 @@ impl StateDatabase for HashMapDatabase {
         &mut self,
         nonce_hex: &String,
         session_id_hex: &String,
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
         self.nonce_session_map
             .insert(nonce_hex.clone(), session_id_hex.clone());
         Ok(true)
     }
 
+    fn get_session_id_for_nonce(&mut self, nonce_hex: &String) -> Result<String, ZkChannelError> {
+        match self.nonce_session_map.get(nonce_hex) {
+            Some(s) => Ok(s.clone()),
+            None => {
+                return Err(ZkChannelError::NotFound(format!(
+                    "could not find session id for specified nonce: {}",
+                    nonce_hex
+                )))
+            }
+        }
+    }
+
+    fn clear_nonce_to_session_id(&mut self, nonce_hex: &String) -> bool {
+        self.nonce_session_map.remove(nonce_hex).is_some()
+    }
+
     fn update_nonce_mask_map(
         &mut self,
         nonce_hex: &String,
         mask: [u8; 32],
         mask_r: [u8; 16],
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
         let pay_mask_map = PayMaskMap {
             mask: FixedSizeArray32(mask),
             r: FixedSizeArray16(mask_r),
@@ -766,18 +1793,22 @@ impl StateDatabase for HashMapDatabase {
     fn get_mask_map_from_nonce(
         &mut self,
         nonce_hex: &String,
-    ) -> Result<([u8; 32], [u8; 16]), String> {
+    ) -> Result<([u8; 32], [u8; 16]), ZkChannelError> {
         match self.nonce_mask_map.get(nonce_hex) {
             Some(p) => Ok((p.mask.0, p.r.0)),
             None => {
-                return Err(format!(
+                return Err(ZkChannelError::NotFound(format!(
                     "could not find pay mask for specified nonce: {}",
                     nonce_hex
-                ))
+                )))
             }
         }
     }
 
+    fn clear_nonce_mask_map(&mut self, nonce_hex: &String) -> bool {
+        self.nonce_mask_map.remove(nonce_hex).is_some()
+    }
+
     fn update_masked_mpc_inputs(
         &mut self,
         nonce_hex: &String,
@@ -789,27 +1820,184 @@ impl StateDatabase for HashMapDatabase {
         }
     }
 
-    fn get_masked_mpc_inputs(&mut self, nonce_hex: &String) -> Result<MaskedMPCInputs, String> {
+    fn get_masked_mpc_inputs(
+        &mut self,
+        nonce_hex: &String,
+    ) -> Result<MaskedMPCInputs, ZkChannelError> {
         match self.mask_mpc_bytes.get(nonce_hex) {
             Some(m) => Ok(m.clone()),
             None => {
-                return Err(format!(
+                return Err(ZkChannelError::NotFound(format!(
                     "could not find masked mpc inputs for specified nonce: {}",
                     nonce_hex
-                ))
+                )))
+            }
+        }
+    }
+
+    fn clear_masked_mpc_inputs(&mut self, session_id_hex: &String) -> bool {
+        self.mask_mpc_bytes.remove(session_id_hex).is_some()
+    }
+
+    fn update_channel_status(
+        &mut self,
+        escrow_txid_hex: &String,
+        status: &ChannelStatus,
+    ) -> Result<bool, ZkChannelError> {
+        self.channel_status_map
+            .insert(escrow_txid_hex.clone(), status.clone());
+        Ok(true)
+    }
+
+    fn get_channel_status(
+        &mut self,
+        escrow_txid_hex: &String,
+    ) -> Result<ChannelStatus, ZkChannelError> {
+        match self.channel_status_map.get(escrow_txid_hex) {
+            Some(s) => Ok(s.clone()),
+            None => {
+                return Err(ZkChannelError::NotFound(format!(
+                    "could not find channel status for escrow txid: {}",
+                    escrow_txid_hex
+                )))
+            }
+        }
+    }
+
+    fn update_channel_statuses(
+        &mut self,
+        statuses: &Vec<(String, ChannelStatus)>,
+    ) -> Result<bool, ZkChannelError> {
+        for (escrow_txid_hex, status) in statuses.iter() {
+            self.channel_status_map
+                .insert(escrow_txid_hex.clone(), status.clone());
+        }
+        Ok(true)
+    }
+
+    fn update_activate_map(
+        &mut self,
+        channel_id_hex: &String,
+        s0: &State,
+    ) -> Result<bool, ZkChannelError> {
+        self.activate_map.insert(channel_id_hex.clone(), s0.clone());
+        Ok(true)
+    }
+
+    fn get_activate_state(&mut self, channel_id_hex: &String) -> Result<State, ZkChannelError> {
+        match self.activate_map.get(channel_id_hex) {
+            Some(s) => Ok(s.clone()),
+            None => {
+                return Err(ZkChannelError::NotFound(format!(
+                    "could not find initial state for channel id: {}",
+                    channel_id_hex
+                )))
+            }
+        }
+    }
+
+    fn update_close_tx_map(
+        &mut self,
+        escrow_txid_hex: &String,
+        merch_close_tx: &MerchCloseTx,
+    ) -> Result<bool, ZkChannelError> {
+        self.close_tx_map
+            .insert(escrow_txid_hex.clone(), merch_close_tx.clone());
+        Ok(true)
+    }
+
+    fn get_merch_close_tx(
+        &mut self,
+        escrow_txid_hex: &String,
+    ) -> Result<MerchCloseTx, ZkChannelError> {
+        match self.close_tx_map.get(escrow_txid_hex) {
+            Some(m) => Ok(m.clone()),
+            None => {
+                return Err(ZkChannelError::NotFound(format!(
+                    "could not find merch_close_tx for escrow txid: {}",
+                    escrow_txid_hex
+                )))
+            }
+        }
+    }
+
+    fn update_merch_txid_map(
+        &mut self,
+        merch_txid_hex: &String,
+        escrow_txid_hex: &String,
+    ) -> Result<bool, ZkChannelError> {
+        self.merch_txid_map
+            .insert(merch_txid_hex.clone(), escrow_txid_hex.clone());
+        Ok(true)
+    }
+
+    fn get_escrow_txid(&mut self, merch_txid_hex: &String) -> Result<String, ZkChannelError> {
+        match self.merch_txid_map.get(merch_txid_hex) {
+            Some(s) => Ok(s.clone()),
+            None => {
+                return Err(ZkChannelError::NotFound(format!(
+                    "could not find escrow txid for merch txid: {}",
+                    merch_txid_hex
+                )))
+            }
+        }
+    }
+
+    fn update_refund_id_map(
+        &mut self,
+        refund_id_hex: &String,
+        nonce_hex: &String,
+    ) -> Result<bool, ZkChannelError> {
+        if self.refund_id_map.contains_key(refund_id_hex) {
+            return Ok(false);
+        }
+        self.refund_id_map
+            .insert(refund_id_hex.clone(), nonce_hex.clone());
+        Ok(true)
+    }
+
+    fn get_nonce_for_refund_id(
+        &mut self,
+        refund_id_hex: &String,
+    ) -> Result<String, ZkChannelError> {
+        match self.refund_id_map.get(refund_id_hex) {
+            Some(s) => Ok(s.clone()),
+            None => {
+                return Err(ZkChannelError::NotFound(format!(
+                    "could not find refund id: {}",
+                    refund_id_hex
+                )))
             }
         }
     }
 }
 
+impl WalletDatabase for HashMapDatabase {
+    fn save_cust_wallet(&mut self, wallet: &CustomerWallet) -> Result<(), ZkChannelError> {
+        self.cust_wallet_map
+            .insert(wallet.name.clone(), wallet.clone());
+        Ok(())
+    }
+
+    fn load_cust_wallet(&mut self, name: &String) -> Result<CustomerWallet, ZkChannelError> {
+        match self.cust_wallet_map.get(name) {
+            Some(w) => Ok(w.clone()),
+            None => Err(ZkChannelError::NotFound(format!(
+                "could not find wallet: {}",
+                name
+            ))),
+        }
+    }
+}
+
 pub fn get_file_from_db(
     conn: &mut redis::Connection,
     key: &String,
     field_name: &String,
-) -> Result<String, String> {
+) -> Result<String, ZkChannelError> {
     match conn.hget::<String, String, String>(key.clone(), field_name.clone()) {
         Ok(s) => Ok(s),
-        Err(e) => return Err(e.to_string()),
+        Err(e) => return Err(lookup_error("get_file_from_db", e)),
     }
 }
 
@@ -818,22 +2006,227 @@ pub fn store_file_in_db(
     key: &String,
     field_name: &String,
     json_blob: &String,
-) -> Result<bool, String> {
+) -> Result<bool, ZkChannelError> {
     match conn.hset::<String, String, String, i32>(
         key.clone(),
         field_name.clone(),
         json_blob.clone(),
     ) {
         Ok(s) => Ok(s != 0),
-        Err(e) => return Err(e.to_string()),
+        Err(e) => return Err(e.into()),
+    }
+}
+
+// the merchant state is stored in a single field
+pub const MERCH_STATE_FIELD: &str = "merch_state";
+
+// stored records are json objects of versioned envelopes (see persist), so that the states of a
+// channel are always saved together and each of them is migrated on its own when it is loaded
+fn get_envelope<'a>(
+    record: &'a HashMap<String, String>,
+    kind: &str,
+) -> Result<&'a String, ZkChannelError> {
+    match record.get(kind) {
+        Some(s) => Ok(s),
+        None => Err(ZkChannelError::SerializationError(format!(
+            "stored record does not have a {}",
+            kind
+        ))),
+    }
+}
+
+pub fn encode_customer_channel(channel: &CustomerChannel) -> Result<String, ZkChannelError> {
+    let mut record = HashMap::new();
+    record.insert(
+        "channel_state",
+        persist::to_json_string(&channel.channel_state)?,
+    );
+    record.insert(
+        "channel_token",
+        persist::to_json_string(&channel.channel_token)?,
+    );
+    record.insert("cust_state", persist::to_json_string(&channel.cust_state)?);
+    Ok(serde_json::to_string(&record)?)
+}
+
+pub fn decode_customer_channel(record: &str) -> Result<CustomerChannel, ZkChannelError> {
+    let record: HashMap<String, String> = serde_json::from_str(record)?;
+    Ok(CustomerChannel {
+        channel_state: persist::from_json_str(get_envelope(&record, "channel_state")?)?,
+        channel_token: persist::from_json_str(get_envelope(&record, "channel_token")?)?,
+        cust_state: persist::from_json_str(get_envelope(&record, "cust_state")?)?,
+    })
+}
+
+#[derive(Serialize, Deserialize)]
+struct WalletRecord {
+    name: String,
+    // channel id => record of the channel (see encode_customer_channel())
+    channels: HashMap<String, String>,
+}
+
+pub fn encode_cust_wallet(wallet: &CustomerWallet) -> Result<String, ZkChannelError> {
+    let mut channels = HashMap::new();
+    for (channel_id, channel) in wallet.get_channels().iter() {
+        channels.insert(channel_id.clone(), encode_customer_channel(channel)?);
+    }
+    Ok(serde_json::to_string(&WalletRecord {
+        name: wallet.name.clone(),
+        channels,
+    })?)
+}
+
+// wallets saved by older versions are the raw json of CustomerWallet
+pub fn decode_cust_wallet(record: &str) -> Result<CustomerWallet, ZkChannelError> {
+    let record: WalletRecord = match serde_json::from_str(record) {
+        Ok(r) => r,
+        Err(_) => return Ok(serde_json::from_str(record)?),
+    };
+    let mut wallet = CustomerWallet::new(record.name);
+    for (channel_id, ser_channel) in record.channels.iter() {
+        let channel = decode_customer_channel(ser_channel)?;
+        let id = wallet.add_channel(
+            channel.channel_state,
+            channel.channel_token,
+            channel.cust_state,
+        )?;
+        if id != *channel_id {
+            return Err(ZkChannelError::SerializationError(format!(
+                "stored channel {} has channel id {}",
+                channel_id, id
+            )));
+        }
+    }
+    Ok(wallet)
+}
+
+// a merchant state from schema version 1 is only stored once its per-channel maps are in the db,
+// since the maps are not part of the current schema and would be lost
+fn check_channel_maps_migrated(merch_state: &MerchantMPCState) -> Result<(), ZkChannelError> {
+    if merch_state.has_legacy_channel_maps() {
+        return Err(ZkChannelError::InvalidInput(String::from(
+            "merchant state has per-channel maps that are not migrated to the db yet",
+        )));
+    }
+    Ok(())
+}
+
+pub fn encode_merchant_state(
+    channel_state: &ChannelMPCState,
+    merch_state: &MerchantMPCState,
+) -> Result<String, ZkChannelError> {
+    check_channel_maps_migrated(merch_state)?;
+    let mut record = HashMap::new();
+    record.insert("channel_state", persist::to_json_string(channel_state)?);
+    record.insert("merch_state", persist::to_json_string(merch_state)?);
+    Ok(serde_json::to_string(&record)?)
+}
+
+pub fn decode_merchant_state(
+    record: &str,
+) -> Result<(ChannelMPCState, MerchantMPCState), ZkChannelError> {
+    let record: HashMap<String, String> = serde_json::from_str(record)?;
+    Ok((
+        persist::from_json_str(get_envelope(&record, "channel_state")?)?,
+        persist::from_json_str(get_envelope(&record, "merch_state")?)?,
+    ))
+}
+
+// records are sealed under the name of their redis key and field
+pub fn get_record_name(key: &String, field_name: &String) -> String {
+    format!("{}:{}", key, field_name)
+}
+
+///
+/// store_encrypted_file_in_db() - stores a (json) blob in the field of a redis hash. The blob is
+/// sealed with the storage key if one is given
+/// output: true if the field is new
+///
+pub fn store_encrypted_file_in_db(
+    conn: &mut redis::Connection,
+    key: &String,
+    field_name: &String,
+    json_blob: &String,
+    storage_key: Option<&StorageKey>,
+) -> Result<bool, ZkChannelError> {
+    let record = seal_record(storage_key, &get_record_name(key, field_name), json_blob)?;
+    store_file_in_db(conn, key, field_name, &record)
+}
+
+///
+/// get_encrypted_file_from_db() - loads a blob that was stored with store_encrypted_file_in_db()
+/// (or a plaintext blob that was stored before a storage key was set)
+/// output: blob
+///
+pub fn get_encrypted_file_from_db(
+    conn: &mut redis::Connection,
+    key: &String,
+    field_name: &String,
+    storage_key: Option<&StorageKey>,
+) -> Result<String, ZkChannelError> {
+    let record = get_file_from_db(conn, key, field_name)?;
+    open_record(storage_key, &get_record_name(key, field_name), &record)
+}
+
+///
+/// get_legacy_file_from_db() - loads a blob like get_encrypted_file_from_db(), but also accepts a
+/// plaintext blob that was stored before a storage key was set (only for migrations)
+/// output: blob
+///
+pub fn get_legacy_file_from_db(
+    conn: &mut redis::Connection,
+    key: &String,
+    field_name: &String,
+    storage_key: Option<&StorageKey>,
+) -> Result<String, ZkChannelError> {
+    let record = get_file_from_db(conn, key, field_name)?;
+    open_legacy_record(storage_key, &get_record_name(key, field_name), &record)
+}
+
+///
+/// reencrypt_files_in_db() - re-encrypts all fields of the given redis hashes with the new storage key
+/// (in a single transaction)
+/// output: number of re-encrypted fields
+///
+pub fn reencrypt_files_in_db(
+    conn: &mut redis::Connection,
+    keys: &Vec<String>,
+    old_key: Option<&StorageKey>,
+    new_key: &StorageKey,
+) -> Result<usize, ZkChannelError> {
+    // every record is opened before any is written, so a wrong old key leaves the hashes unchanged
+    let mut pipe = redis::pipe();
+    pipe.atomic();
+    let mut count = 0;
+    for key in keys.iter() {
+        let records: HashMap<String, String> = conn.hgetall(key.clone())?;
+        let mut resealed = Vec::new();
+        for (field_name, record) in records.iter() {
+            let name = get_record_name(key, field_name);
+            resealed.push((
+                field_name.clone(),
+                reseal_record(old_key, new_key, &name, record)?,
+            ));
+        }
+        if !resealed.is_empty() {
+            count += resealed.len();
+            pipe.hset_multiple(key.clone(), &resealed).ignore();
+        }
+    }
+    if count > 0 {
+        pipe.query::<()>(conn)?;
     }
+    Ok(count)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use event_log::{EventParty, ProtocolEvent};
+    use file_database::FileDatabase;
     use rand::RngCore;
-    use util::hash_to_slice;
+    use std::{env, fs};
+    use util::{get_current_time, hash_to_slice};
 
     #[test]
     fn test_redis_unlink_set() {
@@ -937,6 +2330,53 @@ mod tests {
         assert!(bad_rev_sec.is_err());
     }
 
+    #[test]
+    fn test_redis_encrypted_rev_lock_map() {
+        let db_url = "redis://127.0.0.1/".to_string();
+        let mut db = RedisDatabase::new("enctest", db_url.clone()).unwrap();
+        db.clear_state();
+
+        // a plaintext record written before the storage key was set
+        let rev_sec_hex1 = hex::encode([5u8; 32]);
+        let rev_lock_hex1 = hex::encode(hash_to_slice(&[5u8; 32].to_vec()));
+        db.update_rev_lock_map(&rev_lock_hex1, &rev_sec_hex1)
+            .unwrap();
+
+        let old_key = StorageKey::from_bytes([0x11; 32]);
+        db.set_storage_key(old_key.clone());
+        let rev_sec_hex2 = hex::encode([6u8; 32]);
+        let rev_lock_hex2 = hex::encode(hash_to_slice(&[6u8; 32].to_vec()));
+        db.update_rev_lock_map(&rev_lock_hex2, &rev_sec_hex2)
+            .unwrap();
+
+        // the rev secret is not stored in the clear
+        let record: String = db
+            .conn
+            .hget(db.rev_lock_map_key.clone(), rev_lock_hex2.clone())
+            .unwrap();
+        assert!(!record.contains(&rev_sec_hex2));
+        assert!(db.check_rev_lock_map(&rev_lock_hex2));
+        assert_eq!(db.get_rev_secret(&rev_lock_hex2).unwrap(), rev_sec_hex2);
+        // the plaintext record is not trusted once a storage key is set
+        assert!(db.get_rev_secret(&rev_lock_hex1).is_err());
+
+        // both records are (re-)encrypted with the new key
+        let new_key = StorageKey::from_bytes([0x22; 32]);
+        assert_eq!(db.rotate_storage_key(new_key.clone()).unwrap(), 2);
+        assert_eq!(db.get_rev_secret(&rev_lock_hex1).unwrap(), rev_sec_hex1);
+        assert_eq!(db.get_rev_secret(&rev_lock_hex2).unwrap(), rev_sec_hex2);
+
+        let mut other_db = RedisDatabase::new("enctest", db_url).unwrap();
+        assert!(other_db.get_rev_secret(&rev_lock_hex1).is_err());
+        other_db.set_storage_key(old_key);
+        assert!(other_db.get_rev_secret(&rev_lock_hex2).is_err());
+        other_db.set_storage_key(new_key);
+        assert_eq!(
+            other_db.get_rev_secret(&rev_lock_hex2).unwrap(),
+            rev_sec_hex2
+        );
+    }
+
     #[test]
     fn test_redis_nonce_mask_map() {
         let db_url = "redis://127.0.0.1/".to_string();
@@ -999,6 +2439,8 @@ mod tests {
             rev_lock_com: FixedSizeArray32(rev_lock_com),
             amount: amount,
             status: PaymentStatus::Prepare,
+            created_at: get_current_time(),
+            masks_released: false,
         };
 
         let result = db.save_new_session_state(&session_id, &session_state);
@@ -1021,6 +2463,10 @@ mod tests {
         let bad_session_id = hex::encode([2u8; 16]);
         let result = db.check_session_id(&bad_session_id).unwrap();
         assert!(!result);
+
+        assert_eq!(db.get_session_ids().unwrap(), vec![session_id.clone()]);
+        assert!(db.clear_session_state(&session_id));
+        assert!(db.get_session_ids().unwrap().is_empty());
     }
 
     #[test]
@@ -1049,4 +2495,320 @@ mod tests {
         let result = db.check_dup_nonce_to_session_id(&nonce, &session_id2);
         assert!(result); // should be true, there's a different existing session id with same nonce
     }
+
+    #[test]
+    fn test_redis_channel_status_map() {
+        let db_url = "redis://127.0.0.1/".to_string();
+        let mut db = RedisDatabase::new("test", db_url).unwrap();
+        db.clear_state();
+
+        let escrow_txid = hex::encode([6u8; 32]);
+        let merch_txid = hex::encode([7u8; 32]);
+
+        assert!(db.get_channel_status(&escrow_txid).is_err());
+
+        db.update_channel_status(&escrow_txid, &ChannelStatus::PendingOpen)
+            .unwrap();
+        db.update_channel_status(&escrow_txid, &ChannelStatus::Open)
+            .unwrap();
+        assert_eq!(
+            db.get_channel_status(&escrow_txid).unwrap(),
+            ChannelStatus::Open
+        );
+
+        // both statuses are written at once
+        let new_escrow_txid = hex::encode([8u8; 32]);
+        db.update_channel_statuses(&vec![
+            (escrow_txid.clone(), ChannelStatus::Spliced),
+            (new_escrow_txid.clone(), ChannelStatus::PendingOpen),
+        ])
+        .unwrap();
+        assert_eq!(
+            db.get_channel_status(&escrow_txid).unwrap(),
+            ChannelStatus::Spliced
+        );
+        assert_eq!(
+            db.get_channel_status(&new_escrow_txid).unwrap(),
+            ChannelStatus::PendingOpen
+        );
+
+        db.update_merch_txid_map(&merch_txid, &escrow_txid).unwrap();
+        assert_eq!(db.get_escrow_txid(&merch_txid).unwrap(), escrow_txid);
+        assert!(db.get_escrow_txid(&escrow_txid).is_err());
+
+        db.clear_state();
+        assert!(db.get_channel_status(&escrow_txid).is_err());
+    }
+
+    // stores a customer wallet and the merchant state and loads them back
+    fn check_channel_storage<D: StateDatabase + WalletDatabase>(db: &mut D) {
+        let rng = &mut rand::thread_rng();
+        let mut channel_state = mpc::ChannelMPCState::new(
+            String::from("Channel A -> B"),
+            1487,
+            546,
+            546,
+            1000,
+            false,
+            mpc::BitcoinNetworkType::Testnet,
+        );
+        let merch_state = mpc::init_merchant(rng, "".to_string(), &mut channel_state, "Bob");
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+        let (mut channel_token, cust_state) = mpc::init_customer(
+            rng,
+            &channel_state,
+            &merch_state.pk_m,
+            10000,
+            10000,
+            &tx_fee_info,
+            "Alice",
+        );
+        let mut escrow_txid = [0u8; 32];
+        rng.fill_bytes(&mut escrow_txid);
+        channel_token.escrow_txid = FixedSizeArray32(escrow_txid);
+        let name = String::from("Alice");
+        let mut wallet = CustomerWallet::new(name.clone());
+        wallet
+            .add_channel(channel_state.clone(), channel_token.clone(), cust_state)
+            .unwrap();
+
+        assert!(db.load_cust_wallet(&name).is_err());
+        db.save_cust_wallet(&wallet).unwrap();
+        assert_eq!(db.load_cust_wallet(&name).unwrap(), wallet);
+        // the channels of a wallet are stored in versioned envelopes, but wallets that older
+        // versions stored as plain json are still read
+        assert_eq!(
+            decode_cust_wallet(&serde_json::to_string(&wallet).unwrap()).unwrap(),
+            wallet
+        );
+
+        assert!(db.load_merchant_state().is_err());
+        db.save_merchant_state(&channel_state, &merch_state)
+            .unwrap();
+        assert_eq!(
+            db.load_merchant_state().unwrap(),
+            (channel_state, merch_state)
+        );
+        let channel_id = db.save_merchant_channel(&channel_token).unwrap();
+        assert_eq!(
+            channel_id,
+            hex::encode(channel_token.compute_channel_id().unwrap())
+        );
+        assert_eq!(
+            db.load_merchant_channel(&channel_id).unwrap(),
+            channel_token
+        );
+        assert_eq!(db.list_merchant_channels().unwrap(), vec![channel_id]);
+
+        assert!(db.clear_state());
+        assert!(db.load_cust_wallet(&name).is_err());
+        assert!(db.load_merchant_state().is_err());
+    }
+
+    #[test]
+    fn test_channel_storage() {
+        let mut db = RedisDatabase::new("storetest", "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
+        db.set_storage_key(StorageKey::from_bytes([0x44; 32]));
+        check_channel_storage(&mut db);
+
+        let mut db = HashMapDatabase::new("", "".to_string()).unwrap();
+        check_channel_storage(&mut db);
+
+        let path = env::temp_dir().join(format!("zkchannels-store-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let mut db = FileDatabase::open("storetest", &path).unwrap();
+        check_channel_storage(&mut db);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_redis_migrates_legacy_merchant_state() {
+        let rng = &mut rand::thread_rng();
+        let mut db = RedisDatabase::new("legacytest", "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
+        let mut channel_state = mpc::ChannelMPCState::new(
+            String::from("Channel A -> B"),
+            1487,
+            546,
+            546,
+            1000,
+            false,
+            mpc::BitcoinNetworkType::Testnet,
+        );
+        let merch_state = mpc::init_merchant(rng, "".to_string(), &mut channel_state, "Bob");
+
+        // merchant states from schema version 1 kept the per-channel maps themselves
+        let escrow_txid_hex = hex::encode([9u8; 32]);
+        let mut legacy = serde_json::to_value(&merch_state).unwrap();
+        let mut statuses = HashMap::new();
+        statuses.insert(escrow_txid_hex.clone(), ChannelStatus::Open);
+        let mut close_txs = HashMap::new();
+        close_txs.insert(
+            escrow_txid_hex.clone(),
+            serde_json::from_str::<serde_json::Value>(&format!(
+                "{{\"cust_pk\": \"{}\", \"bc\": 10000, \"bm\": 10000, \"fee_mc\": 1000, \
+                 \"cust_sig\": \"{}\", \"self_delay\": \"05cf\"}}",
+                hex::encode([2u8; 33]),
+                hex::encode([3u8; 72])
+            ))
+            .unwrap(),
+        );
+        legacy["channel_status_map"] = serde_json::to_value(&statuses).unwrap();
+        let merch_txid_hex = hex::encode([8u8; 32]);
+        let s0 = State {
+            nonce: FixedSizeArray16([1u8; 16]),
+            rev_lock: FixedSizeArray32([2u8; 32]),
+            bc: 10000,
+            bm: 10000,
+            escrow_txid: FixedSizeArray32([9u8; 32]),
+            escrow_prevout: FixedSizeArray32([0u8; 32]),
+            merch_txid: FixedSizeArray32([8u8; 32]),
+            merch_prevout: FixedSizeArray32([0u8; 32]),
+            min_fee: 0,
+            max_fee: 10000,
+            fee_mc: 1000,
+        };
+        let mut activations = HashMap::new();
+        activations.insert(hex::encode([4u8; 32]), s0);
+        legacy["activate_map"] = serde_json::to_value(&activations).unwrap();
+        legacy["close_tx"] = serde_json::to_value(&close_txs).unwrap();
+        let legacy_state: MerchantMPCState = persist::from_json_str(&legacy.to_string()).unwrap();
+        assert!(legacy_state.has_legacy_channel_maps());
+        // the maps are never dropped silently
+        assert!(db
+            .save_merchant_state(&channel_state, &legacy_state)
+            .is_err());
+
+        let mut record = HashMap::new();
+        record.insert(
+            "channel_state",
+            persist::to_json_string(&channel_state).unwrap(),
+        );
+        record.insert("merch_state", legacy.to_string());
+        store_file_in_db(
+            &mut db.conn,
+            &db.merch_state_key,
+            &MERCH_STATE_FIELD.to_string(),
+            &serde_json::to_string(&record).unwrap(),
+        )
+        .unwrap();
+
+        // loading moves the maps to the db and stores the migrated state
+        assert_eq!(
+            db.load_merchant_state().unwrap(),
+            (channel_state.clone(), merch_state.clone())
+        );
+        assert_eq!(
+            db.get_channel_status(&escrow_txid_hex).unwrap(),
+            ChannelStatus::Open
+        );
+        assert!(db.get_merch_close_tx(&escrow_txid_hex).is_ok());
+        assert_eq!(db.get_activate_state(&hex::encode([4u8; 32])).unwrap(), s0);
+        // a cust-close-tx that spends from the merch-close-tx can be traced back to the channel
+        assert_eq!(
+            db.get_escrow_txid(&merch_txid_hex).unwrap(),
+            escrow_txid_hex
+        );
+        assert_eq!(
+            db.load_merchant_state().unwrap(),
+            (channel_state, merch_state)
+        );
+        db.clear_state();
+    }
+
+    #[test]
+    fn test_redis_event_log() {
+        let db_url = "redis://127.0.0.1/".to_string();
+        let mut db = RedisDatabase::new("eventtest", db_url).unwrap();
+        db.clear_state();
+
+        let key = EventKey::escrow(&[7u8; 32]);
+        let activated =
+            EventRecord::new(key.clone(), EventParty::Merchant, ProtocolEvent::Activated);
+        let closed = EventRecord::new(
+            key.clone(),
+            EventParty::Merchant,
+            ProtocolEvent::Closed {
+                kind: String::from("mutual"),
+                txid: hex::encode([8u8; 32]),
+            },
+        );
+        assert!(db.append_event(&activated).unwrap());
+        assert!(db.append_event(&closed).unwrap());
+        assert_eq!(db.get_events(&key).unwrap(), vec![activated, closed]);
+        assert!(db
+            .get_events(&EventKey::session(&[7u8; 16]))
+            .unwrap()
+            .is_empty());
+
+        assert!(db.clear_state());
+        assert!(db.get_events(&key).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_redis_apply_batch() {
+        let db_url = "redis://127.0.0.1/".to_string();
+        let mut db = RedisDatabase::new("batchtest", db_url).unwrap();
+        db.clear_state();
+        db.set_storage_key(StorageKey::from_bytes([0x44; 32]));
+
+        let nonce = hex::encode([2u8; 16]);
+        let rev_lock = hex::encode([4u8; 32]);
+        let rev_secret = hex::encode([6u8; 32]);
+        db.update_unlink_set(&nonce).unwrap();
+
+        let mut batch = DbBatch::new();
+        batch.check(BatchCheck::NonceNotSpent(nonce.clone()));
+        batch.check(BatchCheck::RevLockNotRevoked(rev_lock.clone()));
+        batch.write(BatchWrite::UpdateSpentMap(nonce.clone(), rev_lock.clone()));
+        batch.write(BatchWrite::UpdateRevLockMap(
+            rev_lock.clone(),
+            rev_secret.clone(),
+        ));
+        batch.write(BatchWrite::RemoveFromUnlinkSet(nonce.clone()));
+        assert!(db.apply_batch(&batch).unwrap());
+        assert!(db.check_spent_map(&nonce));
+        assert_eq!(db.get_rev_secret(&rev_lock).unwrap(), rev_secret);
+        assert!(!db.is_member_unlink_set(&nonce));
+
+        // the nonce can only be spent once: a failed check leaves the db unchanged
+        let other_rev_lock = hex::encode([8u8; 32]);
+        let mut batch = DbBatch::new();
+        batch.check(BatchCheck::RevLockNotRevoked(other_rev_lock.clone()));
+        batch.check(BatchCheck::NonceNotSpent(nonce.clone()));
+        batch.write(BatchWrite::UpdateRevLockMap(
+            other_rev_lock.clone(),
+            rev_secret.clone(),
+        ));
+        assert!(!db.apply_batch(&batch).unwrap());
+        assert!(!db.check_rev_lock_map(&other_rev_lock));
+
+        // a nonce can be mapped to one session id only
+        let mut batch = DbBatch::new();
+        batch.check(BatchCheck::NonceFreeForSession(
+            other_rev_lock.clone(),
+            "session1".to_string(),
+        ));
+        batch.write(BatchWrite::UpdateNonceToSessionId(
+            other_rev_lock.clone(),
+            "session1".to_string(),
+        ));
+        assert!(db.apply_batch(&batch).unwrap());
+        assert!(db.apply_batch(&batch).unwrap());
+        batch.checks = vec![BatchCheck::NonceFreeForSession(
+            other_rev_lock.clone(),
+            "session2".to_string(),
+        )];
+        assert!(!db.apply_batch(&batch).unwrap());
+        db.clear_state();
+    }
 }
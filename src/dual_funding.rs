@@ -0,0 +1,502 @@
+use super::*;
+use errors::ZkChannelError;
+use secp256k1::{PublicKey, SecretKey};
+use sha2::{Digest, Sha256};
+use util::compute_hash160;
+use zkchan_tx::fixed_size_array::FixedSizeArray32;
+
+pub const TX_VERSION: [u8; 4] = [0x02, 0x00, 0x00, 0x00];
+pub const TX_SEQUENCE: [u8; 4] = [0xff, 0xff, 0xff, 0xff];
+pub const TX_LOCKTIME: [u8; 4] = [0x00, 0x00, 0x00, 0x00];
+pub const SIGHASH_ALL: u8 = 0x01;
+
+pub fn double_sha256(input: &[u8]) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&Sha256::digest(&Sha256::digest(input)));
+    hash
+}
+
+// hash of the outpoint at index 0 of the given tx (the escrow output)
+pub fn compute_escrow_prevout(txid_be: &[u8; 32]) -> [u8; 32] {
+    let mut outpoint = txid_be.to_vec();
+    outpoint.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+    double_sha256(&outpoint)
+}
+
+// 2-of-2 multisig of the escrow (merchant key first)
+pub fn escrow_script(cust_pk: &PublicKey, merch_pk: &PublicKey) -> Vec<u8> {
+    let mut script = vec![0x52, 0x21];
+    script.extend_from_slice(&merch_pk.serialize());
+    script.push(0x21);
+    script.extend_from_slice(&cust_pk.serialize());
+    script.extend_from_slice(&[0x52, 0xae]);
+    script
+}
+
+// P2WSH output paying to the escrow script
+pub fn serialize_escrow_output(
+    escrow_sats: i64,
+    cust_pk: &PublicKey,
+    merch_pk: &PublicKey,
+) -> Vec<u8> {
+    let mut output = escrow_sats.to_le_bytes().to_vec();
+    output.extend_from_slice(&[0x22, 0x00, 0x20]);
+    output.extend_from_slice(&Sha256::digest(&escrow_script(cust_pk, merch_pk)));
+    output
+}
+
+///
+/// compute_sighash() - BIP143 (SIGHASH_ALL) digest of a segwit input
+/// output: sighash
+///
+pub fn compute_sighash(
+    outpoints: &Vec<Vec<u8>>,
+    outpoint: &Vec<u8>,
+    script_code: &Vec<u8>,
+    input_sats: i64,
+    outputs: &Vec<u8>,
+) -> [u8; 32] {
+    let mut sequences = Vec::new();
+    for _ in outpoints.iter() {
+        sequences.extend_from_slice(&TX_SEQUENCE);
+    }
+    let mut preimage = TX_VERSION.to_vec();
+    preimage.extend_from_slice(&double_sha256(&outpoints.concat()));
+    preimage.extend_from_slice(&double_sha256(&sequences));
+    preimage.extend_from_slice(outpoint);
+    preimage.extend_from_slice(script_code);
+    preimage.extend_from_slice(&input_sats.to_le_bytes());
+    preimage.extend_from_slice(&TX_SEQUENCE);
+    preimage.extend_from_slice(&double_sha256(outputs));
+    preimage.extend_from_slice(&TX_LOCKTIME);
+    preimage.extend_from_slice(&(SIGHASH_ALL as u32).to_le_bytes());
+    double_sha256(&preimage)
+}
+
+///
+/// sign_sighash() - signs the sighash with sk, which has to be the secret key of pk
+/// output: DER-encoded signature followed by the sighash flag
+///
+pub fn sign_sighash(
+    sighash: &[u8; 32],
+    sk: &[u8; 32],
+    pk: &PublicKey,
+) -> Result<Vec<u8>, ZkChannelError> {
+    let secp = secp256k1::Secp256k1::new();
+    let sk = handle_error_util!(SecretKey::from_slice(sk), ZkChannelError::InvalidInput);
+    if PublicKey::from_secret_key(&secp, &sk) != *pk {
+        return Err(ZkChannelError::InvalidInput(String::from(
+            "secret key does not match the public key of the input",
+        )));
+    }
+    let msg = secp256k1::Message::from_slice(sighash).unwrap();
+    let mut sig = secp.sign(&msg, &sk).serialize_der().to_vec();
+    sig.push(SIGHASH_ALL);
+    Ok(sig)
+}
+
+pub fn verify_sighash(sighash: &[u8; 32], sig: &Vec<u8>, pk: &PublicKey) -> bool {
+    match sig.split_last() {
+        Some((&SIGHASH_ALL, der)) => match secp256k1::Signature::from_der(der) {
+            Ok(s) => {
+                let msg = secp256k1::Message::from_slice(sighash).unwrap();
+                let secp = secp256k1::Secp256k1::verification_only();
+                secp.verify(&msg, &s, pk).is_ok()
+            }
+            Err(_) => false,
+        },
+        _ => false,
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum FundingParty {
+    Customer,
+    Merchant,
+}
+
+///
+/// EscrowFundingInput - a P2SH-P2WPKH outpoint spent into the escrow-tx.
+/// The txid is little-endian (as shown by block explorers) like the other escrow builders.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EscrowFundingInput {
+    pub txid_le: FixedSizeArray32,
+    pub index: u32,
+    pub input_sats: i64,
+    pub pk: PublicKey,
+}
+
+impl EscrowFundingInput {
+    pub fn serialize_outpoint(&self) -> Vec<u8> {
+        let mut txid_be = self.txid_le.0.clone();
+        txid_be.reverse();
+        let mut outpoint = txid_be.to_vec();
+        outpoint.extend_from_slice(&self.index.to_le_bytes());
+        outpoint
+    }
+
+    fn redeem_script(&self) -> Vec<u8> {
+        let mut script = vec![0x00, 0x14];
+        script.extend_from_slice(&compute_hash160(&self.pk.serialize().to_vec()));
+        script
+    }
+
+    pub fn script_code(&self) -> Vec<u8> {
+        let mut script = vec![0x19, 0x76, 0xa9, 0x14];
+        script.extend_from_slice(&compute_hash160(&self.pk.serialize().to_vec()));
+        script.extend_from_slice(&[0x88, 0xac]);
+        script
+    }
+
+    // outpoint, scriptSig (the P2WPKH redeem script) and sequence
+    pub fn serialize_input(&self) -> Vec<u8> {
+        let redeem_script = self.redeem_script();
+        let mut input = self.serialize_outpoint();
+        input.push(redeem_script.len() as u8 + 1);
+        input.push(redeem_script.len() as u8);
+        input.extend(redeem_script);
+        input.extend_from_slice(&TX_SEQUENCE);
+        input
+    }
+
+    pub fn serialize_witness(&self, sig: &Vec<u8>) -> Vec<u8> {
+        let pk = self.pk.serialize();
+        let mut witness = vec![0x02, sig.len() as u8];
+        witness.extend_from_slice(sig);
+        witness.push(pk.len() as u8);
+        witness.extend_from_slice(&pk);
+        witness
+    }
+}
+
+///
+/// EscrowContribution - the input, escrow amount and change output of one party.
+/// Whatever is left of the input after the escrow amount and change is that party's share of the fee.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EscrowContribution {
+    pub input: EscrowFundingInput,
+    pub escrow_sats: i64,
+    pub change_sats: i64,
+    pub change_pk: Option<PublicKey>,
+}
+
+impl EscrowContribution {
+    pub fn get_fee(&self) -> i64 {
+        self.input.input_sats - self.escrow_sats - self.change_sats
+    }
+
+    pub fn validate(&self, party: FundingParty) -> Result<(), ZkChannelError> {
+        if self.escrow_sats <= 0 || self.change_sats < 0 {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "{:?} contribution has invalid escrow/change amount: {}/{}",
+                party, self.escrow_sats, self.change_sats
+            )));
+        }
+        if self.get_fee() < 0 {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "{:?} contribution spends more than its input: {} < {}",
+                party,
+                self.input.input_sats,
+                self.escrow_sats + self.change_sats
+            )));
+        }
+        if self.change_sats > 0 && self.change_pk.is_none() {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "{:?} contribution has change but no change pk",
+                party
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn serialize_change_output(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        if let Some(pk) = self.change_pk {
+            if self.change_sats > 0 {
+                output.extend_from_slice(&self.change_sats.to_le_bytes());
+                output.extend_from_slice(&[0x16, 0x00, 0x14]);
+                output.extend_from_slice(&compute_hash160(&pk.serialize().to_vec()));
+            }
+        }
+        output
+    }
+}
+
+///
+/// DualFundedEscrowTx - escrow-tx funded by both parties: one input per party (customer first),
+/// the 2-of-2 escrow output followed by the change outputs of the customer and merchant.
+/// Each party signs only its own input, so the tx is complete once both partial signatures are known.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DualFundedEscrowTx {
+    pub cust_pk: PublicKey,
+    pub merch_pk: PublicKey,
+    pub cust: EscrowContribution,
+    pub merch: EscrowContribution,
+}
+
+impl DualFundedEscrowTx {
+    pub fn new(
+        cust_pk: PublicKey,
+        merch_pk: PublicKey,
+        cust: EscrowContribution,
+        merch: EscrowContribution,
+    ) -> Result<Self, ZkChannelError> {
+        cust.validate(FundingParty::Customer)?;
+        merch.validate(FundingParty::Merchant)?;
+        if cust.input.serialize_outpoint() == merch.input.serialize_outpoint() {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "customer and merchant spend the same outpoint",
+            )));
+        }
+        Ok(DualFundedEscrowTx {
+            cust_pk,
+            merch_pk,
+            cust,
+            merch,
+        })
+    }
+
+    pub fn get_contribution(&self, party: FundingParty) -> &EscrowContribution {
+        match party {
+            FundingParty::Customer => &self.cust,
+            FundingParty::Merchant => &self.merch,
+        }
+    }
+
+    pub fn get_escrow_sats(&self) -> i64 {
+        self.cust.escrow_sats + self.merch.escrow_sats
+    }
+
+    fn serialize_outputs(&self) -> Vec<u8> {
+        let mut outputs = Vec::new();
+        outputs.push(self.get_output_count());
+        outputs.extend(serialize_escrow_output(
+            self.get_escrow_sats(),
+            &self.cust_pk,
+            &self.merch_pk,
+        ));
+        outputs.extend(self.cust.serialize_change_output());
+        outputs.extend(self.merch.serialize_change_output());
+        outputs
+    }
+
+    fn get_output_count(&self) -> u8 {
+        1 + [&self.cust, &self.merch]
+            .iter()
+            .filter(|c| !c.serialize_change_output().is_empty())
+            .count() as u8
+    }
+
+    fn serialize_inputs(&self) -> Vec<u8> {
+        let mut inputs = vec![0x02];
+        inputs.extend(self.cust.input.serialize_input());
+        inputs.extend(self.merch.input.serialize_input());
+        inputs
+    }
+
+    ///
+    /// get_sighash() - BIP143 digest that the given party signs for its input
+    /// output: sighash
+    ///
+    pub fn get_sighash(&self, party: FundingParty) -> [u8; 32] {
+        let outpoints = vec![
+            self.cust.input.serialize_outpoint(),
+            self.merch.input.serialize_outpoint(),
+        ];
+        let c = self.get_contribution(party);
+        // hashOutputs does not cover the output count
+        compute_sighash(
+            &outpoints,
+            &c.input.serialize_outpoint(),
+            &c.input.script_code(),
+            c.input.input_sats,
+            &self.serialize_outputs()[1..].to_vec(),
+        )
+    }
+
+    ///
+    /// get_txid() - computes the txid of the escrow-tx (signatures are not covered by the txid)
+    /// output: escrow txid (big-endian and little-endian)
+    ///
+    pub fn get_txid(&self) -> ([u8; 32], [u8; 32]) {
+        let mut tx = TX_VERSION.to_vec();
+        tx.extend(self.serialize_inputs());
+        tx.extend(self.serialize_outputs());
+        tx.extend_from_slice(&TX_LOCKTIME);
+        let txid_be = double_sha256(&tx);
+        let mut txid_le = txid_be.clone();
+        txid_le.reverse();
+        (txid_be, txid_le)
+    }
+
+    ///
+    /// get_escrow_prevout() - hash of the escrow outpoint (always at index 0)
+    /// output: escrow prevout
+    ///
+    pub fn get_escrow_prevout(&self) -> [u8; 32] {
+        let (txid_be, _) = self.get_txid();
+        compute_escrow_prevout(&txid_be)
+    }
+
+    ///
+    /// get_funding_tx_info() - funding tx info of a channel opened with this escrow-tx, where the
+    /// initial balances are the contributions of each party
+    /// output: funding tx info
+    ///
+    pub fn get_funding_tx_info(
+        &self,
+        merch_txid_be: [u8; 32],
+        merch_prevout: [u8; 32],
+    ) -> FundingTxInfo {
+        let (escrow_txid_be, _) = self.get_txid();
+        FundingTxInfo {
+            init_cust_bal: self.cust.escrow_sats,
+            init_merch_bal: self.merch.escrow_sats,
+            escrow_txid: FixedSizeArray32(escrow_txid_be),
+            escrow_prevout: FixedSizeArray32(self.get_escrow_prevout()),
+            merch_txid: FixedSizeArray32(merch_txid_be),
+            merch_prevout: FixedSizeArray32(merch_prevout),
+            cust_funding: self.cust.escrow_sats,
+            merch_funding: self.merch.escrow_sats,
+        }
+    }
+
+    ///
+    /// sign() - the given party signs its own input with the secret key of its funding outpoint
+    /// output: DER-encoded signature followed by the sighash flag
+    ///
+    pub fn sign(&self, party: FundingParty, sk: &[u8; 32]) -> Result<Vec<u8>, ZkChannelError> {
+        sign_sighash(
+            &self.get_sighash(party),
+            sk,
+            &self.get_contribution(party).input.pk,
+        )
+    }
+
+    ///
+    /// verify() - checks the partial signature of the given party on its input
+    /// output: true if the signature is valid
+    ///
+    pub fn verify(&self, party: FundingParty, sig: &Vec<u8>) -> bool {
+        verify_sighash(
+            &self.get_sighash(party),
+            sig,
+            &self.get_contribution(party).input.pk,
+        )
+    }
+
+    ///
+    /// finalize() - verifies both partial signatures and assembles the signed escrow-tx
+    /// output: signed escrow-tx that can be broadcast
+    ///
+    pub fn finalize(
+        &self,
+        cust_sig: &Vec<u8>,
+        merch_sig: &Vec<u8>,
+    ) -> Result<Vec<u8>, ZkChannelError> {
+        if !self.verify(FundingParty::Customer, cust_sig) {
+            return Err(ZkChannelError::TransactionError(String::from(
+                "invalid customer signature on the escrow-tx",
+            )));
+        }
+        if !self.verify(FundingParty::Merchant, merch_sig) {
+            return Err(ZkChannelError::TransactionError(String::from(
+                "invalid merchant signature on the escrow-tx",
+            )));
+        }
+        let mut tx = TX_VERSION.to_vec();
+        tx.extend_from_slice(&[0x00, 0x01]); // segwit marker and flag
+        tx.extend(self.serialize_inputs());
+        tx.extend(self.serialize_outputs());
+        tx.extend(self.cust.input.serialize_witness(cust_sig));
+        tx.extend(self.merch.input.serialize_witness(merch_sig));
+        tx.extend_from_slice(&TX_LOCKTIME);
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keypair(byte: u8) -> ([u8; 32], PublicKey) {
+        let secp = secp256k1::Secp256k1::new();
+        let sk = [byte; 32];
+        let pk = PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&sk).unwrap());
+        (sk, pk)
+    }
+
+    fn contribution(
+        byte: u8,
+        input_sats: i64,
+        escrow_sats: i64,
+        change_sats: i64,
+    ) -> EscrowContribution {
+        let (_, pk) = keypair(byte);
+        let (_, change_pk) = keypair(byte + 1);
+        EscrowContribution {
+            input: EscrowFundingInput {
+                txid_le: FixedSizeArray32([byte; 32]),
+                index: 0,
+                input_sats,
+                pk,
+            },
+            escrow_sats,
+            change_sats,
+            change_pk: Some(change_pk),
+        }
+    }
+
+    #[test]
+    fn test_dual_funded_escrow_partial_sigs() {
+        let (_, cust_pk) = keypair(0x31);
+        let (_, merch_pk) = keypair(0x32);
+        let (cust_funding_sk, _) = keypair(0x11);
+        let (merch_funding_sk, _) = keypair(0x21);
+        let escrow_tx = DualFundedEscrowTx::new(
+            cust_pk,
+            merch_pk,
+            contribution(0x11, 300000, 200000, 99000),
+            contribution(0x21, 400000, 100000, 299000),
+        )
+        .unwrap();
+        assert_eq!(escrow_tx.get_escrow_sats(), 300000);
+        assert_eq!(escrow_tx.get_output_count(), 3);
+
+        let cust_sig = escrow_tx
+            .sign(FundingParty::Customer, &cust_funding_sk)
+            .unwrap();
+        let merch_sig = escrow_tx
+            .sign(FundingParty::Merchant, &merch_funding_sk)
+            .unwrap();
+        assert!(escrow_tx.verify(FundingParty::Customer, &cust_sig));
+        assert!(escrow_tx.verify(FundingParty::Merchant, &merch_sig));
+        // a partial signature only covers the signer's own input
+        assert!(!escrow_tx.verify(FundingParty::Merchant, &cust_sig));
+        assert!(escrow_tx
+            .sign(FundingParty::Merchant, &cust_funding_sk)
+            .is_err());
+        assert!(escrow_tx.finalize(&merch_sig, &cust_sig).is_err());
+
+        let signed_tx = escrow_tx.finalize(&cust_sig, &merch_sig).unwrap();
+        assert_eq!(signed_tx[4..6], [0x00, 0x01]);
+
+        // changing either contribution changes the txid and invalidates the signatures
+        let mut other_tx = escrow_tx.clone();
+        other_tx.merch.change_sats -= 1000;
+        assert_ne!(other_tx.get_txid(), escrow_tx.get_txid());
+        assert!(!other_tx.verify(FundingParty::Customer, &cust_sig));
+
+        // inputs that do not cover the contribution are rejected
+        assert!(DualFundedEscrowTx::new(
+            cust_pk,
+            merch_pk,
+            contribution(0x11, 300000, 200000, 99000),
+            contribution(0x21, 100000, 100000, 1000),
+        )
+        .is_err());
+    }
+}
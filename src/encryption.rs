@@ -0,0 +1,388 @@
+use super::*;
+use chacha20poly1305::aead::generic_array::GenericArray;
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use errors::ZkChannelError;
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+type HmacSha256 = Hmac<Sha256>;
+
+///
+/// Secrets at rest (customer and merchant states, rev secrets) are sealed with ChaCha20-Poly1305
+///     MAGIC || key_id (8 bytes) || nonce (12 bytes) || ciphertext and tag
+/// and stored as hex. The name of the record (e.g., the redis key and field) is authenticated as
+/// associated data, so a sealed record cannot be moved to another record. The key_id identifies
+/// the storage key that sealed a record, which is needed to rotate keys.
+/// Only the rust api and the cli seal records: the ffi shim stores and returns everything unsealed.
+///
+pub const MAGIC: [u8; 4] = *b"ZKCE";
+pub const DEFAULT_KDF_ITERATIONS: u32 = 100_000;
+pub const MIN_SALT_LEN: usize = 16;
+const KEY_ID_LEN: usize = 8;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+// PBKDF2-HMAC-SHA256 (RFC 8018) with a 32-byte output (a single block)
+fn pbkdf2_sha256(passphrase: &[u8], salt: &[u8], iterations: u32) -> [u8; 32] {
+    let prf = HmacSha256::new_varkey(passphrase).expect("HMAC can take key of any size");
+    let mut mac = prf.clone();
+    mac.input(salt);
+    mac.input(&1u32.to_be_bytes());
+    let mut u = mac.result().code();
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&u);
+    for _ in 1..iterations {
+        let mut mac = prf.clone();
+        mac.input(&u);
+        u = mac.result().code();
+        for (k, b) in key.iter_mut().zip(u.iter()) {
+            *k ^= b;
+        }
+    }
+    key
+}
+
+///
+/// StorageKey - symmetric key that seals the secrets of a customer or merchant at rest
+///
+#[derive(Clone)]
+pub struct StorageKey {
+    key: [u8; 32],
+    key_id: [u8; KEY_ID_LEN],
+}
+
+// never print the key itself
+impl fmt::Debug for StorageKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StorageKey {{ key_id: {} }}", hex::encode(&self.key_id))
+    }
+}
+
+impl StorageKey {
+    pub fn from_bytes(key: [u8; 32]) -> Self {
+        let mut h = Sha256::new();
+        h.input(b"zkchannels storage key");
+        h.input(&key);
+        let mut key_id = [0u8; KEY_ID_LEN];
+        key_id.copy_from_slice(&h.result()[..KEY_ID_LEN]);
+        StorageKey { key, key_id }
+    }
+
+    ///
+    /// from_passphrase() - derives the storage key from a passphrase with PBKDF2-HMAC-SHA256
+    /// output: storage key
+    ///
+    pub fn from_passphrase(
+        passphrase: &str,
+        salt: &[u8],
+        iterations: u32,
+    ) -> Result<Self, ZkChannelError> {
+        if passphrase.is_empty() {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "passphrase cannot be empty",
+            )));
+        }
+        if salt.len() < MIN_SALT_LEN || iterations == 0 {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "invalid kdf parameters: salt of {} bytes and {} iterations",
+                salt.len(),
+                iterations
+            )));
+        }
+        Ok(StorageKey::from_bytes(pbkdf2_sha256(
+            passphrase.as_bytes(),
+            salt,
+            iterations,
+        )))
+    }
+
+    ///
+    /// from_key_file() - loads the storage key from a key file (32 bytes in hex)
+    /// output: storage key
+    ///
+    pub fn from_key_file(path: &Path) -> Result<Self, ZkChannelError> {
+        let content = match fs::read_to_string(path) {
+            Ok(c) => c,
+            Err(e) => {
+                return Err(ZkChannelError::InvalidInput(format!(
+                    "could not read key file {}: {}",
+                    path.display(),
+                    e
+                )))
+            }
+        };
+        let key_vec = hex::decode(content.trim())?;
+        if key_vec.len() != 32 {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "key file does not contain a 32-byte key: {}",
+                path.display()
+            )));
+        }
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&key_vec);
+        Ok(StorageKey::from_bytes(key))
+    }
+
+    ///
+    /// generate_key_file() - writes a new random storage key to a key file (that must not exist yet)
+    /// output: storage key
+    ///
+    pub fn generate_key_file<R: Rng>(csprng: &mut R, path: &Path) -> Result<Self, ZkChannelError> {
+        if path.exists() {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "key file already exists: {}",
+                path.display()
+            )));
+        }
+        let mut key = [0u8; 32];
+        csprng.fill(&mut key);
+        if let Err(e) = fs::write(path, hex::encode(&key)) {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "could not write key file {}: {}",
+                path.display(),
+                e
+            )));
+        }
+        Ok(StorageKey::from_bytes(key))
+    }
+
+    pub fn get_key_id(&self) -> [u8; KEY_ID_LEN] {
+        self.key_id
+    }
+
+    fn get_cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(GenericArray::clone_from_slice(&self.key))
+    }
+
+    ///
+    /// encrypt() - seals the plaintext of the named record
+    /// output: sealed record
+    ///
+    pub fn encrypt(&self, name: &str, plaintext: &[u8]) -> Result<Vec<u8>, ZkChannelError> {
+        let mut nonce = [0u8; NONCE_LEN];
+        rand::thread_rng().fill(&mut nonce);
+        let payload = Payload {
+            msg: plaintext,
+            aad: name.as_bytes(),
+        };
+        let ciphertext = match self
+            .get_cipher()
+            .encrypt(GenericArray::from_slice(&nonce), payload)
+        {
+            Ok(c) => c,
+            Err(_) => {
+                return Err(ZkChannelError::SerializationError(format!(
+                    "could not encrypt record: {}",
+                    name
+                )))
+            }
+        };
+        let mut sealed = MAGIC.to_vec();
+        sealed.extend_from_slice(&self.key_id);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend(ciphertext);
+        Ok(sealed)
+    }
+
+    ///
+    /// decrypt() - opens a sealed record (fails if the record was sealed with another key,
+    /// for another name or was modified)
+    /// output: plaintext
+    ///
+    pub fn decrypt(&self, name: &str, sealed: &[u8]) -> Result<Vec<u8>, ZkChannelError> {
+        let header_len = MAGIC.len() + KEY_ID_LEN + NONCE_LEN;
+        if sealed.len() < header_len + TAG_LEN || sealed[..MAGIC.len()] != MAGIC {
+            return Err(ZkChannelError::SerializationError(format!(
+                "record is not encrypted: {}",
+                name
+            )));
+        }
+        if sealed[MAGIC.len()..MAGIC.len() + KEY_ID_LEN] != self.key_id {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "record was encrypted with another storage key: {}",
+                name
+            )));
+        }
+        let nonce = &sealed[MAGIC.len() + KEY_ID_LEN..header_len];
+        let payload = Payload {
+            msg: &sealed[header_len..],
+            aad: name.as_bytes(),
+        };
+        match self
+            .get_cipher()
+            .decrypt(GenericArray::from_slice(nonce), payload)
+        {
+            Ok(p) => Ok(p),
+            Err(_) => Err(ZkChannelError::SerializationError(format!(
+                "could not decrypt record: {}",
+                name
+            ))),
+        }
+    }
+}
+
+///
+/// is_sealed() - checks whether a stored record is sealed (or a legacy plaintext record)
+///
+pub fn is_sealed(record: &String) -> bool {
+    match hex::decode(record) {
+        Ok(b) => b.len() >= MAGIC.len() + KEY_ID_LEN + NONCE_LEN + TAG_LEN && b.starts_with(&MAGIC),
+        Err(_) => false,
+    }
+}
+
+///
+/// seal_record() - seals the value of the named record if a storage key is given
+/// output: record to store
+///
+pub fn seal_record(
+    storage_key: Option<&StorageKey>,
+    name: &str,
+    value: &String,
+) -> Result<String, ZkChannelError> {
+    match storage_key {
+        Some(k) => Ok(hex::encode(k.encrypt(name, value.as_bytes())?)),
+        None => Ok(value.clone()),
+    }
+}
+
+///
+/// open_record() - opens a stored record. Once a storage key is set, plaintext records are rejected,
+/// since anyone who can write to the db could inject them (or replace a sealed record). Plaintext
+/// records written before a storage key was set are only read by open_legacy_record()
+/// output: value of the record
+///
+pub fn open_record(
+    storage_key: Option<&StorageKey>,
+    name: &str,
+    record: &String,
+) -> Result<String, ZkChannelError> {
+    if !is_sealed(record) {
+        return match storage_key {
+            Some(_) => Err(ZkChannelError::InvalidInput(format!(
+                "record is not encrypted, but a storage key was given: {}",
+                name
+            ))),
+            None => Ok(record.clone()),
+        };
+    }
+    let k = match storage_key {
+        Some(k) => k,
+        None => {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "record is encrypted, but no storage key was given: {}",
+                name
+            )))
+        }
+    };
+    let plaintext = k.decrypt(name, &hex::decode(record)?)?;
+    Ok(str::from_utf8(&plaintext)?.to_string())
+}
+
+///
+/// open_legacy_record() - opens a stored record like open_record(), but also returns plaintext records
+/// as is. Only for migrations (key rotation, legacy cli records) that seal the record right away
+/// output: value of the record
+///
+pub fn open_legacy_record(
+    storage_key: Option<&StorageKey>,
+    name: &str,
+    record: &String,
+) -> Result<String, ZkChannelError> {
+    if !is_sealed(record) {
+        return Ok(record.clone());
+    }
+    open_record(storage_key, name, record)
+}
+
+///
+/// reseal_record() - re-encrypts a stored record (sealed with old_key or plaintext) with new_key
+/// output: record to store
+///
+pub fn reseal_record(
+    old_key: Option<&StorageKey>,
+    new_key: &StorageKey,
+    name: &str,
+    record: &String,
+) -> Result<String, ZkChannelError> {
+    let value = open_legacy_record(old_key, name, record)?;
+    seal_record(Some(new_key), name, &value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pbkdf2_sha256_vector() {
+        // RFC 7914 (section 11): PBKDF2-HMAC-SHA256, P = "passwd", S = "salt", c = 1
+        assert_eq!(
+            hex::encode(pbkdf2_sha256(b"passwd", b"salt", 1)),
+            "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc"
+        );
+    }
+
+    #[test]
+    fn test_seal_and_rotate_records() {
+        let old_key = StorageKey::from_passphrase("correct horse", &[0x11; 16], 10).unwrap();
+        let new_key = StorageKey::from_bytes([0x22; 32]);
+        assert_ne!(old_key.get_key_id(), new_key.get_key_id());
+        assert!(StorageKey::from_passphrase("correct horse", &[0x11; 8], 10).is_err());
+
+        let value = String::from("{\"sk_c\": \"secret\"}");
+        let sealed = seal_record(Some(&old_key), "cli:merch_db:merch_state", &value).unwrap();
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.contains("secret"));
+        assert_eq!(
+            open_record(Some(&old_key), "cli:merch_db:merch_state", &sealed).unwrap(),
+            value
+        );
+
+        // a sealed record cannot be opened without the key, with another key or under another name
+        assert!(open_record(None, "cli:merch_db:merch_state", &sealed).is_err());
+        assert!(open_record(Some(&new_key), "cli:merch_db:merch_state", &sealed).is_err());
+        assert!(open_record(Some(&old_key), "cli:merch_db:cust_state", &sealed).is_err());
+        let mut modified = hex::decode(&sealed).unwrap();
+        let last = modified.len() - 1;
+        modified[last] ^= 0x01;
+        assert!(open_record(
+            Some(&old_key),
+            "cli:merch_db:merch_state",
+            &hex::encode(modified)
+        )
+        .is_err());
+
+        // rotation re-encrypts sealed records and encrypts legacy plaintext records
+        let rotated = reseal_record(
+            Some(&old_key),
+            &new_key,
+            "cli:merch_db:merch_state",
+            &sealed,
+        )
+        .unwrap();
+        assert_eq!(
+            open_record(Some(&new_key), "cli:merch_db:merch_state", &rotated).unwrap(),
+            value
+        );
+        assert!(open_record(Some(&old_key), "cli:merch_db:merch_state", &rotated).is_err());
+        let upgraded =
+            reseal_record(Some(&old_key), &new_key, "cli:merch_db:merch_state", &value).unwrap();
+        assert!(is_sealed(&upgraded));
+        assert_eq!(
+            open_record(None, "cli:merch_db:merch_state", &value).unwrap(),
+            value
+        );
+
+        // plaintext records are rejected once a storage key is set, unless they are migrated
+        assert!(open_record(Some(&new_key), "cli:merch_db:merch_state", &value).is_err());
+        assert_eq!(
+            open_legacy_record(Some(&new_key), "cli:merch_db:merch_state", &value).unwrap(),
+            value
+        );
+        assert!(open_legacy_record(Some(&new_key), "cli:merch_db:merch_state", &sealed).is_err());
+    }
+}
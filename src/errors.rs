@@ -0,0 +1,167 @@
+use super::*;
+use std::error::Error;
+
+///
+/// ZkChannelError - errors returned by the mpc protocol (channels_mpc, database and the mpc module).
+/// Each variant carries a human-readable message and maps to a stable numeric code (see code())
+/// that is exposed across the FFI boundary. Codes must never be reused or renumbered.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ZkChannelError {
+    /// the requested channel status transition is not allowed
+    InvalidStatusTransition(String),
+    /// the nonce of a state has been used in a previous payment (possible fraud)
+    DoubleSpentNonce(String),
+    /// the session id has already been used for another payment
+    ReusedSessionId(String),
+    /// a commitment (rev_lock_com, pay_token_mask_com) does not open to the claimed values
+    InvalidCommitmentOpening(String),
+    /// the state database could not be reached (retryable)
+    DatabaseUnavailable(String),
+    /// the mpc protocol failed or returned invalid outputs
+    MpcFailure(String),
+    /// a bitcoin transaction could not be constructed, signed or verified
+    TransactionError(String),
+    /// a malformed or inconsistent argument (bad length, network mismatch, uninitialized state, etc)
+    InvalidInput(String),
+    /// a channel, session or stored entry does not exist
+    NotFound(String),
+    /// a stored or received object could not be (de)serialized
+    SerializationError(String),
+    /// a call failed for a reason outside the protocol (e.g., it panicked on a blocking pool)
+    InternalError(String),
+}
+
+impl ZkChannelError {
+    ///
+    /// code() - stable numeric code of the error for the FFI layer (0 is reserved for success)
+    ///
+    pub fn code(&self) -> u32 {
+        match self {
+            ZkChannelError::InvalidStatusTransition(_) => 1,
+            ZkChannelError::DoubleSpentNonce(_) => 2,
+            ZkChannelError::ReusedSessionId(_) => 3,
+            ZkChannelError::InvalidCommitmentOpening(_) => 4,
+            ZkChannelError::DatabaseUnavailable(_) => 5,
+            ZkChannelError::MpcFailure(_) => 6,
+            ZkChannelError::TransactionError(_) => 7,
+            ZkChannelError::InvalidInput(_) => 8,
+            ZkChannelError::NotFound(_) => 9,
+            ZkChannelError::SerializationError(_) => 10,
+            ZkChannelError::InternalError(_) => 11,
+        }
+    }
+
+    pub fn message(&self) -> &String {
+        match self {
+            ZkChannelError::InvalidStatusTransition(s) => s,
+            ZkChannelError::DoubleSpentNonce(s) => s,
+            ZkChannelError::ReusedSessionId(s) => s,
+            ZkChannelError::InvalidCommitmentOpening(s) => s,
+            ZkChannelError::DatabaseUnavailable(s) => s,
+            ZkChannelError::MpcFailure(s) => s,
+            ZkChannelError::TransactionError(s) => s,
+            ZkChannelError::InvalidInput(s) => s,
+            ZkChannelError::NotFound(s) => s,
+            ZkChannelError::SerializationError(s) => s,
+            ZkChannelError::InternalError(s) => s,
+        }
+    }
+
+    ///
+    /// is_retryable() - true if the same call may succeed later without any change of input
+    ///
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ZkChannelError::DatabaseUnavailable(_) | ZkChannelError::MpcFailure(_) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ZkChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl Error for ZkChannelError {
+    fn description(&self) -> &str {
+        self.message()
+    }
+}
+
+impl From<redis::RedisError> for ZkChannelError {
+    fn from(e: redis::RedisError) -> Self {
+        ZkChannelError::DatabaseUnavailable(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for ZkChannelError {
+    fn from(e: serde_json::Error) -> Self {
+        ZkChannelError::SerializationError(e.to_string())
+    }
+}
+
+impl From<hex::FromHexError> for ZkChannelError {
+    fn from(e: hex::FromHexError) -> Self {
+        ZkChannelError::InvalidInput(e.to_string())
+    }
+}
+
+impl From<secp256k1::Error> for ZkChannelError {
+    fn from(e: secp256k1::Error) -> Self {
+        ZkChannelError::InvalidInput(e.to_string())
+    }
+}
+
+impl From<str::Utf8Error> for ZkChannelError {
+    fn from(e: str::Utf8Error) -> Self {
+        ZkChannelError::InvalidInput(e.to_string())
+    }
+}
+
+// the CLI and test helpers report errors as plain strings
+impl From<ZkChannelError> for String {
+    fn from(e: ZkChannelError) -> Self {
+        e.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes_are_stable() {
+        let errors = vec![
+            ZkChannelError::InvalidStatusTransition(String::new()),
+            ZkChannelError::DoubleSpentNonce(String::new()),
+            ZkChannelError::ReusedSessionId(String::new()),
+            ZkChannelError::InvalidCommitmentOpening(String::new()),
+            ZkChannelError::DatabaseUnavailable(String::new()),
+            ZkChannelError::MpcFailure(String::new()),
+            ZkChannelError::TransactionError(String::new()),
+            ZkChannelError::InvalidInput(String::new()),
+            ZkChannelError::NotFound(String::new()),
+            ZkChannelError::SerializationError(String::new()),
+            ZkChannelError::InternalError(String::new()),
+        ];
+        let codes: Vec<u32> = errors.iter().map(|e| e.code()).collect();
+        assert_eq!(codes, vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]);
+
+        let e = ZkChannelError::DoubleSpentNonce(String::from("nonce has been spent already"));
+        assert_eq!(e.to_string(), "nonce has been spent already");
+        assert!(!e.is_retryable());
+    }
+
+    #[test]
+    fn test_error_sources_have_specific_codes() {
+        let e = ZkChannelError::from(serde_json::from_str::<u32>("x").unwrap_err());
+        assert_eq!(e.code(), 10);
+        let e = ZkChannelError::from(hex::decode("zz").unwrap_err());
+        assert_eq!(e.code(), 8);
+        let e = ZkChannelError::from(str::from_utf8(&[0xff]).unwrap_err());
+        assert_eq!(e.code(), 8);
+    }
+}
@@ -0,0 +1,206 @@
+use super::*;
+use channels_mpc::{ChannelMPCToken, ChannelStatus};
+use database::StateDatabase;
+use errors::ZkChannelError;
+use util::get_current_time;
+
+#[derive(Clone, Debug, PartialEq, Display, Serialize, Deserialize)]
+pub enum EventParty {
+    Customer,
+    Merchant,
+}
+
+///
+/// EventKey - the log that an event is appended to. Channel steps are keyed by the escrow txid
+/// (big endian hex). The merchant cannot link a payment to a channel, so the merchant side of a
+/// payment is keyed by its session id instead.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum EventKey {
+    Escrow(String),
+    Session(String),
+}
+
+impl EventKey {
+    pub fn escrow(escrow_txid_be: &[u8; 32]) -> Self {
+        EventKey::Escrow(hex::encode(escrow_txid_be))
+    }
+
+    // the escrow txid of a channel token is little endian
+    pub fn from_token(channel_token: &ChannelMPCToken) -> Self {
+        let mut escrow_txid_be = channel_token.escrow_txid.0.clone();
+        escrow_txid_be.reverse();
+        EventKey::escrow(&escrow_txid_be)
+    }
+
+    pub fn session(session_id: &[u8; 16]) -> Self {
+        EventKey::Session(hex::encode(session_id))
+    }
+
+    pub fn to_db_key(&self) -> String {
+        match self {
+            EventKey::Escrow(s) => format!("escrow:{}", s),
+            EventKey::Session(s) => format!("session:{}", s),
+        }
+    }
+}
+
+///
+/// ProtocolEvent - a step of the protocol. Events only carry public values (ids, amounts,
+/// statuses, txids and error codes), never keys, rev secrets, masks or error messages.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProtocolEvent {
+    ChannelParamsValidated {
+        merch_txid: String,
+    },
+    Activated,
+    PaymentPrepared {
+        amount: i64,
+    },
+    MpcStarted,
+    MpcFinished {
+        success: bool,
+    },
+    MpcConfirmed {
+        success: bool,
+    },
+    Unmasked {
+        what: String,
+        success: bool,
+    },
+    RevLockValidated,
+    StatusChanged {
+        from: ChannelStatus,
+        to: ChannelStatus,
+    },
+    Closed {
+        kind: String,
+        txid: String,
+    },
+    StepFailed {
+        step: String,
+        error_code: u32,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EventRecord {
+    pub key: EventKey,
+    pub party: EventParty,
+    pub timestamp: u64,
+    pub event: ProtocolEvent,
+}
+
+impl EventRecord {
+    pub fn new(key: EventKey, party: EventParty, event: ProtocolEvent) -> Self {
+        EventRecord {
+            key: key,
+            party: party,
+            timestamp: get_current_time(),
+            event: event,
+        }
+    }
+
+    // records the error code of a failed step (the message may contain secrets)
+    pub fn failed(key: EventKey, party: EventParty, step: &str, e: &ZkChannelError) -> Self {
+        EventRecord::new(
+            key,
+            party,
+            ProtocolEvent::StepFailed {
+                step: step.to_string(),
+                error_code: e.code(),
+            },
+        )
+    }
+}
+
+///
+/// record_event() - appends the event to the log. A protocol step never fails because its event
+/// could not be logged, so errors are only printed
+/// no output
+///
+pub fn record_event(db: &mut dyn StateDatabase, record: EventRecord) {
+    if let Err(e) = db.append_event(&record) {
+        eprintln!(
+            "could not log event for {}: {}",
+            record.key.to_db_key(),
+            e.message()
+        );
+    }
+}
+
+///
+/// record_result() - appends the event of a step if it succeeded or a StepFailed event otherwise
+/// output: the result of the step
+///
+pub fn record_result<T>(
+    db: &mut dyn StateDatabase,
+    key: EventKey,
+    party: EventParty,
+    step: &str,
+    event: ProtocolEvent,
+    result: Result<T, ZkChannelError>,
+) -> Result<T, ZkChannelError> {
+    let record = match &result {
+        Ok(_) => EventRecord::new(key, party, event),
+        Err(e) => EventRecord::failed(key, party, step, e),
+    };
+    record_event(db, record);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::HashMapDatabase;
+
+    #[test]
+    fn test_event_log_is_append_only_per_key() {
+        let mut db = HashMapDatabase::new("", String::new()).unwrap();
+        let escrow = EventKey::escrow(&[1u8; 32]);
+        let session = EventKey::session(&[2u8; 16]);
+
+        record_event(
+            &mut db,
+            EventRecord::new(
+                escrow.clone(),
+                EventParty::Merchant,
+                ProtocolEvent::Activated,
+            ),
+        );
+        let res: Result<(), ZkChannelError> =
+            Err(ZkChannelError::DoubleSpentNonce(String::from("nonce 0101")));
+        assert!(record_result(
+            &mut db,
+            session.clone(),
+            EventParty::Merchant,
+            "pay_prepare_merchant",
+            ProtocolEvent::PaymentPrepared { amount: 10 },
+            res,
+        )
+        .is_err());
+        record_event(
+            &mut db,
+            EventRecord::new(
+                escrow.clone(),
+                EventParty::Merchant,
+                ProtocolEvent::StatusChanged {
+                    from: ChannelStatus::Open,
+                    to: ChannelStatus::MerchantInitClose,
+                },
+            ),
+        );
+
+        let events = db.get_events(&escrow).unwrap();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].event, ProtocolEvent::Activated);
+        let events = db.get_events(&session).unwrap();
+        assert_eq!(events.len(), 1);
+        // only the error code is logged
+        let ser_event = serde_json::to_string(&events[0]).unwrap();
+        assert!(ser_event.contains("\"error_code\":2"));
+        assert!(!ser_event.contains("nonce 0101"));
+    }
+}
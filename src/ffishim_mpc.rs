@@ -4,33 +4,79 @@ pub mod ffishim_mpc {
 
     use bindings::{cb_receive, cb_send};
     use channels_mpc::{
-        ChannelMPCState, ChannelMPCToken, CustomerMPCState, InitCustState, MerchantMPCState,
-        TransactionFeeInfo,
+        BitcoinNetworkType, ChannelMPCState, ChannelMPCToken, CustomerMPCState, InitCustState,
+        MerchantMPCState, TransactionFeeInfo,
     };
     use database::{MaskedTxMPCInputs, RedisDatabase, StateDatabase};
+    use errors::ZkChannelError;
     use hex::FromHexError;
     use libc::{c_char, c_void};
     use mpc;
     use mpc::ChannelStatus;
     use mpc::RevokedState;
+    use persist;
+    use persist::PersistedState;
     use serde::Deserialize;
+    use std::any::Any;
     use std::ffi::{CStr, CString};
     use std::str;
+    use std::str::FromStr;
     use wallet::State;
-    use zkchan_tx::Testnet;
+    use zkchan_tx::{Mainnet, Testnet};
     use FundingTxInfo;
 
+    // NOTE: the ffi does not seal anything at rest. States are returned to the caller as plain
+    // json (the caller stores them and has to encrypt them itself) and the merchant db is opened
+    // without a storage key, so rev secrets are written to redis unsealed. Callers that need
+    // encryption at rest must use the rust api (RedisDatabase::set_storage_key()) instead
     fn error_message(s: String) -> *mut c_char {
-        let ser = ["{\'error\':\'", &s, "\'}"].concat();
+        error_message_with_code(s, ZkChannelError::InvalidInput(String::new()).code())
+    }
+
+    fn error_message_with_code(s: String, code: u32) -> *mut c_char {
+        let ser = [
+            "{\'error\':\'",
+            &s,
+            "\', \'error_code\':",
+            &code.to_string(),
+            "}",
+        ]
+        .concat();
         let cser = CString::new(ser).unwrap();
         cser.into_raw()
     }
 
+    // stable numeric code of an error (see ZkChannelError::code()). errors raised outside of
+    // the protocol are classified by their source: decoding the arguments is invalid input,
+    // (de)serializing a state is a serialization error and anything else is an internal error
+    fn error_code<E: Any>(err: &E) -> u32 {
+        let err = err as &dyn Any;
+        if let Some(e) = err.downcast_ref::<ZkChannelError>() {
+            return e.code();
+        }
+        if err.is::<serde_json::Error>() {
+            ZkChannelError::SerializationError(String::new()).code()
+        } else if err.is::<redis::RedisError>() {
+            ZkChannelError::DatabaseUnavailable(String::new()).code()
+        } else if err.is::<FromHexError>()
+            || err.is::<secp256k1::Error>()
+            || err.is::<str::Utf8Error>()
+        {
+            ZkChannelError::InvalidInput(String::new()).code()
+        } else {
+            ZkChannelError::InternalError(String::new()).code()
+        }
+    }
+
+    fn tx_error<E: ToString>(e: E) -> ZkChannelError {
+        ZkChannelError::TransactionError(e.to_string())
+    }
+
     macro_rules! handle_errors {
         ($e:expr) => {
             match $e {
                 Ok(val) => val,
-                Err(err) => return error_message(err.to_string()),
+                Err(err) => return error_message_with_code(err.to_string(), error_code(&err)),
             }
         };
     }
@@ -58,17 +104,38 @@ pub mod ffishim_mpc {
         serde_json::from_str(&string)
     }
 
+    // channel state objects are accepted in the versioned envelope (and migrated) or as legacy json
+    fn deserialize_persisted_state<T>(serialized: *mut c_char) -> Result<T, ZkChannelError>
+    where
+        T: PersistedState,
+    {
+        let bytes = unsafe { CStr::from_ptr(serialized).to_bytes() };
+        persist::from_json_str(str::from_utf8(bytes)?)
+    }
+
+    // merchant states from schema version 1 carry per-channel maps that are moved to the db first
+    fn deserialize_merchant_state(
+        serialized: *mut c_char,
+    ) -> Result<MerchantMPCState, ZkChannelError> {
+        let mut merch_state: MerchantMPCState = deserialize_persisted_state(serialized)?;
+        if merch_state.has_legacy_channel_maps() {
+            let mut db = RedisDatabase::new("mpc", merch_state.db_url.clone())?;
+            merch_state.migrate_legacy_channel_maps(&mut db as &mut dyn StateDatabase)?;
+        }
+        Ok(merch_state)
+    }
+
     fn deserialize_hex_string(serialized: *mut c_char) -> Result<Vec<u8>, FromHexError> {
         let bytes = unsafe { CStr::from_ptr(serialized).to_bytes() };
         let string: &str = str::from_utf8(bytes).unwrap(); // make sure the bytes are UTF-8
         hex::decode(&string)
     }
 
-    fn deserialize_string(serialized: *mut c_char) -> Result<String, String> {
+    fn deserialize_string(serialized: *mut c_char) -> Result<String, ZkChannelError> {
         let bytes = unsafe { CStr::from_ptr(serialized).to_bytes() };
         match str::from_utf8(bytes) {
             Ok(n) => Ok(String::from(n)),
-            Err(e) => Err(e.to_string()),
+            Err(e) => Err(ZkChannelError::from(e)),
         }
     }
 
@@ -110,10 +177,15 @@ pub mod ffishim_mpc {
         bal_min_merch: i64,
         val_cpfp: i64,
         third_party_support: u32,
+        ser_network: *const c_char,
     ) -> *mut c_char {
         let bytes = unsafe { CStr::from_ptr(channel_name).to_bytes() };
         let name: &str = str::from_utf8(bytes).unwrap(); // make sure the bytes are UTF-8
 
+        let network_bytes = unsafe { CStr::from_ptr(ser_network).to_bytes() };
+        let network_str: &str = handle_errors!(str::from_utf8(network_bytes));
+        let network = handle_errors!(BitcoinNetworkType::from_str(network_str));
+
         let mut tps = false;
         if third_party_support >= 1 {
             tps = true;
@@ -125,6 +197,7 @@ pub mod ffishim_mpc {
             bal_min_merch,
             val_cpfp,
             tps,
+            network,
         );
 
         let ser = [
@@ -180,13 +253,13 @@ pub mod ffishim_mpc {
         ser_dispute_sk: *mut c_char,
     ) -> *mut c_char {
         // Deserialize the merch_state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
         // Deserialize the channel_state
-        let channel_state_result: ResultSerdeType<ChannelMPCState> =
-            deserialize_result_object(ser_channel_state);
+        let channel_state_result: Result<ChannelMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_state);
         let mut channel_state = handle_errors!(channel_state_result);
 
         let sk = deserialize_hex_string(ser_sk_m);
@@ -229,6 +302,7 @@ pub mod ffishim_mpc {
 
     #[no_mangle]
     pub extern "C" fn mpc_init_customer(
+        ser_channel_state: *mut c_char,
         ser_merch_pk: *mut c_char,
         cust_bal: i64,
         merch_bal: i64,
@@ -237,6 +311,11 @@ pub mod ffishim_mpc {
     ) -> *mut c_char {
         let rng = &mut rand::thread_rng();
 
+        // Deserialize the channel_state
+        let channel_state_result: Result<ChannelMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_state);
+        let channel_state = handle_errors!(channel_state_result);
+
         // Deserialize the pk_m
         let merch_pk_result = deserialize_hex_string(ser_merch_pk);
         let merch_pk = handle_errors!(merch_pk_result);
@@ -252,8 +331,15 @@ pub mod ffishim_mpc {
         let name: &str = str::from_utf8(bytes).unwrap(); // make sure the bytes are UTF-8
 
         // We change the channel state
-        let (channel_token, cust_state) =
-            mpc::init_customer(rng, &pk_m, cust_bal, merch_bal, &tx_fee_info, name);
+        let (channel_token, cust_state) = mpc::init_customer(
+            rng,
+            &channel_state,
+            &pk_m,
+            cust_bal,
+            merch_bal,
+            &tx_fee_info,
+            name,
+        );
         let ser = [
             "{\'cust_state\':\'",
             serde_json::to_string(&cust_state).unwrap().as_str(),
@@ -274,13 +360,13 @@ pub mod ffishim_mpc {
         ser_payout_sk: *mut c_char,
     ) -> *mut c_char {
         // Deserialize the cust_state
-        let cust_state_result: ResultSerdeType<CustomerMPCState> =
-            deserialize_result_object(ser_cust_state);
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
         let mut cust_state = handle_errors!(cust_state_result);
 
         // Deserialize the ChannelToken
-        let channel_token_result: ResultSerdeType<ChannelMPCToken> =
-            deserialize_result_object(ser_channel_token);
+        let channel_token_result: Result<ChannelMPCToken, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_token);
         let mut channel_token = handle_errors!(channel_token_result);
 
         let sk = deserialize_hex_string(ser_sk_c);
@@ -313,8 +399,8 @@ pub mod ffishim_mpc {
     #[no_mangle]
     pub extern "C" fn mpc_get_initial_state(ser_cust_state: *mut c_char) -> *mut c_char {
         // Deserialize the cust_state
-        let cust_state_result: ResultSerdeType<CustomerMPCState> =
-            deserialize_result_object(ser_cust_state);
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
         let cust_state = handle_errors!(cust_state_result);
 
         let (init_state, init_hash) = handle_errors!(mpc::get_initial_state(&cust_state));
@@ -338,8 +424,8 @@ pub mod ffishim_mpc {
         ser_merch_state: *mut c_char,
     ) -> *mut c_char {
         // Deserialize the ChannelToken
-        let channel_token_result: ResultSerdeType<ChannelMPCToken> =
-            deserialize_result_object(ser_channel_token);
+        let channel_token_result: Result<ChannelMPCToken, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_token);
         let channel_token = handle_errors!(channel_token_result);
 
         // Deserialize the init state
@@ -355,8 +441,8 @@ pub mod ffishim_mpc {
         init_hash.copy_from_slice(hash_buf.as_slice());
 
         // Deserialize the merch_state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
         // get connection to the database
@@ -385,8 +471,8 @@ pub mod ffishim_mpc {
     #[no_mangle]
     pub extern "C" fn mpc_get_channel_id(ser_channel_token: *mut c_char) -> *mut c_char {
         // Deserialize the ChannelToken
-        let channel_token_result: ResultSerdeType<ChannelMPCToken> =
-            deserialize_result_object(ser_channel_token);
+        let channel_token_result: Result<ChannelMPCToken, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_token);
         let channel_token = handle_errors!(channel_token_result);
 
         let channel_id = handle_errors!(channel_token.compute_channel_id());
@@ -402,8 +488,8 @@ pub mod ffishim_mpc {
         let rng = &mut rand::thread_rng();
 
         // Deserialize the cust_state
-        let cust_state_result: ResultSerdeType<CustomerMPCState> =
-            deserialize_result_object(ser_cust_state);
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
         let mut cust_state = handle_errors!(cust_state_result);
 
         // We change the channel state
@@ -427,8 +513,8 @@ pub mod ffishim_mpc {
         ser_merch_state: *mut c_char,
     ) -> *mut c_char {
         // Deserialize the ChannelToken
-        let channel_token_result: ResultSerdeType<ChannelMPCToken> =
-            deserialize_result_object(ser_channel_token);
+        let channel_token_result: Result<ChannelMPCToken, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_token);
         let channel_token = handle_errors!(channel_token_result);
 
         // Deserialize the state
@@ -436,8 +522,8 @@ pub mod ffishim_mpc {
         let state = handle_errors!(state_result);
 
         // Deserialize the merch_state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
         // get connection to the database
@@ -469,8 +555,8 @@ pub mod ffishim_mpc {
         ser_cust_state: *mut c_char,
     ) -> *mut c_char {
         // Deserialize the cust_state
-        let cust_state_result: ResultSerdeType<CustomerMPCState> =
-            deserialize_result_object(ser_cust_state);
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
         let mut cust_state = handle_errors!(cust_state_result);
 
         // Deserialize pay token
@@ -506,20 +592,20 @@ pub mod ffishim_mpc {
         let rng = &mut rand::thread_rng();
 
         // Deserialize the channel_state
-        let channel_state_result: ResultSerdeType<ChannelMPCState> =
-            deserialize_result_object(ser_channel_state);
+        let channel_state_result: Result<ChannelMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_state);
         let channel_state = handle_errors!(channel_state_result);
 
         // Deserialize the cust_state
-        let cust_state_result: ResultSerdeType<CustomerMPCState> =
-            deserialize_result_object(ser_cust_state);
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
         let mut cust_state = handle_errors!(cust_state_result);
 
         // We change the channel state
         let (state, rev_state, rev_lock_com, session_id) =
             match mpc::pay_prepare_customer(rng, &channel_state, amount, &mut cust_state) {
                 Ok(n) => n,
-                Err(e) => return error_message(e),
+                Err(e) => return error_message_with_code(e.to_string(), e.code()),
             };
         let ser = [
             "{\'rev_state\':\'",
@@ -552,8 +638,8 @@ pub mod ffishim_mpc {
         let rng = &mut rand::thread_rng();
 
         // Deserialize the channel_state
-        let channel_state_result: ResultSerdeType<ChannelMPCState> =
-            deserialize_result_object(ser_channel_state);
+        let channel_state_result: Result<ChannelMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_state);
         let channel_state = handle_errors!(channel_state_result);
 
         // Deserialize rev_lock_com
@@ -581,8 +667,8 @@ pub mod ffishim_mpc {
         let justification = handle_errors!(deserialize_string(ser_justification));
 
         // Deserialize the merch_state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
         // get connection to the database
@@ -628,13 +714,13 @@ pub mod ffishim_mpc {
         receive_cb: cb_receive,
     ) -> *mut c_char {
         // Deserialize the channel_state
-        let channel_state_result: ResultSerdeType<ChannelMPCState> =
-            deserialize_result_object(ser_channel_state);
+        let channel_state_result: Result<ChannelMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_state);
         let mut channel_state = handle_errors!(channel_state_result);
 
         // Deserialize the ChannelToken
-        let channel_token_result: ResultSerdeType<ChannelMPCToken> =
-            deserialize_result_object(ser_channel_token);
+        let channel_token_result: Result<ChannelMPCToken, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_token);
         let channel_token = handle_errors!(channel_token_result);
 
         // Deserialize the start_state
@@ -659,8 +745,8 @@ pub mod ffishim_mpc {
         rev_lock_com_ar.copy_from_slice(rev_lock_com.as_slice());
 
         // Deserialize the cust_state
-        let cust_state_result: ResultSerdeType<CustomerMPCState> =
-            deserialize_result_object(ser_cust_state);
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
         let mut cust_state = handle_errors!(cust_state_result);
 
         // We change the channel state
@@ -703,8 +789,8 @@ pub mod ffishim_mpc {
         let rng = &mut rand::thread_rng();
 
         // Deserialize the channel_state
-        let channel_state_result: ResultSerdeType<ChannelMPCState> =
-            deserialize_result_object(ser_channel_state);
+        let channel_state_result: Result<ChannelMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_state);
         let mut channel_state = handle_errors!(channel_state_result);
 
         // Deserialize session_id
@@ -722,8 +808,8 @@ pub mod ffishim_mpc {
         pay_token_mask_com_ar.copy_from_slice(pay_token_mask_com.as_slice());
 
         // Deserialize the merch_state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
         // get connection to the database
@@ -774,8 +860,8 @@ pub mod ffishim_mpc {
         session_id_ar.copy_from_slice(session_id.as_slice());
 
         // Deserialize the merch_state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
         // get connection to the database
@@ -799,6 +885,32 @@ pub mod ffishim_mpc {
         cser.into_raw()
     }
 
+    #[no_mangle]
+    pub extern "C" fn mpc_merchant_expire_sessions(ser_merch_state: *mut c_char) -> *mut c_char {
+        // Deserialize the merch_state
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
+        let merch_state = handle_errors!(merch_state_result);
+
+        // get connection to the database
+        let mut db: RedisDatabase =
+            handle_errors!(RedisDatabase::new("mpc", merch_state.db_url.clone()));
+
+        let result = mpc::merchant_expire_sessions(&mut db as &mut dyn StateDatabase, &merch_state);
+        let expired_sessions: Vec<String> = handle_errors!(result)
+            .iter()
+            .map(|s| hex::encode(s))
+            .collect();
+        let ser = [
+            "{\'expired_sessions\':\'",
+            serde_json::to_string(&expired_sessions).unwrap().as_str(),
+            "\'}",
+        ]
+        .concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
     #[no_mangle]
     pub extern "C" fn mpc_pay_unmask_sigs_customer(
         ser_channel_state: *mut c_char,
@@ -807,13 +919,13 @@ pub mod ffishim_mpc {
         ser_cust_state: *mut c_char,
     ) -> *mut c_char {
         // Deserialize the channel_state
-        let channel_state_result: ResultSerdeType<ChannelMPCState> =
-            deserialize_result_object(ser_channel_state);
+        let channel_state_result: Result<ChannelMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_state);
         let channel_state = handle_errors!(channel_state_result);
 
         // Deserialize the ChannelToken
-        let channel_token_result: ResultSerdeType<ChannelMPCToken> =
-            deserialize_result_object(ser_channel_token);
+        let channel_token_result: Result<ChannelMPCToken, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_token);
         let channel_token = handle_errors!(channel_token_result);
 
         // Deserialize masked_tx_inputs
@@ -822,8 +934,8 @@ pub mod ffishim_mpc {
         let masked_tx_inputs = handle_errors!(masked_tx_inputs_result);
 
         // Deserialize the cust_state
-        let cust_state_result: ResultSerdeType<CustomerMPCState> =
-            deserialize_result_object(ser_cust_state);
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
         let mut cust_state = handle_errors!(cust_state_result);
 
         // We change the channel state
@@ -864,8 +976,8 @@ pub mod ffishim_mpc {
         let revoked_state = handle_errors!(revoked_state_result);
 
         // Deserialize the merch_state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
         // get connection to the database
@@ -915,8 +1027,8 @@ pub mod ffishim_mpc {
         pt_mask_r_ar.copy_from_slice(pt_mask_r.as_slice());
 
         // Deserialize the cust_state
-        let cust_state_result: ResultSerdeType<CustomerMPCState> =
-            deserialize_result_object(ser_cust_state);
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
         let mut cust_state = handle_errors!(cust_state_result);
 
         // We change the channel state
@@ -938,13 +1050,55 @@ pub mod ffishim_mpc {
     }
 
     // Change customer state to open (after escrow-tx confirmed on chain)
+    #[no_mangle]
+    pub extern "C" fn mpc_pay_rollback_customer(ser_cust_state: *mut c_char) -> *mut c_char {
+        // Deserialize the cust_state
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
+        let mut cust_state = handle_errors!(cust_state_result);
+
+        let state = handle_errors!(mpc::pay_rollback_customer(&mut cust_state));
+        let ser = [
+            "{\'state\':\'",
+            serde_json::to_string(&state).unwrap().as_str(),
+            "\', \'cust_state\':\'",
+            serde_json::to_string(&cust_state).unwrap().as_str(),
+            "\'}",
+        ]
+        .concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
+    #[no_mangle]
+    pub extern "C" fn mpc_pay_resume_customer(ser_cust_state: *mut c_char) -> *mut c_char {
+        // Deserialize the cust_state
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
+        let cust_state = handle_errors!(cust_state_result);
+
+        let (step, session_id, rev_state) = handle_errors!(mpc::pay_resume_customer(&cust_state));
+        let ser = [
+            "{\'step\':\'",
+            serde_json::to_string(&step).unwrap().as_str(),
+            "\', \'session_id\':\'",
+            &hex::encode(session_id),
+            "\', \'rev_state\':\'",
+            serde_json::to_string(&rev_state).unwrap().as_str(),
+            "\'}",
+        ]
+        .concat();
+        let cser = CString::new(ser).unwrap();
+        cser.into_raw()
+    }
+
     #[no_mangle]
     pub extern "C" fn cust_change_channel_status_to_open(
         ser_cust_state: *mut c_char,
     ) -> *mut c_char {
         // Deserialize the cust_state
-        let cust_state_result: ResultSerdeType<CustomerMPCState> =
-            deserialize_result_object(ser_cust_state);
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
         let mut cust_state = handle_errors!(cust_state_result);
 
         handle_errors!(mpc::customer_mark_open_channel(&mut cust_state));
@@ -965,8 +1119,8 @@ pub mod ffishim_mpc {
         ser_cust_state: *mut c_char,
     ) -> *mut c_char {
         // Deserialize the cust_state
-        let cust_state_result: ResultSerdeType<CustomerMPCState> =
-            deserialize_result_object(ser_cust_state);
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
         let mut cust_state = handle_errors!(cust_state_result);
 
         handle_errors!(cust_state.change_channel_status(ChannelStatus::PendingClose));
@@ -987,8 +1141,8 @@ pub mod ffishim_mpc {
         ser_cust_state: *mut c_char,
     ) -> *mut c_char {
         // Deserialize the cust_state
-        let cust_state_result: ResultSerdeType<CustomerMPCState> =
-            deserialize_result_object(ser_cust_state);
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
         let mut cust_state = handle_errors!(cust_state_result);
 
         handle_errors!(cust_state.change_channel_status(ChannelStatus::ConfirmedClose));
@@ -1006,8 +1160,8 @@ pub mod ffishim_mpc {
     #[no_mangle]
     pub extern "C" fn cust_clear_channel_status(ser_cust_state: *mut c_char) -> *mut c_char {
         // Deserialize the cust_state
-        let cust_state_result: ResultSerdeType<CustomerMPCState> =
-            deserialize_result_object(ser_cust_state);
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
         let mut cust_state = handle_errors!(cust_state_result);
 
         handle_errors!(cust_state.change_channel_status(ChannelStatus::None));
@@ -1035,11 +1189,16 @@ pub mod ffishim_mpc {
         escrow_txid_le.copy_from_slice(escrow_txid_le_vec.as_slice());
 
         // Deserialize the merch_state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
+        // get connection to the database
+        let mut db: RedisDatabase =
+            handle_errors!(RedisDatabase::new("mpc", merch_state.db_url.clone()));
+
         handle_errors!(mpc::merchant_mark_open_channel(
+            &mut db as &mut dyn StateDatabase,
             escrow_txid_le,
             &mut merch_state
         ));
@@ -1070,13 +1229,19 @@ pub mod ffishim_mpc {
         escrow_txid_be.reverse();
 
         // Deserialize the merch state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
-        let mut merch_state = handle_errors!(merch_state_result);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
+        let merch_state = handle_errors!(merch_state_result);
 
-        handle_errors!(
-            merch_state.change_channel_status(escrow_txid_be, ChannelStatus::PendingClose)
-        );
+        // get connection to the database
+        let mut db: RedisDatabase =
+            handle_errors!(RedisDatabase::new("mpc", merch_state.db_url.clone()));
+
+        handle_errors!(merch_state.change_channel_status(
+            &mut db as &mut dyn StateDatabase,
+            escrow_txid_be,
+            ChannelStatus::PendingClose
+        ));
 
         let ser = [
             "{\'merch_state\':\'",
@@ -1104,13 +1269,19 @@ pub mod ffishim_mpc {
         escrow_txid_be.reverse();
 
         // Deserialize the merch state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
-        let mut merch_state = handle_errors!(merch_state_result);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
+        let merch_state = handle_errors!(merch_state_result);
 
-        handle_errors!(
-            merch_state.change_channel_status(escrow_txid_be, ChannelStatus::ConfirmedClose)
-        );
+        // get connection to the database
+        let mut db: RedisDatabase =
+            handle_errors!(RedisDatabase::new("mpc", merch_state.db_url.clone()));
+
+        handle_errors!(merch_state.change_channel_status(
+            &mut db as &mut dyn StateDatabase,
+            escrow_txid_be,
+            ChannelStatus::ConfirmedClose
+        ));
 
         let ser = [
             "{\'merch_state\':\'",
@@ -1137,11 +1308,19 @@ pub mod ffishim_mpc {
         escrow_txid_be.reverse();
 
         // Deserialize the merch state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
-        let mut merch_state = handle_errors!(merch_state_result);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
+        let merch_state = handle_errors!(merch_state_result);
+
+        // get connection to the database
+        let mut db: RedisDatabase =
+            handle_errors!(RedisDatabase::new("mpc", merch_state.db_url.clone()));
 
-        handle_errors!(merch_state.change_channel_status(escrow_txid_be, ChannelStatus::None));
+        handle_errors!(merch_state.change_channel_status(
+            &mut db as &mut dyn StateDatabase,
+            escrow_txid_be,
+            ChannelStatus::None
+        ));
 
         let ser = [
             "{\'merch_state\':\'",
@@ -1163,18 +1342,18 @@ pub mod ffishim_mpc {
         ser_cust_state: *mut c_char,
     ) -> *mut c_char {
         // Deserialize the channel_state
-        let channel_state_result: ResultSerdeType<ChannelMPCState> =
-            deserialize_result_object(ser_channel_state);
+        let channel_state_result: Result<ChannelMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_state);
         let channel_state = handle_errors!(channel_state_result);
 
         // Deserialize the ChannelToken
-        let channel_token_result: ResultSerdeType<ChannelMPCToken> =
-            deserialize_result_object(ser_channel_token);
+        let channel_token_result: Result<ChannelMPCToken, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_token);
         let channel_token = handle_errors!(channel_token_result);
 
         // Deserialize the cust_state
-        let cust_state_result: ResultSerdeType<CustomerMPCState> =
-            deserialize_result_object(ser_cust_state);
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
         let mut cust_state = handle_errors!(cust_state_result);
 
         let mut from_escrow = false;
@@ -1215,12 +1394,17 @@ pub mod ffishim_mpc {
         escrow_txid_be.reverse();
 
         // Deserialize the merch_state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
         let mut merch_state = handle_errors!(merch_state_result);
 
+        // get connection to the database
+        let mut db: RedisDatabase =
+            handle_errors!(RedisDatabase::new("mpc", merch_state.db_url.clone()));
+
         // use channel token to retrieve initial channel params, then generate the merch-close-tx and sign it
         let (signed_tx, txid_be, txid_le) = handle_errors!(mpc::force_merchant_close(
+            &mut db as &mut dyn StateDatabase,
             &escrow_txid_be,
             val_cpfp,
             &mut merch_state
@@ -1253,8 +1437,8 @@ pub mod ffishim_mpc {
         let rev_lock_hex = hex::encode(&_rev_lock);
 
         // Deserialize the merch_state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
         let merch_state = handle_errors!(merch_state_result);
 
         // get connection to the database
@@ -1334,7 +1518,8 @@ pub mod ffishim_mpc {
                         Some(&change_pk),
                         change_pk_is_hash,
                         tx_fee
-                    ));
+                    )
+                    .map_err(tx_error));
                 let ser = [
                     "{\'signed_tx\':\'",
                     &hex::encode(signed_tx),
@@ -1363,7 +1548,8 @@ pub mod ffishim_mpc {
                         Some(&change_pk),
                         change_pk_is_hash,
                         tx_fee
-                    ));
+                    )
+                    .map_err(tx_error));
                 let ser = [
                     "{\'txid_be\':\'",
                     &hex::encode(txid_be),
@@ -1413,6 +1599,7 @@ pub mod ffishim_mpc {
         let mut self_delay_be = [0u8; 2];
         self_delay_be.copy_from_slice(&self_delay);
 
+        // the merch-close-tx only pays to scripts, so its preimage is the same on every network
         let (merch_tx_preimage, _) = handle_errors!(
             zkchan_tx::transactions::btc::merchant_form_close_transaction::<Testnet>(
                 escrow_txid_be,
@@ -1425,6 +1612,7 @@ pub mod ffishim_mpc {
                 val_cpfp,
                 self_delay_be
             )
+            .map_err(tx_error)
         );
 
         let ser = [
@@ -1453,7 +1641,8 @@ pub mod ffishim_mpc {
         let cust_sig = handle_errors!(zkchan_tx::txutil::customer_sign_merch_close_transaction(
             &cust_sk,
             &merch_tx_preimage
-        ));
+        )
+        .map_err(tx_error));
         let ser = ["{\'cust_sig\':\'", &hex::encode(cust_sig), "\'}"].concat();
         let cser = CString::new(ser).unwrap();
         cser.into_raw()
@@ -1489,13 +1678,18 @@ pub mod ffishim_mpc {
         let cust_sig = handle_errors!(cust_sig_result);
 
         // Deserialize the merch_state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
-        let mut merch_state = handle_errors!(merch_state_result);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
+        let merch_state = handle_errors!(merch_state_result);
+
+        // get connection to the database
+        let mut db: RedisDatabase =
+            handle_errors!(RedisDatabase::new("mpc", merch_state.db_url.clone()));
 
         let merch_pk = merch_state.pk_m.serialize().to_vec();
         let merch_close_pk = merch_state.payout_pk.serialize().to_vec();
 
+        // the merch-close-tx only pays to scripts, so its preimage (and txid) is the same on every network
         let (merch_tx_preimage, tx_params) = handle_errors!(
             zkchan_tx::transactions::btc::merchant_form_close_transaction::<Testnet>(
                 escrow_txid_be.clone(),
@@ -1508,15 +1702,18 @@ pub mod ffishim_mpc {
                 val_cpfp,
                 self_delay_be
             )
+            .map_err(tx_error)
         );
 
         let is_ok = handle_errors!(zkchan_tx::txutil::merchant_verify_merch_close_transaction(
             &merch_tx_preimage,
             &cust_sig,
             &cust_pk
-        ));
+        )
+        .map_err(tx_error));
         if is_ok {
-            merch_state.store_merch_close_tx(
+            handle_errors!(merch_state.store_merch_close_tx(
+                &mut db as &mut dyn StateDatabase,
                 &escrow_txid_be,
                 &cust_pk,
                 cust_bal_sats,
@@ -1524,15 +1721,16 @@ pub mod ffishim_mpc {
                 fee_mc,
                 self_delay_be,
                 &cust_sig,
-            );
+            ));
         } else {
-            return error_message(
+            return error_message_with_code(
                 "could not validate customer signature on the merch-close-tx".to_string(),
+                ZkChannelError::TransactionError(String::new()).code(),
             );
         }
 
         let (txid_be, prevout) = handle_errors!(
-            zkchan_tx::txutil::merchant_generate_transaction_id(tx_params)
+            zkchan_tx::txutil::merchant_generate_transaction_id(tx_params).map_err(tx_error)
         );
         let mut txid_le = txid_be.to_vec();
         txid_le.reverse();
@@ -1591,21 +1789,35 @@ pub mod ffishim_mpc {
         self_delay_be.copy_from_slice(&self_delay);
 
         // Deserialize the merch_state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
         let merch_state = handle_errors!(merch_state_result);
 
-        let (escrow_sig, merch_sig) = handle_errors!(merch_state
-            .sign_initial_closing_transaction::<Testnet>(
-                funding_tx,
-                rl,
-                cust_pk,
-                cust_close_pk,
-                self_delay_be,
-                fee_cc,
-                fee_mc,
-                val_cpfp,
-            ));
+        let (escrow_sig, merch_sig) = match merch_state.get_network() {
+            BitcoinNetworkType::Mainnet => handle_errors!(merch_state
+                .sign_initial_closing_transaction::<Mainnet>(
+                    funding_tx,
+                    rl,
+                    cust_pk,
+                    cust_close_pk,
+                    self_delay_be,
+                    fee_cc,
+                    fee_mc,
+                    val_cpfp,
+                )),
+            BitcoinNetworkType::Testnet | BitcoinNetworkType::Regtest => {
+                handle_errors!(merch_state.sign_initial_closing_transaction::<Testnet>(
+                    funding_tx,
+                    rl,
+                    cust_pk,
+                    cust_close_pk,
+                    self_delay_be,
+                    fee_cc,
+                    fee_mc,
+                    val_cpfp,
+                ))
+            }
+        };
 
         let ser = [
             "{\'escrow_sig\': \'",
@@ -1640,13 +1852,13 @@ pub mod ffishim_mpc {
         let tx_fee_info = handle_errors!(tx_fee_info_result);
 
         // Deserialize the channel_state
-        let channel_state_result: ResultSerdeType<ChannelMPCState> =
-            deserialize_result_object(ser_channel_state);
+        let channel_state_result: Result<ChannelMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_state);
         let channel_state = handle_errors!(channel_state_result);
 
         // Deserialize the ChannelToken
-        let channel_token_result: ResultSerdeType<ChannelMPCToken> =
-            deserialize_result_object(ser_channel_token);
+        let channel_token_result: Result<ChannelMPCToken, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_token);
         let mut channel_token = handle_errors!(channel_token_result);
 
         // Deserialize escrow-sig & merch-sig
@@ -1657,10 +1869,13 @@ pub mod ffishim_mpc {
         let merch_sig = handle_errors!(merch_sig_result);
 
         // Deserialize the cust_state
-        let cust_state_result: ResultSerdeType<CustomerMPCState> =
-            deserialize_result_object(ser_cust_state);
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
         let mut cust_state = handle_errors!(cust_state_result);
 
+        handle_errors!(channel_state.check_network(channel_token.network));
+        handle_errors!(channel_state.check_network(cust_state.get_network()));
+
         handle_errors!(cust_state.set_initial_cust_state(
             &mut channel_token,
             &funding_tx,
@@ -1668,12 +1883,24 @@ pub mod ffishim_mpc {
         ));
 
         // now sign the customer's initial closing txs iff escrow-sig and merch-sig are valid
-        let got_close_tx = handle_errors!(cust_state.sign_initial_closing_transaction::<Testnet>(
-            &channel_state,
-            &channel_token,
-            &escrow_sig,
-            &merch_sig
-        ));
+        let got_close_tx = match channel_state.get_network() {
+            BitcoinNetworkType::Mainnet => {
+                handle_errors!(cust_state.sign_initial_closing_transaction::<Mainnet>(
+                    &channel_state,
+                    &channel_token,
+                    &escrow_sig,
+                    &merch_sig
+                ))
+            }
+            BitcoinNetworkType::Testnet | BitcoinNetworkType::Regtest => {
+                handle_errors!(cust_state.sign_initial_closing_transaction::<Testnet>(
+                    &channel_state,
+                    &channel_token,
+                    &escrow_sig,
+                    &merch_sig
+                ))
+            }
+        };
         let ser = [
             "{\'is_ok\':",
             serde_json::to_string(&got_close_tx).unwrap().as_str(),
@@ -1730,9 +1957,13 @@ pub mod ffishim_mpc {
         self_delay_be.copy_from_slice(&self_delay);
 
         // Deserialize the merch_state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
-        let mut merch_state = handle_errors!(merch_state_result);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
+        let merch_state = handle_errors!(merch_state_result);
+
+        // get connection to the database
+        let mut db: RedisDatabase =
+            handle_errors!(RedisDatabase::new("mpc", merch_state.db_url.clone()));
 
         let merch_disp_pk = merch_state.dispute_pk.serialize().to_vec();
         let merch_disp_sk = merch_state.get_dispute_secret_key();
@@ -1753,12 +1984,15 @@ pub mod ffishim_mpc {
             cust_close_pk,
             merch_disp_pk,
             merch_disp_sk
-        ));
+        )
+        .map_err(tx_error));
 
         // if signed_tx successfully created, then proceed with updating the channel status
-        handle_errors!(
-            merch_state.change_channel_status(escrow_txid_be, ChannelStatus::PendingClose)
-        );
+        handle_errors!(merch_state.change_channel_status(
+            &mut db as &mut dyn StateDatabase,
+            escrow_txid_be,
+            ChannelStatus::PendingClose
+        ));
 
         let ser = [
             "{\'signed_tx\': \'",
@@ -1789,8 +2023,8 @@ pub mod ffishim_mpc {
         let output_pk = handle_errors!(output_pk_result);
 
         // Deserialize the merch_state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
         let merch_state = handle_errors!(merch_state_result);
 
         let merch_close_sk = merch_state.get_close_secret_key();
@@ -1804,6 +2038,7 @@ pub mod ffishim_mpc {
                 output_pk,
                 merch_close_sk
             )
+            .map_err(tx_error)
         );
         let ser = ["{\'signed_tx\': \'", &hex::encode(signed_tx), "\'}"].concat();
         let cser = CString::new(ser).unwrap();
@@ -1838,8 +2073,8 @@ pub mod ffishim_mpc {
         let output_pk = handle_errors!(output_pk_result);
 
         // Deserialize the merch_state
-        let merch_state_result: ResultSerdeType<MerchantMPCState> =
-            deserialize_result_object(ser_merch_state);
+        let merch_state_result: Result<MerchantMPCState, ZkChannelError> =
+            deserialize_merchant_state(ser_merch_state);
         let merch_state = handle_errors!(merch_state_result);
 
         let merch_pk = merch_state.pk_m.serialize().to_vec();
@@ -1859,6 +2094,7 @@ pub mod ffishim_mpc {
                 merch_close_pk,
                 merch_close_sk
             )
+            .map_err(tx_error)
         );
         let ser = ["{\'signed_tx\': \'", &hex::encode(signed_tx), "\'}"].concat();
         let cser = CString::new(ser).unwrap();
@@ -1880,8 +2116,8 @@ pub mod ffishim_mpc {
         ser_cust_state: *mut c_char,
     ) -> *mut c_char {
         // Deserialize the channel_state
-        let channel_state_result: ResultSerdeType<ChannelMPCState> =
-            deserialize_result_object(ser_channel_state);
+        let channel_state_result: Result<ChannelMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_channel_state);
         let channel_state = handle_errors!(channel_state_result);
 
         let txid_result = deserialize_hex_string(ser_tx_index);
@@ -1903,8 +2139,8 @@ pub mod ffishim_mpc {
         self_delay_be.copy_from_slice(&self_delay);
 
         // Deserialize the cust_state
-        let cust_state_result: ResultSerdeType<CustomerMPCState> =
-            deserialize_result_object(ser_cust_state);
+        let cust_state_result: Result<CustomerMPCState, ZkChannelError> =
+            deserialize_persisted_state(ser_cust_state);
         let cust_state = handle_errors!(cust_state_result);
 
         let merch_disp_pk = match channel_state.merch_dispute_pk {
@@ -1930,6 +2166,7 @@ pub mod ffishim_mpc {
                 merch_disp_pk,
                 cust_sk
             )
+            .map_err(tx_error)
         );
         let ser = ["{\'signed_tx\': \'", &hex::encode(signed_tx), "\'}"].concat();
         let cser = CString::new(ser).unwrap();
@@ -1990,7 +2227,8 @@ pub mod ffishim_mpc {
                 cust_amount,
                 merch_amount,
                 &cust_escrow_sk,
-            ));
+            )
+            .map_err(tx_error));
 
         let ser = ["{\'cust_sig\': \'", &hex::encode(cust_signature), "\'}"].concat();
         let cser = CString::new(ser).unwrap();
@@ -2055,7 +2293,8 @@ pub mod ffishim_mpc {
                 merch_amount,
                 &cust_sig,
                 &merch_escrow_sk,
-            ));
+            )
+            .map_err(tx_error));
         let ser = [
             "{\'signed_tx\': \'",
             &hex::encode(signed_tx),
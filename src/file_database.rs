@@ -0,0 +1,1288 @@
+use super::*;
+use channels_mpc::{
+    ChannelMPCState, ChannelMPCToken, ChannelStatus, MerchCloseTx, MerchantMPCState,
+};
+use customer_wallet::CustomerWallet;
+use database::{
+    decode_cust_wallet, decode_merchant_state, encode_cust_wallet, encode_merchant_state,
+    get_record_name, BatchCheck, BatchWrite, DbBatch, MaskedMPCInputs, SessionState, StateDatabase,
+    WalletDatabase, MERCH_STATE_FIELD,
+};
+use encryption::{open_record, reseal_record, seal_record, StorageKey};
+use errors::ZkChannelError;
+use event_log::{EventKey, EventRecord};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use wallet::State;
+
+// an entry of the log (a single line of json)
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum LogEntry {
+    Hset {
+        key: String,
+        field: String,
+        value: String,
+    },
+    Hdel {
+        key: String,
+        field: String,
+    },
+    Sadd {
+        key: String,
+        member: String,
+    },
+    Srem {
+        key: String,
+        member: String,
+    },
+    Rpush {
+        key: String,
+        value: String,
+    },
+    Del {
+        key: String,
+    },
+    Batch {
+        entries: Vec<LogEntry>,
+    },
+}
+
+fn file_error(context: &str, path: &Path, e: std::io::Error) -> ZkChannelError {
+    ZkChannelError::DatabaseUnavailable(format!("{} {}: {}", context, path.display(), e))
+}
+
+// the lock is released when the file is closed, so a crashed process never leaves it behind
+fn lock_file(path: &Path) -> Result<File, ZkChannelError> {
+    let lock = match OpenOptions::new().write(true).create(true).open(path) {
+        Ok(f) => f,
+        Err(e) => return Err(file_error("could not open", path, e)),
+    };
+    if unsafe { libc::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        return Err(file_error(
+            "could not lock",
+            path,
+            std::io::Error::last_os_error(),
+        ));
+    }
+    Ok(lock)
+}
+
+// a rename is only durable once the directory that holds the file is synced
+fn sync_parent_dir(path: &Path) -> Result<(), ZkChannelError> {
+    let dir = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    match File::open(dir).and_then(|d| d.sync_all()) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(file_error("could not sync", dir, e)),
+    }
+}
+
+///
+/// FileDatabase is an embedded StateDatabase that needs no external service. It keeps the same
+/// hashes and sets as RedisDatabase (under the same key names) in memory and appends every write
+/// to a log file as a line of json. The log is synced before a write is applied and replayed when
+/// the database is opened. Writes that span several records are logged as a single batch, so they
+/// are either replayed in full or not at all. A log can only be opened once at a time: open() takes
+/// an exclusive lock on the lock file "{path}.lock" that is held until the database is dropped.
+///
+pub struct FileDatabase {
+    path: PathBuf,
+    log: File,
+    // held for the lifetime of the database
+    _lock: File,
+    hashes: HashMap<String, HashMap<String, String>>,
+    sets: HashMap<String, HashSet<String>>,
+    lists: HashMap<String, Vec<String>>,
+    session_map_key: String,
+    nonce_to_session_key: String,
+    unlink_set_key: String,
+    spent_map_key: String,
+    rev_lock_map_key: String,
+    nonce_mask_map_key: String,
+    masked_bytes_key: String,
+    channel_status_key: String,
+    activate_map_key: String,
+    close_tx_map_key: String,
+    merch_txid_map_key: String,
+    refund_id_map_key: String,
+    cust_wallet_key: String,
+    merch_state_key: String,
+    merch_channel_key: String,
+    event_list_prefix: String,
+    // seals rev secrets, customer wallets and stored files at rest (if set)
+    storage_key: Option<StorageKey>,
+}
+
+impl FileDatabase {
+    ///
+    /// open() - opens (or creates) the log file at path and replays it. A partially written
+    /// last line (e.g., after a crash) is discarded. Fails if the log is open elsewhere
+    /// output: file database
+    ///
+    pub fn open(prefix: &str, path: &Path) -> Result<Self, ZkChannelError> {
+        let mut lock_name = path.as_os_str().to_owned();
+        lock_name.push(".lock");
+        let lock = lock_file(Path::new(&lock_name))?;
+        let log = match OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+        {
+            Ok(f) => f,
+            Err(e) => return Err(file_error("could not open", path, e)),
+        };
+        let mut db = FileDatabase {
+            path: path.to_path_buf(),
+            log: log,
+            _lock: lock,
+            hashes: HashMap::new(),
+            sets: HashMap::new(),
+            lists: HashMap::new(),
+            session_map_key: format!("{}:hashmap:session", prefix),
+            unlink_set_key: format!("{}:hashset:unlink", prefix),
+            spent_map_key: format!("{}:hashmap:spent", prefix),
+            rev_lock_map_key: format!("{}:hashmap:revlock", prefix),
+            nonce_to_session_key: format!("{}:hashmap:nonce_session", prefix),
+            nonce_mask_map_key: format!("{}:hashmap:nonce_paymasks", prefix),
+            masked_bytes_key: format!("{}:hashmap:masked_bytes", prefix),
+            channel_status_key: format!("{}:hashmap:channel_status", prefix),
+            activate_map_key: format!("{}:hashmap:activate", prefix),
+            close_tx_map_key: format!("{}:hashmap:close_tx", prefix),
+            merch_txid_map_key: format!("{}:hashmap:merch_txid", prefix),
+            refund_id_map_key: format!("{}:hashmap:refund_ids", prefix),
+            cust_wallet_key: format!("{}:hashmap:cust_wallet", prefix),
+            merch_state_key: format!("{}:hashmap:merch_state", prefix),
+            merch_channel_key: format!("{}:hashmap:merch_channels", prefix),
+            event_list_prefix: format!("{}:list:events", prefix),
+            storage_key: None,
+        };
+        db.replay()?;
+        Ok(db)
+    }
+
+    fn replay(&mut self) -> Result<(), ZkChannelError> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(c) => c,
+            Err(e) => return Err(file_error("could not read", &self.path, e)),
+        };
+        // every complete entry ends with a newline
+        let complete_len = match content.rfind('\n') {
+            Some(i) => i + 1,
+            None => 0,
+        };
+        for (i, line) in content[..complete_len].lines().enumerate() {
+            if line.is_empty() {
+                continue;
+            }
+            let entry: LogEntry = match serde_json::from_str(line) {
+                Ok(e) => e,
+                Err(e) => {
+                    return Err(ZkChannelError::SerializationError(format!(
+                        "corrupt entry at line {} of {}: {}",
+                        i + 1,
+                        self.path.display(),
+                        e
+                    )))
+                }
+            };
+            self.apply(entry);
+        }
+        if complete_len < content.len() {
+            if let Err(e) = self.log.set_len(complete_len as u64) {
+                return Err(file_error("could not truncate", &self.path, e));
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, entry: LogEntry) {
+        match entry {
+            LogEntry::Hset { key, field, value } => {
+                self.hashes
+                    .entry(key)
+                    .or_insert_with(HashMap::new)
+                    .insert(field, value);
+            }
+            LogEntry::Hdel { key, field } => {
+                if let Some(h) = self.hashes.get_mut(&key) {
+                    h.remove(&field);
+                }
+            }
+            LogEntry::Sadd { key, member } => {
+                self.sets
+                    .entry(key)
+                    .or_insert_with(HashSet::new)
+                    .insert(member);
+            }
+            LogEntry::Srem { key, member } => {
+                if let Some(s) = self.sets.get_mut(&key) {
+                    s.remove(&member);
+                }
+            }
+            LogEntry::Rpush { key, value } => {
+                self.lists.entry(key).or_insert_with(Vec::new).push(value);
+            }
+            LogEntry::Del { key } => {
+                self.hashes.remove(&key);
+                self.sets.remove(&key);
+                self.lists.remove(&key);
+            }
+            LogEntry::Batch { entries } => {
+                for e in entries {
+                    self.apply(e);
+                }
+            }
+        }
+    }
+
+    // appends the entry to the log (and syncs it) before applying it
+    fn write(&mut self, entry: LogEntry) -> Result<(), ZkChannelError> {
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        let log_len = match self.log.metadata() {
+            Ok(m) => m.len(),
+            Err(e) => return Err(file_error("could not stat", &self.path, e)),
+        };
+        if let Err(e) = self
+            .log
+            .write_all(line.as_bytes())
+            .and_then(|_| self.log.sync_data())
+        {
+            // drop a partially written entry, so later entries are not appended to it
+            let _ = self.log.set_len(log_len);
+            return Err(file_error("could not append to", &self.path, e));
+        }
+        self.apply(entry);
+        Ok(())
+    }
+
+    fn hget(&self, key: &String, field: &String) -> Option<&String> {
+        match self.hashes.get(key) {
+            Some(h) => h.get(field),
+            None => None,
+        }
+    }
+
+    fn hget_or_not_found(
+        &self,
+        context: &str,
+        key: &String,
+        field: &String,
+    ) -> Result<String, ZkChannelError> {
+        match self.hget(key, field) {
+            Some(s) => Ok(s.clone()),
+            None => Err(ZkChannelError::NotFound(format!(
+                "{}: key({}) field({})",
+                context, key, field
+            ))),
+        }
+    }
+
+    fn hexists(&self, key: &String, field: &String) -> bool {
+        self.hget(key, field).is_some()
+    }
+
+    fn hkeys(&self, key: &String) -> Vec<String> {
+        match self.hashes.get(key) {
+            Some(h) => h.keys().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // output: true if the field is new
+    fn hset(
+        &mut self,
+        key: &String,
+        field: &String,
+        value: String,
+    ) -> Result<bool, ZkChannelError> {
+        let is_new = !self.hexists(key, field);
+        self.write(LogEntry::Hset {
+            key: key.clone(),
+            field: field.clone(),
+            value: value,
+        })?;
+        Ok(is_new)
+    }
+
+    // only sets the field if it does not exist yet
+    fn hset_nx(
+        &mut self,
+        key: &String,
+        field: &String,
+        value: String,
+    ) -> Result<bool, ZkChannelError> {
+        if self.hexists(key, field) {
+            return Ok(false);
+        }
+        self.hset(key, field, value)
+    }
+
+    // output: true if the field existed
+    fn hdel(&mut self, key: &String, field: &String) -> Result<bool, ZkChannelError> {
+        if !self.hexists(key, field) {
+            return Ok(false);
+        }
+        self.write(LogEntry::Hdel {
+            key: key.clone(),
+            field: field.clone(),
+        })?;
+        Ok(true)
+    }
+
+    fn set_contains(&self, key: &String, member: &String) -> bool {
+        match self.sets.get(key) {
+            Some(s) => s.contains(member),
+            None => false,
+        }
+    }
+
+    pub fn set_storage_key(&mut self, storage_key: StorageKey) {
+        self.storage_key = Some(storage_key);
+    }
+
+    ///
+    /// rotate_storage_key() - re-encrypts the rev secrets and customer wallets with the new storage key
+    /// (files stored with store_file() are re-encrypted with reencrypt_files())
+    /// output: number of re-encrypted records
+    ///
+    pub fn rotate_storage_key(&mut self, new_key: StorageKey) -> Result<usize, ZkChannelError> {
+        let keys = vec![
+            self.rev_lock_map_key.clone(),
+            self.cust_wallet_key.clone(),
+            self.merch_state_key.clone(),
+            self.merch_channel_key.clone(),
+        ];
+        let old_key = self.storage_key.clone();
+        let count = self.reseal_hashes(&keys, old_key.as_ref(), &new_key)?;
+        self.storage_key = Some(new_key);
+        Ok(count)
+    }
+
+    ///
+    /// reencrypt_files() - re-encrypts all files stored under key with the new storage key. The
+    /// storage key of the database is not changed
+    /// output: number of re-encrypted files
+    ///
+    pub fn reencrypt_files(
+        &mut self,
+        key: &String,
+        old_key: Option<&StorageKey>,
+        new_key: &StorageKey,
+    ) -> Result<usize, ZkChannelError> {
+        self.reseal_hashes(&vec![key.clone()], old_key, new_key)
+    }
+
+    fn reseal_hashes(
+        &mut self,
+        keys: &Vec<String>,
+        old_key: Option<&StorageKey>,
+        new_key: &StorageKey,
+    ) -> Result<usize, ZkChannelError> {
+        // every record is opened before any is written, so a wrong old key leaves the log unchanged
+        let mut entries = Vec::new();
+        for key in keys.iter() {
+            if let Some(h) = self.hashes.get(key) {
+                for (field, record) in h.iter() {
+                    let name = get_record_name(key, field);
+                    entries.push(LogEntry::Hset {
+                        key: key.clone(),
+                        field: field.clone(),
+                        value: reseal_record(old_key, new_key, &name, record)?,
+                    });
+                }
+            }
+        }
+        let count = entries.len();
+        if count > 0 {
+            self.write(LogEntry::Batch { entries: entries })?;
+        }
+        Ok(count)
+    }
+
+    ///
+    /// store_file() - stores a (json) blob, e.g. a serialized channel state, in the field of a hash.
+    /// The blob is sealed with the storage key if one is set
+    /// output: true if the field is new
+    ///
+    pub fn store_file(
+        &mut self,
+        key: &String,
+        field_name: &String,
+        json_blob: &String,
+    ) -> Result<bool, ZkChannelError> {
+        let record = seal_record(
+            self.storage_key.as_ref(),
+            &get_record_name(key, field_name),
+            json_blob,
+        )?;
+        self.hset(key, field_name, record)
+    }
+
+    ///
+    /// get_file() - loads a blob that was stored with store_file()
+    /// output: blob
+    ///
+    pub fn get_file(&self, key: &String, field_name: &String) -> Result<String, ZkChannelError> {
+        let record = self.hget_or_not_found("get_file", key, field_name)?;
+        open_record(
+            self.storage_key.as_ref(),
+            &get_record_name(key, field_name),
+            &record,
+        )
+    }
+
+    ///
+    /// get_file_names() - lists the fields of a hash of stored files
+    /// output: field names
+    ///
+    pub fn get_file_names(&self, key: &String) -> Vec<String> {
+        self.hkeys(key)
+    }
+
+    ///
+    /// compact() - rewrites the log so it holds a single entry per record (via a temporary file
+    /// that replaces the log atomically)
+    ///
+    pub fn compact(&mut self) -> Result<(), ZkChannelError> {
+        let mut tmp_name = self.path.as_os_str().to_owned();
+        tmp_name.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_name);
+        let mut content = String::new();
+        for (key, h) in self.hashes.iter() {
+            for (field, value) in h.iter() {
+                content.push_str(&serde_json::to_string(&LogEntry::Hset {
+                    key: key.clone(),
+                    field: field.clone(),
+                    value: value.clone(),
+                })?);
+                content.push('\n');
+            }
+        }
+        for (key, s) in self.sets.iter() {
+            for member in s.iter() {
+                content.push_str(&serde_json::to_string(&LogEntry::Sadd {
+                    key: key.clone(),
+                    member: member.clone(),
+                })?);
+                content.push('\n');
+            }
+        }
+        for (key, l) in self.lists.iter() {
+            for value in l.iter() {
+                content.push_str(&serde_json::to_string(&LogEntry::Rpush {
+                    key: key.clone(),
+                    value: value.clone(),
+                })?);
+                content.push('\n');
+            }
+        }
+        let mut tmp = match File::create(&tmp_path) {
+            Ok(f) => f,
+            Err(e) => return Err(file_error("could not create", &tmp_path, e)),
+        };
+        if let Err(e) = tmp
+            .write_all(content.as_bytes())
+            .and_then(|_| tmp.sync_all())
+        {
+            return Err(file_error("could not write", &tmp_path, e));
+        }
+        if let Err(e) = fs::rename(&tmp_path, &self.path) {
+            return Err(file_error("could not replace", &self.path, e));
+        }
+        sync_parent_dir(&self.path)?;
+        self.log = match OpenOptions::new().read(true).append(true).open(&self.path) {
+            Ok(f) => f,
+            Err(e) => return Err(file_error("could not open", &self.path, e)),
+        };
+        Ok(())
+    }
+}
+
+impl StateDatabase for FileDatabase {
+    // url is the path of the log file
+    fn new(prefix: &str, url: String) -> Result<Self, ZkChannelError> {
+        FileDatabase::open(prefix, Path::new(&url))
+    }
+
+    fn is_connected(&mut self) -> Result<bool, ZkChannelError> {
+        Ok(true)
+    }
+
+    fn check_session_id(&mut self, session_id_hex: &String) -> Result<bool, ZkChannelError> {
+        Ok(self.hexists(&self.session_map_key, session_id_hex))
+    }
+
+    fn save_new_session_state(
+        &mut self,
+        session_id_hex: &String,
+        session_state: &SessionState,
+    ) -> bool {
+        let ser_session_state = match serde_json::to_string(session_state) {
+            Ok(s) => s,
+            Err(_) => return false,
+        };
+        let key = self.session_map_key.clone();
+        match self.hset_nx(&key, session_id_hex, ser_session_state) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("save_new_session_state: {}", e);
+                false
+            }
+        }
+    }
+
+    fn load_session_state(
+        &mut self,
+        session_id_hex: &String,
+    ) -> Result<SessionState, ZkChannelError> {
+        let ser_session_state =
+            self.hget_or_not_found("load_session_state", &self.session_map_key, session_id_hex)?;
+        let t: SessionState = serde_json::from_str(&ser_session_state)?;
+        Ok(t)
+    }
+
+    fn update_session_state(
+        &mut self,
+        session_id_hex: &String,
+        session_state: &SessionState,
+    ) -> bool {
+        let ser_session_state = match serde_json::to_string(session_state) {
+            Ok(s) => s,
+            Err(_) => return false,
+        };
+        let key = self.session_map_key.clone();
+        match self.hset(&key, session_id_hex, ser_session_state) {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!("update_session_state: {}", e);
+                false
+            }
+        }
+    }
+
+    fn clear_session_state(&mut self, session_id_hex: &String) -> bool {
+        let key = self.session_map_key.clone();
+        match self.hdel(&key, session_id_hex) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!(
+                    "clear_session_state: failed to delete key: {} => {}",
+                    session_id_hex, e
+                );
+                false
+            }
+        }
+    }
+
+    fn get_session_ids(&mut self) -> Result<Vec<String>, ZkChannelError> {
+        Ok(self.hkeys(&self.session_map_key))
+    }
+
+    fn update_spent_map(
+        &mut self,
+        nonce_hex: &String,
+        rev_lock_hex: &String,
+    ) -> Result<bool, ZkChannelError> {
+        let key = self.spent_map_key.clone();
+        self.hset(&key, nonce_hex, rev_lock_hex.clone())
+    }
+
+    fn check_spent_map(&mut self, nonce_hex: &String) -> bool {
+        self.hexists(&self.spent_map_key, nonce_hex)
+    }
+
+    fn get_spent_rev_lock(&mut self, nonce_hex: &String) -> Result<String, ZkChannelError> {
+        self.hget_or_not_found("get_spent_rev_lock", &self.spent_map_key, nonce_hex)
+    }
+
+    fn update_rev_lock_map(
+        &mut self,
+        rev_lock_hex: &String,
+        rev_secret_hex: &String,
+    ) -> Result<bool, ZkChannelError> {
+        let key = self.rev_lock_map_key.clone();
+        let record = seal_record(
+            self.storage_key.as_ref(),
+            &get_record_name(&key, rev_lock_hex),
+            rev_secret_hex,
+        )?;
+        self.hset(&key, rev_lock_hex, record)
+    }
+
+    fn check_rev_lock_map(&mut self, rev_lock_hex: &String) -> bool {
+        self.hexists(&self.rev_lock_map_key, rev_lock_hex)
+    }
+
+    fn get_rev_secret(&mut self, rev_lock_hex: &String) -> Result<String, ZkChannelError> {
+        let record =
+            self.hget_or_not_found("get_rev_secret", &self.rev_lock_map_key, rev_lock_hex)?;
+        open_record(
+            self.storage_key.as_ref(),
+            &get_record_name(&self.rev_lock_map_key, rev_lock_hex),
+            &record,
+        )
+    }
+
+    fn update_unlink_set(&mut self, nonce: &String) -> Result<bool, ZkChannelError> {
+        if !self.set_contains(&self.unlink_set_key, nonce) {
+            let key = self.unlink_set_key.clone();
+            self.write(LogEntry::Sadd {
+                key: key,
+                member: nonce.clone(),
+            })?;
+        }
+        Ok(true)
+    }
+
+    fn get_unlink_set(&mut self) -> Result<HashSet<String>, ZkChannelError> {
+        match self.sets.get(&self.unlink_set_key) {
+            Some(s) => Ok(s.clone()),
+            None => Ok(HashSet::new()),
+        }
+    }
+
+    fn is_member_unlink_set(&mut self, nonce: &String) -> bool {
+        self.set_contains(&self.unlink_set_key, nonce)
+    }
+
+    fn remove_from_unlink_set(&mut self, nonce: &String) -> bool {
+        if !self.set_contains(&self.unlink_set_key, nonce) {
+            return false;
+        }
+        let key = self.unlink_set_key.clone();
+        match self.write(LogEntry::Srem {
+            key: key,
+            member: nonce.clone(),
+        }) {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!("remove_from_unlink_set: {} {}", e, nonce);
+                false
+            }
+        }
+    }
+
+    fn check_dup_nonce_to_session_id(
+        &mut self,
+        nonce_hex: &String,
+        session_id_hex: &String,
+    ) -> bool {
+        match self.hget(&self.nonce_to_session_key, nonce_hex) {
+            Some(s) => !s.eq_ignore_ascii_case(session_id_hex),
+            None => false,
+        }
+    }
+
+    fn update_nonce_to_session_id(
+        &mut self,
+        nonce_hex: &String,
+        session_id_hex: &String,
+    ) -> Result<bool, ZkChannelError> {
+        let key = self.nonce_to_session_key.clone();
+        self.hset_nx(&key, nonce_hex, session_id_hex.clone())
+    }
+
+    fn get_session_id_for_nonce(&mut self, nonce_hex: &String) -> Result<String, ZkChannelError> {
+        self.hget_or_not_found(
+            "get_session_id_for_nonce",
+            &self.nonce_to_session_key,
+            nonce_hex,
+        )
+    }
+
+    fn clear_nonce_to_session_id(&mut self, nonce_hex: &String) -> bool {
+        let key = self.nonce_to_session_key.clone();
+        match self.hdel(&key, nonce_hex) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!(
+                    "clear_nonce_to_session_id: failed to delete key: {} => {}",
+                    nonce_hex, e
+                );
+                false
+            }
+        }
+    }
+
+    fn update_nonce_mask_map(
+        &mut self,
+        nonce_hex: &String,
+        mask: [u8; 32],
+        mask_r: [u8; 16],
+    ) -> Result<bool, ZkChannelError> {
+        let mut m = mask.to_vec();
+        m.extend(mask_r.to_vec());
+        let key = self.nonce_mask_map_key.clone();
+        self.hset(&key, nonce_hex, hex::encode(&m))
+    }
+
+    fn get_mask_map_from_nonce(
+        &mut self,
+        nonce_hex: &String,
+    ) -> Result<([u8; 32], [u8; 16]), ZkChannelError> {
+        let s = self.hget_or_not_found(
+            "could not find mask for specified nonce",
+            &self.nonce_mask_map_key,
+            nonce_hex,
+        )?;
+        let t = hex::decode(s)?;
+        if t.len() != 48 {
+            return Err(ZkChannelError::SerializationError(format!(
+                "invalid length for mask: {}",
+                t.len()
+            )));
+        }
+        let mut mask = [0u8; 32];
+        let mut mask_r = [0u8; 16];
+        mask.copy_from_slice(&t[0..32]);
+        mask_r.copy_from_slice(&t[32..48]);
+        Ok((mask, mask_r))
+    }
+
+    fn clear_nonce_mask_map(&mut self, nonce_hex: &String) -> bool {
+        let key = self.nonce_mask_map_key.clone();
+        match self.hdel(&key, nonce_hex) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!(
+                    "clear_nonce_mask_map: failed to delete key: {} => {}",
+                    nonce_hex, e
+                );
+                false
+            }
+        }
+    }
+
+    fn update_masked_mpc_inputs(
+        &mut self,
+        session_id_hex: &String,
+        mask_bytes: MaskedMPCInputs,
+    ) -> bool {
+        let ser_mask_bytes = match serde_json::to_string(&mask_bytes) {
+            Ok(s) => s,
+            Err(_) => return false,
+        };
+        let key = self.masked_bytes_key.clone();
+        match self.hset(&key, session_id_hex, ser_mask_bytes) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!("update_masked_mpc_inputs: {}", e);
+                false
+            }
+        }
+    }
+
+    fn get_masked_mpc_inputs(
+        &mut self,
+        session_id_hex: &String,
+    ) -> Result<MaskedMPCInputs, ZkChannelError> {
+        let ser_masked_bytes = self.hget_or_not_found(
+            "get_masked_mpc_inputs",
+            &self.masked_bytes_key,
+            session_id_hex,
+        )?;
+        let t: MaskedMPCInputs = serde_json::from_str(&ser_masked_bytes)?;
+        Ok(t)
+    }
+
+    fn clear_masked_mpc_inputs(&mut self, session_id_hex: &String) -> bool {
+        let key = self.masked_bytes_key.clone();
+        match self.hdel(&key, session_id_hex) {
+            Ok(c) => c,
+            Err(e) => {
+                eprintln!(
+                    "clear_masked_mpc_inputs: failed to delete key: {} => {}",
+                    session_id_hex, e
+                );
+                false
+            }
+        }
+    }
+
+    fn update_channel_status(
+        &mut self,
+        escrow_txid_hex: &String,
+        status: &ChannelStatus,
+    ) -> Result<bool, ZkChannelError> {
+        let ser_status = serde_json::to_string(status)?;
+        let key = self.channel_status_key.clone();
+        self.hset(&key, escrow_txid_hex, ser_status)
+    }
+
+    fn get_channel_status(
+        &mut self,
+        escrow_txid_hex: &String,
+    ) -> Result<ChannelStatus, ZkChannelError> {
+        let ser_status = self.hget_or_not_found(
+            "get_channel_status",
+            &self.channel_status_key,
+            escrow_txid_hex,
+        )?;
+        let status: ChannelStatus = serde_json::from_str(&ser_status)?;
+        Ok(status)
+    }
+
+    fn update_channel_statuses(
+        &mut self,
+        statuses: &Vec<(String, ChannelStatus)>,
+    ) -> Result<bool, ZkChannelError> {
+        let mut entries = Vec::new();
+        for (escrow_txid_hex, status) in statuses.iter() {
+            entries.push(LogEntry::Hset {
+                key: self.channel_status_key.clone(),
+                field: escrow_txid_hex.clone(),
+                value: serde_json::to_string(status)?,
+            });
+        }
+        // a single batch writes all statuses at once
+        self.write(LogEntry::Batch { entries: entries })?;
+        Ok(true)
+    }
+
+    fn update_activate_map(
+        &mut self,
+        channel_id_hex: &String,
+        s0: &State,
+    ) -> Result<bool, ZkChannelError> {
+        let ser_state = serde_json::to_string(s0)?;
+        let key = self.activate_map_key.clone();
+        self.hset(&key, channel_id_hex, ser_state)
+    }
+
+    fn get_activate_state(&mut self, channel_id_hex: &String) -> Result<State, ZkChannelError> {
+        let ser_state =
+            self.hget_or_not_found("get_activate_state", &self.activate_map_key, channel_id_hex)?;
+        let s0: State = serde_json::from_str(&ser_state)?;
+        Ok(s0)
+    }
+
+    fn update_close_tx_map(
+        &mut self,
+        escrow_txid_hex: &String,
+        merch_close_tx: &MerchCloseTx,
+    ) -> Result<bool, ZkChannelError> {
+        let ser_close_tx = serde_json::to_string(merch_close_tx)?;
+        let key = self.close_tx_map_key.clone();
+        self.hset(&key, escrow_txid_hex, ser_close_tx)
+    }
+
+    fn get_merch_close_tx(
+        &mut self,
+        escrow_txid_hex: &String,
+    ) -> Result<MerchCloseTx, ZkChannelError> {
+        let ser_close_tx = self.hget_or_not_found(
+            "get_merch_close_tx",
+            &self.close_tx_map_key,
+            escrow_txid_hex,
+        )?;
+        let merch_close_tx: MerchCloseTx = serde_json::from_str(&ser_close_tx)?;
+        Ok(merch_close_tx)
+    }
+
+    fn update_merch_txid_map(
+        &mut self,
+        merch_txid_hex: &String,
+        escrow_txid_hex: &String,
+    ) -> Result<bool, ZkChannelError> {
+        let key = self.merch_txid_map_key.clone();
+        self.hset(&key, merch_txid_hex, escrow_txid_hex.clone())
+    }
+
+    fn get_escrow_txid(&mut self, merch_txid_hex: &String) -> Result<String, ZkChannelError> {
+        self.hget_or_not_found("get_escrow_txid", &self.merch_txid_map_key, merch_txid_hex)
+    }
+
+    fn update_refund_id_map(
+        &mut self,
+        refund_id_hex: &String,
+        nonce_hex: &String,
+    ) -> Result<bool, ZkChannelError> {
+        // only set if the refund id has not been used yet
+        let key = self.refund_id_map_key.clone();
+        self.hset_nx(&key, refund_id_hex, nonce_hex.clone())
+    }
+
+    fn get_nonce_for_refund_id(
+        &mut self,
+        refund_id_hex: &String,
+    ) -> Result<String, ZkChannelError> {
+        self.hget_or_not_found(
+            "get_nonce_for_refund_id",
+            &self.refund_id_map_key,
+            refund_id_hex,
+        )
+    }
+
+    fn apply_batch(&mut self, batch: &DbBatch) -> Result<bool, ZkChannelError> {
+        for check in batch.checks.iter() {
+            let passed = match check {
+                BatchCheck::NewSessionId(s) => !self.hexists(&self.session_map_key, s),
+                BatchCheck::NonceNotSpent(n) => !self.hexists(&self.spent_map_key, n),
+                BatchCheck::NonceFreeForSession(n, s) => {
+                    match self.hget(&self.nonce_to_session_key, n) {
+                        Some(t) => t == s,
+                        None => true,
+                    }
+                }
+                BatchCheck::RevLockNotRevoked(r) => !self.hexists(&self.rev_lock_map_key, r),
+            };
+            if !passed {
+                return Ok(false);
+            }
+        }
+        let mut entries = Vec::new();
+        for write in batch.writes.iter() {
+            let (key, field, value) = match write {
+                BatchWrite::SaveSessionState(s, session_state) => (
+                    &self.session_map_key,
+                    s,
+                    serde_json::to_string(session_state)?,
+                ),
+                BatchWrite::UpdateNonceMaskMap(n, mask, mask_r) => {
+                    let mut m = mask.to_vec();
+                    m.extend(mask_r.to_vec());
+                    (&self.nonce_mask_map_key, n, hex::encode(&m))
+                }
+                BatchWrite::UpdateNonceToSessionId(n, s) => {
+                    (&self.nonce_to_session_key, n, s.clone())
+                }
+                BatchWrite::UpdateSpentMap(n, r) => (&self.spent_map_key, n, r.clone()),
+                BatchWrite::UpdateRevLockMap(r, rev_secret) => (
+                    &self.rev_lock_map_key,
+                    r,
+                    seal_record(
+                        self.storage_key.as_ref(),
+                        &get_record_name(&self.rev_lock_map_key, r),
+                        rev_secret,
+                    )?,
+                ),
+                BatchWrite::RemoveFromUnlinkSet(n) => {
+                    entries.push(LogEntry::Srem {
+                        key: self.unlink_set_key.clone(),
+                        member: n.clone(),
+                    });
+                    continue;
+                }
+                BatchWrite::AddToUnlinkSet(n) => {
+                    entries.push(LogEntry::Sadd {
+                        key: self.unlink_set_key.clone(),
+                        member: n.clone(),
+                    });
+                    continue;
+                }
+                BatchWrite::UpdateActivateMap(c, s0) => {
+                    (&self.activate_map_key, c, serde_json::to_string(s0)?)
+                }
+                BatchWrite::UpdateMerchTxidMap(m, e) => (&self.merch_txid_map_key, m, e.clone()),
+                BatchWrite::UpdateChannelStatus(e, status) => {
+                    (&self.channel_status_key, e, serde_json::to_string(status)?)
+                }
+            };
+            entries.push(LogEntry::Hset {
+                key: key.clone(),
+                field: field.clone(),
+                value: value,
+            });
+        }
+        // the checks and the writes are made under &mut self, and the writes are logged as one entry
+        self.write(LogEntry::Batch { entries: entries })?;
+        Ok(true)
+    }
+
+    fn append_event(&mut self, record: &EventRecord) -> Result<bool, ZkChannelError> {
+        let key = format!("{}:{}", self.event_list_prefix, record.key.to_db_key());
+        self.write(LogEntry::Rpush {
+            key: key,
+            value: serde_json::to_string(record)?,
+        })?;
+        Ok(true)
+    }
+
+    fn get_events(&mut self, key: &EventKey) -> Result<Vec<EventRecord>, ZkChannelError> {
+        let key = format!("{}:{}", self.event_list_prefix, key.to_db_key());
+        let mut records = Vec::new();
+        if let Some(l) = self.lists.get(&key) {
+            for ser_record in l.iter() {
+                records.push(serde_json::from_str(ser_record)?);
+            }
+        }
+        Ok(records)
+    }
+
+    fn save_merchant_state(
+        &mut self,
+        channel_state: &ChannelMPCState,
+        merch_state: &MerchantMPCState,
+    ) -> Result<(), ZkChannelError> {
+        let record = encode_merchant_state(channel_state, merch_state)?;
+        let key = self.merch_state_key.clone();
+        self.store_file(&key, &MERCH_STATE_FIELD.to_string(), &record)?;
+        Ok(())
+    }
+
+    fn load_merchant_state(
+        &mut self,
+    ) -> Result<(ChannelMPCState, MerchantMPCState), ZkChannelError> {
+        let record = self.get_file(&self.merch_state_key, &MERCH_STATE_FIELD.to_string())?;
+        let (channel_state, mut merch_state) = decode_merchant_state(&record)?;
+        if merch_state.migrate_legacy_channel_maps(self)? {
+            self.save_merchant_state(&channel_state, &merch_state)?;
+        }
+        Ok((channel_state, merch_state))
+    }
+
+    fn save_merchant_channel(
+        &mut self,
+        channel_token: &ChannelMPCToken,
+    ) -> Result<String, ZkChannelError> {
+        let channel_id = hex::encode(channel_token.compute_channel_id()?);
+        let key = self.merch_channel_key.clone();
+        self.store_file(&key, &channel_id, &persist::to_json_string(channel_token)?)?;
+        Ok(channel_id)
+    }
+
+    fn load_merchant_channel(
+        &mut self,
+        channel_id: &String,
+    ) -> Result<ChannelMPCToken, ZkChannelError> {
+        let record = self.get_file(&self.merch_channel_key, channel_id)?;
+        persist::from_json_str(&record)
+    }
+
+    fn list_merchant_channels(&mut self) -> Result<Vec<String>, ZkChannelError> {
+        let mut channel_ids = self.get_file_names(&self.merch_channel_key);
+        channel_ids.sort();
+        Ok(channel_ids)
+    }
+
+    fn clear_state(&mut self) -> bool {
+        let mut keys = vec![
+            self.session_map_key.clone(),
+            self.unlink_set_key.clone(),
+            self.spent_map_key.clone(),
+            self.rev_lock_map_key.clone(),
+            self.nonce_to_session_key.clone(),
+            self.nonce_mask_map_key.clone(),
+            self.masked_bytes_key.clone(),
+            self.channel_status_key.clone(),
+            self.activate_map_key.clone(),
+            self.close_tx_map_key.clone(),
+            self.merch_txid_map_key.clone(),
+            self.refund_id_map_key.clone(),
+            self.cust_wallet_key.clone(),
+            self.merch_state_key.clone(),
+            self.merch_channel_key.clone(),
+        ];
+        keys.extend(self.lists.keys().cloned());
+        let entries = keys.into_iter().map(|k| LogEntry::Del { key: k }).collect();
+        match self.write(LogEntry::Batch { entries: entries }) {
+            Ok(_) => true,
+            Err(e) => {
+                eprintln!("clear_state: {}", e);
+                false
+            }
+        }
+    }
+}
+
+impl WalletDatabase for FileDatabase {
+    fn save_cust_wallet(&mut self, wallet: &CustomerWallet) -> Result<(), ZkChannelError> {
+        let ser_wallet = encode_cust_wallet(wallet)?;
+        let key = self.cust_wallet_key.clone();
+        self.store_file(&key, &wallet.name, &ser_wallet)?;
+        Ok(())
+    }
+
+    fn load_cust_wallet(&mut self, name: &String) -> Result<CustomerWallet, ZkChannelError> {
+        let ser_wallet = self.get_file(&self.cust_wallet_key, name)?;
+        decode_cust_wallet(&ser_wallet)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channels_mpc::PaymentStatus;
+    use event_log::{EventParty, ProtocolEvent};
+    use std::env;
+    use zkchan_tx::fixed_size_array::{FixedSizeArray16, FixedSizeArray32};
+
+    fn get_temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("zkchannels-{}-{}.log", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_file_db_replays_state() {
+        let path = get_temp_path("replay");
+        let session_id = hex::encode([1u8; 16]);
+        let nonce = hex::encode([2u8; 16]);
+        let rev_lock = hex::encode([3u8; 32]);
+        let rev_secret = hex::encode([4u8; 32]);
+        let session_state = SessionState {
+            nonce: FixedSizeArray16([2u8; 16]),
+            rev_lock_com: FixedSizeArray32([5u8; 32]),
+            amount: 10,
+            status: PaymentStatus::Prepare,
+            created_at: 0,
+            masks_released: false,
+        };
+        let masked_inputs = MaskedMPCInputs {
+            pt_mask: FixedSizeArray32([6u8; 32]),
+            pt_mask_r: FixedSizeArray16([7u8; 16]),
+            escrow_mask: FixedSizeArray32([8u8; 32]),
+            merch_mask: FixedSizeArray32([9u8; 32]),
+            r_escrow_sig: FixedSizeArray32([10u8; 32]),
+            r_merch_sig: FixedSizeArray32([11u8; 32]),
+        };
+        let storage_key = StorageKey::from_bytes([0x33; 32]);
+        let event_key = EventKey::session(&[1u8; 16]);
+        let prepared = EventRecord::new(
+            event_key.clone(),
+            EventParty::Merchant,
+            ProtocolEvent::PaymentPrepared { amount: 10 },
+        );
+        let started = EventRecord::new(
+            event_key.clone(),
+            EventParty::Merchant,
+            ProtocolEvent::MpcStarted,
+        );
+
+        {
+            let mut db = FileDatabase::open("cli", &path).unwrap();
+            db.set_storage_key(storage_key.clone());
+            assert!(db.save_new_session_state(&session_id, &session_state));
+            assert!(!db.save_new_session_state(&session_id, &session_state));
+            assert!(db.update_spent_map(&nonce, &rev_lock).unwrap());
+            assert!(db.update_rev_lock_map(&rev_lock, &rev_secret).unwrap());
+            db.update_unlink_set(&nonce).unwrap();
+            db.update_unlink_set(&hex::encode([12u8; 16])).unwrap();
+            assert!(db.remove_from_unlink_set(&nonce));
+            db.update_nonce_mask_map(&nonce, [6u8; 32], [7u8; 16])
+                .unwrap();
+            assert!(db.update_masked_mpc_inputs(&session_id, masked_inputs));
+            assert!(db.update_nonce_to_session_id(&nonce, &session_id).unwrap());
+            assert!(!db.update_nonce_to_session_id(&nonce, &nonce).unwrap());
+            assert!(db
+                .store_file(
+                    &"cli:merch_channels".to_string(),
+                    &"channel_state".to_string(),
+                    &"{\"name\":\"secret-channel\"}".to_string()
+                )
+                .unwrap());
+            db.append_event(&prepared).unwrap();
+            db.compact().unwrap();
+            assert!(db.clear_nonce_mask_map(&nonce));
+            db.append_event(&started).unwrap();
+        }
+
+        // a torn write at the end of the log is discarded
+        let mut log = OpenOptions::new().append(true).open(&path).unwrap();
+        log.write_all(b"{\"op\":\"hset\",\"key\":").unwrap();
+
+        let mut db = FileDatabase::open("cli", &path).unwrap();
+        assert_eq!(db.load_session_state(&session_id).unwrap(), session_state);
+        assert_eq!(db.get_session_ids().unwrap(), vec![session_id.clone()]);
+        assert!(db.check_spent_map(&nonce));
+        assert!(db.check_rev_lock_map(&rev_lock));
+        assert!(!db.is_member_unlink_set(&nonce));
+        assert_eq!(db.get_unlink_set().unwrap().len(), 1);
+        assert!(db.get_mask_map_from_nonce(&nonce).is_err());
+        assert_eq!(
+            db.get_masked_mpc_inputs(&session_id).unwrap(),
+            masked_inputs
+        );
+        assert_eq!(db.get_session_id_for_nonce(&nonce).unwrap(), session_id);
+        assert_eq!(db.get_events(&event_key).unwrap(), vec![prepared, started]);
+
+        // rev secrets and files are sealed at rest
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(!content.contains(&rev_secret));
+        assert!(!content.contains("secret-channel"));
+        assert!(db.get_rev_secret(&rev_lock).is_err());
+        db.set_storage_key(storage_key);
+        assert_eq!(db.get_rev_secret(&rev_lock).unwrap(), rev_secret);
+        assert_eq!(
+            db.get_file(
+                &"cli:merch_channels".to_string(),
+                &"channel_state".to_string()
+            )
+            .unwrap(),
+            "{\"name\":\"secret-channel\"}"
+        );
+
+        assert!(db.clear_state());
+        drop(db);
+        let mut db = FileDatabase::open("cli", &path).unwrap();
+        assert!(!db.check_spent_map(&nonce));
+        assert!(db.load_session_state(&session_id).is_err());
+        assert!(db.get_events(&event_key).unwrap().is_empty());
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_file_db_is_opened_once() {
+        let path = get_temp_path("lock");
+        let mut db = FileDatabase::open("cli", &path).unwrap();
+        db.update_unlink_set(&hex::encode([1u8; 16])).unwrap();
+        assert!(FileDatabase::open("cli", &path).is_err());
+
+        // compaction keeps the lock
+        db.compact().unwrap();
+        assert!(FileDatabase::open("cli", &path).is_err());
+
+        drop(db);
+        let db = FileDatabase::open("cli", &path).unwrap();
+        assert_eq!(db.sets.len(), 1);
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_file_db_apply_batch() {
+        let path = get_temp_path("batch");
+        let nonce = hex::encode([2u8; 16]);
+        let session_id = hex::encode([1u8; 16]);
+        let session_state = SessionState {
+            nonce: FixedSizeArray16([2u8; 16]),
+            rev_lock_com: FixedSizeArray32([5u8; 32]),
+            amount: 10,
+            status: PaymentStatus::Prepare,
+            created_at: 0,
+            masks_released: false,
+        };
+
+        let mut db = FileDatabase::open("cli", &path).unwrap();
+        let mut batch = DbBatch::new();
+        batch.check(BatchCheck::NewSessionId(session_id.clone()));
+        batch.check(BatchCheck::NonceNotSpent(nonce.clone()));
+        batch.write(BatchWrite::SaveSessionState(
+            session_id.clone(),
+            session_state.clone(),
+        ));
+        batch.write(BatchWrite::UpdateNonceMaskMap(
+            nonce.clone(),
+            [6u8; 32],
+            [7u8; 16],
+        ));
+        assert!(db.apply_batch(&batch).unwrap());
+        // the session id is in use now
+        assert!(!db.apply_batch(&batch).unwrap());
+
+        drop(db);
+        let mut db = FileDatabase::open("cli", &path).unwrap();
+        assert_eq!(db.load_session_state(&session_id).unwrap(), session_state);
+        assert_eq!(
+            db.get_mask_map_from_nonce(&nonce).unwrap(),
+            ([6u8; 32], [7u8; 16])
+        );
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -33,6 +33,7 @@ extern crate hex;
 extern crate libc;
 
 extern crate bit_array;
+extern crate chacha20poly1305;
 extern crate hmac;
 extern crate num;
 extern crate ripemd160;
@@ -42,6 +43,9 @@ extern crate typenum;
 extern crate redis;
 extern crate zkchan_tx;
 
+#[cfg(feature = "async")]
+extern crate futures;
+
 #[macro_use]
 extern crate enum_display_derive;
 
@@ -50,22 +54,42 @@ extern crate enum_display_derive;
 extern crate rusty_fork;
 extern crate rand_xorshift;
 extern crate serde_bytes;
+#[cfg(all(test, feature = "async"))]
+extern crate tokio;
 
+#[cfg(feature = "async")]
+pub mod async_database;
+#[cfg(feature = "async")]
+pub mod async_mpc;
 pub mod bindings;
+pub mod bip32;
 pub mod ccs08;
+pub mod chain_monitor;
 pub mod channels;
 pub mod channels_mpc;
 pub mod cl;
+pub mod cpfp;
+pub mod customer_wallet;
 pub mod database;
+pub mod dual_funding;
 pub mod ecdsa_partial;
+pub mod encryption;
+pub mod errors;
+pub mod event_log;
 pub mod ffishim_bls12;
 pub mod ffishim_bn256;
 pub mod ffishim_mpc;
+pub mod file_database;
+pub mod merchant_server;
 pub mod mpcwrapper;
 pub mod nizk;
 pub mod ped92;
+pub mod persist;
+pub mod pool;
+pub mod splice;
 pub mod util;
 pub mod wallet;
+pub mod watchtower;
 
 #[cfg(test)]
 pub mod test_e2e;
@@ -887,24 +911,52 @@ pub struct FundingTxInfo {
     pub escrow_prevout: FixedSizeArray32,
     pub merch_txid: FixedSizeArray32,
     pub merch_prevout: FixedSizeArray32,
+    // amounts each party put into the escrow (merch_funding is 0 if only the customer funds it)
+    #[serde(default)]
+    pub cust_funding: i64,
+    #[serde(default)]
+    pub merch_funding: i64,
 }
 
 pub mod mpc {
     use bindings::{cb_receive, cb_send, ConnType_LNDNETIO, ConnType_NETIO};
+    pub use bip32::{derive_customer_keys, derive_merchant_keys, CustomerKeys, MerchantKeys};
+    pub use chain_monitor::{
+        BlockHeader, ChainMonitor, ChainSource, MonitoredChannel, OutPoint, SpendingTx,
+        DEFAULT_MIN_DEPTH,
+    };
     pub use channels_mpc::{
-        ChannelMPCState, ChannelMPCToken, CustomerMPCState, MerchantMPCState, RevokedState,
-        TransactionFeeInfo,
+        BitcoinNetworkType, ChannelMPCState, ChannelMPCToken, CustomerMPCState, MerchantMPCState,
+        MutualCloseRequest, PaymentJournal, PaymentStep, PendingTopUp, RevokedState, TopUpRequest,
+        TopUpSignatures, TransactionFeeInfo,
     };
     pub use channels_mpc::{
         ChannelStatus, InitCustState, NetworkConfig, PaymentStatus, ProtocolStatus,
     };
+    pub use channels_mpc::{
+        JustificationVerifier, NegativePaymentPolicy, RefundAuthorityVerifier, RefundJustification,
+    };
+    pub use cpfp::{AnchorOutput, CpfpChildTx};
+    pub use customer_wallet::{ChannelBalance, CustomerChannel, CustomerWallet};
     use database::{MaskedTxMPCInputs, StateDatabase};
+    pub use dual_funding::{
+        DualFundedEscrowTx, EscrowContribution, EscrowFundingInput, FundingParty,
+    };
+    pub use errors::ZkChannelError;
+    use event_log::{record_event, record_result};
+    pub use event_log::{EventKey, EventParty, EventRecord, ProtocolEvent};
     use libc::c_void;
     use rand::Rng;
     use secp256k1::PublicKey;
+    pub use splice::EscrowSpliceTx;
+    use util::get_current_time;
     pub use wallet::{State, NONCE_LEN};
+    pub use watchtower::{
+        BlockSource, DisputeAuthorization, DisputeMaterial, MockChain, SkippedTx, TxPublisher,
+        WatchedChannel, Watchtower, WatchtowerDispute, WatchtowerReport,
+    };
     use zkchan_tx::fixed_size_array::{FixedSizeArray16, FixedSizeArray32};
-    use zkchan_tx::Testnet;
+    use zkchan_tx::{Mainnet, Testnet};
 
     ///
     /// init_merchant() - takes as input the public params, merchant balance and keypair.
@@ -925,12 +977,13 @@ pub mod mpc {
     }
 
     ///
-    /// init_customer() - takes as input the merchant's public key, and initial balance for customer and merchant.
-    /// Generate initial customer channel state and channel token.
+    /// init_customer() - takes as input the channel state, the merchant's public key, and initial balance for customer and merchant.
+    /// Generate initial customer channel state and channel token (on the network of the channel state).
     /// output: a channel token and customer state
     ///
     pub fn init_customer<'a, R: Rng>(
         csprng: &mut R,
+        channel_state: &ChannelMPCState,
         pk_m: &PublicKey,
         b0_cust: i64,
         b0_merch: i64,
@@ -958,7 +1011,14 @@ pub mod mpc {
         };
 
         let cust_name = String::from(name);
-        let mut cust_state = CustomerMPCState::new(csprng, b0_cust, b0_merch, fee_cc, cust_name);
+        let mut cust_state = CustomerMPCState::new(
+            csprng,
+            b0_cust,
+            b0_merch,
+            fee_cc,
+            cust_name,
+            channel_state.get_network(),
+        );
 
         // generate the initial channel token and initial state
         let channel_token = cust_state.generate_init_state(csprng, &pk_m, min_fee, max_fee, fee_mc);
@@ -966,16 +1026,108 @@ pub mod mpc {
         (channel_token, cust_state)
     }
 
+    ///
+    /// init_merchant_from_seed() - same as init_merchant() but the merchant keys (escrow, payout, dispute and hmac key)
+    /// are derived from the seed at the given merchant index, so that the merchant state can be restored from the seed
+    /// output: merchant state
+    ///
+    pub fn init_merchant_from_seed<R: Rng>(
+        csprng: &mut R,
+        db_url: String,
+        channel_state: &mut ChannelMPCState,
+        name: &str,
+        seed: &[u8],
+        merch_index: u32,
+    ) -> Result<MerchantMPCState, ZkChannelError> {
+        let keys = derive_merchant_keys(seed, channel_state.get_network(), merch_index)?;
+        let mut merch_state = init_merchant(csprng, db_url, channel_state, name);
+        merch_state.load_derived_keys(channel_state, &keys)?;
+        Ok(merch_state)
+    }
+
+    ///
+    /// init_customer_from_seed() - same as init_customer() but the customer keys (escrow and payout key) are derived
+    /// from the seed at the given channel index. Each channel of the customer should use its own index.
+    /// output: a channel token and customer state
+    ///
+    pub fn init_customer_from_seed<R: Rng>(
+        csprng: &mut R,
+        channel_state: &ChannelMPCState,
+        pk_m: &PublicKey,
+        b0_cust: i64,
+        b0_merch: i64,
+        tx_fee_info: &TransactionFeeInfo,
+        name: &str,
+        seed: &[u8],
+        channel_index: u32,
+    ) -> Result<(ChannelMPCToken, CustomerMPCState), ZkChannelError> {
+        let keys = derive_customer_keys(seed, channel_state.get_network(), channel_index)?;
+        let (mut channel_token, mut cust_state) = init_customer(
+            csprng,
+            channel_state,
+            pk_m,
+            b0_cust,
+            b0_merch,
+            tx_fee_info,
+            name,
+        );
+        cust_state.load_external_wallet(&mut channel_token, keys.sk_c, keys.payout_sk)?;
+        Ok((channel_token, cust_state))
+    }
+
+    ///
+    /// restore_customer_keys() - takes as input the seed, the channel index and the channel token of a channel.
+    /// Re-derives the customer keys and checks that they belong to the channel
+    /// output: customer keys
+    ///
+    pub fn restore_customer_keys(
+        seed: &[u8],
+        channel_index: u32,
+        channel_token: &ChannelMPCToken,
+    ) -> Result<CustomerKeys, ZkChannelError> {
+        let keys = derive_customer_keys(seed, channel_token.network, channel_index)?;
+        if channel_token.pk_c != Some(keys.get_pk_c()) {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "channel token was not derived from the seed at channel index: {}",
+                channel_index
+            )));
+        }
+        Ok(keys)
+    }
+
+    ///
+    /// restore_merchant_keys() - takes as input the seed, the merchant index and the channel state.
+    /// Re-derives the merchant keys and checks them against the merchant public keys and key commitment of the channel state
+    /// output: merchant keys
+    ///
+    pub fn restore_merchant_keys(
+        seed: &[u8],
+        merch_index: u32,
+        channel_state: &ChannelMPCState,
+    ) -> Result<MerchantKeys, ZkChannelError> {
+        let keys = derive_merchant_keys(seed, channel_state.get_network(), merch_index)?;
+        if keys.get_key_com() != channel_state.get_key_com()
+            || channel_state.merch_payout_pk != Some(keys.get_payout_pk())
+            || channel_state.merch_dispute_pk != Some(keys.get_dispute_pk())
+        {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "channel state was not derived from the seed at merchant index: {}",
+                merch_index
+            )));
+        }
+        Ok(keys)
+    }
+
     ///
     /// get_initial_state() - takes as input the customer state
     /// output: initial cust state and expected hash
     ///
     pub fn get_initial_state(
         cust_state: &CustomerMPCState,
-    ) -> Result<(InitCustState, [u8; 32]), String> {
+    ) -> Result<(InitCustState, [u8; 32]), ZkChannelError> {
         let init_state = match cust_state.get_initial_cust_state() {
             Ok(n) => n,
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(e),
         };
         let init_state_hash = cust_state.get_current_state().compute_hash();
         Ok((init_state, init_state_hash))
@@ -991,14 +1143,127 @@ pub mod mpc {
         init_state: &InitCustState,
         init_hash: [u8; 32],
         merch_state: &mut MerchantMPCState,
-    ) -> Result<bool, String> {
-        merch_state.validate_channel_params(db, channel_token, init_state, init_hash)
+    ) -> Result<bool, ZkChannelError> {
+        let res = merch_state.validate_channel_params(db, channel_token, init_state, init_hash);
+        let mut merch_txid_be = channel_token.merch_txid.0.clone();
+        merch_txid_be.reverse();
+        record_result(
+            db,
+            EventKey::from_token(channel_token),
+            EventParty::Merchant,
+            "validate_channel_params",
+            ProtocolEvent::ChannelParamsValidated {
+                merch_txid: hex::encode(&merch_txid_be),
+            },
+            res,
+        )
+    }
+
+    ///
+    /// init_dual_funded_customer() - takes as input the channel state, the merchant's public key, and the amounts
+    /// the customer and merchant each contribute to the escrow. Unlike init_customer(), the merchant balance is not
+    /// taken from the customer's funds: the merchant adds its own input and change to the escrow-tx.
+    /// output: a channel token and customer state
+    ///
+    pub fn init_dual_funded_customer<R: Rng>(
+        csprng: &mut R,
+        channel_state: &ChannelMPCState,
+        pk_m: &PublicKey,
+        b0_cust: i64,
+        b0_merch: i64,
+        tx_fee_info: &TransactionFeeInfo,
+        name: &str,
+    ) -> Result<(ChannelMPCToken, CustomerMPCState), ZkChannelError> {
+        let min_cust_bal = tx_fee_info.bal_min_cust + tx_fee_info.fee_cc + tx_fee_info.val_cpfp;
+        if b0_cust <= min_cust_bal {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "customer contribution must be larger than {}: {}",
+                min_cust_bal, b0_cust
+            )));
+        }
+        // the merchant pays for its own merch-close-tx out of its contribution
+        let min_merch_bal = tx_fee_info.bal_min_merch + tx_fee_info.fee_mc + tx_fee_info.val_cpfp;
+        if b0_merch < min_merch_bal {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "merchant contribution must be at least {}: {}",
+                min_merch_bal, b0_merch
+            )));
+        }
+
+        let mut cust_state = CustomerMPCState::new(
+            csprng,
+            b0_cust,
+            b0_merch,
+            tx_fee_info.fee_cc,
+            String::from(name),
+            channel_state.get_network(),
+        );
+        cust_state.set_merch_funding(b0_merch)?;
+
+        let channel_token = cust_state.generate_init_state(
+            csprng,
+            &pk_m,
+            tx_fee_info.min_fee,
+            tx_fee_info.max_fee,
+            tx_fee_info.fee_mc,
+        );
+
+        Ok((channel_token, cust_state))
+    }
+
+    ///
+    /// customer_sign_dual_funded_escrow() - takes as input the channel token, the escrow-tx with both contributions,
+    /// the secret key of the customer's funding input and the customer state. Verifies that the escrow-tx funds this
+    /// channel and that the initial closing txs are signed before signing the customer's input.
+    /// output: customer's partial signature on the escrow-tx
+    ///
+    pub fn customer_sign_dual_funded_escrow(
+        channel_token: &ChannelMPCToken,
+        escrow_tx: &DualFundedEscrowTx,
+        funding_sk: &[u8; 32],
+        cust_state: &CustomerMPCState,
+    ) -> Result<Vec<u8>, ZkChannelError> {
+        cust_state.verify_dual_funded_escrow(channel_token, escrow_tx)?;
+        escrow_tx.sign(FundingParty::Customer, funding_sk)
+    }
+
+    ///
+    /// merchant_sign_dual_funded_escrow() - takes as input the db, the escrow-tx, the contribution the merchant proposed,
+    /// the secret key of the merchant's funding input and the merchant state. Verifies that the merchant's contribution
+    /// is unchanged and that the channel params have been validated before signing the merchant's input.
+    /// output: merchant's partial signature on the escrow-tx
+    ///
+    pub fn merchant_sign_dual_funded_escrow(
+        db: &mut dyn StateDatabase,
+        escrow_tx: &DualFundedEscrowTx,
+        merch_contribution: &EscrowContribution,
+        funding_sk: &[u8; 32],
+        merch_state: &MerchantMPCState,
+    ) -> Result<Vec<u8>, ZkChannelError> {
+        merch_state.verify_dual_funded_escrow(db, escrow_tx, merch_contribution)?;
+        escrow_tx.sign(FundingParty::Merchant, funding_sk)
+    }
+
+    ///
+    /// finalize_dual_funded_escrow() - takes as input the escrow-tx and both partial signatures
+    /// output: signed escrow-tx and its txid (little-endian), ready to be broadcast
+    ///
+    pub fn finalize_dual_funded_escrow(
+        escrow_tx: &DualFundedEscrowTx,
+        cust_sig: &Vec<u8>,
+        merch_sig: &Vec<u8>,
+    ) -> Result<(Vec<u8>, [u8; 32]), ZkChannelError> {
+        let signed_tx = escrow_tx.finalize(cust_sig, merch_sig)?;
+        let (_, txid_le) = escrow_tx.get_txid();
+        Ok((signed_tx, txid_le))
     }
 
     ///
     /// customer_mark_open_channel() - changes channel status in customer state
     ///
-    pub fn customer_mark_open_channel(cust_state: &mut CustomerMPCState) -> Result<(), String> {
+    pub fn customer_mark_open_channel(
+        cust_state: &mut CustomerMPCState,
+    ) -> Result<(), ZkChannelError> {
         cust_state.change_channel_status(ChannelStatus::Open)
     }
 
@@ -1007,12 +1272,50 @@ pub mod mpc {
     /// fails if not in pending open state and assumes escrow-txid has been broadcast on chain
     ///
     pub fn merchant_mark_open_channel(
+        db: &mut dyn StateDatabase,
         escrow_txid_le: [u8; 32],
         merch_state: &mut MerchantMPCState,
-    ) -> Result<(), String> {
+    ) -> Result<(), ZkChannelError> {
         let mut escrow_txid_be = escrow_txid_le.clone();
         escrow_txid_be.reverse();
-        merch_state.change_channel_status(escrow_txid_be, ChannelStatus::Open)
+        merch_state.change_channel_status(db, escrow_txid_be, ChannelStatus::Open)
+    }
+
+    ///
+    /// customer_sync_channel_status() - takes as input the chain monitor, a chain source, the channel state,
+    /// channel token and the customer state. applies the status transitions of the channel that are observed
+    /// on chain (escrow confirmed, close tx confirmed, dispute or expiry of self_delay)
+    /// output: the new channel status
+    ///
+    pub fn customer_sync_channel_status(
+        monitor: &mut ChainMonitor,
+        source: &mut dyn ChainSource,
+        channel_state: &ChannelMPCState,
+        channel_token: &ChannelMPCToken,
+        cust_state: &mut CustomerMPCState,
+    ) -> Result<ChannelStatus, ZkChannelError> {
+        channel_state.check_network(cust_state.get_network())?;
+        let channel = MonitoredChannel::new(channel_state, channel_token);
+        monitor.update_customer(source, &channel, cust_state)
+    }
+
+    ///
+    /// merchant_sync_channel_status() - takes as input the chain monitor, a chain source, the db, the channel state,
+    /// channel token and the merchant state. applies the status transitions of the channel that are observed
+    /// on chain to the channel status in the db
+    /// output: the new channel status
+    ///
+    pub fn merchant_sync_channel_status(
+        monitor: &mut ChainMonitor,
+        source: &mut dyn ChainSource,
+        db: &mut dyn StateDatabase,
+        channel_state: &ChannelMPCState,
+        channel_token: &ChannelMPCToken,
+        merch_state: &MerchantMPCState,
+    ) -> Result<ChannelStatus, ZkChannelError> {
+        channel_state.check_network(merch_state.get_network())?;
+        let channel = MonitoredChannel::new(channel_state, channel_token);
+        monitor.update_merchant(source, db, &channel, merch_state)
     }
 
     ///
@@ -1023,18 +1326,21 @@ pub mod mpc {
     pub fn activate_customer<R: Rng>(
         csprng: &mut R,
         cust_state: &mut CustomerMPCState,
-    ) -> Result<State, String> {
+    ) -> Result<State, ZkChannelError> {
         // check that customer already in the Initialized state
         if cust_state.protocol_status != ProtocolStatus::Initialized {
-            return Err(format!(
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
                 "invalid channel status for activate_customer(): {}",
                 cust_state.protocol_status
-            ));
+            )));
         }
 
         let channel_status = cust_state.get_channel_status();
         if channel_status != ChannelStatus::Open {
-            return Err(format!("channel is not open yet: {}", channel_status));
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
+                "channel is not open yet: {}",
+                channel_status
+            )));
         }
 
         let _r_com = cust_state.generate_rev_lock_commitment(csprng);
@@ -1053,19 +1359,37 @@ pub mod mpc {
         channel_token: ChannelMPCToken,
         s0: &State,
         merch_state: &mut MerchantMPCState,
-    ) -> Result<[u8; 32], String> {
+    ) -> Result<[u8; 32], ZkChannelError> {
         // TODO: implement ZKC-19
+        if channel_token.network != merch_state.get_network() {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "channel token network does not match merch state network: {} != {}",
+                channel_token.network,
+                merch_state.get_network()
+            )));
+        }
         // activate channel - generate pay_token
         let mut escrow_txid_be = channel_token.escrow_txid.0.clone();
         escrow_txid_be.reverse();
-        let channel_status = match merch_state.get_channel_status(escrow_txid_be) {
+        let channel_status = match merch_state.get_channel_status(db, escrow_txid_be) {
             Ok(s) => s,
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(e),
         };
         if channel_status != ChannelStatus::Open {
-            return Err(format!("channel is not open yet: {}", channel_status));
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
+                "channel is not open yet: {}",
+                channel_status
+            )));
         }
-        merch_state.activate_channel(db, &channel_token, s0)
+        let res = merch_state.activate_channel(db, &channel_token, s0);
+        record_result(
+            db,
+            EventKey::from_token(&channel_token),
+            EventParty::Merchant,
+            "activate_merchant",
+            ProtocolEvent::Activated,
+            res,
+        )
     }
 
     ///
@@ -1076,8 +1400,9 @@ pub mod mpc {
     pub fn activate_customer_finalize(
         pay_token_0: [u8; 32],
         cust_state: &mut CustomerMPCState,
-    ) -> Result<(), String> {
-        cust_state.store_initial_pay_token(pay_token_0)
+    ) -> Result<(), ZkChannelError> {
+        let res = cust_state.store_initial_pay_token(pay_token_0);
+        cust_state.record_result("activate_customer_finalize", ProtocolEvent::Activated, res)
     }
 
     ///
@@ -1091,7 +1416,27 @@ pub mod mpc {
         channel: &ChannelMPCState,
         amount: i64,
         cust_state: &mut CustomerMPCState,
-    ) -> Result<(State, RevokedState, [u8; 32], [u8; 16]), String> {
+    ) -> Result<(State, RevokedState, [u8; 32], [u8; 16]), ZkChannelError> {
+        channel.check_network(cust_state.get_network())?;
+        // no more payments once the customer signed a mutual close
+        if cust_state.get_channel_status() == ChannelStatus::MutualClose {
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
+                "Invalid channel status for pay_prepare_customer(): {}",
+                ChannelStatus::MutualClose
+            )));
+        }
+        // an interrupted payment has to be resumed or rolled back first
+        if cust_state.has_pending_payment() {
+            return Err(ZkChannelError::InvalidStatusTransition(String::from(
+                "a previous payment is still pending: resume it or call pay_rollback_customer()",
+            )));
+        }
+        // a payment would revoke the state that the pending top-up carries forward
+        if cust_state.has_pending_top_up() {
+            return Err(ZkChannelError::InvalidStatusTransition(String::from(
+                "cannot pay while a top-up is pending",
+            )));
+        }
         // verify that channel status is already activated or established
         if (cust_state.protocol_status == ProtocolStatus::Activated && amount >= 0)
             || (cust_state.protocol_status == ProtocolStatus::Established && amount > 0)
@@ -1108,7 +1453,7 @@ pub mod mpc {
                     channel.get_bal_min_cust(),
                     max_payment
                 );
-                return Err(s);
+                return Err(ZkChannelError::InvalidInput(s));
             }
             let (cur_rev_lock, cur_rev_secret) = cust_state.get_rev_pair();
             // get current rev lock commitment
@@ -1121,6 +1466,8 @@ pub mod mpc {
             // pick new session ID
             let mut session_id = [0u8; 16];
             csprng.fill_bytes(&mut session_id);
+            cust_state.set_payment_session_id(session_id);
+            cust_state.record_event(ProtocolEvent::PaymentPrepared { amount });
             Ok((
                 new_state,
                 RevokedState {
@@ -1132,10 +1479,10 @@ pub mod mpc {
                 session_id,
             ))
         } else {
-            return Err(format!(
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
                 "Invalid channel status for pay_prepare_customer(): {}",
                 cust_state.protocol_status
-            ));
+            )));
         }
     }
 
@@ -1154,9 +1501,48 @@ pub mod mpc {
         amount: i64,
         justification: Option<String>,
         merch_state: &mut MerchantMPCState,
-    ) -> Result<[u8; 32], String> {
+    ) -> Result<[u8; 32], ZkChannelError> {
+        channel_state.check_network(merch_state.get_network())?;
         // checks that no existing session with the specified session_id/nonce combo
-        merch_state.generate_pay_mask_commitment(
+        let res = merch_state.generate_pay_mask_commitment(
+            csprng,
+            db,
+            channel_state,
+            session_id,
+            nonce,
+            rev_lock_com,
+            amount,
+            justification,
+        );
+        record_result(
+            db,
+            EventKey::session(&session_id),
+            EventParty::Merchant,
+            "pay_prepare_merchant",
+            ProtocolEvent::PaymentPrepared { amount },
+            res,
+        )
+    }
+
+    ///
+    /// pay_prepare_merchant_with_verifier() - same as pay_prepare_merchant() but checks the justification of a
+    /// negative payment with the given verifier instead of the refund authority configured in the merchant state
+    /// output: commitment of the payment token mask
+    ///
+    pub fn pay_prepare_merchant_with_verifier<R: Rng>(
+        csprng: &mut R,
+        db: &mut dyn StateDatabase,
+        channel_state: &ChannelMPCState,
+        session_id: [u8; 16],
+        nonce: [u8; NONCE_LEN],
+        rev_lock_com: [u8; 32],
+        amount: i64,
+        justification: Option<String>,
+        verifier: &dyn JustificationVerifier,
+        merch_state: &mut MerchantMPCState,
+    ) -> Result<[u8; 32], ZkChannelError> {
+        channel_state.check_network(merch_state.get_network())?;
+        let res = merch_state.generate_pay_mask_commitment_with_verifier(
             csprng,
             db,
             channel_state,
@@ -1165,6 +1551,15 @@ pub mod mpc {
             rev_lock_com,
             amount,
             justification,
+            Some(verifier),
+        );
+        record_result(
+            db,
+            EventKey::session(&session_id),
+            EventParty::Merchant,
+            "pay_prepare_merchant",
+            ProtocolEvent::PaymentPrepared { amount },
+            res,
         )
     }
 
@@ -1186,7 +1581,9 @@ pub mod mpc {
         p_ptr: *mut c_void,
         send_cb: cb_send,
         receive_cb: cb_receive,
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
+        channel_state.check_network(channel_token.network)?;
+        channel_state.check_network(cust_state.get_network())?;
         // verify that channel status is already activated or established (unlink)
         if (cust_state.protocol_status == ProtocolStatus::Activated && amount >= 0)
             || (cust_state.protocol_status == ProtocolStatus::Established && amount > 0)
@@ -1206,7 +1603,8 @@ pub mod mpc {
                 });
             }
             let circuit = cust_state.get_circuit_file();
-            cust_state.execute_mpc_context(
+            cust_state.record_event(ProtocolEvent::MpcStarted);
+            let res = cust_state.execute_mpc_context(
                 &channel_state,
                 &channel_token,
                 s0,
@@ -1218,12 +1616,21 @@ pub mod mpc {
                 p_ptr,
                 send_cb,
                 receive_cb,
+            );
+            let success = match &res {
+                Ok(s) => *s,
+                Err(_) => false,
+            };
+            cust_state.record_result(
+                "pay_update_customer",
+                ProtocolEvent::MpcFinished { success },
+                res,
             )
         } else {
-            return Err(format!(
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
                 "Invalid channel status for pay_update_customer(): {}",
                 cust_state.protocol_status
-            ));
+            )));
         }
     }
 
@@ -1243,7 +1650,8 @@ pub mod mpc {
         p_ptr: *mut c_void,
         send_cb: cb_send,
         receive_cb: cb_receive,
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
+        channel.check_network(merch_state.get_network())?;
         if merch_state.net_config.is_none() {
             // use default ip/port
             let conn_type = match send_cb.is_some() && receive_cb.is_some() {
@@ -1258,7 +1666,16 @@ pub mod mpc {
             });
         }
         let circuit = merch_state.get_circuit_file();
-        return merch_state.execute_mpc_context(
+        let event_key = EventKey::session(&session_id);
+        record_event(
+            db,
+            EventRecord::new(
+                event_key.clone(),
+                EventParty::Merchant,
+                ProtocolEvent::MpcStarted,
+            ),
+        );
+        let res = merch_state.execute_mpc_context(
             csprng,
             db,
             &channel,
@@ -1269,6 +1686,18 @@ pub mod mpc {
             send_cb,
             receive_cb,
         );
+        let success = match &res {
+            Ok(s) => *s,
+            Err(_) => false,
+        };
+        record_result(
+            db,
+            event_key,
+            EventParty::Merchant,
+            "pay_update_merchant",
+            ProtocolEvent::MpcFinished { success },
+            res,
+        )
     }
 
     ///
@@ -1280,34 +1709,86 @@ pub mod mpc {
         session_id: [u8; 16],
         mpc_result: bool,
         _merch_state: &mut MerchantMPCState,
-    ) -> Result<MaskedTxMPCInputs, String> {
+    ) -> Result<MaskedTxMPCInputs, ZkChannelError> {
         // check db is connected
         db.is_connected()?;
 
+        // the result of the mpc as reported by the customer
+        record_event(
+            db,
+            EventRecord::new(
+                EventKey::session(&session_id),
+                EventParty::Merchant,
+                ProtocolEvent::MpcConfirmed {
+                    success: mpc_result,
+                },
+            ),
+        );
         let session_id_hex = hex::encode(session_id);
         match mpc_result {
             true => {
+                let mut session_state = db.load_session_state(&session_id_hex)?;
+                if session_state.status == PaymentStatus::Error {
+                    return Err(ZkChannelError::InvalidStatusTransition(format!(
+                        "pay_confirm_mpc_result: session {} already failed",
+                        session_id_hex
+                    )));
+                }
                 let mask_bytes = match db.get_masked_mpc_inputs(&session_id_hex) {
                     Ok(n) => Some(n),
-                    Err(e) => return Err(e.to_string()),
+                    Err(e) => return Err(e),
                 };
                 let mask_bytes_unwrapped = mask_bytes.unwrap();
+                // from now on the nonce stays bound to this session (see expire_sessions())
+                session_state.masks_released = true;
+                if !db.update_session_state(&session_id_hex, &session_state) {
+                    return Err(ZkChannelError::DatabaseUnavailable(format!(
+                        "pay_confirm_mpc_result: failed to update session {}",
+                        session_id_hex
+                    )));
+                }
                 return Ok(mask_bytes_unwrapped.get_tx_masks());
             }
             false => {
                 let mut session_state = match db.load_session_state(&session_id_hex) {
                     Ok(s) => s,
-                    Err(e) => return Err(e.to_string()),
+                    Err(e) => return Err(e),
                 };
+                if session_state.masks_released {
+                    return Err(ZkChannelError::InvalidStatusTransition(format!(
+                        "pay_confirm_mpc_result: masks of session {} were already released",
+                        session_id_hex
+                    )));
+                }
                 session_state.status = PaymentStatus::Error;
                 db.update_session_state(&session_id_hex, &session_state);
-                return Err(format!(
+                return Err(ZkChannelError::MpcFailure(format!(
                     "pay_confirm_mpc_result: will need to restart MPC session"
-                ));
+                )));
             }
         }
     }
 
+    ///
+    /// merchant_expire_sessions() - takes as input a db and merch state.
+    /// Removes payment sessions (and the pay masks of their nonces) that are older than the session timeout
+    /// of the merchant. A customer can then restart the payment for the same nonce with a new session id.
+    /// output: ids of the expired sessions
+    ///
+    pub fn merchant_expire_sessions(
+        db: &mut dyn StateDatabase,
+        merch_state: &MerchantMPCState,
+    ) -> Result<Vec<[u8; 16]>, ZkChannelError> {
+        let expired = merch_state.expire_sessions(db, get_current_time())?;
+        let mut session_ids = Vec::new();
+        for session_id_hex in expired {
+            let mut session_id = [0u8; 16];
+            session_id.copy_from_slice(&hex::decode(&session_id_hex)?);
+            session_ids.push(session_id);
+        }
+        Ok(session_ids)
+    }
+
     ///
     /// pay_unmask_sigs_customer() - takes as input the transaction masks and the customer state.
     /// Unmask the transactions received from the MPC
@@ -1318,20 +1799,43 @@ pub mod mpc {
         channel_token: &ChannelMPCToken,
         mask_bytes: MaskedTxMPCInputs,
         cust_state: &mut CustomerMPCState,
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
+        channel_state.check_network(channel_token.network)?;
+        channel_state.check_network(cust_state.get_network())?;
         if (cust_state.protocol_status == ProtocolStatus::Activated
             || cust_state.protocol_status == ProtocolStatus::Established)
         {
-            cust_state.unmask_and_verify_transactions::<Testnet>(
-                channel_state,
-                channel_token,
-                mask_bytes,
+            let res = match channel_state.get_network() {
+                BitcoinNetworkType::Mainnet => cust_state
+                    .unmask_and_verify_transactions::<Mainnet>(
+                        channel_state,
+                        channel_token,
+                        mask_bytes,
+                    ),
+                BitcoinNetworkType::Testnet | BitcoinNetworkType::Regtest => cust_state
+                    .unmask_and_verify_transactions::<Testnet>(
+                        channel_state,
+                        channel_token,
+                        mask_bytes,
+                    ),
+            };
+            let success = match &res {
+                Ok(s) => *s,
+                Err(_) => false,
+            };
+            cust_state.record_result(
+                "pay_unmask_sigs_customer",
+                ProtocolEvent::Unmasked {
+                    what: String::from("close_sigs"),
+                    success,
+                },
+                res,
             )
         } else {
-            return Err(format!(
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
                 "Invalid channel status for pay_unmask_sigs_customer(): {}",
                 cust_state.protocol_status
-            ));
+            )));
         }
     }
 
@@ -1346,16 +1850,24 @@ pub mod mpc {
         session_id: [u8; 16],
         rev_state: RevokedState,
         merch_state: &mut MerchantMPCState,
-    ) -> Result<([u8; 32], [u8; 16]), String> {
-        let (pt_mask, pt_mask_r) = match merch_state.verify_revoked_state(
+    ) -> Result<([u8; 32], [u8; 16]), ZkChannelError> {
+        let res = merch_state.verify_revoked_state(
             db,
             session_id,
             rev_state.get_rev_lock(),
             rev_state.get_rev_secret(),
             rev_state.get_randomness(),
+        );
+        let (pt_mask, pt_mask_r) = match record_result(
+            db,
+            EventKey::session(&session_id),
+            EventParty::Merchant,
+            "pay_validate_rev_lock_merchant",
+            ProtocolEvent::RevLockValidated,
+            res,
         ) {
             Ok(n) => (n.0, n.1),
-            Err(e) => return Err(e.to_string()),
+            Err(e) => return Err(e),
         };
         Ok((pt_mask, pt_mask_r))
     }
@@ -1369,22 +1881,55 @@ pub mod mpc {
         pt_mask_bytes: [u8; 32],
         pt_mask_r: [u8; 16],
         cust_state: &mut CustomerMPCState,
-    ) -> Result<bool, String> {
+    ) -> Result<bool, ZkChannelError> {
         if (cust_state.protocol_status == ProtocolStatus::Activated
             || cust_state.protocol_status == ProtocolStatus::Established)
         {
-            Ok(cust_state.unmask_and_verify_pay_token(pt_mask_bytes, pt_mask_r))
+            let success = cust_state.unmask_and_verify_pay_token(pt_mask_bytes, pt_mask_r);
+            cust_state.record_event(ProtocolEvent::Unmasked {
+                what: String::from("pay_token"),
+                success,
+            });
+            Ok(success)
         } else {
-            return Err(format!(
+            return Err(ZkChannelError::InvalidStatusTransition(format!(
                 "Invalid channel status for pay_unmask_pay_token_customer(): {}",
                 cust_state.protocol_status
-            ));
+            )));
         }
     }
 
+    ///
+    /// pay_rollback_customer() - takes as input the customer state.
+    /// Restores the state from before an interrupted payment (e.g., after a crash or a failed mpc), which still has
+    /// valid close signatures. Fails once the close signatures on the new state are unmasked: the payment must then be
+    /// completed with pay_resume_customer().
+    /// output: the restored state
+    ///
+    pub fn pay_rollback_customer(
+        cust_state: &mut CustomerMPCState,
+    ) -> Result<State, ZkChannelError> {
+        cust_state.rollback_payment()
+    }
+
+    ///
+    /// pay_resume_customer() - takes as input the customer state.
+    /// Tells how to complete a payment that was interrupted after the mpc (e.g., by a crash). In step MpcCompleted, the
+    /// masked close signatures are requested again for the session (pay_confirm_mpc_result()) and unmasked with
+    /// pay_unmask_sigs_customer(). In step SigsUnmasked, the revoked state is sent to the merchant again
+    /// (pay_validate_rev_lock_merchant()) and the pay token is unmasked with pay_unmask_pay_token_customer().
+    /// A payment that did not complete the mpc can only be rolled back.
+    /// output: the step of the payment, its session id and the revoked state (once the close signatures are unmasked)
+    ///
+    pub fn pay_resume_customer(
+        cust_state: &CustomerMPCState,
+    ) -> Result<(PaymentStep, [u8; 16], Option<RevokedState>), ZkChannelError> {
+        cust_state.resume_payment()
+    }
+
     ///
     /// force_customer_close() - takes as input the channel_state, channel_token, from_escrow and customer state.
-    /// signs the closing tx on the current state of the channel
+    /// signs the closing tx on the current state of the channel (an interrupted payment is rolled back first)
     /// output: cust-close-(signed_tx, txid) from escrow-tx or merch-close-tx
     ///
     pub fn force_customer_close(
@@ -1392,75 +1937,479 @@ pub mod mpc {
         channel_token: &ChannelMPCToken,
         from_escrow: bool,
         cust_state: &mut CustomerMPCState,
-    ) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), String> {
+    ) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), ZkChannelError> {
+        channel_state.check_network(channel_token.network)?;
+        channel_state.check_network(cust_state.get_network())?;
         // (close_tx, close_txid_be, close_txid_le) that spends from escrow (if from_escrow = true)
-        cust_state.customer_close::<Testnet>(&channel_state, &channel_token, from_escrow)
+        let res = match channel_state.get_network() {
+            BitcoinNetworkType::Mainnet => {
+                cust_state.customer_close::<Mainnet>(&channel_state, &channel_token, from_escrow)
+            }
+            BitcoinNetworkType::Testnet | BitcoinNetworkType::Regtest => {
+                cust_state.customer_close::<Testnet>(&channel_state, &channel_token, from_escrow)
+            }
+        };
+        let txid = match &res {
+            Ok(r) => hex::encode(&r.1),
+            Err(_) => String::new(),
+        };
+        cust_state.record_result(
+            "force_customer_close",
+            ProtocolEvent::Closed {
+                kind: String::from("cust_close"),
+                txid,
+            },
+            res,
+        )
     }
 
     ///
-    /// force_merchant_close() - takes as input the escrow txid and merchant state.
-    /// signs the merch-close-tx tx on the current state of the channel
+    /// force_merchant_close() - takes as input the db, escrow txid and merchant state.
+    /// signs the merch-close-tx tx on the current state of the channel (on the network of the merchant state)
     /// output: merch-close-signed-tx on a given channel (identified by the escrow-txid)
     ///
     pub fn force_merchant_close(
+        db: &mut dyn StateDatabase,
         escrow_txid: &Vec<u8>,
         val_cpfp: i64,
         merch_state: &mut MerchantMPCState,
-    ) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), String> {
+    ) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), ZkChannelError> {
         if escrow_txid.len() != 32 {
-            return Err(format!(
+            return Err(ZkChannelError::InvalidInput(format!(
                 "escrow-txid does not have expected length: {}",
                 escrow_txid.len()
-            ));
+            )));
         }
         let mut txid = [0u8; 32];
         txid.copy_from_slice(escrow_txid.as_slice());
-        merch_state.get_closing_tx::<Testnet>(txid, val_cpfp)
+        // (merch_close_tx, merch_close_txid_be, merch_close_txid_le)
+        let res = match merch_state.get_network() {
+            BitcoinNetworkType::Mainnet => {
+                merch_state.get_closing_tx::<Mainnet>(db, txid, val_cpfp)
+            }
+            BitcoinNetworkType::Testnet | BitcoinNetworkType::Regtest => {
+                merch_state.get_closing_tx::<Testnet>(db, txid, val_cpfp)
+            }
+        };
+        let close_txid = match &res {
+            Ok(r) => hex::encode(&r.1),
+            Err(_) => String::new(),
+        };
+        record_result(
+            db,
+            EventKey::escrow(&txid),
+            EventParty::Merchant,
+            "force_merchant_close",
+            ProtocolEvent::Closed {
+                kind: String::from("merch_close"),
+                txid: close_txid,
+            },
+            res,
+        )
     }
-}
 
-#[cfg(all(test, feature = "unstable"))]
-mod benches {
-    use rand::{thread_rng, Rng};
-    use test::Bencher;
+    ///
+    /// cpfp_customer() - takes as input the channel_state, a broadcast cust-close-tx, the target fee rate (sat/vbyte),
+    /// an optional extra funding input (with its secret key) and the customer state.
+    /// signs a child tx that spends the anchor output of the cust-close-tx, such that the package pays the fee rate
+    /// output: signed child tx and its txid (little-endian)
+    ///
+    pub fn cpfp_customer(
+        channel_state: &ChannelMPCState,
+        cust_close_tx: &Vec<u8>,
+        fee_rate: i64,
+        funding: Option<(EscrowFundingInput, [u8; 32])>,
+        cust_state: &CustomerMPCState,
+    ) -> Result<(Vec<u8>, [u8; 32]), ZkChannelError> {
+        channel_state.check_network(cust_state.get_network())?;
+        cust_state.sign_cpfp_child_tx(cust_close_tx, fee_rate, funding)
+    }
 
-    #[bench]
-    pub fn bench_one(bh: &mut Bencher) {
-        println!("Run benchmark tests here!");
+    ///
+    /// cpfp_merchant() - takes as input the db, channel_state, a broadcast merch-close-tx, the target fee rate
+    /// (sat/vbyte), an optional extra funding input (with its secret key) and the merchant state.
+    /// signs a child tx that spends the anchor output of the merch-close-tx, such that the package pays the fee rate
+    /// output: signed child tx and its txid (little-endian)
+    ///
+    pub fn cpfp_merchant(
+        db: &mut dyn StateDatabase,
+        channel_state: &ChannelMPCState,
+        merch_close_tx: &Vec<u8>,
+        fee_rate: i64,
+        funding: Option<(EscrowFundingInput, [u8; 32])>,
+        merch_state: &MerchantMPCState,
+    ) -> Result<(Vec<u8>, [u8; 32]), ZkChannelError> {
+        channel_state.check_network(merch_state.get_network())?;
+        merch_state.sign_cpfp_child_tx(db, merch_close_tx, fee_rate, funding)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use pairing::bls12_381::Bls12;
-    use rand::{Rng, SeedableRng};
-    use rand_xorshift::XorShiftRng;
-    use sha2::{Digest, Sha256};
+    ///
+    /// customer_claim() - takes as input the channel_state, a confirmed cust-close-tx, the height it confirmed at,
+    /// the current height, the fee of the claim tx and the customer state.
+    /// claims the to_customer output after the timelock expired and marks the channel as closed
+    /// output: signed claim tx and its txid (little-endian)
+    ///
+    pub fn customer_claim(
+        channel_state: &ChannelMPCState,
+        cust_close_tx: &Vec<u8>,
+        close_height: u64,
+        cur_height: u64,
+        fee: i64,
+        cust_state: &mut CustomerMPCState,
+    ) -> Result<(Vec<u8>, [u8; 32]), ZkChannelError> {
+        channel_state.check_network(cust_state.get_network())?;
+        cust_state.claim_close_output(channel_state, cust_close_tx, close_height, cur_height, fee)
+    }
 
-    use bindings::ConnType_NETIO;
-    use channels_mpc::{ChannelStatus, PaymentStatus, ProtocolStatus};
-    use database::{
-        get_file_from_db, store_file_in_db, HashMapDatabase, MaskedTxMPCInputs, RedisDatabase,
-        StateDatabase,
-    };
-    use std::process::Command;
-    use std::{env, ptr};
-    use zkchan_tx::fixed_size_array::FixedSizeArray32;
-    use zkchan_tx::Testnet;
+    ///
+    /// merchant_claim() - takes as input the db, channel_state, a confirmed merch-close-tx or cust-close-tx,
+    /// the height it confirmed at, the current height, the fee of the claim tx and the merchant state.
+    /// claims the merchant's output of the close tx (after the timelock expired for a merch-close-tx)
+    /// and marks the channel as closed
+    /// output: signed claim tx and its txid (little-endian)
+    ///
+    pub fn merchant_claim(
+        db: &mut dyn StateDatabase,
+        channel_state: &ChannelMPCState,
+        close_tx: &Vec<u8>,
+        close_height: u64,
+        cur_height: u64,
+        fee: i64,
+        merch_state: &MerchantMPCState,
+    ) -> Result<(Vec<u8>, [u8; 32]), ZkChannelError> {
+        channel_state.check_network(merch_state.get_network())?;
+        merch_state.claim_close_output(db, close_tx, close_height, cur_height, fee)
+    }
 
-    fn setup_new_channel_helper(
-        channel_state: &mut zkproofs::ChannelState<Bls12>,
-        init_cust_bal: i64,
-        init_merch_bal: i64,
-    ) -> (
-        zkproofs::ChannelToken<Bls12>,
-        zkproofs::MerchantState<Bls12>,
-        zkproofs::CustomerState<Bls12>,
-        zkproofs::ChannelState<Bls12>,
-    ) {
-        let rng = &mut rand::thread_rng();
-        let merch_name = "Bob";
+    ///
+    /// mutual_close_customer() - takes as input the channel_state, channel_token and customer state.
+    /// signs the mutual close tx that splits the escrow according to the balances of the current state
+    /// (the customer pays the fee). The channel stays open until mutual_close_customer_finalize()
+    /// output: mutual close request (current state, pay token, proposed split and cust-sig)
+    ///
+    pub fn mutual_close_customer(
+        channel_state: &ChannelMPCState,
+        channel_token: &ChannelMPCToken,
+        cust_state: &mut CustomerMPCState,
+    ) -> Result<MutualCloseRequest, ZkChannelError> {
+        channel_state.check_network(channel_token.network)?;
+        channel_state.check_network(cust_state.get_network())?;
+        cust_state.sign_mutual_close_transaction(channel_state, channel_token)
+    }
+
+    ///
+    /// mutual_close_customer_finalize() - takes as input the channel_state, channel_token, the mutual close request,
+    /// the signed mutual close tx returned by the merchant and the customer state. verifies the merchant's signature
+    /// and refuses further payments on the channel
+    /// output: error if the signed tx is invalid
+    ///
+    pub fn mutual_close_customer_finalize(
+        channel_state: &ChannelMPCState,
+        channel_token: &ChannelMPCToken,
+        request: &MutualCloseRequest,
+        signed_tx: &Vec<u8>,
+        cust_state: &mut CustomerMPCState,
+    ) -> Result<(), ZkChannelError> {
+        channel_state.check_network(channel_token.network)?;
+        channel_state.check_network(cust_state.get_network())?;
+        cust_state.verify_mutual_close_transaction(channel_token, request, signed_tx)
+    }
+
+    ///
+    /// mutual_close_merchant() - takes as input the db, channel_state, channel_token, the customer's mutual close request
+    /// and the merchant state. checks that the proposed split matches the last state the merchant revoked to
+    /// and completes the mutual close tx. the nonce of that state can no longer be used for payments
+    /// output: mutual close signed tx and txid (little endian)
+    ///
+    pub fn mutual_close_merchant(
+        db: &mut dyn StateDatabase,
+        channel_state: &ChannelMPCState,
+        channel_token: &ChannelMPCToken,
+        request: &MutualCloseRequest,
+        merch_state: &mut MerchantMPCState,
+    ) -> Result<(Vec<u8>, Vec<u8>), ZkChannelError> {
+        channel_state.check_network(channel_token.network)?;
+        channel_state.check_network(merch_state.get_network())?;
+        let res =
+            merch_state.sign_mutual_close_transaction(db, channel_state, channel_token, request);
+        // the txid is little endian
+        let txid = match &res {
+            Ok(r) => {
+                let mut txid_be = r.1.clone();
+                txid_be.reverse();
+                hex::encode(&txid_be)
+            }
+            Err(_) => String::new(),
+        };
+        record_result(
+            db,
+            EventKey::from_token(channel_token),
+            EventParty::Merchant,
+            "mutual_close_merchant",
+            ProtocolEvent::Closed {
+                kind: String::from("mutual_close"),
+                txid,
+            },
+            res,
+        )
+    }
+
+    ///
+    /// top_up_customer_prepare() - takes as input an rng, the channel state, channel token, the splice-tx that spends the
+    /// current escrow and the customer's top-up input into a new escrow, the txid/prevout of the new merch-close-tx
+    /// and the customer state. Generates the initial state of the topped up channel (the merch-close-tx on the new
+    /// escrow has to be stored by the merchant as in establish)
+    /// output: top-up request (current state, pay token, splice-tx and the new initial state)
+    ///
+    pub fn top_up_customer_prepare<R: Rng>(
+        csprng: &mut R,
+        channel_state: &ChannelMPCState,
+        channel_token: &ChannelMPCToken,
+        splice_tx: &EscrowSpliceTx,
+        merch_txid_be: [u8; 32],
+        merch_prevout: [u8; 32],
+        cust_state: &mut CustomerMPCState,
+    ) -> Result<TopUpRequest, ZkChannelError> {
+        channel_state.check_network(channel_token.network)?;
+        channel_state.check_network(cust_state.get_network())?;
+        cust_state.prepare_top_up(
+            csprng,
+            channel_token,
+            splice_tx,
+            merch_txid_be,
+            merch_prevout,
+        )
+    }
+
+    ///
+    /// top_up_merchant_sign() - takes as input the db, channel state, channel token, the top-up request, the customer's
+    /// close fee and the merchant state. Checks that the balances of the latest state are carried forward and signs the
+    /// initial closing txs on the new escrow
+    /// output: escrow and merch signatures on the new initial closing txs
+    ///
+    pub fn top_up_merchant_sign(
+        db: &mut dyn StateDatabase,
+        channel_state: &ChannelMPCState,
+        channel_token: &ChannelMPCToken,
+        request: &TopUpRequest,
+        fee_cc: i64,
+        merch_state: &MerchantMPCState,
+    ) -> Result<(Vec<u8>, Vec<u8>), ZkChannelError> {
+        channel_state.check_network(channel_token.network)?;
+        channel_state.check_network(merch_state.get_network())?;
+        match channel_state.get_network() {
+            BitcoinNetworkType::Mainnet => merch_state.sign_top_up::<Mainnet>(
+                db,
+                channel_state,
+                channel_token,
+                request,
+                fee_cc,
+            ),
+            BitcoinNetworkType::Testnet | BitcoinNetworkType::Regtest => merch_state
+                .sign_top_up::<Testnet>(db, channel_state, channel_token, request, fee_cc),
+        }
+    }
+
+    ///
+    /// top_up_customer_sign() - takes as input the channel state, channel token, the merchant's signatures on the new
+    /// initial closing txs, the secret key of the top-up input and the customer state. Verifies the close signatures
+    /// before signing the splice-tx (the current state is not revoked yet)
+    /// output: customer signatures on the splice-tx
+    ///
+    pub fn top_up_customer_sign(
+        channel_state: &ChannelMPCState,
+        channel_token: &ChannelMPCToken,
+        escrow_sig: &Vec<u8>,
+        merch_sig: &Vec<u8>,
+        funding_sk: &[u8; 32],
+        cust_state: &mut CustomerMPCState,
+    ) -> Result<TopUpSignatures, ZkChannelError> {
+        channel_state.check_network(channel_token.network)?;
+        channel_state.check_network(cust_state.get_network())?;
+        match channel_state.get_network() {
+            BitcoinNetworkType::Mainnet => cust_state.sign_top_up::<Mainnet>(
+                channel_state,
+                channel_token,
+                escrow_sig,
+                merch_sig,
+                funding_sk,
+            ),
+            BitcoinNetworkType::Testnet | BitcoinNetworkType::Regtest => cust_state
+                .sign_top_up::<Testnet>(
+                    channel_state,
+                    channel_token,
+                    escrow_sig,
+                    merch_sig,
+                    funding_sk,
+                ),
+        }
+    }
+
+    ///
+    /// top_up_merchant_finalize() - takes as input the db, channel token, the top-up request, the customer signatures and
+    /// the merchant state. Completes the splice-tx and registers the new escrow: the current state is marked spent and
+    /// the new escrow as pending open. The old escrow stays open until the splice-tx confirms
+    /// output: signed splice-tx and the merchant's escrow signature
+    ///
+    pub fn top_up_merchant_finalize(
+        db: &mut dyn StateDatabase,
+        channel_token: &ChannelMPCToken,
+        request: &TopUpRequest,
+        cust_sigs: &TopUpSignatures,
+        merch_state: &MerchantMPCState,
+    ) -> Result<(Vec<u8>, Vec<u8>), ZkChannelError> {
+        merch_state.finalize_top_up(db, channel_token, request, cust_sigs)
+    }
+
+    ///
+    /// top_up_customer_finalize() - takes as input the merchant's escrow signature and the customer state. Verifies the
+    /// signature and completes the splice-tx. The customer keeps the current state until the splice-tx confirms
+    /// output: signed splice-tx
+    ///
+    pub fn top_up_customer_finalize(
+        merch_escrow_sig: &Vec<u8>,
+        cust_state: &mut CustomerMPCState,
+    ) -> Result<Vec<u8>, ZkChannelError> {
+        cust_state.finalize_top_up(merch_escrow_sig)
+    }
+
+    ///
+    /// top_up_customer_complete() - takes as input the channel token and the customer state. Once the chain monitor saw the
+    /// splice-tx confirm, switches the channel token and customer state over to the new escrow (activate again)
+    /// output: rev_secret of the replaced state (for the merchant)
+    ///
+    pub fn top_up_customer_complete(
+        channel_token: &mut ChannelMPCToken,
+        cust_state: &mut CustomerMPCState,
+    ) -> Result<FixedSizeArray32, ZkChannelError> {
+        cust_state.complete_top_up(channel_token)
+    }
+
+    ///
+    /// top_up_merchant_revoke() - takes as input the db, the top-up request, the customer's rev_secret and the merchant
+    /// state. Once the splice-tx confirmed, stores the rev_secret of the state the top-up replaced (to dispute it)
+    /// output: success or error
+    ///
+    pub fn top_up_merchant_revoke(
+        db: &mut dyn StateDatabase,
+        request: &TopUpRequest,
+        rev_secret: &FixedSizeArray32,
+        merch_state: &MerchantMPCState,
+    ) -> Result<(), ZkChannelError> {
+        merch_state.verify_top_up_revocation(db, request, rev_secret)
+    }
+
+    ///
+    /// merchant_dispute() - takes as input the db, the channel state, a broadcast cust-close-tx (from escrow or merch-close)
+    /// and the merchant state. extracts the rev_lock from the cust-close-tx and looks up the corresponding rev_secret.
+    /// signs a dispute tx that claims the to_customer output of the revoked cust-close-tx and marks the channel as disputed
+    /// output: merch-dispute-signed-tx
+    ///
+    pub fn merchant_dispute(
+        db: &mut dyn StateDatabase,
+        channel_state: &ChannelMPCState,
+        raw_close_tx: &Vec<u8>,
+        merch_state: &mut MerchantMPCState,
+    ) -> Result<Vec<u8>, ZkChannelError> {
+        channel_state.check_network(merch_state.get_network())?;
+        merch_state.sign_dispute_transaction(db, raw_close_tx)
+    }
+
+    ///
+    /// watchtower_authorize_merchant() - takes as input the merchant state.
+    /// pre-authorizes a watchtower to sign dispute txs that pay to the merchant's payout-pk
+    /// output: dispute authorization (includes the dispute secret key)
+    ///
+    pub fn watchtower_authorize_merchant(
+        merch_state: &MerchantMPCState,
+    ) -> Result<DisputeAuthorization, ZkChannelError> {
+        merch_state.get_dispute_authorization()
+    }
+
+    ///
+    /// watchtower_channel_merchant() - takes as input the db, the channel state, the channel token and the merchant state.
+    /// looks up the outpoints and the merch-close-tx parameters of an activated channel
+    /// output: channel for Watchtower::add_channel()
+    ///
+    pub fn watchtower_channel_merchant(
+        db: &mut dyn StateDatabase,
+        channel_state: &ChannelMPCState,
+        channel_token: &ChannelMPCToken,
+        merch_state: &MerchantMPCState,
+    ) -> Result<WatchedChannel, ZkChannelError> {
+        channel_state.check_network(merch_state.get_network())?;
+        merch_state.get_watched_channel(db, channel_token)
+    }
+
+    ///
+    /// watchtower_dispute_material_merchant() - takes as input the db, a rev_lock and the merchant state.
+    /// looks up the rev_secret that was stored by pay_validate_rev_lock_merchant()
+    /// output: dispute material for Watchtower::add_dispute_material()
+    ///
+    pub fn watchtower_dispute_material_merchant(
+        db: &mut dyn StateDatabase,
+        rev_lock: [u8; 32],
+        merch_state: &MerchantMPCState,
+    ) -> Result<DisputeMaterial, ZkChannelError> {
+        merch_state.get_dispute_material(db, rev_lock)
+    }
+
+    ///
+    /// watchtower_record_dispute_merchant() - takes as input the db, a dispute published by the watchtower and
+    /// the merchant state. marks the channel as disputed
+    /// output: success or error
+    ///
+    pub fn watchtower_record_dispute_merchant(
+        db: &mut dyn StateDatabase,
+        dispute: &WatchtowerDispute,
+        merch_state: &MerchantMPCState,
+    ) -> Result<(), ZkChannelError> {
+        merch_state.mark_channel_disputed(db, dispute.escrow_txid_be.0)
+    }
+}
+
+#[cfg(all(test, feature = "unstable"))]
+mod benches {
+    use rand::{thread_rng, Rng};
+    use test::Bencher;
+
+    #[bench]
+    pub fn bench_one(bh: &mut Bencher) {
+        println!("Run benchmark tests here!");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pairing::bls12_381::Bls12;
+    use rand::{Rng, SeedableRng};
+    use rand_xorshift::XorShiftRng;
+    use sha2::{Digest, Sha256};
+
+    use bindings::ConnType_NETIO;
+    use channels_mpc::{ChannelStatus, PaymentStatus, ProtocolStatus};
+    use database::{
+        HashMapDatabase, MaskedMPCInputs, MaskedTxMPCInputs, RedisDatabase, StateDatabase,
+    };
+    use std::process::Command;
+    use std::{env, ptr};
+    use util::get_current_time;
+    use zkchan_tx::fixed_size_array::{FixedSizeArray16, FixedSizeArray32};
+    use zkchan_tx::Testnet;
+
+    fn setup_new_channel_helper(
+        channel_state: &mut zkproofs::ChannelState<Bls12>,
+        init_cust_bal: i64,
+        init_merch_bal: i64,
+    ) -> (
+        zkproofs::ChannelToken<Bls12>,
+        zkproofs::MerchantState<Bls12>,
+        zkproofs::CustomerState<Bls12>,
+        zkproofs::ChannelState<Bls12>,
+    ) {
+        let rng = &mut rand::thread_rng();
+        let merch_name = "Bob";
         let cust_name = "Alice";
 
         let b0_cust = init_cust_bal;
@@ -2146,6 +3095,7 @@ mod tests {
             min_threshold,
             val_cpfp,
             false,
+            mpc::BitcoinNetworkType::Testnet,
         );
         // init merchant
         let mut merch_state = mpc::init_merchant(rng, "".to_string(), &mut channel_state, "Bob");
@@ -2170,6 +3120,7 @@ mod tests {
         // init customer
         let (mut channel_token, mut cust_state) = mpc::init_customer(
             rng,
+            &channel_state,
             &merch_state.pk_m,
             b0_cust,
             b0_merch,
@@ -2222,11 +3173,15 @@ mod tests {
         );
         assert!(res2.is_ok(), res2.err().unwrap());
         let _rc = mpc::customer_mark_open_channel(&mut cust_state).unwrap();
-        let _rc =
-            mpc::merchant_mark_open_channel(channel_token.escrow_txid.0.clone(), &mut merch_state)
-                .unwrap();
+        let _rc = mpc::merchant_mark_open_channel(
+            &mut db as &mut dyn StateDatabase,
+            channel_token.escrow_txid.0.clone(),
+            &mut merch_state,
+        )
+        .unwrap();
 
         let s0 = mpc::activate_customer(rng, &mut cust_state).unwrap();
+        let event_key = mpc::EventKey::from_token(&channel_token);
 
         let pay_token = mpc::activate_merchant(
             &mut db as &mut dyn StateDatabase,
@@ -2238,74 +3193,478 @@ mod tests {
 
         mpc::activate_customer_finalize(pay_token.unwrap(), &mut cust_state).unwrap();
 
+        // both sides logged the establishment of the channel under its escrow txid
+        let merch_events = db.get_events(&event_key).unwrap();
+        assert!(merch_events.iter().any(|r| match r.event {
+            mpc::ProtocolEvent::ChannelParamsValidated { .. } => true,
+            _ => false,
+        }));
+        assert_eq!(
+            merch_events.last().unwrap().event,
+            mpc::ProtocolEvent::Activated
+        );
+        let cust_events = cust_state.take_events();
+        assert!(cust_events.iter().all(|r| r.key == event_key));
+        assert_eq!(
+            cust_events.last().unwrap().event,
+            mpc::ProtocolEvent::Activated
+        );
+        assert!(cust_state.take_events().is_empty());
+
         //TODO: test unlinking with a 0-payment of pay protocol
     }
 
-    fn generate_funding_tx<R: Rng>(csprng: &mut R, b0_cust: i64, b0_merch: i64) -> FundingTxInfo {
-        let mut escrow_txid = [0u8; 32];
-        let mut merch_txid = [0u8; 32];
+    #[test]
+    fn test_init_from_seed_and_restore() {
+        let mut rng = XorShiftRng::seed_from_u64(0xbb67ae8584caa73b);
+        let seed = [0x5au8; 32];
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+        let new_channel_state = || {
+            mpc::ChannelMPCState::new(
+                String::from("Channel A -> B"),
+                1487,
+                546,
+                546,
+                1000,
+                false,
+                mpc::BitcoinNetworkType::Testnet,
+            )
+        };
 
-        csprng.fill_bytes(&mut escrow_txid);
-        csprng.fill_bytes(&mut merch_txid);
+        // the merchant state is the same whenever it is derived from the same seed
+        let mut channel_state = new_channel_state();
+        let merch_state = mpc::init_merchant_from_seed(
+            &mut rng,
+            "".to_string(),
+            &mut channel_state,
+            "Bob",
+            &seed,
+            0,
+        )
+        .unwrap();
+        let mut rec_channel_state = new_channel_state();
+        let rec_merch_state = mpc::init_merchant_from_seed(
+            &mut rng,
+            "".to_string(),
+            &mut rec_channel_state,
+            "Bob",
+            &seed,
+            0,
+        )
+        .unwrap();
+        assert_eq!(merch_state, rec_merch_state);
+        assert_eq!(channel_state, rec_channel_state);
 
-        let mut escrow_prevout = [0u8; 32];
-        let mut merch_prevout = [0u8; 32];
+        let merch_keys = mpc::restore_merchant_keys(&seed, 0, &channel_state).unwrap();
+        assert_eq!(merch_keys.get_pk_m(), merch_state.pk_m);
+        assert!(mpc::restore_merchant_keys(&seed, 1, &channel_state).is_err());
 
-        let mut prevout_preimage1: Vec<u8> = Vec::new();
-        prevout_preimage1.extend(escrow_txid.iter()); // txid1
-        prevout_preimage1.extend(vec![0x00, 0x00, 0x00, 0x00]); // index
-        let result1 = Sha256::digest(&Sha256::digest(&prevout_preimage1));
-        escrow_prevout.copy_from_slice(&result1);
+        // every channel of the customer has its own keys
+        let (channel_token, cust_state) = mpc::init_customer_from_seed(
+            &mut rng,
+            &channel_state,
+            &merch_state.pk_m,
+            10000,
+            10000,
+            &tx_fee_info,
+            "Alice",
+            &seed,
+            0,
+        )
+        .unwrap();
+        let (other_channel_token, _) = mpc::init_customer_from_seed(
+            &mut rng,
+            &channel_state,
+            &merch_state.pk_m,
+            10000,
+            10000,
+            &tx_fee_info,
+            "Alice",
+            &seed,
+            1,
+        )
+        .unwrap();
+        assert_eq!(channel_token.pk_c, Some(cust_state.pk_c));
+        assert_ne!(channel_token.pk_c, other_channel_token.pk_c);
 
-        let mut prevout_preimage2: Vec<u8> = Vec::new();
-        prevout_preimage2.extend(merch_txid.iter()); // txid2
-        prevout_preimage2.extend(vec![0x00, 0x00, 0x00, 0x00]); // index
-        let result2 = Sha256::digest(&Sha256::digest(&prevout_preimage2));
-        merch_prevout.copy_from_slice(&result2);
+        let cust_keys = mpc::restore_customer_keys(&seed, 0, &channel_token).unwrap();
+        assert_eq!(cust_keys.sk_c.to_vec(), cust_state.get_secret_key());
+        assert_eq!(
+            cust_keys.payout_sk.to_vec(),
+            cust_state.get_close_secret_key()
+        );
+        assert!(mpc::restore_customer_keys(&seed, 0, &other_channel_token).is_err());
+        assert!(mpc::restore_customer_keys(&[0x5bu8; 32], 0, &channel_token).is_err());
+    }
 
-        return FundingTxInfo {
-            init_cust_bal: b0_cust,
-            init_merch_bal: b0_merch,
-            escrow_txid: FixedSizeArray32(escrow_txid),
-            merch_txid: FixedSizeArray32(merch_txid),
-            escrow_prevout: FixedSizeArray32(escrow_prevout),
-            merch_prevout: FixedSizeArray32(merch_prevout),
+    fn generate_escrow_contribution<R: Rng>(
+        rng: &mut R,
+        escrow_sats: i64,
+    ) -> ([u8; 32], mpc::EscrowContribution) {
+        let secp = secp256k1::Secp256k1::new();
+        let mut funding_sk = [0u8; 32];
+        rng.fill_bytes(&mut funding_sk);
+        let mut change_sk = [0u8; 32];
+        rng.fill_bytes(&mut change_sk);
+        let mut txid_le = [0u8; 32];
+        rng.fill_bytes(&mut txid_le);
+
+        let sk = secp256k1::SecretKey::from_slice(&funding_sk).unwrap();
+        let change_sk = secp256k1::SecretKey::from_slice(&change_sk).unwrap();
+        let contribution = mpc::EscrowContribution {
+            input: mpc::EscrowFundingInput {
+                txid_le: FixedSizeArray32(txid_le),
+                index: 0,
+                input_sats: escrow_sats + 10000,
+                pk: secp256k1::PublicKey::from_secret_key(&secp, &sk),
+            },
+            escrow_sats: escrow_sats,
+            change_sats: 9000,
+            change_pk: Some(secp256k1::PublicKey::from_secret_key(&secp, &change_sk)),
         };
+        (funding_sk, contribution)
     }
 
-    fn setup_new_zkchannel_helper<R: Rng>(
-        rng: &mut R,
-        cust_bal: i64,
-        merch_bal: i64,
-        tx_fee_info: &mpc::TransactionFeeInfo,
-    ) -> (
-        mpc::ChannelMPCState,
-        mpc::ChannelMPCToken,
-        mpc::CustomerMPCState,
-        mpc::MerchantMPCState,
-    ) {
-        // init channel state
+    #[test]
+    fn test_establish_dual_funded_mpc_channel() {
+        let mut rng = XorShiftRng::seed_from_u64(0x8d313d765dbe6259);
+        let mut db = HashMapDatabase::new("", "".to_string()).unwrap();
+
+        let min_threshold = 546;
+        let val_cpfp = 1000;
         let mut channel_state = mpc::ChannelMPCState::new(
             String::from("Channel A -> B"),
             1487,
-            tx_fee_info.bal_min_cust,
-            tx_fee_info.bal_min_merch,
-            tx_fee_info.val_cpfp,
+            min_threshold,
+            min_threshold,
+            val_cpfp,
             false,
+            mpc::BitcoinNetworkType::Testnet,
         );
-        // init merchant
-        let merch_state = mpc::init_merchant(rng, "".to_string(), &mut channel_state, "Bob");
+        let mut merch_state =
+            mpc::init_merchant(&mut rng, "".to_string(), &mut channel_state, "Bob");
 
-        let b0_cust = cust_bal;
-        let b0_merch = merch_bal;
-        // init customer
-        let (channel_token, cust_state) = mpc::init_customer(
-            rng,
-            &merch_state.pk_m,
-            b0_cust,
-            b0_merch,
-            tx_fee_info,
-            "Alice",
+        let fee_cc = 1000;
+        let fee_mc = 1000;
+        let b0_cust = 20000;
+        let b0_merch = 5000;
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: min_threshold,
+            bal_min_merch: min_threshold,
+            val_cpfp: val_cpfp,
+            fee_cc: fee_cc,
+            fee_mc: fee_mc,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+
+        // the merchant contribution has to cover its own merch-close-tx
+        assert!(mpc::init_dual_funded_customer(
+            &mut rng,
+            &channel_state,
+            &merch_state.pk_m,
+            b0_cust,
+            1000,
+            &tx_fee_info,
+            "Alice",
+        )
+        .is_err());
+        let (mut channel_token, mut cust_state) = mpc::init_dual_funded_customer(
+            &mut rng,
+            &channel_state,
+            &merch_state.pk_m,
+            b0_cust,
+            b0_merch,
+            &tx_fee_info,
+            "Alice",
+        )
+        .unwrap();
+        assert_eq!(cust_state.get_funding_contributions(), (b0_cust, b0_merch));
+
+        // merchant proposes its input and change, customer completes the escrow-tx
+        let (merch_funding_sk, merch_contribution) =
+            generate_escrow_contribution(&mut rng, b0_merch);
+        let (cust_funding_sk, cust_contribution) = generate_escrow_contribution(&mut rng, b0_cust);
+        let escrow_tx = mpc::DualFundedEscrowTx::new(
+            cust_state.pk_c,
+            merch_state.pk_m,
+            cust_contribution,
+            merch_contribution.clone(),
+        )
+        .unwrap();
+
+        let merch_txid: [u8; 32] = rng.gen();
+        let merch_prevout: [u8; 32] = rng.gen();
+        let funding_tx_info = escrow_tx.get_funding_tx_info(merch_txid, merch_prevout);
+        assert_eq!(funding_tx_info.merch_funding, b0_merch);
+        cust_state
+            .set_initial_cust_state(&mut channel_token, &funding_tx_info, &tx_fee_info)
+            .unwrap();
+
+        // customer only signs its escrow input once it holds the signed initial closing txs
+        assert!(mpc::customer_sign_dual_funded_escrow(
+            &channel_token,
+            &escrow_tx,
+            &cust_funding_sk,
+            &cust_state
+        )
+        .is_err());
+
+        let pubkeys = cust_state.get_pubkeys(&channel_state, &channel_token);
+        let to_self_delay_be = channel_state.get_self_delay_be();
+        let (escrow_sig, merch_sig) = merch_state
+            .sign_initial_closing_transaction::<Testnet>(
+                funding_tx_info.clone(),
+                pubkeys.rev_lock.0,
+                pubkeys.cust_pk.clone(),
+                pubkeys.cust_close_pk,
+                to_self_delay_be,
+                fee_cc,
+                fee_mc,
+                val_cpfp,
+            )
+            .unwrap();
+        let got_close_tx = cust_state
+            .sign_initial_closing_transaction::<Testnet>(
+                &channel_state,
+                &channel_token,
+                &escrow_sig,
+                &merch_sig,
+            )
+            .unwrap();
+        assert!(got_close_tx);
+
+        // merchant keeps the merch-close-tx and validates the initial state
+        merch_state
+            .store_merch_close_tx(
+                &mut db as &mut dyn StateDatabase,
+                &funding_tx_info.escrow_txid.0.to_vec(),
+                &pubkeys.cust_pk,
+                b0_cust,
+                b0_merch,
+                fee_mc,
+                to_self_delay_be,
+                &vec![0u8; 72],
+            )
+            .unwrap();
+        assert!(mpc::merchant_sign_dual_funded_escrow(
+            &mut db as &mut dyn StateDatabase,
+            &escrow_tx,
+            &merch_contribution,
+            &merch_funding_sk,
+            &merch_state
+        )
+        .is_err());
+
+        let (init_cust_state, init_hash) = mpc::get_initial_state(&cust_state).unwrap();
+        assert_eq!(init_cust_state.cust_funding, b0_cust);
+        assert_eq!(init_cust_state.merch_funding, b0_merch);
+        let res = mpc::validate_channel_params(
+            &mut db as &mut dyn StateDatabase,
+            &channel_token,
+            &init_cust_state,
+            init_hash,
+            &mut merch_state,
+        );
+        assert!(res.is_ok(), res.err().unwrap());
+
+        // merchant refuses to sign if its contribution was modified
+        let mut bad_escrow_tx = escrow_tx.clone();
+        bad_escrow_tx.merch.change_sats -= 1000;
+        assert!(mpc::merchant_sign_dual_funded_escrow(
+            &mut db as &mut dyn StateDatabase,
+            &bad_escrow_tx,
+            &merch_contribution,
+            &merch_funding_sk,
+            &merch_state
+        )
+        .is_err());
+
+        // exchange partial signatures on the escrow-tx
+        let cust_sig = mpc::customer_sign_dual_funded_escrow(
+            &channel_token,
+            &escrow_tx,
+            &cust_funding_sk,
+            &cust_state,
+        )
+        .unwrap();
+        let merch_sig = mpc::merchant_sign_dual_funded_escrow(
+            &mut db as &mut dyn StateDatabase,
+            &escrow_tx,
+            &merch_contribution,
+            &merch_funding_sk,
+            &merch_state,
+        )
+        .unwrap();
+        assert!(escrow_tx.verify(mpc::FundingParty::Customer, &cust_sig));
+        assert!(escrow_tx.verify(mpc::FundingParty::Merchant, &merch_sig));
+
+        let (signed_tx, escrow_txid_le) =
+            mpc::finalize_dual_funded_escrow(&escrow_tx, &cust_sig, &merch_sig).unwrap();
+        assert!(!signed_tx.is_empty());
+        assert_eq!(escrow_txid_le, channel_token.escrow_txid.0);
+
+        mpc::customer_mark_open_channel(&mut cust_state).unwrap();
+        mpc::merchant_mark_open_channel(
+            &mut db as &mut dyn StateDatabase,
+            escrow_txid_le,
+            &mut merch_state,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_mpc_channel_network_mismatch() {
+        let mut rng = XorShiftRng::seed_from_u64(0x5dbe62598d313d76);
+        let mut db = HashMapDatabase::new("", "".to_string()).unwrap();
+
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+        let mut channel_state = mpc::ChannelMPCState::new(
+            String::from("Channel A -> B"),
+            1487,
+            tx_fee_info.bal_min_cust,
+            tx_fee_info.bal_min_merch,
+            tx_fee_info.val_cpfp,
+            false,
+            mpc::BitcoinNetworkType::Mainnet,
+        );
+        let mut merch_state =
+            mpc::init_merchant(&mut rng, "".to_string(), &mut channel_state, "Bob");
+        assert_eq!(merch_state.get_network(), mpc::BitcoinNetworkType::Mainnet);
+
+        let (mut channel_token, mut cust_state) = mpc::init_customer(
+            &mut rng,
+            &channel_state,
+            &merch_state.pk_m,
+            10000,
+            10000,
+            &tx_fee_info,
+            "Alice",
+        );
+        assert_eq!(channel_token.network, mpc::BitcoinNetworkType::Mainnet);
+        assert_eq!(cust_state.get_network(), mpc::BitcoinNetworkType::Mainnet);
+
+        // the same channel parameters on a different network
+        let regtest_state = mpc::ChannelMPCState::new(
+            String::from("Channel A -> B"),
+            1487,
+            tx_fee_info.bal_min_cust,
+            tx_fee_info.bal_min_merch,
+            tx_fee_info.val_cpfp,
+            false,
+            mpc::BitcoinNetworkType::Regtest,
+        );
+        let res = mpc::pay_prepare_customer(&mut rng, &regtest_state, 100, &mut cust_state);
+        assert!(res.is_err());
+        let res = mpc::force_customer_close(&regtest_state, &channel_token, true, &mut cust_state);
+        assert!(res.is_err());
+
+        // merchant rejects a channel token created for another network
+        let funding_tx_info = generate_funding_tx(&mut rng, 10000, 10000);
+        cust_state
+            .set_initial_cust_state(&mut channel_token, &funding_tx_info, &tx_fee_info)
+            .unwrap();
+        let (init_cust_state, init_hash) = mpc::get_initial_state(&cust_state).unwrap();
+        channel_token.network = mpc::BitcoinNetworkType::Testnet;
+        let res = mpc::validate_channel_params(
+            &mut db as &mut dyn StateDatabase,
+            &channel_token,
+            &init_cust_state,
+            init_hash,
+            &mut merch_state,
+        );
+        assert!(res.is_err());
+
+        let network: mpc::BitcoinNetworkType = "regtest".parse().unwrap();
+        assert_eq!(network, mpc::BitcoinNetworkType::Regtest);
+        assert!("signet".parse::<mpc::BitcoinNetworkType>().is_err());
+    }
+
+    fn generate_funding_tx<R: Rng>(csprng: &mut R, b0_cust: i64, b0_merch: i64) -> FundingTxInfo {
+        let mut escrow_txid = [0u8; 32];
+        let mut merch_txid = [0u8; 32];
+
+        csprng.fill_bytes(&mut escrow_txid);
+        csprng.fill_bytes(&mut merch_txid);
+
+        let mut escrow_prevout = [0u8; 32];
+        let mut merch_prevout = [0u8; 32];
+
+        let mut prevout_preimage1: Vec<u8> = Vec::new();
+        prevout_preimage1.extend(escrow_txid.iter()); // txid1
+        prevout_preimage1.extend(vec![0x00, 0x00, 0x00, 0x00]); // index
+        let result1 = Sha256::digest(&Sha256::digest(&prevout_preimage1));
+        escrow_prevout.copy_from_slice(&result1);
+
+        let mut prevout_preimage2: Vec<u8> = Vec::new();
+        prevout_preimage2.extend(merch_txid.iter()); // txid2
+        prevout_preimage2.extend(vec![0x00, 0x00, 0x00, 0x00]); // index
+        let result2 = Sha256::digest(&Sha256::digest(&prevout_preimage2));
+        merch_prevout.copy_from_slice(&result2);
+
+        return FundingTxInfo {
+            init_cust_bal: b0_cust,
+            init_merch_bal: b0_merch,
+            escrow_txid: FixedSizeArray32(escrow_txid),
+            merch_txid: FixedSizeArray32(merch_txid),
+            escrow_prevout: FixedSizeArray32(escrow_prevout),
+            merch_prevout: FixedSizeArray32(merch_prevout),
+            cust_funding: b0_cust + b0_merch,
+            merch_funding: 0,
+        };
+    }
+
+    fn setup_new_zkchannel_helper<R: Rng>(
+        rng: &mut R,
+        cust_bal: i64,
+        merch_bal: i64,
+        tx_fee_info: &mpc::TransactionFeeInfo,
+    ) -> (
+        mpc::ChannelMPCState,
+        mpc::ChannelMPCToken,
+        mpc::CustomerMPCState,
+        mpc::MerchantMPCState,
+    ) {
+        // init channel state
+        let mut channel_state = mpc::ChannelMPCState::new(
+            String::from("Channel A -> B"),
+            1487,
+            tx_fee_info.bal_min_cust,
+            tx_fee_info.bal_min_merch,
+            tx_fee_info.val_cpfp,
+            false,
+            mpc::BitcoinNetworkType::Testnet,
+        );
+        // init merchant
+        let merch_state = mpc::init_merchant(rng, "".to_string(), &mut channel_state, "Bob");
+
+        let b0_cust = cust_bal;
+        let b0_merch = merch_bal;
+        // init customer
+        let (channel_token, cust_state) = mpc::init_customer(
+            rng,
+            &channel_state,
+            &merch_state.pk_m,
+            b0_cust,
+            b0_merch,
+            tx_fee_info,
+            "Alice",
         );
 
         return (channel_state, channel_token, cust_state, merch_state);
@@ -2326,6 +3685,7 @@ mod tests {
             min_threshold,
             val_cpfp,
             false,
+            mpc::BitcoinNetworkType::Testnet,
         );
 
         let mut merch_state =
@@ -2350,6 +3710,7 @@ mod tests {
 
         let (mut channel_token, mut cust_state) = mpc::init_customer(
             &mut rng,
+            &channel_state,
             &merch_state.pk_m,
             b0_cust,
             b0_merch,
@@ -2381,13 +3742,20 @@ mod tests {
         escrow_txid_be.reverse();
         let rc = cust_state.change_channel_status(ChannelStatus::PendingOpen);
         assert!(rc.is_ok());
-        let rc = merch_state.change_channel_status(escrow_txid_be, ChannelStatus::PendingOpen);
+        let rc = merch_state.change_channel_status(
+            &mut db as &mut dyn StateDatabase,
+            escrow_txid_be,
+            ChannelStatus::PendingOpen,
+        );
         assert!(rc.is_ok());
 
         let _rc = mpc::customer_mark_open_channel(&mut cust_state).unwrap();
-        let _rc =
-            mpc::merchant_mark_open_channel(channel_token.escrow_txid.0.clone(), &mut merch_state)
-                .unwrap();
+        let _rc = mpc::merchant_mark_open_channel(
+            &mut db as &mut dyn StateDatabase,
+            channel_token.escrow_txid.0.clone(),
+            &mut merch_state,
+        )
+        .unwrap();
 
         let s0 = mpc::activate_customer(&mut rng, &mut cust_state).unwrap();
 
@@ -2475,7 +3843,7 @@ mod tests {
 
             let min_threshold = 546;
             let val_cpfp = 1000;
-            let mut channel_state = mpc::ChannelMPCState::new(String::from("Channel A -> B"), 1487, min_threshold, min_threshold, val_cpfp, false);
+            let mut channel_state = mpc::ChannelMPCState::new(String::from("Channel A -> B"), 1487, min_threshold, min_threshold, val_cpfp, false, mpc::BitcoinNetworkType::Testnet);
             let mut merch_state = mpc::init_merchant(&mut rng, "".to_string(), &mut channel_state, "Bob");
 
             let b0_cust = 100000;
@@ -2495,7 +3863,7 @@ mod tests {
                 max_fee: max_fee
             };
 
-            let (mut channel_token, mut cust_state) = mpc::init_customer(&mut rng, &merch_state.pk_m, b0_cust, b0_merch, &tx_fee_info, "Alice");
+            let (mut channel_token, mut cust_state) = mpc::init_customer(&mut rng, &channel_state, &merch_state.pk_m, b0_cust, b0_merch, &tx_fee_info, "Alice");
 
             let funding_tx_info = generate_funding_tx(&mut rng, b0_cust, b0_merch);
 
@@ -2515,12 +3883,12 @@ mod tests {
             escrow_txid_be.reverse();
             let rc = cust_state.change_channel_status(ChannelStatus::PendingOpen);
             assert!(rc.is_ok());
-            let rc = merch_state.change_channel_status(escrow_txid_be, ChannelStatus::PendingOpen);
+            let rc = merch_state.change_channel_status(&mut db as &mut dyn StateDatabase, escrow_txid_be, ChannelStatus::PendingOpen);
             assert!(rc.is_ok());
 
             let rc = mpc::customer_mark_open_channel(&mut cust_state);
             assert!(rc.is_ok());
-            let rc = mpc::merchant_mark_open_channel(channel_token.escrow_txid.0.clone(), &mut merch_state);
+            let rc = mpc::merchant_mark_open_channel(&mut db as &mut dyn StateDatabase, channel_token.escrow_txid.0.clone(), &mut merch_state);
             assert!(rc.is_ok());
 
             let s0 = mpc::activate_customer(&mut rng, &mut cust_state).unwrap();
@@ -2620,6 +3988,7 @@ mod tests {
 
     // establish the init merch-close-tx
     fn establish_merch_close_tx_helper(
+        db: &mut RedisDatabase,
         funding_tx_info: &mut FundingTxInfo,
         channel_state: &mpc::ChannelMPCState,
         channel_token: &mpc::ChannelMPCToken,
@@ -2667,7 +4036,8 @@ mod tests {
         .unwrap();
 
         // store the signature for merch-close-tx
-        merch_state.store_merch_close_tx(
+        let rc = merch_state.store_merch_close_tx(
+            db as &mut dyn StateDatabase,
             &escrow_txid_be.to_vec(),
             &pubkeys.cust_pk,
             cust_bal,
@@ -2676,6 +4046,7 @@ mod tests {
             to_self_delay_be,
             &cust_sig,
         );
+        assert!(rc.is_ok(), rc.err().unwrap());
     }
 
     // validate the initial state of the channel
@@ -2827,8 +4198,11 @@ mod tests {
 
         let rc = mpc::customer_mark_open_channel(&mut cust_state);
         assert!(rc.is_ok());
-        let rc =
-            mpc::merchant_mark_open_channel(channel_token.escrow_txid.0.clone(), &mut merch_state);
+        let rc = mpc::merchant_mark_open_channel(
+            &mut db as &mut dyn StateDatabase,
+            channel_token.escrow_txid.0.clone(),
+            &mut merch_state,
+        );
         assert!(rc.is_ok());
 
         activate_channel_helper(
@@ -2842,32 +4216,1151 @@ mod tests {
         println!("cust_state channel status: {}", cust_state.protocol_status);
     }
 
-    fn zkchannel_full_establish_setup_helper<R: Rng>(
-        rng: &mut R,
-        db: &mut RedisDatabase,
-        tx_fee_info: &mpc::TransactionFeeInfo,
-    ) -> (
-        mpc::ChannelMPCState,
-        mpc::ChannelMPCToken,
-        mpc::CustomerMPCState,
-        mpc::MerchantMPCState,
-    ) {
-        let b0_cust = 10000;
-        let b0_merch = 10000;
-
-        let (channel_state, mut channel_token, mut cust_state, mut merch_state) =
-            setup_new_zkchannel_helper(rng, b0_cust, b0_merch, &tx_fee_info);
+    #[test]
+    fn test_payment_session_expiry_and_restart() {
+        let mut rng = XorShiftRng::seed_from_u64(0x8d313d865dbe6259);
+        let mut db = RedisDatabase::new("mpcexpire", "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
 
-        // generate random funding tx for testing
-        let mut funding_tx_info = generate_funding_tx(rng, b0_cust, b0_merch);
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+        let (channel_state, _channel_token, _cust_state, mut merch_state) =
+            setup_new_zkchannel_helper(&mut rng, 10000, 10000, &tx_fee_info);
+
+        let mut nonce = [0u8; mpc::NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+        let rev_lock_com = [1u8; 32];
+        let session_a = [1u8; 16];
+        let session_b = [2u8; 16];
+        let session_c = [3u8; 16];
+
+        for session_id in vec![session_a, session_b] {
+            let result = mpc::pay_prepare_merchant(
+                &mut rng,
+                &mut db as &mut dyn StateDatabase,
+                &channel_state,
+                session_id,
+                nonce,
+                rev_lock_com,
+                100,
+                None,
+                &mut merch_state,
+            );
+            if session_id == session_a {
+                assert!(result.is_ok());
+            } else {
+                // the nonce is still bound to the active session
+                match result {
+                    Err(mpc::ZkChannelError::DoubleSpentNonce(_)) => (),
+                    _ => panic!("expected the nonce to be in use"),
+                }
+            }
+        }
 
-        // customer and merchant jointly sign merch-close-tx
-        establish_merch_close_tx_helper(
-            &mut funding_tx_info,
+        // a failed session releases the nonce for a new session
+        assert!(mpc::pay_confirm_mpc_result(
+            &mut db as &mut dyn StateDatabase,
+            session_a,
+            false,
+            &mut merch_state
+        )
+        .is_err());
+        mpc::pay_prepare_merchant(
+            &mut rng,
+            &mut db as &mut dyn StateDatabase,
             &channel_state,
-            &channel_token,
-            b0_cust,
-            b0_merch,
+            session_b,
+            nonce,
+            rev_lock_com,
+            100,
+            None,
+            &mut merch_state,
+        )
+        .unwrap();
+        assert!(!db.check_session_id(&hex::encode(session_a)).unwrap());
+
+        // nothing expires before the timeout
+        let expired =
+            mpc::merchant_expire_sessions(&mut db as &mut dyn StateDatabase, &merch_state).unwrap();
+        assert!(expired.is_empty());
+
+        merch_state.set_session_timeout(0);
+        let expired =
+            mpc::merchant_expire_sessions(&mut db as &mut dyn StateDatabase, &merch_state).unwrap();
+        assert_eq!(expired, vec![session_b]);
+        assert!(!db.check_session_id(&hex::encode(session_b)).unwrap());
+        assert!(db.get_mask_map_from_nonce(&hex::encode(nonce)).is_err());
+
+        // the customer restarts the payment with the same nonce
+        merch_state.set_session_timeout(600);
+        mpc::pay_prepare_merchant(
+            &mut rng,
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            session_c,
+            nonce,
+            rev_lock_com,
+            100,
+            None,
+            &mut merch_state,
+        )
+        .unwrap();
+        assert!(db.check_session_id(&hex::encode(session_c)).unwrap());
+    }
+
+    #[test]
+    fn test_session_with_released_masks_cannot_restart() {
+        let mut rng = XorShiftRng::seed_from_u64(0x3d865dbe62598d31);
+        let mut db = RedisDatabase::new("mpcreleased", "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
+
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+        let (channel_state, _channel_token, _cust_state, mut merch_state) =
+            setup_new_zkchannel_helper(&mut rng, 10000, 10000, &tx_fee_info);
+
+        let mut nonce = [0u8; mpc::NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+        let rev_lock_com = [1u8; 32];
+        let session_a = [1u8; 16];
+        let session_b = [2u8; 16];
+        mpc::pay_prepare_merchant(
+            &mut rng,
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            session_a,
+            nonce,
+            rev_lock_com,
+            100,
+            None,
+            &mut merch_state,
+        )
+        .unwrap();
+
+        // the mpc succeeded and the customer gets the masks of the session
+        let masked_inputs = MaskedMPCInputs {
+            pt_mask: FixedSizeArray32([6u8; 32]),
+            pt_mask_r: FixedSizeArray16([7u8; 16]),
+            escrow_mask: FixedSizeArray32([8u8; 32]),
+            merch_mask: FixedSizeArray32([9u8; 32]),
+            r_escrow_sig: FixedSizeArray32([10u8; 32]),
+            r_merch_sig: FixedSizeArray32([11u8; 32]),
+        };
+        db.update_masked_mpc_inputs(&hex::encode(session_a), masked_inputs);
+        mpc::pay_confirm_mpc_result(
+            &mut db as &mut dyn StateDatabase,
+            session_a,
+            true,
+            &mut merch_state,
+        )
+        .unwrap();
+        let session_state = db.load_session_state(&hex::encode(session_a)).unwrap();
+        assert!(session_state.masks_released);
+
+        // a late failure report does not turn the session into a restartable one
+        assert!(mpc::pay_confirm_mpc_result(
+            &mut db as &mut dyn StateDatabase,
+            session_a,
+            false,
+            &mut merch_state
+        )
+        .is_err());
+
+        // the session does not expire and its nonce cannot be used for a new session
+        merch_state.set_session_timeout(0);
+        let expired =
+            mpc::merchant_expire_sessions(&mut db as &mut dyn StateDatabase, &merch_state).unwrap();
+        assert!(expired.is_empty());
+        assert!(db.check_session_id(&hex::encode(session_a)).unwrap());
+        let result = mpc::pay_prepare_merchant(
+            &mut rng,
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            session_b,
+            nonce,
+            rev_lock_com,
+            100,
+            None,
+            &mut merch_state,
+        );
+        match result {
+            Err(mpc::ZkChannelError::DoubleSpentNonce(_)) => (),
+            _ => panic!("expected the nonce to stay bound to the released session"),
+        }
+        assert!(!db.check_session_id(&hex::encode(session_b)).unwrap());
+    }
+
+    #[test]
+    fn test_refund_justification_is_verified() {
+        let mut rng = XorShiftRng::seed_from_u64(0x5dbe62598d313d77);
+        let mut db = RedisDatabase::new("mpcrefund", "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
+
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+        let (channel_state, _channel_token, _cust_state, mut merch_state) =
+            setup_new_zkchannel_helper(&mut rng, 10000, 10000, &tx_fee_info);
+
+        let secp = secp256k1::Secp256k1::new();
+        let mut sk = [0u8; 32];
+        rng.fill_bytes(&mut sk);
+        let authority_sk = secp256k1::SecretKey::from_slice(&sk).unwrap();
+        let authority_pk = secp256k1::PublicKey::from_secret_key(&secp, &authority_sk);
+        merch_state.set_refund_policy(mpc::NegativePaymentPolicy::CHECK_JUSTIFICATION);
+        merch_state.set_refund_authority(authority_pk);
+
+        let refund_id = [7u8; 32];
+        let expiry = get_current_time() + 3600;
+        let refund = mpc::RefundJustification::sign(&authority_sk, refund_id, -100, expiry);
+        let justification = serde_json::to_string(&refund).unwrap();
+        let expired = mpc::RefundJustification::sign(&authority_sk, [8u8; 32], -100, 1);
+        let wrong_sk = secp256k1::SecretKey::from_slice(&[9u8; 32]).unwrap();
+        let forged = mpc::RefundJustification::sign(&wrong_sk, [9u8; 32], -100, expiry);
+
+        let mut prepare = |session_id: [u8; 16], justification: Option<String>| {
+            let mut nonce = [0u8; mpc::NONCE_LEN];
+            rng.fill_bytes(&mut nonce);
+            mpc::pay_prepare_merchant(
+                &mut rng,
+                &mut db as &mut dyn StateDatabase,
+                &channel_state,
+                session_id,
+                nonce,
+                [1u8; 32],
+                -100,
+                justification,
+                &mut merch_state,
+            )
+        };
+
+        assert!(prepare([1u8; 16], None).is_err());
+        assert!(prepare([2u8; 16], Some(serde_json::to_string(&expired).unwrap())).is_err());
+        assert!(prepare([3u8; 16], Some(serde_json::to_string(&forged).unwrap())).is_err());
+        assert!(prepare([4u8; 16], Some(justification.clone())).is_ok());
+        // the refund cannot be replayed on another state
+        assert!(prepare([5u8; 16], Some(justification.clone())).is_err());
+    }
+
+    #[test]
+    fn test_merchant_dispute_revoked_close_tx() {
+        let mut rng = XorShiftRng::seed_from_u64(0x8a3f11d0b2c54e97);
+        let mut db = RedisDatabase::new("disputetest", "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
+
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+
+        let (channel_state, channel_token, mut cust_state, mut merch_state) =
+            zkchannel_full_establish_setup_helper(&mut rng, &mut db, &tx_fee_info);
+        let (rev_lock, rev_secret) = cust_state.get_rev_pair();
+
+        let (cust_close_escrow_tx, _, close_txid_le) =
+            mpc::force_customer_close(&channel_state, &channel_token, true, &mut cust_state)
+                .unwrap();
+        let (cust_close_merch_tx, _, _) =
+            mpc::force_customer_close(&channel_state, &channel_token, false, &mut cust_state)
+                .unwrap();
+
+        // the current state has not been revoked yet
+        let res = mpc::merchant_dispute(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &cust_close_escrow_tx,
+            &mut merch_state,
+        );
+        assert!(res.is_err());
+
+        // cust-close-tx is parsed as expected
+        let close_tx = channels_mpc::parse_cust_close_transaction(&cust_close_escrow_tx).unwrap();
+        assert_eq!(close_tx.txid_le.to_vec(), close_txid_le);
+        assert_eq!(close_tx.rev_lock, rev_lock);
+
+        // customer revokes the state (as in pay_validate_rev_lock_merchant)
+        db.update_rev_lock_map(&hex::encode(&rev_lock), &hex::encode(&rev_secret))
+            .unwrap();
+
+        let res = mpc::merchant_dispute(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &cust_close_escrow_tx,
+            &mut merch_state,
+        );
+        assert!(res.is_ok(), res.err().unwrap());
+
+        let mut escrow_txid_be = channel_token.escrow_txid.0.clone();
+        escrow_txid_be.reverse();
+        assert_eq!(
+            merch_state
+                .get_channel_status(&mut db as &mut dyn StateDatabase, escrow_txid_be)
+                .unwrap(),
+            ChannelStatus::Disputed
+        );
+
+        // the revoked cust-close-tx spending from the merch-close-tx can be disputed as well
+        let res = mpc::merchant_dispute(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &cust_close_merch_tx,
+            &mut merch_state,
+        );
+        assert!(res.is_ok(), res.err().unwrap());
+    }
+
+    #[test]
+    fn test_claim_close_outputs() {
+        let mut rng = XorShiftRng::seed_from_u64(0x5d2c8e17a94b3f60);
+        let mut db = RedisDatabase::new("claimtest", "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
+
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+
+        let (channel_state, channel_token, mut cust_state, mut merch_state) =
+            zkchannel_full_establish_setup_helper(&mut rng, &mut db, &tx_fee_info);
+        let self_delay = channel_state.self_delay as u64;
+        let close_height = 100;
+        let mut escrow_txid_be = channel_token.escrow_txid.0.clone();
+        escrow_txid_be.reverse();
+
+        let (cust_close_tx, _, _) =
+            mpc::force_customer_close(&channel_state, &channel_token, true, &mut cust_state)
+                .unwrap();
+        let (merch_close_tx, _, _) = mpc::force_merchant_close(
+            &mut db as &mut dyn StateDatabase,
+            &escrow_txid_be.to_vec(),
+            channel_state.get_val_cpfp(),
+            &mut merch_state,
+        )
+        .unwrap();
+
+        // the to_customer output is timelocked
+        let res = mpc::customer_claim(
+            &channel_state,
+            &cust_close_tx,
+            close_height,
+            close_height + self_delay - 1,
+            1000,
+            &mut cust_state,
+        );
+        assert!(res.is_err());
+        assert_eq!(
+            cust_state.get_channel_status(),
+            ChannelStatus::CustomerInitClose
+        );
+        let res = mpc::customer_claim(
+            &channel_state,
+            &cust_close_tx,
+            close_height,
+            close_height + self_delay,
+            1000,
+            &mut cust_state,
+        );
+        assert!(res.is_ok(), res.err().unwrap());
+        assert_eq!(
+            cust_state.get_channel_status(),
+            ChannelStatus::ConfirmedClose
+        );
+
+        // a tx that spends the escrow but is not the stored merch-close-tx is not claimed
+        let mut other_tx = merch_close_tx.clone();
+        let last = other_tx.len() - 1;
+        other_tx[last] ^= 0x01;
+        let res = mpc::merchant_claim(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &other_tx,
+            close_height,
+            close_height + self_delay,
+            1000,
+            &merch_state,
+        );
+        assert!(res.is_err());
+
+        // the to_merchant output of the merch-close-tx is timelocked as well
+        let res = mpc::merchant_claim(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &merch_close_tx,
+            close_height,
+            close_height + self_delay - 1,
+            1000,
+            &merch_state,
+        );
+        assert!(res.is_err());
+        let res = mpc::merchant_claim(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &merch_close_tx,
+            close_height,
+            close_height + self_delay,
+            1000,
+            &merch_state,
+        );
+        assert!(res.is_ok(), res.err().unwrap());
+        assert_eq!(
+            merch_state
+                .get_channel_status(&mut db as &mut dyn StateDatabase, escrow_txid_be)
+                .unwrap(),
+            ChannelStatus::ConfirmedClose
+        );
+
+        // the to_merchant output of a cust-close-tx can be claimed right away
+        let res = mpc::merchant_claim(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &cust_close_tx,
+            close_height,
+            close_height + 1,
+            1000,
+            &merch_state,
+        );
+        assert!(res.is_ok(), res.err().unwrap());
+
+        // the fee cannot consume the claimed output
+        let res = mpc::merchant_claim(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &cust_close_tx,
+            close_height,
+            close_height + 1,
+            1000000,
+            &merch_state,
+        );
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_watchtower_disputes_revoked_close_tx() {
+        let mut rng = XorShiftRng::seed_from_u64(0x5be1c07a93d2f416);
+        let mut db = RedisDatabase::new("towertest", "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
+
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+
+        let (channel_state, channel_token, mut cust_state, mut merch_state) =
+            zkchannel_full_establish_setup_helper(&mut rng, &mut db, &tx_fee_info);
+        let (rev_lock, rev_secret) = cust_state.get_rev_pair();
+        let (cust_close_escrow_tx, _, close_txid_le) =
+            mpc::force_customer_close(&channel_state, &channel_token, true, &mut cust_state)
+                .unwrap();
+
+        let auth = mpc::watchtower_authorize_merchant(&merch_state).unwrap();
+        assert_eq!(auth.dispute_pk, merch_state.dispute_pk);
+        let mut tower = mpc::Watchtower::new(auth, 0);
+        let channel = mpc::watchtower_channel_merchant(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &channel_token,
+            &merch_state,
+        )
+        .unwrap();
+        tower.add_channel(channel);
+
+        // the current state has not been revoked yet
+        let res = mpc::watchtower_dispute_material_merchant(
+            &mut db as &mut dyn StateDatabase,
+            rev_lock,
+            &merch_state,
+        );
+        assert!(res.is_err());
+
+        // customer revokes the state (as in pay_validate_rev_lock_merchant)
+        db.update_rev_lock_map(&hex::encode(&rev_lock), &hex::encode(&rev_secret))
+            .unwrap();
+        let material = mpc::watchtower_dispute_material_merchant(
+            &mut db as &mut dyn StateDatabase,
+            rev_lock,
+            &merch_state,
+        )
+        .unwrap();
+        tower.add_dispute_material(material).unwrap();
+
+        let mut chain = mpc::MockChain::new();
+        chain.mine_block(vec![channel_token.escrow_txid.0.to_vec()]);
+        chain.mine_block(vec![cust_close_escrow_tx.clone()]);
+        let mut publisher = mpc::MockChain::new();
+        let disputes = tower
+            .process_new_blocks(&mut chain, &mut publisher)
+            .unwrap()
+            .published;
+        assert_eq!(disputes.len(), 1);
+        assert_eq!(disputes[0].height, 1);
+        assert_eq!(disputes[0].close_txid_le.0.to_vec(), close_txid_le);
+        assert_eq!(publisher.mempool, vec![disputes[0].signed_tx.clone()]);
+
+        mpc::watchtower_record_dispute_merchant(
+            &mut db as &mut dyn StateDatabase,
+            &disputes[0],
+            &merch_state,
+        )
+        .unwrap();
+        let mut escrow_txid_be = channel_token.escrow_txid.0.clone();
+        escrow_txid_be.reverse();
+        assert_eq!(
+            merch_state
+                .get_channel_status(&mut db as &mut dyn StateDatabase, escrow_txid_be)
+                .unwrap(),
+            ChannelStatus::Disputed
+        );
+
+        // the watchtower signs the same dispute tx as the merchant
+        let signed_tx = mpc::merchant_dispute(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &cust_close_escrow_tx,
+            &mut merch_state,
+        )
+        .unwrap();
+        assert_eq!(signed_tx, disputes[0].signed_tx);
+
+        // the dispute tx and a repeated cust-close-tx are not disputed again
+        let dispute_tx = publisher.mempool.clone();
+        chain.mine_block(dispute_tx);
+        chain.mine_block(vec![cust_close_escrow_tx]);
+        let disputes = tower
+            .process_new_blocks(&mut chain, &mut publisher)
+            .unwrap()
+            .published;
+        assert!(disputes.is_empty());
+        assert_eq!(tower.get_next_height(), 4);
+    }
+
+    #[test]
+    fn test_watchtower_skips_undisputable_close_tx() {
+        let mut rng = XorShiftRng::seed_from_u64(0x7a93d2f4165be1c0);
+        let mut db = RedisDatabase::new("towerskip", "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
+
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+
+        let (channel_state, channel_token, mut cust_state, merch_state) =
+            zkchannel_full_establish_setup_helper(&mut rng, &mut db, &tx_fee_info);
+        let (rev_lock, rev_secret) = cust_state.get_rev_pair();
+        let (cust_close_escrow_tx, _, close_txid_le) =
+            mpc::force_customer_close(&channel_state, &channel_token, true, &mut cust_state)
+                .unwrap();
+
+        let auth = mpc::watchtower_authorize_merchant(&merch_state).unwrap();
+        let mut tower = mpc::Watchtower::new(auth, 0);
+        let channel = mpc::watchtower_channel_merchant(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &channel_token,
+            &merch_state,
+        )
+        .unwrap();
+        db.update_rev_lock_map(&hex::encode(&rev_lock), &hex::encode(&rev_secret))
+            .unwrap();
+        let material = mpc::watchtower_dispute_material_merchant(
+            &mut db as &mut dyn StateDatabase,
+            rev_lock,
+            &merch_state,
+        )
+        .unwrap();
+        tower.add_dispute_material(material).unwrap();
+
+        // the to_customer output of the revoked close tx does not cover the dispute fee
+        let mut costly_channel = channel.clone();
+        costly_channel.fee_mc = cust_state.cust_balance;
+        tower.add_channel(costly_channel);
+
+        let mut chain = mpc::MockChain::new();
+        chain.mine_block(vec![cust_close_escrow_tx.clone(), vec![0x01, 0x02]]);
+        chain.mine_block(Vec::new());
+        let mut publisher = mpc::MockChain::new();
+        let report = tower
+            .process_new_blocks(&mut chain, &mut publisher)
+            .unwrap();
+        assert!(report.published.is_empty());
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].raw_tx, cust_close_escrow_tx);
+        assert!(publisher.mempool.is_empty());
+        assert_eq!(tower.get_next_height(), 2);
+
+        // the scan goes on and disputes the close tx once it can pay the fee
+        tower.add_channel(channel);
+        chain.mine_block(vec![cust_close_escrow_tx]);
+        let disputes = tower
+            .process_new_blocks(&mut chain, &mut publisher)
+            .unwrap()
+            .published;
+        assert_eq!(disputes.len(), 1);
+        assert_eq!(disputes[0].height, 2);
+        assert_eq!(disputes[0].close_txid_le.0.to_vec(), close_txid_le);
+        assert_eq!(tower.get_next_height(), 3);
+        assert!(tower.get_pending_disputes().is_empty());
+    }
+
+    #[test]
+    fn test_mutual_close_mpc_channel() {
+        let mut rng = XorShiftRng::seed_from_u64(0x3b6e0f19c2d7a854);
+        let mut db = RedisDatabase::new("mutualtest", "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
+
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+
+        let (channel_state, channel_token, mut cust_state, mut merch_state) =
+            zkchannel_full_establish_setup_helper(&mut rng, &mut db, &tx_fee_info);
+        let cur_state = cust_state.get_current_state();
+
+        let request =
+            mpc::mutual_close_customer(&channel_state, &channel_token, &mut cust_state).unwrap();
+        assert_eq!(request.cust_bal, cur_state.bc - tx_fee_info.fee_cc);
+        assert_eq!(request.merch_bal, cur_state.bm);
+        // the channel stays open until the merchant signed
+        assert_eq!(cust_state.get_channel_status(), ChannelStatus::Open);
+
+        // the customer's balance after the fee must stay above the minimum
+        let mut strict_channel_state = mpc::ChannelMPCState::new(
+            channel_state.name.clone(),
+            channel_state.self_delay,
+            request.cust_bal + 1,
+            tx_fee_info.bal_min_merch,
+            tx_fee_info.val_cpfp,
+            false,
+            channel_state.get_network(),
+        );
+        strict_channel_state.merch_payout_pk = channel_state.merch_payout_pk.clone();
+        let res =
+            mpc::mutual_close_customer(&strict_channel_state, &channel_token, &mut cust_state);
+        match res {
+            Err(mpc::ZkChannelError::InvalidInput(_)) => (),
+            _ => panic!("expected an invalid input error"),
+        }
+
+        // merchant rejects a split that does not match the state
+        let mut bad_request = request.clone();
+        bad_request.merch_bal -= 100;
+        let res = mpc::mutual_close_merchant(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &channel_token,
+            &bad_request,
+            &mut merch_state,
+        );
+        assert!(res.is_err());
+
+        let res = mpc::mutual_close_merchant(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &channel_token,
+            &request,
+            &mut merch_state,
+        );
+        assert!(res.is_ok(), res.err().unwrap());
+        let (signed_tx, _txid_le) = res.unwrap();
+
+        // customer only accepts the tx if the merchant's signature is valid
+        let mut bad_tx = signed_tx.clone();
+        let last = bad_tx.len() - 5;
+        bad_tx[last] ^= 0x01;
+        let res = mpc::mutual_close_customer_finalize(
+            &channel_state,
+            &channel_token,
+            &request,
+            &bad_tx,
+            &mut cust_state,
+        );
+        assert!(res.is_err());
+        assert_eq!(cust_state.get_channel_status(), ChannelStatus::Open);
+        let res = mpc::mutual_close_customer_finalize(
+            &channel_state,
+            &channel_token,
+            &request,
+            &signed_tx,
+            &mut cust_state,
+        );
+        assert!(res.is_ok(), res.err().unwrap());
+        assert_eq!(cust_state.get_channel_status(), ChannelStatus::MutualClose);
+
+        // customer refuses to pay after the mutual close
+        let res = mpc::pay_prepare_customer(&mut rng, &channel_state, 10, &mut cust_state);
+        assert!(res.is_err());
+
+        let mut escrow_txid_be = channel_token.escrow_txid.0.clone();
+        escrow_txid_be.reverse();
+        assert_eq!(
+            merch_state
+                .get_channel_status(&mut db as &mut dyn StateDatabase, escrow_txid_be)
+                .unwrap(),
+            ChannelStatus::MutualClose
+        );
+
+        // merchant refuses to pay on the state that was closed
+        let session_id: [u8; 16] = rng.gen();
+        let res = mpc::pay_prepare_merchant(
+            &mut rng,
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            session_id,
+            cur_state.get_nonce(),
+            [0u8; 32],
+            10,
+            None,
+            &mut merch_state,
+        );
+        match res {
+            Err(mpc::ZkChannelError::DoubleSpentNonce(_)) => (),
+            _ => panic!("expected a double spent nonce error"),
+        }
+
+        let res = mpc::mutual_close_merchant(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &channel_token,
+            &request,
+            &mut merch_state,
+        );
+        match res {
+            Err(mpc::ZkChannelError::InvalidStatusTransition(_)) => (),
+            _ => panic!("expected an invalid status transition error"),
+        }
+
+        // mutual close tx confirmed on chain
+        let res = cust_state.change_channel_status(ChannelStatus::ConfirmedClose);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_top_up_mpc_channel() {
+        let mut rng = XorShiftRng::seed_from_u64(0x6a09e667f3bcc908);
+        let mut db = RedisDatabase::new("topuptest", "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
+
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+
+        let (channel_state, mut channel_token, mut cust_state, mut merch_state) =
+            zkchannel_full_establish_setup_helper(&mut rng, &mut db, &tx_fee_info);
+        let cur_state = cust_state.get_current_state();
+        let old_escrow_txid_be = cur_state.escrow_txid.0;
+
+        // customer spends the escrow together with a new input into a new escrow
+        let top_up = 20000;
+        let (funding_sk, contribution) = generate_escrow_contribution(&mut rng, top_up);
+        let splice_tx = mpc::EscrowSpliceTx::new(
+            cust_state.pk_c,
+            merch_state.pk_m,
+            old_escrow_txid_be,
+            cur_state.bc + cur_state.bm,
+            contribution,
+        )
+        .unwrap();
+        let (new_escrow_txid_be, new_escrow_txid_le) = splice_tx.get_txid();
+
+        // customer and merchant jointly sign the merch-close-tx on the new escrow
+        let mut funding_tx_info =
+            generate_funding_tx(&mut rng, cur_state.bc + top_up, cur_state.bm);
+        funding_tx_info.escrow_txid = FixedSizeArray32(new_escrow_txid_be);
+        establish_merch_close_tx_helper(
+            &mut db,
+            &mut funding_tx_info,
+            &channel_state,
+            &channel_token,
+            cur_state.bc + top_up,
+            cur_state.bm,
+            &mut cust_state,
+            &mut merch_state,
+            tx_fee_info.fee_mc,
+        );
+
+        let request = mpc::top_up_customer_prepare(
+            &mut rng,
+            &channel_state,
+            &channel_token,
+            &splice_tx,
+            funding_tx_info.merch_txid.0,
+            funding_tx_info.merch_prevout.0,
+            &mut cust_state,
+        )
+        .unwrap();
+        assert_eq!(request.state, cur_state);
+        assert_eq!(request.funding_tx.init_cust_bal, cur_state.bc + top_up);
+        assert_eq!(request.funding_tx.init_merch_bal, cur_state.bm);
+
+        // the current state is carried forward, so no payments until the top-up is done
+        let res = mpc::pay_prepare_customer(&mut rng, &channel_state, 10, &mut cust_state);
+        assert!(res.is_err());
+
+        // merchant rejects balances that are not carried forward
+        let mut bad_request = request.clone();
+        bad_request.funding_tx.init_merch_bal -= 100;
+        let res = mpc::top_up_merchant_sign(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &channel_token,
+            &bad_request,
+            tx_fee_info.fee_cc,
+            &merch_state,
+        );
+        assert!(res.is_err());
+
+        let (escrow_sig, merch_sig) = mpc::top_up_merchant_sign(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &channel_token,
+            &request,
+            tx_fee_info.fee_cc,
+            &merch_state,
+        )
+        .unwrap();
+
+        // customer only signs the splice-tx with valid close signatures on the new escrow
+        assert!(mpc::top_up_customer_sign(
+            &channel_state,
+            &channel_token,
+            &merch_sig,
+            &escrow_sig,
+            &funding_sk,
+            &mut cust_state,
+        )
+        .is_err());
+        let cust_sigs = mpc::top_up_customer_sign(
+            &channel_state,
+            &channel_token,
+            &escrow_sig,
+            &merch_sig,
+            &funding_sk,
+            &mut cust_state,
+        )
+        .unwrap();
+
+        // merchant does not sign the splice-tx with invalid customer signatures
+        let mut bad_sigs = cust_sigs.clone();
+        bad_sigs.escrow_sig = bad_sigs.top_up_sig.clone();
+        let res = mpc::top_up_merchant_finalize(
+            &mut db as &mut dyn StateDatabase,
+            &channel_token,
+            &request,
+            &bad_sigs,
+            &merch_state,
+        );
+        assert!(res.is_err());
+
+        let (signed_tx, merch_escrow_sig) = mpc::top_up_merchant_finalize(
+            &mut db as &mut dyn StateDatabase,
+            &channel_token,
+            &request,
+            &cust_sigs,
+            &merch_state,
+        )
+        .unwrap();
+        assert_eq!(
+            merch_state
+                .get_channel_status(&mut db as &mut dyn StateDatabase, old_escrow_txid_be)
+                .unwrap(),
+            ChannelStatus::Open
+        );
+        assert_eq!(
+            merch_state
+                .get_channel_status(&mut db as &mut dyn StateDatabase, new_escrow_txid_be)
+                .unwrap(),
+            ChannelStatus::PendingOpen
+        );
+        assert!(!db.check_rev_lock_map(&hex::encode(cur_state.get_rev_lock())));
+
+        // the old escrow can not be topped up twice
+        let res = mpc::top_up_merchant_finalize(
+            &mut db as &mut dyn StateDatabase,
+            &channel_token,
+            &request,
+            &cust_sigs,
+            &merch_state,
+        );
+        assert!(res.is_err());
+
+        // customer only accepts the splice-tx with a valid merchant signature
+        let res = mpc::top_up_customer_finalize(&cust_sigs.escrow_sig, &mut cust_state);
+        assert!(res.is_err());
+        let cust_signed_tx =
+            mpc::top_up_customer_finalize(&merch_escrow_sig, &mut cust_state).unwrap();
+        assert_eq!(cust_signed_tx, signed_tx);
+        assert_eq!(cust_state.get_current_state(), cur_state);
+
+        // splice-tx confirmed on chain
+        cust_state
+            .change_channel_status(ChannelStatus::Spliced)
+            .unwrap();
+        merch_state
+            .change_channel_status(
+                &mut db as &mut dyn StateDatabase,
+                old_escrow_txid_be,
+                ChannelStatus::Spliced,
+            )
+            .unwrap();
+
+        let rev_secret =
+            mpc::top_up_customer_complete(&mut channel_token, &mut cust_state).unwrap();
+        assert_eq!(
+            util::hash_to_slice(&rev_secret.0.to_vec()),
+            cur_state.get_rev_lock()
+        );
+        assert_eq!(channel_token.escrow_txid.0, new_escrow_txid_le);
+        assert_eq!(cust_state.cust_balance, cur_state.bc + top_up);
+        assert_eq!(cust_state.merch_balance, cur_state.bm);
+        assert_eq!(cust_state.get_channel_status(), ChannelStatus::PendingOpen);
+        assert!(!cust_state.has_pending_top_up());
+
+        // the replaced state is revoked last, so it can be disputed if the customer closes on it
+        let mut bad_rev_secret = rev_secret.clone();
+        bad_rev_secret.0[0] ^= 1;
+        let res = mpc::top_up_merchant_revoke(
+            &mut db as &mut dyn StateDatabase,
+            &request,
+            &bad_rev_secret,
+            &merch_state,
+        );
+        assert!(res.is_err());
+        mpc::top_up_merchant_revoke(
+            &mut db as &mut dyn StateDatabase,
+            &request,
+            &rev_secret,
+            &merch_state,
+        )
+        .unwrap();
+        assert_eq!(
+            db.get_rev_secret(&hex::encode(cur_state.get_rev_lock()))
+                .unwrap(),
+            hex::encode(&rev_secret.0)
+        );
+
+        // once the splice-tx confirms, the channel is activated on the new escrow
+        mpc::customer_mark_open_channel(&mut cust_state).unwrap();
+        mpc::merchant_mark_open_channel(
+            &mut db as &mut dyn StateDatabase,
+            new_escrow_txid_le,
+            &mut merch_state,
+        )
+        .unwrap();
+        activate_channel_helper(
+            &mut rng,
+            &mut db,
+            &channel_token,
+            &mut cust_state,
+            &mut merch_state,
+        );
+        assert!(cust_state.protocol_status == ProtocolStatus::Activated);
+    }
+
+    #[test]
+    fn test_top_up_mpc_channel_splice_not_confirmed() {
+        let mut rng = XorShiftRng::seed_from_u64(0x510e527fade682d1);
+        let mut db = RedisDatabase::new("topupnoconf", "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
+
+        let tx_fee_info = mpc::TransactionFeeInfo {
+            bal_min_cust: 546,
+            bal_min_merch: 546,
+            val_cpfp: 1000,
+            fee_cc: 1000,
+            fee_mc: 1000,
+            min_fee: 0,
+            max_fee: 10000,
+        };
+
+        let (channel_state, mut channel_token, mut cust_state, mut merch_state) =
+            zkchannel_full_establish_setup_helper(&mut rng, &mut db, &tx_fee_info);
+        let cur_state = cust_state.get_current_state();
+        let old_escrow_txid_be = cur_state.escrow_txid.0;
+        let old_channel_token = channel_token.clone();
+
+        let top_up = 20000;
+        let (funding_sk, contribution) = generate_escrow_contribution(&mut rng, top_up);
+        let splice_tx = mpc::EscrowSpliceTx::new(
+            cust_state.pk_c,
+            merch_state.pk_m,
+            old_escrow_txid_be,
+            cur_state.bc + cur_state.bm,
+            contribution,
+        )
+        .unwrap();
+        let (new_escrow_txid_be, _) = splice_tx.get_txid();
+        let mut funding_tx_info =
+            generate_funding_tx(&mut rng, cur_state.bc + top_up, cur_state.bm);
+        funding_tx_info.escrow_txid = FixedSizeArray32(new_escrow_txid_be);
+        establish_merch_close_tx_helper(
+            &mut db,
+            &mut funding_tx_info,
+            &channel_state,
+            &channel_token,
+            cur_state.bc + top_up,
+            cur_state.bm,
+            &mut cust_state,
+            &mut merch_state,
+            tx_fee_info.fee_mc,
+        );
+
+        let request = mpc::top_up_customer_prepare(
+            &mut rng,
+            &channel_state,
+            &channel_token,
+            &splice_tx,
+            funding_tx_info.merch_txid.0,
+            funding_tx_info.merch_prevout.0,
+            &mut cust_state,
+        )
+        .unwrap();
+        let (escrow_sig, merch_sig) = mpc::top_up_merchant_sign(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &channel_token,
+            &request,
+            tx_fee_info.fee_cc,
+            &merch_state,
+        )
+        .unwrap();
+        let cust_sigs = mpc::top_up_customer_sign(
+            &channel_state,
+            &channel_token,
+            &escrow_sig,
+            &merch_sig,
+            &funding_sk,
+            &mut cust_state,
+        )
+        .unwrap();
+        let (_, merch_escrow_sig) = mpc::top_up_merchant_finalize(
+            &mut db as &mut dyn StateDatabase,
+            &channel_token,
+            &request,
+            &cust_sigs,
+            &merch_state,
+        )
+        .unwrap();
+        mpc::top_up_customer_finalize(&merch_escrow_sig, &mut cust_state).unwrap();
+
+        // the splice-tx never confirms (e.g., the top-up input was double spent): the customer keeps
+        // the current state and does not reveal its rev_secret
+        let res = mpc::top_up_customer_complete(&mut channel_token, &mut cust_state);
+        assert!(res.is_err());
+        assert_eq!(channel_token, old_channel_token);
+        assert_eq!(cust_state.get_current_state(), cur_state);
+        assert_eq!(cust_state.get_channel_status(), ChannelStatus::Open);
+        assert!(cust_state.has_pending_top_up());
+
+        // the merchant does not consider the old escrow spliced and does not accept a revocation yet
+        assert_eq!(
+            merch_state
+                .get_channel_status(&mut db as &mut dyn StateDatabase, old_escrow_txid_be)
+                .unwrap(),
+            ChannelStatus::Open
+        );
+        let res = mpc::top_up_merchant_revoke(
+            &mut db as &mut dyn StateDatabase,
+            &request,
+            &FixedSizeArray32(cust_state.get_rev_pair().1),
+            &merch_state,
+        );
+        match res {
+            Err(mpc::ZkChannelError::InvalidStatusTransition(_)) => (),
+            _ => panic!("expected an invalid status transition error"),
+        }
+        assert!(!db.check_rev_lock_map(&hex::encode(cur_state.get_rev_lock())));
+
+        // the customer can still close on the current state of the old escrow
+        let (close_tx, _, _) =
+            mpc::force_customer_close(&channel_state, &channel_token, true, &mut cust_state)
+                .unwrap();
+        let close_tx_info = channels_mpc::parse_transaction(&close_tx).unwrap();
+        assert_eq!(close_tx_info.outpoints, vec![(old_escrow_txid_be, 0)]);
+        assert_eq!(
+            cust_state.get_channel_status(),
+            ChannelStatus::CustomerInitClose
+        );
+    }
+
+    fn zkchannel_full_establish_setup_helper<R: Rng>(
+        rng: &mut R,
+        db: &mut RedisDatabase,
+        tx_fee_info: &mpc::TransactionFeeInfo,
+    ) -> (
+        mpc::ChannelMPCState,
+        mpc::ChannelMPCToken,
+        mpc::CustomerMPCState,
+        mpc::MerchantMPCState,
+    ) {
+        let b0_cust = 10000;
+        let b0_merch = 10000;
+
+        let (channel_state, mut channel_token, mut cust_state, mut merch_state) =
+            setup_new_zkchannel_helper(rng, b0_cust, b0_merch, &tx_fee_info);
+
+        // generate random funding tx for testing
+        let mut funding_tx_info = generate_funding_tx(rng, b0_cust, b0_merch);
+
+        // customer and merchant jointly sign merch-close-tx
+        establish_merch_close_tx_helper(
+            db,
+            &mut funding_tx_info,
+            &channel_state,
+            &channel_token,
+            b0_cust,
+            b0_merch,
             &mut cust_state,
             &mut merch_state,
             tx_fee_info.fee_mc,
@@ -2897,8 +5390,11 @@ mod tests {
         // if escrow-tx confirmed on chain, can proceed to change status for both customer/merchant
         let rc = mpc::customer_mark_open_channel(&mut cust_state);
         assert!(rc.is_ok());
-        let rc =
-            mpc::merchant_mark_open_channel(channel_token.escrow_txid.0.clone(), &mut merch_state);
+        let rc = mpc::merchant_mark_open_channel(
+            db as &mut dyn StateDatabase,
+            channel_token.escrow_txid.0.clone(),
+            &mut merch_state,
+        );
         assert!(rc.is_ok());
 
         // customer/merchant activate the channel
@@ -2958,51 +5454,11 @@ mod tests {
         assert!(is_ok);
     }
 
-    fn load_merchant_state_info(
-        db_conn: &mut redis::Connection,
-        db_key: &String,
-        merch_state_key: &String,
-    ) -> Result<mpc::MerchantMPCState, String> {
-        // load the merchant state from DB
-        let ser_merch_state = get_file_from_db(db_conn, &db_key, &merch_state_key).unwrap();
-        let merch_state: mpc::MerchantMPCState = serde_json::from_str(&ser_merch_state).unwrap();
-        Ok(merch_state)
-    }
-
-    fn save_merchant_state_info(
-        db_conn: &mut redis::Connection,
-        db_key: &String,
-        channel_state_key: &String,
-        channel_state: Option<&mpc::ChannelMPCState>,
-        merch_state_key: &String,
-        merch_state: &mpc::MerchantMPCState,
-    ) -> Result<(), String> {
-        // let key = String::from("cli:merch_db");
-        match channel_state {
-            Some(n) => {
-                let channel_state_json_str = serde_json::to_string(n).unwrap();
-                store_file_in_db(
-                    db_conn,
-                    &db_key,
-                    &channel_state_key,
-                    &channel_state_json_str,
-                )?
-            }
-            None => false, // do nothing
-        };
-
-        let merch_state_json_str = serde_json::to_string(merch_state).unwrap();
-        store_file_in_db(db_conn, &db_key, &merch_state_key, &merch_state_json_str)?;
-        Ok(())
-    }
-
     fn run_mpctest_as_merchant(
         db: &mut RedisDatabase,
-        db_key: &String,
         session_id: [u8; 16],
         pay_mask_com: [u8; 32],
         channel_state: &mpc::ChannelMPCState,
-        merch_state_key: &String,
         merch_state: &mpc::MerchantMPCState,
     ) -> std::process::Child {
         let cur_dir = env::var("CARGO_MANIFEST_DIR").unwrap();
@@ -3017,20 +5473,9 @@ mod tests {
         let pay_mask_com_arg = format!("{}", hex::encode(pay_mask_com));
 
         // let's start a thread but block until we get to pay_update_customer()
-        let channel_state_key = "channel_state".to_string();
-        save_merchant_state_info(
-            &mut db.conn,
-            db_key,
-            &channel_state_key,
-            Some(&channel_state),
-            &merch_state_key,
-            &merch_state,
-        )
-        .unwrap();
+        db.save_merchant_state(channel_state, merch_state).unwrap();
 
         let child = Command::new(mpc_test_bin)
-            .arg("--db-key")
-            .arg(db_key.clone())
             .arg("--pay-mask-com")
             .arg(pay_mask_com_arg)
             .arg("--session-id")
@@ -3088,15 +5533,11 @@ mod tests {
         cust_state.set_network_config(nc.clone());
         merch_state.set_network_config(nc.clone());
 
-        let db_key = "mpctest:merch_db".to_string();
-        let merch_state_key = "merch_state".to_string();
         let mut mpc_child = run_mpctest_as_merchant(
             &mut db,
-            &db_key,
             session_id.clone(),
             pay_mask_com,
             &channel_state,
-            &merch_state_key,
             &merch_state,
         );
 
@@ -3123,8 +5564,7 @@ mod tests {
         assert!(ecode.success());
 
         // load the updated merchant state
-        let mut merch_state =
-            load_merchant_state_info(&mut db.conn, &db_key, &merch_state_key).unwrap();
+        let mut merch_state = db.load_merchant_state().unwrap().1;
 
         // complete the rest of unlink
         complete_pay_helper(
@@ -3155,11 +5595,9 @@ mod tests {
 
         let mut mpc_child = run_mpctest_as_merchant(
             &mut db,
-            &db_key,
             session_id1.clone(),
             pay_mask_com1,
             &channel_state,
-            &merch_state_key,
             &merch_state,
         );
 
@@ -3185,9 +5623,7 @@ mod tests {
         assert!(ecode.success());
 
         // load the updated merchant state
-        let merch_state_key = "merch_state".to_string();
-        let mut merch_state =
-            load_merchant_state_info(&mut db.conn, &db_key, &merch_state_key).unwrap();
+        let mut merch_state = db.load_merchant_state().unwrap().1;
 
         // complete the rest of unlink
         complete_pay_helper(
@@ -3219,13 +5655,16 @@ mod tests {
         let mut escrow_txid_be = channel_token.escrow_txid.0.clone(); // originally in LE
         escrow_txid_be.reverse();
         let (_merch_close_signed_tx, _merch_txid_be, _merch_txid_le) = mpc::force_merchant_close(
+            &mut db as &mut dyn StateDatabase,
             &escrow_txid_be.to_vec(),
             channel_state.get_val_cpfp(),
             &mut merch_state,
         )
         .unwrap();
         assert!(
-            merch_state.get_channel_status(escrow_txid_be).unwrap()
+            merch_state
+                .get_channel_status(&mut db as &mut dyn StateDatabase, escrow_txid_be)
+                .unwrap()
                 == ChannelStatus::MerchantInitClose
         );
 
@@ -3289,15 +5728,11 @@ mod tests {
         cust_state.set_network_config(nc.clone());
         merch_state.set_network_config(nc.clone());
 
-        let db_key = "mpctest:merch_db".to_string();
-        let merch_state_key = "merch_state".to_string();
         let mut mpc_child = run_mpctest_as_merchant(
             &mut db,
-            &db_key,
             session_id,
             pay_mask_com,
             &channel_state,
-            &merch_state_key,
             &merch_state,
         );
 
@@ -3322,8 +5757,7 @@ mod tests {
         assert!(ecode.success());
 
         // load the updated merchant state
-        let mut merch_state =
-            load_merchant_state_info(&mut db.conn, &db_key, &merch_state_key).unwrap();
+        let mut merch_state = db.load_merchant_state().unwrap().1;
         let mask = mpc::pay_confirm_mpc_result(
             &mut db as &mut dyn StateDatabase,
             session_id.clone(),
@@ -14,20 +14,31 @@ use libc::{c_int, c_void};
 use rand::Rng;
 use redis::Commands;
 use serde::Deserialize;
+use std::cmp;
+use std::env;
 use std::ffi::CString;
 use std::fs::File;
 use std::io::{BufRead, Read, Write};
 use std::net::{SocketAddr, TcpListener, TcpStream};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::ptr;
 use std::str::FromStr;
 use std::thread::sleep;
 use std::time;
 use structopt::StructOpt;
-use zkchan_tx::Testnet;
+use zkchan_tx::{Mainnet, Testnet};
 use zkchannels::bindings::Receive_return;
-use zkchannels::database::create_db_connection;
+use zkchannels::channels_mpc::{ChannelMPCState, ChannelMPCToken, MerchantMPCState};
+use zkchannels::customer_wallet::{CustomerChannel, CustomerWallet};
+use zkchannels::database::{
+    get_legacy_file_from_db, get_namespace, RedisDatabase, StateDatabase, WalletDatabase,
+};
+use zkchannels::encryption::StorageKey;
+use zkchannels::errors::ZkChannelError;
+use zkchannels::file_database::FileDatabase;
 use zkchannels::mpc;
+use zkchannels::mpc::BitcoinNetworkType;
+use zkchannels::persist::{self, PersistedState};
 use zkchannels::FundingTxInfo;
 
 extern "C" fn cb_send_data(_data: *mut c_void, _len: c_int, _peer: *mut c_void) -> *mut i8 {
@@ -114,6 +125,8 @@ pub struct Open {
     self_delay: u16,
     #[structopt(short = "n", long = "channel-name", default_value = "")]
     channel_name: String,
+    #[structopt(long = "network", default_value = "testnet")]
+    network: BitcoinNetworkType,
 }
 
 #[derive(Clone, Debug, StructOpt, Deserialize)]
@@ -192,6 +205,13 @@ pub struct Pay {
     other_port: String,
     #[structopt(short = "n", long = "channel-name", default_value = "")]
     channel_name: String,
+    // signed refund justification (json) for negative payments
+    #[structopt(long = "justification")]
+    justification: Option<String>,
+    // merchant only: number of payments served in parallel. Worker i listens on own-port + i
+    // and runs its MPC with the customer on other-port + i
+    #[structopt(short = "w", long = "workers", default_value = "1")]
+    workers: u16,
     #[structopt(short)]
     verbose: bool,
 }
@@ -208,6 +228,39 @@ pub struct Close {
     channel_id: String,
 }
 
+#[derive(Clone, Debug, StructOpt, Deserialize)]
+pub struct Bump {
+    #[structopt(long = "party")]
+    party: Party,
+    #[structopt(short = "t", long = "tx-file")]
+    tx_file: PathBuf,
+    #[structopt(short = "r", long = "fee-rate")]
+    fee_rate: i64,
+    #[structopt(short = "f", long = "file")]
+    file: PathBuf,
+    #[structopt(short = "n", long = "channel-id", default_value = "")]
+    channel_id: String,
+    // optional extra input that funds the child tx
+    #[structopt(long = "txid")]
+    txid: Option<String>,
+    #[structopt(long = "index")]
+    index: Option<u32>,
+    #[structopt(short = "a", long = "input-sats")]
+    input_sats: Option<i64>,
+    // file with the hex-encoded secret key of the extra input (kept off the command line)
+    #[structopt(long = "input-sk-file")]
+    input_sk_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, StructOpt, Deserialize)]
+pub struct RotateKey {
+    #[structopt(short = "k", long = "new-key-file")]
+    new_key_file: PathBuf,
+}
+
+#[derive(Clone, Debug, StructOpt, Deserialize)]
+pub struct Migrate {}
+
 #[derive(Clone, Debug, StructOpt, Deserialize)]
 pub enum Command {
     #[structopt(name = "setfees")] // for setting transaction fees for zkchannels
@@ -224,6 +277,12 @@ pub enum Command {
     PAY(Pay),
     #[structopt(name = "close")] // for generating closing txs
     CLOSE(Close),
+    #[structopt(name = "bump")] // for fee-bumping closing txs with a CPFP child tx
+    BUMP(Bump),
+    #[structopt(name = "rotatekey")] // for re-encrypting the stored states with a new key
+    ROTATEKEY(RotateKey),
+    #[structopt(name = "migrate")] // for moving the states of older cli versions into the db
+    MIGRATE(Migrate),
 }
 
 impl FromStr for Command {
@@ -258,6 +317,18 @@ pub fn write_file(file_name: &'static str, content: String) -> Result<(), String
     }
 }
 
+pub fn read_pathfile(path_buf: PathBuf) -> Result<String, String> {
+    let mut file = match File::open(path_buf) {
+        Ok(n) => n,
+        Err(e) => return Err(e.to_string()),
+    };
+    let mut content = String::new();
+    match file.read_to_string(&mut content) {
+        Ok(_) => Ok(content),
+        Err(e) => return Err(e.to_string()),
+    }
+}
+
 pub fn write_pathfile(path_buf: PathBuf, content: String) -> Result<(), String> {
     let mut file = match File::create(path_buf) {
         Ok(n) => n,
@@ -281,39 +352,345 @@ pub fn generate_keypair<R: Rng>(csprng: &mut R) -> (secp256k1::PublicKey, secp25
     (pk, sk)
 }
 
-pub fn connect_db(url: String) -> Result<redis::Connection, String> {
-    let conn = match create_db_connection(url) {
-        Ok(c) => c,
-        Err(e) => return Err(e.to_string()),
-    };
+// states and secrets are sealed at rest if ZK_STORAGE_KEY_FILE points to a key file
+fn get_storage_key() -> Result<Option<StorageKey>, String> {
+    match env::var("ZK_STORAGE_KEY_FILE") {
+        Ok(path) => Ok(Some(StorageKey::from_key_file(Path::new(&path))?)),
+        Err(_) => Ok(None),
+    }
+}
+
+const DEFAULT_DB_NAMESPACE: &str = "cli";
+
+// several merchants can share a db if ZK_MERCHANT_ID is set, each in the namespace "cli:{id}"
+fn get_db_namespace() -> Result<String, String> {
+    match env::var("ZK_MERCHANT_ID") {
+        Ok(id) => Ok(get_namespace(DEFAULT_DB_NAMESPACE, &id)?),
+        Err(_) => Ok(String::from(DEFAULT_DB_NAMESPACE)),
+    }
+}
+
+// the cli keeps all channels of the customer in a single wallet
+const CUST_WALLET_NAME: &str = "cli";
+
+///
+/// MigrationReport - the number of legacy records that were moved into the typed storage, and
+/// the legacy records that were left in place because they could not be read or verified
+///
+#[derive(Debug, Default)]
+pub struct MigrationReport {
+    pub migrated: usize,
+    pub skipped: Vec<String>,
+}
+
+///
+/// CliDatabase - a StateDatabase the cli can keep its states in. ZK_DB_URL selects the backend:
+/// a redis url (the default is a local redis server) or the path of a FileDatabase log
+///
+pub trait CliDatabase: StateDatabase + WalletDatabase + Send + Sized + 'static {
+    fn set_storage_key(&mut self, storage_key: StorageKey);
+    fn rotate_storage_key(&mut self, new_key: StorageKey) -> Result<usize, String>;
+    // moves the states that older versions of the cli kept outside of the typed storage
+    fn migrate_legacy_state(
+        &mut self,
+        _namespace: &String,
+        _storage_key: Option<&StorageKey>,
+    ) -> Result<MigrationReport, String> {
+        Ok(MigrationReport::default())
+    }
+}
+
+impl CliDatabase for RedisDatabase {
+    fn set_storage_key(&mut self, storage_key: StorageKey) {
+        RedisDatabase::set_storage_key(self, storage_key)
+    }
+
+    fn rotate_storage_key(&mut self, new_key: StorageKey) -> Result<usize, String> {
+        Ok(RedisDatabase::rotate_storage_key(self, new_key)?)
+    }
+
+    // older versions kept each customer channel in the hash "id:{name}", and the merchant state
+    // and channel tokens in the hashes "cli:merch_db" and "cli:merch_channels" (sealed with the
+    // storage key if one was set). The merchant hashes predate namespaces, so they are only moved
+    // into the default namespace. A legacy record is only copied if the typed storage has no
+    // record of the same name, and it is deleted only once the typed storage holds the same record
+    fn migrate_legacy_state(
+        &mut self,
+        namespace: &String,
+        storage_key: Option<&StorageKey>,
+    ) -> Result<MigrationReport, String> {
+        let mut report = MigrationReport::default();
+
+        let cust_keys = scan_keys(self, "id:*")?;
+        if !cust_keys.is_empty() {
+            let mut wallet = load_cust_wallet(self)?;
+            let mut legacy = Vec::new();
+            for key in cust_keys.iter() {
+                let name = key["id:".len()..].to_string();
+                let channel = match get_legacy_channel(self, key, &name, storage_key) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        report.skipped.push(format!("{}: {}", key, e));
+                        continue;
+                    }
+                };
+                let is_copied = wallet.find_channel_by_name(&name).is_none();
+                if is_copied {
+                    let added = wallet.add_channel(
+                        channel.channel_state.clone(),
+                        channel.channel_token.clone(),
+                        channel.cust_state.clone(),
+                    );
+                    if let Err(e) = added {
+                        report.skipped.push(format!("{}: {}", key, e));
+                        continue;
+                    }
+                }
+                legacy.push((key, name, channel, is_copied));
+            }
+            wallet.save(self)?;
+
+            let saved = load_cust_wallet(self)?;
+            for (key, name, channel, is_copied) in legacy {
+                let stored = saved
+                    .find_channel_by_name(&name)
+                    .and_then(|id| saved.get_channel(&id).ok().cloned());
+                if stored == Some(channel) {
+                    handle_error_result!(self.conn.del::<&String, ()>(key));
+                    if is_copied {
+                        report.migrated += 1;
+                    }
+                } else {
+                    report
+                        .skipped
+                        .push(format!("{}: differs from the stored channel", key));
+                }
+            }
+        }
+
+        if namespace != DEFAULT_DB_NAMESPACE {
+            return Ok(report);
+        }
+
+        let merch_key = String::from("cli:merch_db");
+        if handle_error_result!(self.conn.exists::<&String, bool>(&merch_key)) {
+            match get_legacy_merch_state(self, &merch_key, storage_key) {
+                Ok((channel_state, mut merch_state)) => {
+                    let is_copied = is_missing(self.load_merchant_state())?;
+                    if is_copied {
+                        merch_state.migrate_legacy_channel_maps(self)?;
+                        self.save_merchant_state(&channel_state, &merch_state)?;
+                    }
+                    if self.load_merchant_state().ok() == Some((channel_state, merch_state)) {
+                        handle_error_result!(self.conn.del::<&String, ()>(&merch_key));
+                        if is_copied {
+                            report.migrated += 1;
+                        }
+                    } else {
+                        report.skipped.push(format!(
+                            "{}: differs from the stored merchant state",
+                            merch_key
+                        ));
+                    }
+                }
+                Err(e) => report.skipped.push(format!("{}: {}", merch_key, e)),
+            }
+        }
+
+        let channels_key = String::from("cli:merch_channels");
+        let fields: Vec<String> = handle_error_result!(self.conn.hkeys(&channels_key));
+        for field in fields.iter().filter(|f| f.starts_with("id:")) {
+            let channel_token: ChannelMPCToken =
+                match get_legacy_record(self, &channels_key, field, storage_key) {
+                    Ok(t) => t,
+                    Err(e) => {
+                        report
+                            .skipped
+                            .push(format!("{} {}: {}", channels_key, field, e));
+                        continue;
+                    }
+                };
+            let channel_id = field["id:".len()..].to_string();
+            let is_copied = is_missing(self.load_merchant_channel(&channel_id))?;
+            if is_copied {
+                self.save_merchant_channel(&channel_token)?;
+            }
+            if self.load_merchant_channel(&channel_id).ok() == Some(channel_token) {
+                handle_error_result!(self.conn.hdel::<&String, &String, ()>(&channels_key, field));
+                if is_copied {
+                    report.migrated += 1;
+                }
+            } else {
+                report.skipped.push(format!(
+                    "{} {}: differs from the stored channel token",
+                    channels_key, field
+                ));
+            }
+        }
+        Ok(report)
+    }
+}
+
+impl CliDatabase for FileDatabase {
+    fn set_storage_key(&mut self, storage_key: StorageKey) {
+        FileDatabase::set_storage_key(self, storage_key)
+    }
+
+    fn rotate_storage_key(&mut self, new_key: StorageKey) -> Result<usize, String> {
+        Ok(FileDatabase::rotate_storage_key(self, new_key)?)
+    }
+}
 
-    Ok(conn)
+// the wallet of the cli customer (empty if none has been saved yet)
+fn load_cust_wallet(db: &mut dyn WalletDatabase) -> Result<CustomerWallet, String> {
+    match CustomerWallet::load(db, &CUST_WALLET_NAME.to_string()) {
+        Ok(w) => Ok(w),
+        Err(ZkChannelError::NotFound(_)) => Ok(CustomerWallet::new(CUST_WALLET_NAME.to_string())),
+        Err(e) => Err(e.to_string()),
+    }
 }
 
-pub fn store_file_in_db(
-    conn: &mut redis::Connection,
+// the keys that match the pattern (SCAN walks the keyspace in steps instead of blocking the
+// server like KEYS, and may return a key more than once)
+fn scan_keys(db: &mut RedisDatabase, pattern: &str) -> Result<Vec<String>, String> {
+    let mut keys: Vec<String> =
+        handle_error_result!(db.conn.scan_match::<&str, String>(pattern)).collect();
+    keys.sort();
+    keys.dedup();
+    Ok(keys)
+}
+
+// whether the typed storage has no record yet (any other error stops the migration)
+fn is_missing<T>(result: Result<T, ZkChannelError>) -> Result<bool, String> {
+    match result {
+        Ok(_) => Ok(false),
+        Err(ZkChannelError::NotFound(_)) => Ok(true),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+fn get_legacy_record<T: PersistedState>(
+    db: &mut RedisDatabase,
     key: &String,
     field_name: &String,
-    json_blob: &String,
-) -> Result<bool, String> {
-    match conn.hset::<String, String, String, i32>(
-        key.clone(),
-        field_name.clone(),
-        json_blob.clone(),
+    storage_key: Option<&StorageKey>,
+) -> Result<T, String> {
+    let record = get_legacy_file_from_db(&mut db.conn, key, field_name, storage_key)?;
+    Ok(persist::from_json_str(&record)?)
+}
+
+fn get_legacy_channel(
+    db: &mut RedisDatabase,
+    key: &String,
+    name: &String,
+    storage_key: Option<&StorageKey>,
+) -> Result<CustomerChannel, String> {
+    let field = |f: &str| format!("cust:{}:{}", name, f);
+    Ok(CustomerChannel {
+        channel_state: get_legacy_record(db, key, &field("channel_state"), storage_key)?,
+        channel_token: get_legacy_record(db, key, &field("channel_token"), storage_key)?,
+        cust_state: get_legacy_record(db, key, &field("cust_state"), storage_key)?,
+    })
+}
+
+fn get_legacy_merch_state(
+    db: &mut RedisDatabase,
+    key: &String,
+    storage_key: Option<&StorageKey>,
+) -> Result<(ChannelMPCState, MerchantMPCState), String> {
+    let channel_state = get_legacy_record(db, key, &String::from("channel_state"), storage_key)?;
+    let merch_state = get_legacy_record(db, key, &String::from("merch_state"), storage_key)?;
+    Ok((channel_state, merch_state))
+}
+
+pub fn open_db<D: CliDatabase>(db_url: &String) -> Result<D, String> {
+    let namespace = get_db_namespace()?;
+    let mut db = handle_error_result!(D::new(&namespace, db_url.clone()));
+    if let Some(k) = get_storage_key()? {
+        db.set_storage_key(k);
+    }
+    Ok(db)
+}
+
+// moves the states of older versions of the cli into the typed storage. This scans the whole db,
+// so it only runs when asked to; records it cannot move are reported and left in place
+fn migrate_legacy_state<D: CliDatabase>(db_url: &String) -> Result<(), String> {
+    let namespace = get_db_namespace()?;
+    let storage_key = get_storage_key()?;
+    let mut db = open_db::<D>(db_url)?;
+    let report = db.migrate_legacy_state(&namespace, storage_key.as_ref())?;
+    println!("migrated {} legacy records", report.migrated);
+    for skipped in report.skipped.iter() {
+        println!("skipped legacy record {}", skipped);
+    }
+    Ok(())
+}
+
+// re-encrypts the states and secrets in the db with the new storage key
+fn rotate_storage_key<D: CliDatabase>(
+    db_url: &String,
+    new_key_file: PathBuf,
+) -> Result<(), String> {
+    let new_key = StorageKey::from_key_file(&new_key_file)?;
+    let mut db = open_db::<D>(db_url)?;
+    let count = db.rotate_storage_key(new_key)?;
+    println!(
+        "re-encrypted {} records, set ZK_STORAGE_KEY_FILE={}",
+        count,
+        new_key_file.display()
+    );
+    Ok(())
+}
+
+fn get_cpfp_funding_input(
+    bump: &Bump,
+) -> Result<Option<(mpc::EscrowFundingInput, [u8; 32])>, String> {
+    let (txid, index, input_sats, input_sk_file) = match (
+        bump.txid.as_ref(),
+        bump.index,
+        bump.input_sats,
+        bump.input_sk_file.as_ref(),
     ) {
-        Ok(s) => Ok(s != 0),
-        Err(e) => return Err(e.to_string()),
+        (None, None, None, None) => return Ok(None),
+        (Some(t), Some(i), Some(a), Some(k)) => (t, i, a, k),
+        _ => {
+            return Err(String::from(
+                "funding input requires txid, index, input-sats and input-sk-file",
+            ))
+        }
+    };
+    let txid_vec = handle_error_result!(hex::decode(txid));
+    let input_sk = read_pathfile(input_sk_file.clone())?;
+    let sk_vec = handle_error_result!(hex::decode(input_sk.trim()));
+    if txid_vec.len() != 32 || sk_vec.len() != 32 {
+        return Err(String::from(
+            "txid and the key in input-sk-file must be 32 bytes",
+        ));
     }
+    let mut txid_le = [0u8; 32];
+    txid_le.copy_from_slice(&txid_vec);
+    let mut sk = [0u8; 32];
+    sk.copy_from_slice(&sk_vec);
+    let secp = secp256k1::Secp256k1::new();
+    let seckey = handle_error_result!(secp256k1::SecretKey::from_slice(&sk));
+    let input = mpc::EscrowFundingInput {
+        txid_le: zkchan_tx::fixed_size_array::FixedSizeArray32(txid_le),
+        index,
+        input_sats,
+        pk: secp256k1::PublicKey::from_secret_key(&secp, &seckey),
+    };
+    Ok(Some((input, sk)))
 }
 
-pub fn get_file_from_db(
-    conn: &mut redis::Connection,
-    key: &String,
-    field_name: &String,
-) -> Result<String, String> {
-    match conn.hget::<String, String, String>(key.clone(), field_name.clone()) {
-        Ok(s) => Ok(s),
-        Err(e) => return Err(e.to_string()),
+// the port of the i-th worker of a merchant that serves payments in parallel
+fn get_worker_port(port: &String, i: u16) -> Result<String, String> {
+    let p = handle_error_result!(port.parse::<u16>());
+    match p.checked_add(i) {
+        Some(worker_port) => Ok(worker_port.to_string()),
+        None => Err(format!(
+            "port of worker {} is out of range: {} + {}",
+            i, p, i
+        )),
     }
 }
 
@@ -367,7 +744,9 @@ impl Conn {
     }
 
     pub fn send(&mut self, msg: &[String]) {
-        for i in 1..6 {
+        // the other party is usually up within a few ms, so back off from a short delay
+        let mut delay = time::Duration::from_millis(100);
+        for i in 1..11 {
             match TcpStream::connect(self.out_addr) {
                 Ok(stream) => {
                     let mut buf_stream = BufStream::new(stream);
@@ -380,8 +759,8 @@ impl Conn {
                 }
                 Err(e) => {
                     println!("Failed to connect, try: {}, error: {}", i, e);
-                    let duration = time::Duration::from_secs(5);
-                    sleep(duration)
+                    sleep(delay);
+                    delay = cmp::min(delay * 2, time::Duration::from_secs(5));
                 }
             }
         }
@@ -430,23 +809,39 @@ impl Conn {
 
 fn main() {
     let args = Cli::from_args();
-    let db_url = "redis://127.0.0.1/".to_string(); // make constant
+    let db_url = match env::var("ZK_DB_URL") {
+        Ok(url) => url,
+        Err(_) => String::from("redis://127.0.0.1/"),
+    };
 
     println!("******************************************");
 
-    match args.command {
+    if db_url.starts_with("redis://") {
+        run::<RedisDatabase>(args.command, &db_url);
+    } else {
+        run::<FileDatabase>(args.command, &db_url);
+    }
+}
+
+fn run<D: CliDatabase>(command: Command, db_url: &String) {
+    match command {
         Command::SETFEES(_setfees) => {
             println!("Setting tx fees config: ");
         }
         Command::OPEN(open) => match open.party {
-            Party::MERCH => match merch::open(create_connection!(open), &db_url, open.self_delay) {
+            Party::MERCH => match merch::open::<D>(
+                create_connection!(open),
+                db_url,
+                open.self_delay,
+                open.network,
+            ) {
                 Err(e) => println!("Channel opening phase failed with error: {}", e),
                 _ => (),
             },
             Party::CUST => {
-                match cust::open(
+                match cust::open::<D>(
                     create_connection!(open),
-                    &db_url,
+                    db_url,
                     open.cust_bal,
                     open.merch_bal,
                     open.channel_name,
@@ -457,14 +852,14 @@ fn main() {
             }
         },
         Command::INIT(init) => match init.party {
-            Party::MERCH => match merch::init(create_connection!(init), &db_url) {
+            Party::MERCH => match merch::init::<D>(create_connection!(init), db_url) {
                 Err(e) => println!("Initialize phase failed with error: {}", e),
                 _ => (),
             },
             // TODO: clean this up
-            Party::CUST => match cust::init(
+            Party::CUST => match cust::init::<D>(
                 create_connection!(init),
-                &db_url,
+                db_url,
                 init.txid.unwrap(),
                 init.index.unwrap(),
                 init.input_sats.unwrap(),
@@ -477,29 +872,22 @@ fn main() {
             },
         },
         Command::ACTIVATE(activate) => match activate.party {
-            Party::MERCH => merch::activate(create_connection!(activate), &db_url).unwrap(),
+            Party::MERCH => merch::activate::<D>(create_connection!(activate), db_url).unwrap(),
             Party::CUST => {
-                cust::activate(create_connection!(activate), &db_url, activate.channel_name)
+                cust::activate::<D>(create_connection!(activate), db_url, activate.channel_name)
                     .unwrap()
             }
         },
         Command::UNLINK(unlink) => match unlink.party {
             Party::MERCH => {
-                let (mut channel_state, mut merch_state) =
-                    merch::load_merchant_state_info(&db_url).unwrap();
-                merch::pay(
-                    Some(0),
-                    create_connection!(unlink),
-                    &db_url,
-                    &mut channel_state,
-                    &mut merch_state,
-                )
-                .unwrap()
+                let server = merch::start_server::<D>(db_url, 1).unwrap();
+                merch::pay(Some(0), create_connection!(unlink), &server).unwrap()
             }
-            Party::CUST => cust::pay(
+            Party::CUST => cust::pay::<D>(
                 0,
+                None,
                 create_connection!(unlink),
-                &db_url,
+                db_url,
                 unlink.channel_name,
                 unlink.verbose,
             )
@@ -507,26 +895,49 @@ fn main() {
         },
         Command::PAY(pay) => match pay.party {
             Party::MERCH => {
-                let (mut channel_state, mut merch_state) =
-                    merch::load_merchant_state_info(&db_url).unwrap();
-                loop {
-                    match merch::pay(
-                        pay.amount.clone(),
-                        create_connection!(pay.clone()),
-                        &db_url,
-                        &mut channel_state,
-                        &mut merch_state,
-                    ) {
-                        Err(e) => println!("Pay phase failed with error: {}", e),
-                        _ => (),
+                let server = match merch::start_server::<D>(db_url, pay.workers as usize) {
+                    Ok(s) => s,
+                    Err(e) => {
+                        println!("Pay phase failed with error: {}", e);
+                        return;
+                    }
+                };
+                // check the ports of all workers before any of them starts
+                let mut ports = Vec::new();
+                for i in 0..pay.workers {
+                    match get_worker_port(&pay.own_port, i)
+                        .and_then(|own| Ok((own, get_worker_port(&pay.other_port, i)?)))
+                    {
+                        Ok(p) => ports.push(p),
+                        Err(e) => {
+                            println!("Pay phase failed with error: {}", e);
+                            return;
+                        }
                     }
                 }
+                let mut workers = Vec::new();
+                for (own_port, other_port) in ports {
+                    let pay = pay.clone();
+                    workers.push(server.spawn(move |server| {
+                        let conn = &mut Conn::new(pay.own_ip, own_port, pay.other_ip, other_port);
+                        loop {
+                            match merch::pay(pay.amount.clone(), conn, &server) {
+                                Err(e) => println!("Pay phase failed with error: {}", e),
+                                _ => (),
+                            }
+                        }
+                    }));
+                }
+                for w in workers {
+                    let _ = w.join();
+                }
             }
             Party::CUST => {
-                match cust::pay(
+                match cust::pay::<D>(
                     pay.amount.unwrap(),
+                    pay.justification.clone(),
                     create_connection!(pay),
-                    &db_url,
+                    db_url,
                     pay.channel_name,
                     pay.verbose,
                 ) {
@@ -537,15 +948,46 @@ fn main() {
         },
         Command::CLOSE(close) => match close.party {
             Party::MERCH => {
-                print_error_result!(merch::close(&db_url, close.file, close.channel_id))
+                print_error_result!(merch::close::<D>(db_url, close.file, close.channel_id))
             }
-            Party::CUST => print_error_result!(cust::close(
-                &db_url,
+            Party::CUST => print_error_result!(cust::close::<D>(
+                db_url,
                 close.file,
                 close.from_merch_close,
                 close.channel_id
             )),
         },
+        Command::BUMP(bump) => {
+            let funding = match get_cpfp_funding_input(&bump) {
+                Ok(f) => f,
+                Err(e) => {
+                    println!("Error: {}", e);
+                    return;
+                }
+            };
+            match bump.party {
+                Party::MERCH => print_error_result!(merch::bump::<D>(
+                    db_url,
+                    bump.tx_file,
+                    bump.fee_rate,
+                    funding,
+                    bump.file,
+                    bump.channel_id
+                )),
+                Party::CUST => print_error_result!(cust::bump::<D>(
+                    db_url,
+                    bump.tx_file,
+                    bump.fee_rate,
+                    funding,
+                    bump.file,
+                    bump.channel_id
+                )),
+            }
+        }
+        Command::ROTATEKEY(rotate) => {
+            print_error_result!(rotate_storage_key::<D>(db_url, rotate.new_key_file))
+        }
+        Command::MIGRATE(_migrate) => print_error_result!(migrate_legacy_state::<D>(db_url)),
     }
 
     println!("******************************************");
@@ -564,9 +1006,10 @@ mod cust {
     use zkchannels::channels_mpc::{
         ChannelMPCState, ChannelMPCToken, CustomerMPCState, NetworkConfig,
     };
+    use zkchannels::customer_wallet::CustomerChannel;
     use zkchannels::database::MaskedTxMPCInputs;
 
-    pub fn open(
+    pub fn open<D: CliDatabase>(
         conn: &mut Conn,
         db_url: &String,
         b0_cust: i64,
@@ -578,7 +1021,7 @@ mod cust {
         }
 
         let rng = &mut rand::thread_rng();
-        let mut db_conn = handle_error_result!(create_db_connection(db_url.clone()));
+        let mut db = open_db::<D>(db_url)?;
 
         let tx_fee_info = get_tx_fee_info();
 
@@ -603,6 +1046,7 @@ mod cust {
 
         let (channel_token, cust_state) = mpc::init_customer(
             rng,
+            &channel_state,
             &pk_m,
             b0_cust,
             b0_merch,
@@ -612,7 +1056,7 @@ mod cust {
 
         println!("Saving the initial customer state...");
         cust_save_state_in_db(
-            &mut db_conn,
+            &mut db,
             channel_name,
             channel_state,
             channel_token,
@@ -620,7 +1064,7 @@ mod cust {
         )
     }
 
-    pub fn init(
+    pub fn init<D: CliDatabase>(
         conn: &mut Conn,
         db_url: &String,
         txid: String,
@@ -635,30 +1079,15 @@ mod cust {
         }
 
         let mut rng = &mut rand::thread_rng();
-        let mut db_conn = handle_error_result!(create_db_connection(db_url.clone()));
-        let key = format!("id:{}", channel_name);
+        let mut db = open_db::<D>(db_url)?;
         let tx_fee_info = get_tx_fee_info();
 
-        // load the customer state from DB
-        let cust_state_key = format!("cust:{}:cust_state", channel_name);
-        let ser_cust_state =
-            handle_error_result!(get_file_from_db(&mut db_conn, &key, &cust_state_key));
-        let mut cust_state: CustomerMPCState =
-            handle_error_result!(serde_json::from_str(&ser_cust_state));
-
-        // load the channel state from DB
-        let channel_state_key = format!("cust:{}:channel_state", channel_name);
-        let ser_channel_state =
-            handle_error_result!(get_file_from_db(&mut db_conn, &key, &channel_state_key));
-        let channel_state: ChannelMPCState =
-            handle_error_result!(serde_json::from_str(&ser_channel_state));
-
-        // load the channel token from DB
-        let channel_token_key = format!("cust:{}:channel_token", channel_name);
-        let ser_channel_token =
-            handle_error_result!(get_file_from_db(&mut db_conn, &key, &channel_token_key));
-        let mut channel_token: ChannelMPCToken =
-            handle_error_result!(serde_json::from_str(&ser_channel_token));
+        // load the channel from DB
+        let CustomerChannel {
+            channel_state,
+            mut channel_token,
+            mut cust_state,
+        } = cust_load_channel(&mut db, &channel_name)?;
 
         let to_self_delay_be = channel_state.get_self_delay_be();
 
@@ -694,6 +1123,7 @@ mod cust {
         let cust_bal = cust_state.cust_balance;
         let merch_bal = cust_state.merch_balance;
         let merch_close_pk = channel_state.merch_payout_pk.unwrap().serialize().to_vec();
+        // the merch-close-tx only pays to scripts, so its preimage is the same on every network
         let (merch_tx_preimage, _) =
             handle_error_result!(merchant_form_close_transaction::<Testnet>(
                 escrow_txid_be.to_vec(),
@@ -740,21 +1170,30 @@ mod cust {
             escrow_prevout: FixedSizeArray32(escrow_prevout),
             merch_txid: FixedSizeArray32(merch_txid),
             merch_prevout: FixedSizeArray32(merch_prevout),
+            cust_funding: cust_bal + merch_bal,
+            merch_funding: 0,
         };
 
         cust_state.set_initial_cust_state(&mut channel_token, &funding_tx, &tx_fee_info)?;
 
         // now sign the customer's initial closing txs
         println!("Signing the initial closing transactions...");
-        let got_close_tx = match cust_state.sign_initial_closing_transaction::<Testnet>(
-            &channel_state,
-            &channel_token,
-            &escrow_sig,
-            &merch_sig,
-        ) {
-            Ok(n) => n,
-            Err(e) => return Err(e.to_string()),
+        let got_close_tx = match channel_state.get_network() {
+            BitcoinNetworkType::Mainnet => cust_state.sign_initial_closing_transaction::<Mainnet>(
+                &channel_state,
+                &channel_token,
+                &escrow_sig,
+                &merch_sig,
+            ),
+            BitcoinNetworkType::Testnet | BitcoinNetworkType::Regtest => cust_state
+                .sign_initial_closing_transaction::<Testnet>(
+                    &channel_state,
+                    &channel_token,
+                    &escrow_sig,
+                    &merch_sig,
+                ),
         };
+        let got_close_tx = handle_error_result!(got_close_tx);
 
         // handle_error_result!(serde_json::to_string(&init_hash))];
         let (init_cust_state, init_hash) =
@@ -772,9 +1211,10 @@ mod cust {
         if got_close_tx {
             // if broadcast successful, then we can mark the channel as open
             handle_error_result!(mpc::customer_mark_open_channel(&mut cust_state));
+            cust_save_events(&mut db, &mut cust_state)?;
 
             cust_save_state_in_db(
-                &mut db_conn,
+                &mut db,
                 channel_name,
                 channel_state,
                 channel_token,
@@ -806,24 +1246,20 @@ mod cust {
         Ok(())
     }
 
-    pub fn activate(conn: &mut Conn, db_url: &String, channel_name: String) -> Result<(), String> {
+    pub fn activate<D: CliDatabase>(
+        conn: &mut Conn,
+        db_url: &String,
+        channel_name: String,
+    ) -> Result<(), String> {
         let rng = &mut rand::thread_rng();
-        let mut db_conn = handle_error_result!(create_db_connection(db_url.clone()));
-        let key = format!("id:{}", channel_name);
-
-        // load the customer state from DB
-        let cust_state_key = format!("cust:{}:cust_state", channel_name);
-        let ser_cust_state =
-            handle_error_result!(get_file_from_db(&mut db_conn, &key, &cust_state_key));
-        let mut cust_state: CustomerMPCState =
-            handle_error_result!(serde_json::from_str(&ser_cust_state));
-
-        // load the channel token from DB
-        let channel_token_key = format!("cust:{}:channel_token", channel_name);
-        let ser_channel_token =
-            handle_error_result!(get_file_from_db(&mut db_conn, &key, &channel_token_key));
-        let channel_token: ChannelMPCToken =
-            handle_error_result!(serde_json::from_str(&ser_channel_token));
+        let mut db = open_db::<D>(db_url)?;
+
+        // load the channel from DB
+        let CustomerChannel {
+            channel_state,
+            channel_token,
+            mut cust_state,
+        } = cust_load_channel(&mut db, &channel_name)?;
 
         let s0 = handle_error_result!(mpc::activate_customer(rng, &mut cust_state));
 
@@ -838,38 +1274,34 @@ mod cust {
         let pay_token: [u8; 32] = serde_json::from_str(&msg2.get(0).unwrap()).unwrap();
         println!("Obtained pay token (p0): {}", hex::encode(&pay_token));
         handle_error_result!(mpc::activate_customer_finalize(pay_token, &mut cust_state));
+        cust_save_events(&mut db, &mut cust_state)?;
 
-        let cust_state_key = format!("cust:{}:cust_state", channel_name);
-        let cust_state_json_str = handle_error_result!(serde_json::to_string(&cust_state));
-        store_file_in_db(&mut db_conn, &key, &cust_state_key, &cust_state_json_str)?;
-
-        Ok(())
+        cust_save_state_in_db(
+            &mut db,
+            channel_name,
+            channel_state,
+            channel_token,
+            cust_state,
+        )
     }
 
-    pub fn pay(
+    pub fn pay<D: CliDatabase>(
         amount: i64,
+        justification: Option<String>,
         conn: &mut Conn,
         db_url: &String,
         channel_name: String,
         verbose: bool,
     ) -> Result<(), String> {
         let rng = &mut rand::thread_rng();
-        let mut db_conn = handle_error_result!(create_db_connection(db_url.clone()));
-        let key = format!("id:{}", channel_name);
-
-        // load the channel state from DB
-        let channel_state_key = format!("cust:{}:channel_state", channel_name);
-        let ser_channel_state =
-            handle_error_result!(get_file_from_db(&mut db_conn, &key, &channel_state_key));
-        let mut channel_state: ChannelMPCState =
-            handle_error_result!(serde_json::from_str(&ser_channel_state));
-
-        // load the customer state from DB
-        let cust_state_key = format!("cust:{}:cust_state", channel_name);
-        let ser_cust_state =
-            handle_error_result!(get_file_from_db(&mut db_conn, &key, &cust_state_key));
-        let mut cust_state: CustomerMPCState =
-            handle_error_result!(serde_json::from_str(&ser_cust_state));
+        let mut db = open_db::<D>(db_url)?;
+
+        // load the channel from DB
+        let CustomerChannel {
+            mut channel_state,
+            mut channel_token,
+            mut cust_state,
+        } = cust_load_channel(&mut db, &channel_name)?;
 
         if verbose {
             println!("Payment amount: {}", amount);
@@ -877,20 +1309,13 @@ mod cust {
             println!("Merchant balance: {}", cust_state.merch_balance);
         }
 
-        // load the channel token from DB
-        let channel_token_key = format!("cust:{}:channel_token", channel_name);
-        let ser_channel_token =
-            handle_error_result!(get_file_from_db(&mut db_conn, &key, &channel_token_key));
-        let mut channel_token: ChannelMPCToken =
-            handle_error_result!(serde_json::from_str(&ser_channel_token));
-
         let old_state = cust_state.get_current_state();
 
         // prepare phase
         let (new_state, rev_state, rev_lock_com, session_id) =
             match mpc::pay_prepare_customer(rng, &mut channel_state, amount, &mut cust_state) {
                 Ok(n) => n,
-                Err(e) => return Err(e),
+                Err(e) => return Err(e.to_string()),
             };
         if verbose {
             let chan_id = channel_token.compute_channel_id().unwrap();
@@ -905,7 +1330,10 @@ mod cust {
         let rev_lock_com_str = hex::encode(&rev_lock_com);
         let old_nonce_str = hex::encode(&old_state.get_nonce());
 
-        let msg = [session_id_str, old_nonce_str, rev_lock_com_str, amount_str];
+        let mut msg = vec![session_id_str, old_nonce_str, rev_lock_com_str, amount_str];
+        if let Some(j) = justification {
+            msg.push(j);
+        }
         let msg1 = conn.send_and_wait(
             &msg,
             Some(String::from("amount, nonce and rev_lock com")),
@@ -938,7 +1366,10 @@ mod cust {
             Some(cb_recv_data),
         ) {
             Ok(n) => n,
-            Err(e) => return Err(e.to_string()),
+            Err(e) => {
+                cust_save_events(&mut db, &mut cust_state)?;
+                return Err(e.to_string());
+            }
         };
 
         let msg1a = [handle_error_result!(serde_json::to_string(&is_ok))];
@@ -981,9 +1412,10 @@ mod cust {
             true => println!("Transaction succeeded!"),
             false => println!("Transaction failed!"),
         }
+        cust_save_events(&mut db, &mut cust_state)?;
 
         cust_save_state_in_db(
-            &mut db_conn,
+            &mut db,
             channel_name,
             channel_state,
             channel_token,
@@ -991,35 +1423,20 @@ mod cust {
         )
     }
 
-    pub fn close(
+    pub fn close<D: CliDatabase>(
         db_url: &String,
         out_file: PathBuf,
         from_merch_close: bool,
         channel_id: String,
     ) -> Result<(), String> {
-        let mut db_conn = handle_error_result!(create_db_connection(db_url.clone()));
-        let key = format!("id:{}", channel_id);
-
-        // load the channel state from DB
-        let channel_state_key = format!("cust:{}:channel_state", channel_id);
-        let ser_channel_state =
-            handle_error_result!(get_file_from_db(&mut db_conn, &key, &channel_state_key));
-        let channel_state: ChannelMPCState =
-            handle_error_result!(serde_json::from_str(&ser_channel_state));
-
-        // load the customer state from DB
-        let cust_state_key = format!("cust:{}:cust_state", channel_id);
-        let ser_cust_state =
-            handle_error_result!(get_file_from_db(&mut db_conn, &key, &cust_state_key));
-        let mut cust_state: CustomerMPCState =
-            handle_error_result!(serde_json::from_str(&ser_cust_state));
-
-        // load the channel token from DB
-        let channel_token_key = format!("cust:{}:channel_token", channel_id);
-        let ser_channel_token =
-            handle_error_result!(get_file_from_db(&mut db_conn, &key, &channel_token_key));
-        let channel_token: ChannelMPCToken =
-            handle_error_result!(serde_json::from_str(&ser_channel_token));
+        let mut db = open_db::<D>(db_url)?;
+
+        // load the channel from DB
+        let CustomerChannel {
+            channel_state,
+            channel_token,
+            mut cust_state,
+        } = cust_load_channel(&mut db, &channel_id)?;
 
         let from_escrow = !from_merch_close;
 
@@ -1035,56 +1452,111 @@ mod cust {
         } else {
             println!("cust-close from merch txid: {}", hex::encode(txid_be));
         }
-        let cust_state_key = format!("cust:{}:cust_state", channel_id);
-        let cust_state_json_str = handle_error_result!(serde_json::to_string(&cust_state));
-        store_file_in_db(&mut db_conn, &key, &cust_state_key, &cust_state_json_str)?;
+        cust_save_events(&mut db, &mut cust_state)?;
+        cust_save_state_in_db(
+            &mut db,
+            channel_id,
+            channel_state,
+            channel_token,
+            cust_state,
+        )?;
 
         // write out to a file
         write_pathfile(out_file, hex::encode(signed_tx))?;
         Ok(())
     }
 
+    pub fn bump<D: CliDatabase>(
+        db_url: &String,
+        tx_file: PathBuf,
+        fee_rate: i64,
+        funding: Option<(mpc::EscrowFundingInput, [u8; 32])>,
+        out_file: PathBuf,
+        channel_id: String,
+    ) -> Result<(), String> {
+        let mut db = open_db::<D>(db_url)?;
+
+        // load the channel from DB
+        let channel = cust_load_channel(&mut db, &channel_id)?;
+        let channel_state = channel.channel_state;
+        let cust_state = channel.cust_state;
+
+        // load the broadcast cust-close-tx
+        let cust_close_tx = handle_error_result!(hex::decode(read_pathfile(tx_file)?.trim()));
+
+        let (child_tx, txid_le) = handle_error_result!(mpc::cpfp_customer(
+            &channel_state,
+            &cust_close_tx,
+            fee_rate,
+            funding,
+            &cust_state
+        ));
+        println!("cpfp child txid: {}", hex::encode(txid_le));
+
+        // write out to a file
+        write_pathfile(out_file, hex::encode(child_tx))?;
+        Ok(())
+    }
+
+    // the customer state only records its protocol events, so append them to the event log of the db
+    fn cust_save_events(
+        db: &mut dyn StateDatabase,
+        cust_state: &mut CustomerMPCState,
+    ) -> Result<(), String> {
+        for record in cust_state.take_events() {
+            handle_error_result!(db.append_event(&record));
+        }
+        Ok(())
+    }
+
+    // channels are looked up in the wallet by the name given to them when they were opened
+    fn cust_load_channel(
+        db: &mut dyn WalletDatabase,
+        channel_name: &String,
+    ) -> Result<CustomerChannel, String> {
+        let wallet = load_cust_wallet(db)?;
+        match wallet.find_channel_by_name(channel_name) {
+            Some(id) => Ok(handle_error_result!(wallet.get_channel(&id)).clone()),
+            None => Err(format!("unknown channel: {}", channel_name)),
+        }
+    }
+
     fn cust_save_state_in_db(
-        db_conn: &mut redis::Connection,
+        db: &mut dyn WalletDatabase,
         channel_name: String,
         channel_state: ChannelMPCState,
         channel_token: ChannelMPCToken,
         cust_state: CustomerMPCState,
     ) -> Result<(), String> {
-        let key = format!("id:{}", channel_name);
-
-        let channel_state_key = format!("cust:{}:channel_state", channel_name);
-        let channel_state_json_str = handle_error_result!(serde_json::to_string(&channel_state));
-        store_file_in_db(db_conn, &key, &channel_state_key, &channel_state_json_str)?;
-
-        let channel_token_key = format!("cust:{}:channel_token", channel_name);
-        let channel_token_json_str = handle_error_result!(serde_json::to_string(&channel_token));
-        store_file_in_db(db_conn, &key, &channel_token_key, &channel_token_json_str)?;
-
-        let cust_state_key = format!("cust:{}:cust_state", channel_name);
-        let cust_state_json_str = handle_error_result!(serde_json::to_string(&cust_state));
-        store_file_in_db(db_conn, &key, &cust_state_key, &cust_state_json_str)?;
+        let mut wallet = load_cust_wallet(db)?;
+        // the channel id changes once the funding txs are known, so the channel is added again
+        if let Some(id) = wallet.find_channel_by_name(&channel_name) {
+            handle_error_result!(wallet.remove_channel(&id));
+        }
+        handle_error_result!(wallet.add_channel(channel_state, channel_token, cust_state));
+        handle_error_result!(wallet.save(db));
         Ok(())
     }
 }
 
 mod merch {
     use super::*;
-    use std::ptr;
     use zkchan_tx::fixed_size_array::FixedSizeArray32;
     use zkchan_tx::transactions::btc::merchant_form_close_transaction;
-    use zkchannels::bindings::ConnType_NETIO;
-    use zkchannels::channels_mpc::{
-        ChannelMPCState, ChannelMPCToken, InitCustState, MerchantMPCState, NetworkConfig,
-    };
-    use zkchannels::database::{RedisDatabase, StateDatabase};
+    use zkchannels::channels_mpc::{ChannelMPCState, ChannelMPCToken, InitCustState};
+    use zkchannels::database::StateDatabase;
+    use zkchannels::merchant_server::MerchantServer;
+    use zkchannels::pool::DatabasePool;
     use zkchannels::wallet::State;
 
-    static MERCH_STATE_KEY: &str = "merch_state";
-    static CHANNEL_STATE_KEY: &str = "channel_state";
-
-    pub fn open(conn: &mut Conn, db_url: &String, self_delay: u16) -> Result<(), String> {
-        let merch_state_info = load_merchant_state_info(&db_url);
+    pub fn open<D: CliDatabase>(
+        conn: &mut Conn,
+        db_url: &String,
+        self_delay: u16,
+        network: BitcoinNetworkType,
+    ) -> Result<(), String> {
+        let mut db = open_db::<D>(db_url)?;
+        let merch_state_info = db.load_merchant_state();
         let tx_fee_info = get_tx_fee_info();
         let (channel_state, merch_state) = match merch_state_info {
             Err(_) => {
@@ -1098,23 +1570,24 @@ mod merch {
                     tx_fee_info.bal_min_merch,
                     tx_fee_info.val_cpfp,
                     false,
+                    network,
                 );
                 if tx_fee_info.bal_min_cust == 0 || tx_fee_info.bal_min_merch == 0 {
                     let s = format!("Dust limit must be greater than 0!");
                     return Err(s);
                 }
 
-                let mut db = handle_error_result!(RedisDatabase::new("cli", db_url.clone()));
-
+                let merch_id = env::var("ZK_MERCHANT_ID").unwrap_or(String::from("Merchant"));
                 let merch_state =
-                    mpc::init_merchant(rng, db_url.clone(), &mut channel_state, "Merchant");
+                    mpc::init_merchant(rng, db_url.clone(), &mut channel_state, &merch_id);
 
-                merch_save_state_in_db(&mut db.conn, Some(&channel_state), &merch_state)?;
+                handle_error_result!(db.save_merchant_state(&channel_state, &merch_state));
 
                 (channel_state, merch_state)
             }
             Ok(n) => (n.0, n.1),
         };
+        handle_error_result!(channel_state.check_network(network));
 
         // send initial channel info
         let msg1 = [
@@ -1126,27 +1599,16 @@ mod merch {
         Ok(())
     }
 
-    pub fn init(conn: &mut Conn, db_url: &String) -> Result<(), String> {
+    pub fn init<D: CliDatabase>(conn: &mut Conn, db_url: &String) -> Result<(), String> {
         // build tx and sign it
-        let mut db = handle_error_result!(RedisDatabase::new("cli", db_url.clone()));
-        let key = String::from("cli:merch_db");
+        let mut db = open_db::<D>(db_url)?;
         let tx_fee_info = get_tx_fee_info();
 
-        // load the channel state from DB
-        let ser_channel_state = handle_error_with_string!(
-            get_file_from_db(&mut db.conn, &key, &CHANNEL_STATE_KEY.to_string()),
-            "Could not load the merchant channel state"
-        );
-        let channel_state: ChannelMPCState =
-            handle_error_result!(serde_json::from_str(&ser_channel_state));
-
-        // load the merchant state from DB
-        let ser_merch_state = handle_error_with_string!(
-            get_file_from_db(&mut db.conn, &key, &MERCH_STATE_KEY.to_string()),
+        // load the channel state and merchant state from DB
+        let (channel_state, mut merch_state) = handle_error_with_string!(
+            db.load_merchant_state(),
             "Could not load the merchant state DB"
         );
-        let mut merch_state: MerchantMPCState =
-            handle_error_result!(serde_json::from_str(&ser_merch_state));
 
         let msg0 = conn.wait_for(None, false);
 
@@ -1168,7 +1630,7 @@ mod merch {
         let cust_bal = init_cust_state.cust_bal;
         let merch_bal = init_cust_state.merch_bal;
 
-        // form the merch-close-tx
+        // form the merch-close-tx (its preimage and txid are the same on every network)
         let (merch_tx_preimage, tx_params) =
             handle_error_result!(merchant_form_close_transaction::<Testnet>(
                 escrow_txid.to_vec(),
@@ -1190,7 +1652,8 @@ mod merch {
                 &cust_pk
             ));
         if is_ok {
-            merch_state.store_merch_close_tx(
+            handle_error_result!(merch_state.store_merch_close_tx(
+                &mut db as &mut dyn StateDatabase,
                 &escrow_txid.to_vec(),
                 &cust_pk,
                 cust_bal,
@@ -1198,7 +1661,7 @@ mod merch {
                 tx_fee_info.fee_mc,
                 to_self_delay_be,
                 &cust_sig,
-            );
+            ));
         }
 
         let (merch_txid, merch_prevout) = handle_error_result!(
@@ -1213,20 +1676,36 @@ mod merch {
             escrow_prevout: FixedSizeArray32(escrow_prevout),
             merch_txid: FixedSizeArray32(merch_txid.clone()),
             merch_prevout: FixedSizeArray32(merch_prevout.clone()),
+            cust_funding: cust_bal + merch_bal,
+            merch_funding: 0,
         };
 
         // now proceed to sign the cust-close transactions (escrow + merch-close-tx)
         println!("Signing customer's initial closing tx...");
-        let (escrow_sig, merch_sig) = merch_state.sign_initial_closing_transaction::<Testnet>(
-            funding_tx,
-            rev_lock,
-            cust_pk,
-            cust_close_pk,
-            to_self_delay_be,
-            tx_fee_info.fee_cc,
-            tx_fee_info.fee_mc,
-            tx_fee_info.val_cpfp,
-        )?;
+        let (escrow_sig, merch_sig) = match merch_state.get_network() {
+            BitcoinNetworkType::Mainnet => merch_state
+                .sign_initial_closing_transaction::<Mainnet>(
+                    funding_tx,
+                    rev_lock,
+                    cust_pk,
+                    cust_close_pk,
+                    to_self_delay_be,
+                    tx_fee_info.fee_cc,
+                    tx_fee_info.fee_mc,
+                    tx_fee_info.val_cpfp,
+                )?,
+            BitcoinNetworkType::Testnet | BitcoinNetworkType::Regtest => merch_state
+                .sign_initial_closing_transaction::<Testnet>(
+                    funding_tx,
+                    rev_lock,
+                    cust_pk,
+                    cust_close_pk,
+                    to_self_delay_be,
+                    tx_fee_info.fee_cc,
+                    tx_fee_info.fee_mc,
+                    tx_fee_info.val_cpfp,
+                )?,
+        };
 
         let msg3 = [
             handle_error_result!(serde_json::to_string(&merch_txid)),
@@ -1255,24 +1734,22 @@ mod merch {
 
         // if broadcast successful, then we can mark the channel as open
         handle_error_result!(mpc::merchant_mark_open_channel(
+            &mut db as &mut dyn StateDatabase,
             channel_token.escrow_txid.0.clone(),
             &mut merch_state
         ));
 
-        merch_save_state_in_db(&mut db.conn, None, &merch_state)?;
+        handle_error_result!(db.save_merchant_state(&channel_state, &merch_state));
         Ok(())
     }
 
-    pub fn activate(conn: &mut Conn, db_url: &String) -> Result<(), String> {
-        let mut db = handle_error_result!(RedisDatabase::new("cli", db_url.clone()));
-        let key = String::from("cli:merch_db");
+    pub fn activate<D: CliDatabase>(conn: &mut Conn, db_url: &String) -> Result<(), String> {
+        let mut db = open_db::<D>(db_url)?;
 
-        let ser_merch_state = handle_error_with_string!(
-            get_file_from_db(&mut db.conn, &key, &MERCH_STATE_KEY.to_string()),
+        let (channel_state, mut merch_state) = handle_error_with_string!(
+            db.load_merchant_state(),
             "Could not load the merchant state DB"
         );
-        let mut merch_state: MerchantMPCState =
-            handle_error_result!(serde_json::from_str(&ser_merch_state));
 
         let msg2 = conn.wait_for(None, false);
 
@@ -1290,46 +1767,39 @@ mod merch {
         conn.send(&msg3);
 
         // save the channel token for the channel
-        save_channel_token(&mut db.conn, &channel_token)?;
+        handle_error_result!(db.save_merchant_channel(&channel_token));
 
-        merch_save_state_in_db(&mut db.conn, None, &merch_state)
+        handle_error_result!(db.save_merchant_state(&channel_state, &merch_state));
+        Ok(())
     }
 
-    pub fn load_merchant_state_info(
+    // loads the merchant state and opens a db connection for every worker
+    pub fn start_server<D: CliDatabase>(
         db_url: &String,
-    ) -> Result<(ChannelMPCState, MerchantMPCState), String> {
-        let mut db = handle_error_result!(RedisDatabase::new("cli", db_url.clone()));
-        let key = String::from("cli:merch_db");
-
-        // load the channel state from DB
-        let ser_channel_state = handle_error_with_string!(
-            get_file_from_db(&mut db.conn, &key, &CHANNEL_STATE_KEY.to_string()),
-            "Could not load the merchant channel state"
-        );
-        let channel_state: ChannelMPCState =
-            handle_error_result!(serde_json::from_str(&ser_channel_state));
-
-        // load the merchant state from DB
-        let ser_merch_state = handle_error_with_string!(
-            get_file_from_db(&mut db.conn, &key, &MERCH_STATE_KEY.to_string()),
+        workers: usize,
+    ) -> Result<MerchantServer<D>, String> {
+        let (channel_state, merch_state) = handle_error_with_string!(
+            open_db::<D>(db_url)?.load_merchant_state(),
             "Could not load the merchant state DB"
         );
-        let merch_state: MerchantMPCState =
-            handle_error_result!(serde_json::from_str(&ser_merch_state));
-
-        Ok((channel_state, merch_state))
+        if workers > 1 && !D::is_shared() {
+            return Err(String::from(
+                "this database can only serve one worker at a time",
+            ));
+        }
+        let mut dbs = Vec::new();
+        for _ in 0..workers {
+            dbs.push(open_db::<D>(db_url)?);
+        }
+        let db_pool = handle_error_result!(DatabasePool::new(dbs));
+        Ok(MerchantServer::new(db_pool, channel_state, merch_state))
     }
 
-    pub fn pay(
+    pub fn pay<D: CliDatabase>(
         cmd_amount: Option<i64>,
         conn: &mut Conn,
-        db_url: &String,
-        channel_state: &mut ChannelMPCState,
-        merch_state: &mut MerchantMPCState,
+        server: &MerchantServer<D>,
     ) -> Result<(), String> {
-        let rng = &mut rand::thread_rng();
-        let mut db = handle_error_result!(RedisDatabase::new("cli", db_url.clone()));
-
         let msg0 = conn.wait_for(None, false);
         // get the session id
         let session_id_vec = hex::decode(msg0.get(0).unwrap()).unwrap();
@@ -1360,45 +1830,26 @@ mod merch {
             amount
         );
 
-        let justification = match amount < 0 {
-            true => Some(format!("empty-sig")),
-            false => None,
-        };
+        // the customer sends a justification along with negative payments
+        let justification = msg0.get(4).cloned();
 
-        let pay_token_mask_com = handle_error_result!(mpc::pay_prepare_merchant(
-            rng,
-            &mut db as &mut dyn StateDatabase,
-            &channel_state,
+        let pay_token_mask_com = handle_error_result!(server.prepare(
             session_id.clone(),
             nonce,
             rev_lock_com.clone(),
             amount,
-            justification,
-            merch_state
+            justification
         ));
 
         let msg1 = [hex::encode(&pay_token_mask_com)];
         conn.send(&msg1);
 
-        let nc = NetworkConfig {
-            conn_type: ConnType_NETIO,
-            path: String::new(),
-            dest_ip: String::from("127.0.0.1"),
-            dest_port: conn.other_port,
-        };
-        merch_state.set_network_config(nc);
-
-        // execute mpc context
-        let _mpc_ok = handle_error_result!(mpc::pay_update_merchant(
-            rng,
-            &mut db as &mut dyn StateDatabase,
-            channel_state,
+        // execute mpc context (on the port of this connection)
+        let _mpc_ok = handle_error_result!(server.update(
             session_id.clone(),
             pay_token_mask_com,
-            merch_state,
-            ptr::null_mut(),
-            Some(cb_send_data),
-            Some(cb_recv_data),
+            "127.0.0.1",
+            conn.other_port
         ));
 
         // confirm customer got mpc output
@@ -1408,23 +1859,12 @@ mod merch {
             return Err(format!("failed to execute MPC successfully."));
         }
 
-        let masked_inputs = mpc::pay_confirm_mpc_result(
-            &mut db as &mut dyn StateDatabase,
-            session_id,
-            cust_mpc_ok,
-            merch_state,
-        )
-        .unwrap();
+        let masked_inputs = handle_error_result!(server.confirm(session_id, cust_mpc_ok));
         let msg3 = [handle_error_result!(serde_json::to_string(&masked_inputs))];
         let msg4 = conn.send_and_wait(&msg3, Some(String::from("Received revoked state")), true);
         let rev_state = serde_json::from_str(msg4.get(0).unwrap()).unwrap();
 
-        let (pt_mask_bytes, pt_mask_r) = match mpc::pay_validate_rev_lock_merchant(
-            &mut db as &mut dyn StateDatabase,
-            session_id,
-            rev_state,
-            merch_state,
-        ) {
+        let (pt_mask_bytes, pt_mask_r) = match server.validate_rev_lock(session_id, rev_state) {
             Ok(n) => (n.0, n.1),
             _ => {
                 return Err(String::from(
@@ -1442,100 +1882,42 @@ mod merch {
             println!("Transaction failed!")
         }
         println!("******************************************");
-
-        merch_save_state_in_db(&mut db.conn, Some(&channel_state), &merch_state)
-    }
-
-    pub fn merch_save_state_in_db(
-        db_conn: &mut redis::Connection,
-        channel_state: Option<&ChannelMPCState>,
-        merch_state: &MerchantMPCState,
-    ) -> Result<(), String> {
-        let key = String::from("cli:merch_db");
-        match channel_state {
-            Some(n) => {
-                let channel_state_json_str = handle_error_result!(serde_json::to_string(n));
-                store_file_in_db(
-                    db_conn,
-                    &key,
-                    &CHANNEL_STATE_KEY.to_string(),
-                    &channel_state_json_str,
-                )?
-            }
-            None => false, // do nothing
-        };
-
-        let merch_state_json_str = handle_error_result!(serde_json::to_string(merch_state));
-        store_file_in_db(
-            db_conn,
-            &key,
-            &MERCH_STATE_KEY.to_string(),
-            &merch_state_json_str,
-        )?;
         Ok(())
     }
 
-    pub fn save_channel_token(
-        db_conn: &mut redis::Connection,
-        channel_token: &ChannelMPCToken,
-    ) -> Result<(), String> {
-        let key = String::from("cli:merch_channels");
-        let channel_id = channel_token.compute_channel_id().unwrap();
-        let channel_id_str = hex::encode(channel_id.to_vec());
-
-        let channel_token_key = format!("id:{}", channel_id_str);
-        let channel_token_json_str = handle_error_result!(serde_json::to_string(&channel_token));
-        store_file_in_db(db_conn, &key, &channel_token_key, &channel_token_json_str)?;
-        Ok(())
-    }
-
-    pub fn list_channels(db_conn: &mut redis::Connection) {
-        let key = String::from("cli:merch_channels");
-
-        let channel_ids: Vec<String> = db_conn.hkeys(key).unwrap();
+    pub fn list_channels(db: &mut dyn StateDatabase) -> Result<(), String> {
+        let channel_ids = handle_error_result!(db.list_merchant_channels());
         println!("List zkchannels...");
         for id in channel_ids {
             println!("{}", id);
         }
+        Ok(())
     }
 
-    pub fn close(db_url: &String, out_file: PathBuf, channel_id: String) -> Result<(), String> {
+    pub fn close<D: CliDatabase>(
+        db_url: &String,
+        out_file: PathBuf,
+        channel_id: String,
+    ) -> Result<(), String> {
         // output the merch-close-tx (only thing merchant can broadcast to close channel)
-        let mut db = handle_error_result!(RedisDatabase::new("cli", db_url.clone()));
+        let mut db = open_db::<D>(db_url)?;
 
         if channel_id == "" {
-            list_channels(&mut db.conn);
-            return Ok(());
+            return list_channels(&mut db);
         }
 
-        let key1 = String::from("cli:merch_db");
-        let ser_merch_state = handle_error_with_string!(
-            get_file_from_db(&mut db.conn, &key1, &MERCH_STATE_KEY.to_string()),
+        let (channel_state, mut merch_state) = handle_error_with_string!(
+            db.load_merchant_state(),
             "Could not load the merchant state DB"
         );
-        let mut merch_state: MerchantMPCState =
-            handle_error_result!(serde_json::from_str(&ser_merch_state));
-
-        let key2 = String::from("cli:merch_channels");
-        let channel_token_key = format!("id:{}", channel_id);
-        let ser_channel_token = handle_error_with_string!(
-            get_file_from_db(&mut db.conn, &key2, &channel_token_key),
-            "Invalid channel ID"
-        );
-        let channel_token: ChannelMPCToken =
-            handle_error_result!(serde_json::from_str(&ser_channel_token));
 
-        // load the channel state from DB
-        let ser_channel_state = handle_error_with_string!(
-            get_file_from_db(&mut db.conn, &key2, &CHANNEL_STATE_KEY.to_string()),
-            "Could not load the merchant channel state"
-        );
-        let channel_state: ChannelMPCState =
-            handle_error_result!(serde_json::from_str(&ser_channel_state));
+        let channel_token =
+            handle_error_with_string!(db.load_merchant_channel(&channel_id), "Invalid channel ID");
 
         let escrow_txid = channel_token.escrow_txid.0.to_vec();
 
         let (merch_close_tx, txid_be, _) = handle_error_result!(mpc::force_merchant_close(
+            &mut db as &mut dyn StateDatabase,
             &escrow_txid,
             channel_state.get_val_cpfp(),
             &mut merch_state
@@ -1544,4 +1926,39 @@ mod merch {
         println!("merch-close-tx signed txid: {}", hex::encode(txid_be));
         Ok(())
     }
+
+    pub fn bump<D: CliDatabase>(
+        db_url: &String,
+        tx_file: PathBuf,
+        fee_rate: i64,
+        funding: Option<(mpc::EscrowFundingInput, [u8; 32])>,
+        out_file: PathBuf,
+        channel_id: String,
+    ) -> Result<(), String> {
+        let mut db = open_db::<D>(db_url)?;
+
+        if channel_id == "" {
+            return list_channels(&mut db);
+        }
+
+        let (channel_state, merch_state) = handle_error_with_string!(
+            db.load_merchant_state(),
+            "Could not load the merchant state DB"
+        );
+
+        // load the broadcast merch-close-tx
+        let merch_close_tx = handle_error_result!(hex::decode(read_pathfile(tx_file)?.trim()));
+
+        let (child_tx, txid_le) = handle_error_result!(mpc::cpfp_merchant(
+            &mut db as &mut dyn StateDatabase,
+            &channel_state,
+            &merch_close_tx,
+            fee_rate,
+            funding,
+            &merch_state
+        ));
+        write_pathfile(out_file, hex::encode(child_tx))?;
+        println!("cpfp child txid: {}", hex::encode(txid_le));
+        Ok(())
+    }
 }
@@ -0,0 +1,492 @@
+use super::*;
+use bindings::ConnType_NETIO;
+use channels_mpc::{ChannelMPCState, MerchantMPCState, NetworkConfig, RevokedState};
+use database::{get_namespace, MaskedTxMPCInputs, StateDatabase};
+use errors::ZkChannelError;
+use pool::DatabasePool;
+use std::ptr;
+use std::sync::{Arc, RwLock};
+use std::thread::{self, JoinHandle};
+use wallet::NONCE_LEN;
+
+///
+/// MerchantServer - runs the merchant side of many payment sessions in parallel. Every call
+/// takes its own connection from the database pool, and every MPC runs on a copy of the merchant
+/// state with the network config (port) of its session, so sessions never share a connection or
+/// a port. The state that sessions share (spent map, rev lock map, unlink set and nonce to session
+/// mapping) is only changed with atomic batches (see StateDatabase::apply_batch()). Any other
+/// change a call makes to the merchant state is shared with later calls and saved in the
+/// database. The pool should have a connection for every MPC that can run at a time, since a
+/// session holds its connection for the whole MPC.
+///
+pub struct MerchantServer<D: StateDatabase + Send> {
+    db_pool: Arc<DatabasePool<D>>,
+    channel_state: Arc<ChannelMPCState>,
+    merch_state: Arc<RwLock<MerchantMPCState>>,
+}
+
+// clones share the same pool and states
+impl<D: StateDatabase + Send> Clone for MerchantServer<D> {
+    fn clone(&self) -> Self {
+        MerchantServer {
+            db_pool: self.db_pool.clone(),
+            channel_state: self.channel_state.clone(),
+            merch_state: self.merch_state.clone(),
+        }
+    }
+}
+
+impl<D: StateDatabase + Send> MerchantServer<D> {
+    pub fn new(
+        db_pool: DatabasePool<D>,
+        channel_state: ChannelMPCState,
+        merch_state: MerchantMPCState,
+    ) -> Self {
+        MerchantServer {
+            db_pool: Arc::new(db_pool),
+            channel_state: Arc::new(channel_state),
+            merch_state: Arc::new(RwLock::new(merch_state)),
+        }
+    }
+
+    pub fn get_channel_state(&self) -> &ChannelMPCState {
+        &self.channel_state
+    }
+
+    // a copy of the current merchant state
+    pub fn get_merchant_state(&self) -> MerchantMPCState {
+        match self.merch_state.read() {
+            Ok(m) => m.clone(),
+            Err(poisoned) => poisoned.into_inner().clone(),
+        }
+    }
+
+    pub fn get_db_pool(&self) -> &DatabasePool<D> {
+        &self.db_pool
+    }
+
+    // e.g., for an AsyncDatabase on the same connections
+    pub fn share_db_pool(&self) -> Arc<DatabasePool<D>> {
+        self.db_pool.clone()
+    }
+
+    ///
+    /// prepare() - checks the nonce and session id of a new payment and commits to its pay token mask
+    /// (see mpc::pay_prepare_merchant())
+    /// output: pay token mask commitment
+    ///
+    pub fn prepare(
+        &self,
+        session_id: [u8; 16],
+        nonce: [u8; NONCE_LEN],
+        rev_lock_com: [u8; 32],
+        amount: i64,
+        justification: Option<String>,
+    ) -> Result<[u8; 32], ZkChannelError> {
+        let mut db = self.db_pool.get();
+        self.with_merch_state(&mut *db, None, |db, merch_state| {
+            mpc::pay_prepare_merchant(
+                &mut rand::thread_rng(),
+                db,
+                &self.channel_state,
+                session_id,
+                nonce,
+                rev_lock_com,
+                amount,
+                justification,
+                merch_state,
+            )
+        })
+    }
+
+    ///
+    /// update() - runs the MPC of the session with the customer at dest_ip on the given port
+    /// (see mpc::pay_update_merchant())
+    /// output: true if the MPC succeeded
+    ///
+    pub fn update(
+        &self,
+        session_id: [u8; 16],
+        pay_token_mask_com: [u8; 32],
+        dest_ip: &str,
+        mpc_port: i32,
+    ) -> Result<bool, ZkChannelError> {
+        let mut db = self.db_pool.get();
+        let net_config = NetworkConfig {
+            conn_type: ConnType_NETIO,
+            path: String::new(),
+            dest_ip: dest_ip.to_string(),
+            dest_port: mpc_port,
+        };
+        self.with_merch_state(&mut *db, Some(net_config), |db, merch_state| {
+            mpc::pay_update_merchant(
+                &mut rand::thread_rng(),
+                db,
+                &self.channel_state,
+                session_id,
+                pay_token_mask_com,
+                merch_state,
+                ptr::null_mut(),
+                None,
+                None,
+            )
+        })
+    }
+
+    ///
+    /// confirm() - records whether the customer got the MPC output (see mpc::pay_confirm_mpc_result())
+    /// output: masked inputs of the session
+    ///
+    pub fn confirm(
+        &self,
+        session_id: [u8; 16],
+        mpc_result: bool,
+    ) -> Result<MaskedTxMPCInputs, ZkChannelError> {
+        let mut db = self.db_pool.get();
+        self.with_merch_state(&mut *db, None, |db, merch_state| {
+            mpc::pay_confirm_mpc_result(db, session_id, mpc_result, merch_state)
+        })
+    }
+
+    ///
+    /// validate_rev_lock() - spends the nonce and revokes the previous state of the session
+    /// (see mpc::pay_validate_rev_lock_merchant())
+    /// output: pay token mask and randomness
+    ///
+    pub fn validate_rev_lock(
+        &self,
+        session_id: [u8; 16],
+        rev_state: RevokedState,
+    ) -> Result<([u8; 32], [u8; 16]), ZkChannelError> {
+        let mut db = self.db_pool.get();
+        self.with_merch_state(&mut *db, None, |db, merch_state| {
+            mpc::pay_validate_rev_lock_merchant(db, session_id, rev_state, merch_state)
+        })
+    }
+
+    ///
+    /// spawn() - runs a session (or a loop of sessions) on its own thread
+    /// output: handle of the thread
+    ///
+    pub fn spawn<F, T>(&self, session: F) -> JoinHandle<T>
+    where
+        D: 'static,
+        F: FnOnce(MerchantServer<D>) -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let server = self.clone();
+        thread::spawn(move || session(server))
+    }
+
+    // runs a call on a copy of the merchant state (with the network config of the session, if
+    // any). The network config only applies to the session, but any other change to the state is
+    // saved in the database and shared with later calls
+    fn with_merch_state<T, F>(
+        &self,
+        db: &mut dyn StateDatabase,
+        net_config: Option<NetworkConfig>,
+        call: F,
+    ) -> Result<T, ZkChannelError>
+    where
+        F: FnOnce(&mut dyn StateDatabase, &mut MerchantMPCState) -> Result<T, ZkChannelError>,
+    {
+        let old_state = self.get_merchant_state();
+        let mut merch_state = old_state.clone();
+        if let Some(nc) = net_config {
+            merch_state.set_network_config(nc);
+        }
+        let result = call(db, &mut merch_state);
+
+        merch_state.net_config = old_state.net_config.clone();
+        if merch_state != old_state {
+            let mut shared = match self.merch_state.write() {
+                Ok(m) => m,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            db.save_merchant_state(&self.channel_state, &merch_state)?;
+            *shared = merch_state;
+        }
+        result
+    }
+}
+
+///
+/// MerchantRegistry - hosts several merchants (each with its own keys, hmac key commitment and
+/// channel state) in one process and database. Every merchant has a server with its own pool of
+/// connections to the namespace "{prefix}:{merchant_id}" of the database, so merchants never share
+/// sessions, nonces or rev locks. Incoming sessions are routed to a merchant by its id (the id of
+/// its MerchantMPCState)
+///
+pub struct MerchantRegistry<D: StateDatabase + Send> {
+    prefix: String,
+    url: String,
+    servers: HashMap<String, MerchantServer<D>>,
+}
+
+impl<D: StateDatabase + Send> MerchantRegistry<D> {
+    pub fn new(prefix: &str, url: String) -> Self {
+        MerchantRegistry {
+            prefix: prefix.to_string(),
+            url: url,
+            servers: HashMap::new(),
+        }
+    }
+
+    ///
+    /// add() - registers a new merchant and saves its states in its namespace (so that load()
+    /// can restore it later)
+    /// output: server of the merchant
+    ///
+    pub fn add(
+        &mut self,
+        channel_state: ChannelMPCState,
+        merch_state: MerchantMPCState,
+        pool_size: usize,
+    ) -> Result<&MerchantServer<D>, ZkChannelError> {
+        let merchant_id = merch_state.get_id().clone();
+        let db_pool = self.connect(&merchant_id, pool_size)?;
+        db_pool
+            .get()
+            .save_merchant_state(&channel_state, &merch_state)?;
+        self.insert(
+            merchant_id,
+            MerchantServer::new(db_pool, channel_state, merch_state),
+        )
+    }
+
+    ///
+    /// load() - registers a merchant that was added before with the states saved in its namespace
+    /// output: server of the merchant
+    ///
+    pub fn load(
+        &mut self,
+        merchant_id: &str,
+        pool_size: usize,
+    ) -> Result<&MerchantServer<D>, ZkChannelError> {
+        let db_pool = self.connect(merchant_id, pool_size)?;
+        let (channel_state, merch_state) = db_pool.get().load_merchant_state()?;
+        if merch_state.get_id() != merchant_id {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "namespace of merchant '{}' holds the state of merchant '{}'",
+                merchant_id,
+                merch_state.get_id()
+            )));
+        }
+        self.insert(
+            merchant_id.to_string(),
+            MerchantServer::new(db_pool, channel_state, merch_state),
+        )
+    }
+
+    ///
+    /// get() - routes a session to the server of its merchant
+    /// output: server of the merchant or NotFound
+    ///
+    pub fn get(&self, merchant_id: &str) -> Result<&MerchantServer<D>, ZkChannelError> {
+        match self.servers.get(merchant_id) {
+            Some(server) => Ok(server),
+            None => Err(ZkChannelError::NotFound(format!(
+                "unknown merchant id: {}",
+                merchant_id
+            ))),
+        }
+    }
+
+    pub fn remove(&mut self, merchant_id: &str) -> Option<MerchantServer<D>> {
+        self.servers.remove(merchant_id)
+    }
+
+    pub fn get_merchant_ids(&self) -> Vec<String> {
+        let mut ids: Vec<String> = self.servers.keys().cloned().collect();
+        ids.sort();
+        ids
+    }
+
+    fn connect(
+        &self,
+        merchant_id: &str,
+        pool_size: usize,
+    ) -> Result<DatabasePool<D>, ZkChannelError> {
+        if self.servers.contains_key(merchant_id) {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "merchant id already registered: {}",
+                merchant_id
+            )));
+        }
+        let namespace = get_namespace(&self.prefix, merchant_id)?;
+        DatabasePool::connect(&namespace, self.url.clone(), pool_size)
+    }
+
+    fn insert(
+        &mut self,
+        merchant_id: String,
+        server: MerchantServer<D>,
+    ) -> Result<&MerchantServer<D>, ZkChannelError> {
+        let server: &MerchantServer<D> = self.servers.entry(merchant_id).or_insert(server);
+        Ok(server)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::{HashMapDatabase, RedisDatabase};
+    use rand::RngCore;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_parallel_prepare_with_same_nonce() {
+        assert_send_sync::<MerchantServer<RedisDatabase>>();
+
+        let rng = &mut rand::thread_rng();
+        let db_url = "redis://127.0.0.1/".to_string();
+        let mut channel_state = mpc::ChannelMPCState::new(
+            String::from("Channel A -> B"),
+            1487,
+            546,
+            546,
+            1000,
+            false,
+            mpc::BitcoinNetworkType::Testnet,
+        );
+        let merch_state = mpc::init_merchant(rng, db_url.clone(), &mut channel_state, "Bob");
+        let pool = DatabasePool::<RedisDatabase>::connect("servertest", db_url, 4).unwrap();
+        pool.get().clear_state();
+        let server = MerchantServer::new(pool, channel_state, merch_state);
+
+        // a single payment can start with a nonce, however many sessions try at once
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+        add_to_unlink_set(&server, &nonce);
+        let mut handles = Vec::new();
+        for _ in 0..8 {
+            handles.push(server.spawn(move |server| {
+                let mut session_id = [0u8; 16];
+                rand::thread_rng().fill_bytes(&mut session_id);
+                server.prepare(session_id, nonce, [1u8; 32], 0, None)
+            }));
+        }
+        let started = handles
+            .into_iter()
+            .map(|h| h.join().unwrap())
+            .filter(|r| r.is_ok())
+            .count();
+        assert_eq!(started, 1);
+        server.get_db_pool().get().clear_state();
+    }
+
+    // the unlink payment (amount 0) is only allowed with a nonce in the unlink set
+    fn add_to_unlink_set(server: &MerchantServer<RedisDatabase>, nonce: &[u8; NONCE_LEN]) {
+        let mut db = server.get_db_pool().get();
+        db.update_unlink_set(&hex::encode(nonce)).unwrap();
+    }
+
+    #[test]
+    fn test_merchant_state_changes_are_saved() {
+        let rng = &mut rand::thread_rng();
+        let mut channel_state = mpc::ChannelMPCState::new(
+            String::from("Channel A -> B"),
+            1487,
+            546,
+            546,
+            1000,
+            false,
+            mpc::BitcoinNetworkType::Testnet,
+        );
+        let merch_state = mpc::init_merchant(rng, String::new(), &mut channel_state, "Bob");
+        let pool =
+            DatabasePool::<HashMapDatabase>::connect("servertest", String::new(), 1).unwrap();
+        let server = MerchantServer::new(pool, channel_state, merch_state);
+
+        // the network config of a session is not kept
+        let net_config = NetworkConfig {
+            conn_type: ConnType_NETIO,
+            path: String::new(),
+            dest_ip: String::from("127.0.0.1"),
+            dest_port: 5000,
+        };
+        let mut db = server.get_db_pool().get();
+        let used_config = server
+            .with_merch_state(&mut *db, Some(net_config), |_, m| Ok(m.net_config.clone()))
+            .unwrap();
+        assert!(used_config.is_some());
+        assert!(server.get_merchant_state().net_config.is_none());
+        assert!(db.load_merchant_state().is_err());
+
+        // other changes are seen by later calls and saved
+        server
+            .with_merch_state(&mut *db, None, |_, m| {
+                m.set_session_timeout(30);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(server.get_merchant_state().get_session_timeout(), 30);
+        let (_, saved_state) = db.load_merchant_state().unwrap();
+        assert_eq!(saved_state, server.get_merchant_state());
+    }
+
+    #[test]
+    fn test_registry_routes_sessions_by_merchant_id() {
+        let rng = &mut rand::thread_rng();
+        let db_url = "redis://127.0.0.1/".to_string();
+        let mut registry = MerchantRegistry::<RedisDatabase>::new("registrytest", db_url.clone());
+        for id in ["brand1", "brand2"].iter() {
+            clear_namespace(id);
+            let mut channel_state = mpc::ChannelMPCState::new(
+                String::from("Channel A -> B"),
+                1487,
+                546,
+                546,
+                1000,
+                false,
+                mpc::BitcoinNetworkType::Testnet,
+            );
+            let merch_state = mpc::init_merchant(rng, db_url.clone(), &mut channel_state, id);
+            registry.add(channel_state, merch_state, 2).unwrap();
+        }
+        assert!(registry.get("brand3").is_err());
+        assert_eq!(registry.get_merchant_ids(), vec!["brand1", "brand2"]);
+        let brand1 = registry.get("brand1").unwrap();
+        let brand2 = registry.get("brand2").unwrap();
+        assert_ne!(
+            brand1.get_merchant_state().pk_m,
+            brand2.get_merchant_state().pk_m
+        );
+
+        // each merchant has its own nonce namespace
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+        add_to_unlink_set(brand1, &nonce);
+        assert!(brand1.prepare([1u8; 16], nonce, [1u8; 32], 0, None).is_ok());
+        assert!(brand1
+            .prepare([2u8; 16], nonce, [1u8; 32], 0, None)
+            .is_err());
+        assert!(brand2
+            .prepare([2u8; 16], nonce, [1u8; 32], 0, None)
+            .is_err());
+        add_to_unlink_set(brand2, &nonce);
+        assert!(brand2.prepare([3u8; 16], nonce, [1u8; 32], 0, None).is_ok());
+
+        // a merchant can be restored from its namespace (e.g., after a restart)
+        let mut restarted = MerchantRegistry::<RedisDatabase>::new("registrytest", db_url);
+        assert!(restarted.load("brand:2", 1).is_err());
+        assert_eq!(
+            restarted
+                .load("brand2", 1)
+                .unwrap()
+                .get_merchant_state()
+                .pk_m,
+            brand2.get_merchant_state().pk_m
+        );
+        assert!(restarted.load("brand2", 1).is_err());
+        clear_namespace("brand1");
+        clear_namespace("brand2");
+    }
+
+    fn clear_namespace(merchant_id: &str) {
+        let namespace = get_namespace("registrytest", merchant_id).unwrap();
+        let mut db = RedisDatabase::new(&namespace, "redis://127.0.0.1/".to_string()).unwrap();
+        db.clear_state();
+    }
+}
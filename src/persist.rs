@@ -0,0 +1,599 @@
+use super::*;
+use channels_mpc::{ChannelMPCState, ChannelMPCToken, CustomerMPCState, MerchantMPCState};
+use errors::ZkChannelError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Number, Value};
+
+///
+/// Persisted channel state is wrapped in a versioned envelope, either as json
+///     {"kind": "cust_state", "schema_version": 1, "state": {...}}
+/// or in the binary encoding
+///     MAGIC || schema_version (u32, big-endian) || kind (u8) || encoded state
+/// Both encodings are self-describing, so states written by older versions of the library are
+/// migrated (one schema version at a time) before they are deserialized. Json without an envelope
+/// (as written before the envelope was introduced) is read as LEGACY_SCHEMA_VERSION.
+///
+pub const MAGIC: [u8; 4] = *b"ZKCS";
+pub const LEGACY_SCHEMA_VERSION: u32 = 1;
+// persisted states are only a few levels deep, so anything deeper is not a state
+const MAX_DEPTH: usize = 32;
+
+// upgrades the state of the given kind from schema version i+1 to i+2 (for MIGRATIONS[i])
+type Migration = fn(StateKind, Value) -> Result<Value, ZkChannelError>;
+
+// append a migration (and never change an existing one) whenever a persisted struct changes
+const MIGRATIONS: [Migration; 1] = [move_merchant_channel_maps];
+
+// version 2: the per-channel maps of the merchant state moved to the StateDatabase. They are kept
+// in legacy_channel_maps until MerchantMPCState::migrate_legacy_channel_maps() writes them to the db
+fn move_merchant_channel_maps(kind: StateKind, mut state: Value) -> Result<Value, ZkChannelError> {
+    if kind != StateKind::MerchantState {
+        return Ok(state);
+    }
+    let m = match state.as_object_mut() {
+        Some(m) => m,
+        None => {
+            return Err(ZkChannelError::SerializationError(String::from(
+                "persisted merchant state is not an object",
+            )))
+        }
+    };
+    let mut legacy = Map::new();
+    for name in ["channel_status_map", "activate_map", "close_tx"].iter() {
+        match m.remove(*name) {
+            Some(Value::Object(map)) => {
+                if !map.is_empty() {
+                    legacy.insert(name.to_string(), Value::Object(map));
+                }
+            }
+            None | Some(Value::Null) => (),
+            Some(_) => {
+                return Err(ZkChannelError::SerializationError(format!(
+                    "persisted merchant state has an invalid {}",
+                    name
+                )))
+            }
+        }
+    }
+    if !legacy.is_empty() {
+        m.insert(String::from("legacy_channel_maps"), Value::Object(legacy));
+    }
+    Ok(state)
+}
+
+///
+/// get_schema_version() - the schema version that this version of the library writes
+///
+pub fn get_schema_version() -> u32 {
+    LEGACY_SCHEMA_VERSION + MIGRATIONS.len() as u32
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StateKind {
+    CustomerState,
+    MerchantState,
+    ChannelState,
+    ChannelToken,
+}
+
+impl StateKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StateKind::CustomerState => "cust_state",
+            StateKind::MerchantState => "merch_state",
+            StateKind::ChannelState => "channel_state",
+            StateKind::ChannelToken => "channel_token",
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        match self {
+            StateKind::CustomerState => 1,
+            StateKind::MerchantState => 2,
+            StateKind::ChannelState => 3,
+            StateKind::ChannelToken => 4,
+        }
+    }
+
+    pub fn from_name(s: &str) -> Result<Self, ZkChannelError> {
+        match s {
+            "cust_state" => Ok(StateKind::CustomerState),
+            "merch_state" => Ok(StateKind::MerchantState),
+            "channel_state" => Ok(StateKind::ChannelState),
+            "channel_token" => Ok(StateKind::ChannelToken),
+            _ => Err(ZkChannelError::SerializationError(format!(
+                "unknown kind of persisted state: {}",
+                s
+            ))),
+        }
+    }
+
+    pub fn from_byte(b: u8) -> Result<Self, ZkChannelError> {
+        match b {
+            1 => Ok(StateKind::CustomerState),
+            2 => Ok(StateKind::MerchantState),
+            3 => Ok(StateKind::ChannelState),
+            4 => Ok(StateKind::ChannelToken),
+            _ => Err(ZkChannelError::SerializationError(format!(
+                "unknown kind of persisted state: {}",
+                b
+            ))),
+        }
+    }
+}
+
+///
+/// PersistedState - the channel state objects that are stored by customers and merchants
+///
+pub trait PersistedState: Serialize + DeserializeOwned {
+    const KIND: StateKind;
+}
+
+impl PersistedState for CustomerMPCState {
+    const KIND: StateKind = StateKind::CustomerState;
+}
+
+impl PersistedState for MerchantMPCState {
+    const KIND: StateKind = StateKind::MerchantState;
+}
+
+impl PersistedState for ChannelMPCState {
+    const KIND: StateKind = StateKind::ChannelState;
+}
+
+impl PersistedState for ChannelMPCToken {
+    const KIND: StateKind = StateKind::ChannelToken;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Json,
+    Binary,
+}
+
+fn migrate_with(
+    migrations: &[Migration],
+    kind: StateKind,
+    version: u32,
+    state: Value,
+) -> Result<Value, ZkChannelError> {
+    let schema_version = LEGACY_SCHEMA_VERSION + migrations.len() as u32;
+    if version < LEGACY_SCHEMA_VERSION || version > schema_version {
+        return Err(ZkChannelError::SerializationError(format!(
+            "unsupported schema version for {}: {} (expected at most {})",
+            kind.as_str(),
+            version,
+            schema_version
+        )));
+    }
+    let mut state = state;
+    for migration in migrations[(version - LEGACY_SCHEMA_VERSION) as usize..].iter() {
+        state = migration(kind, state)?;
+    }
+    Ok(state)
+}
+
+///
+/// migrate() - upgrades a persisted state from the given schema version to the current one
+/// output: state in the current schema
+///
+pub fn migrate(kind: StateKind, version: u32, state: Value) -> Result<Value, ZkChannelError> {
+    migrate_with(&MIGRATIONS, kind, version, state)
+}
+
+fn from_value<T: PersistedState>(
+    kind: StateKind,
+    version: u32,
+    state: Value,
+) -> Result<T, ZkChannelError> {
+    if kind != T::KIND {
+        return Err(ZkChannelError::SerializationError(format!(
+            "expected persisted {}, but got {}",
+            T::KIND.as_str(),
+            kind.as_str()
+        )));
+    }
+    let state = migrate(kind, version, state)?;
+    Ok(serde_json::from_value(state)?)
+}
+
+///
+/// to_json_string() - serializes the state in the versioned json envelope
+/// output: json string
+///
+pub fn to_json_string<T: PersistedState>(state: &T) -> Result<String, ZkChannelError> {
+    let mut envelope = Map::new();
+    envelope.insert(
+        String::from("kind"),
+        Value::String(T::KIND.as_str().to_string()),
+    );
+    envelope.insert(
+        String::from("schema_version"),
+        Value::Number(Number::from(get_schema_version())),
+    );
+    envelope.insert(String::from("state"), serde_json::to_value(state)?);
+    Ok(serde_json::to_string(&Value::Object(envelope))?)
+}
+
+///
+/// from_json_str() - deserializes a state from the versioned json envelope (or legacy json
+/// without an envelope) and migrates it to the current schema
+/// output: state
+///
+pub fn from_json_str<T: PersistedState>(s: &str) -> Result<T, ZkChannelError> {
+    let value: Value = serde_json::from_str(s)?;
+    let mut envelope = match value {
+        Value::Object(m) => {
+            if !m.contains_key("schema_version") {
+                return from_value(T::KIND, LEGACY_SCHEMA_VERSION, Value::Object(m));
+            }
+            m
+        }
+        v => return from_value(T::KIND, LEGACY_SCHEMA_VERSION, v),
+    };
+    let kind = match envelope.get("kind") {
+        Some(Value::String(s)) => StateKind::from_name(s)?,
+        _ => {
+            return Err(ZkChannelError::SerializationError(String::from(
+                "persisted state does not have a kind",
+            )))
+        }
+    };
+    let version = match envelope.get("schema_version").and_then(|v| v.as_u64()) {
+        Some(v) if v <= u32::max_value() as u64 => v as u32,
+        _ => {
+            return Err(ZkChannelError::SerializationError(String::from(
+                "persisted state has an invalid schema version",
+            )))
+        }
+    };
+    let state = match envelope.remove("state") {
+        Some(s) => s,
+        None => {
+            return Err(ZkChannelError::SerializationError(String::from(
+                "persisted state is missing",
+            )))
+        }
+    };
+    from_value(kind, version, state)
+}
+
+const TAG_NULL: u8 = 0x00;
+const TAG_FALSE: u8 = 0x01;
+const TAG_TRUE: u8 = 0x02;
+const TAG_UINT: u8 = 0x03;
+const TAG_INT: u8 = 0x04;
+const TAG_FLOAT: u8 = 0x05;
+const TAG_STRING: u8 = 0x06;
+const TAG_ARRAY: u8 = 0x07;
+const TAG_OBJECT: u8 = 0x08;
+
+fn encode_len(len: usize, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(len as u32).to_be_bytes());
+}
+
+fn encode_value(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(TAG_NULL),
+        Value::Bool(false) => out.push(TAG_FALSE),
+        Value::Bool(true) => out.push(TAG_TRUE),
+        Value::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                out.push(TAG_UINT);
+                out.extend_from_slice(&u.to_be_bytes());
+            } else if let Some(i) = n.as_i64() {
+                out.push(TAG_INT);
+                out.extend_from_slice(&i.to_be_bytes());
+            } else {
+                // json numbers are always finite
+                out.push(TAG_FLOAT);
+                out.extend_from_slice(&n.as_f64().unwrap_or(0.0).to_bits().to_be_bytes());
+            }
+        }
+        Value::String(s) => {
+            out.push(TAG_STRING);
+            encode_len(s.len(), out);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(a) => {
+            out.push(TAG_ARRAY);
+            encode_len(a.len(), out);
+            for v in a.iter() {
+                encode_value(v, out);
+            }
+        }
+        Value::Object(m) => {
+            // keys are sorted, so the encoding of a state is deterministic
+            out.push(TAG_OBJECT);
+            encode_len(m.len(), out);
+            for (k, v) in m.iter() {
+                encode_len(k.len(), out);
+                out.extend_from_slice(k.as_bytes());
+                encode_value(v, out);
+            }
+        }
+    }
+}
+
+struct BinaryReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl<'a> BinaryReader<'a> {
+    fn read(&mut self, len: usize) -> Result<&'a [u8], ZkChannelError> {
+        if self.buf.len() - self.pos < len {
+            return Err(ZkChannelError::SerializationError(String::from(
+                "persisted state is truncated",
+            )));
+        }
+        let bytes = &self.buf[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, ZkChannelError> {
+        Ok(self.read(1)?[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32, ZkChannelError> {
+        let mut b = [0u8; 4];
+        b.copy_from_slice(self.read(4)?);
+        Ok(u32::from_be_bytes(b))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, ZkChannelError> {
+        let mut b = [0u8; 8];
+        b.copy_from_slice(self.read(8)?);
+        Ok(u64::from_be_bytes(b))
+    }
+
+    fn read_string(&mut self) -> Result<String, ZkChannelError> {
+        let len = self.read_u32()? as usize;
+        Ok(str::from_utf8(self.read(len)?)?.to_string())
+    }
+
+    fn read_value(&mut self) -> Result<Value, ZkChannelError> {
+        // arrays and objects are read recursively, so crafted input must not exhaust the stack
+        if self.depth == MAX_DEPTH {
+            return Err(ZkChannelError::SerializationError(String::from(
+                "persisted state is nested too deeply",
+            )));
+        }
+        self.depth += 1;
+        let value = self.read_tagged_value();
+        self.depth -= 1;
+        value
+    }
+
+    fn read_tagged_value(&mut self) -> Result<Value, ZkChannelError> {
+        let value = match self.read_u8()? {
+            TAG_NULL => Value::Null,
+            TAG_FALSE => Value::Bool(false),
+            TAG_TRUE => Value::Bool(true),
+            TAG_UINT => Value::Number(Number::from(self.read_u64()?)),
+            TAG_INT => Value::Number(Number::from(self.read_u64()? as i64)),
+            TAG_FLOAT => match Number::from_f64(f64::from_bits(self.read_u64()?)) {
+                Some(n) => Value::Number(n),
+                None => {
+                    return Err(ZkChannelError::SerializationError(String::from(
+                        "persisted state has an invalid number",
+                    )))
+                }
+            },
+            TAG_STRING => Value::String(self.read_string()?),
+            TAG_ARRAY => {
+                let len = self.read_u32()? as usize;
+                let mut a = Vec::new();
+                for _ in 0..len {
+                    a.push(self.read_value()?);
+                }
+                Value::Array(a)
+            }
+            TAG_OBJECT => {
+                let len = self.read_u32()? as usize;
+                let mut m = Map::new();
+                for _ in 0..len {
+                    let k = self.read_string()?;
+                    m.insert(k, self.read_value()?);
+                }
+                Value::Object(m)
+            }
+            t => {
+                return Err(ZkChannelError::SerializationError(format!(
+                    "persisted state has an unknown tag: {}",
+                    t
+                )))
+            }
+        };
+        Ok(value)
+    }
+}
+
+///
+/// to_binary() - serializes the state in the versioned binary envelope
+/// output: encoded state
+///
+pub fn to_binary<T: PersistedState>(state: &T) -> Result<Vec<u8>, ZkChannelError> {
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&get_schema_version().to_be_bytes());
+    out.push(T::KIND.to_byte());
+    encode_value(&serde_json::to_value(state)?, &mut out);
+    Ok(out)
+}
+
+///
+/// from_binary() - deserializes a state from the versioned binary envelope and migrates it
+/// to the current schema
+/// output: state
+///
+pub fn from_binary<T: PersistedState>(bytes: &[u8]) -> Result<T, ZkChannelError> {
+    let mut r = BinaryReader {
+        buf: bytes,
+        pos: 0,
+        depth: 0,
+    };
+    if r.read(MAGIC.len())? != MAGIC {
+        return Err(ZkChannelError::SerializationError(String::from(
+            "persisted state is not in the binary encoding",
+        )));
+    }
+    let version = r.read_u32()?;
+    let kind = StateKind::from_byte(r.read_u8()?)?;
+    let state = r.read_value()?;
+    if r.pos != bytes.len() {
+        return Err(ZkChannelError::SerializationError(String::from(
+            "persisted state has trailing bytes",
+        )));
+    }
+    from_value(kind, version, state)
+}
+
+///
+/// encode_state() - serializes the state in the versioned envelope with the given encoding
+/// output: encoded state
+///
+pub fn encode_state<T: PersistedState>(
+    state: &T,
+    encoding: Encoding,
+) -> Result<Vec<u8>, ZkChannelError> {
+    match encoding {
+        Encoding::Json => Ok(to_json_string(state)?.into_bytes()),
+        Encoding::Binary => to_binary(state),
+    }
+}
+
+///
+/// decode_state() - deserializes a state in either encoding (or legacy json) and migrates it
+/// to the current schema
+/// output: state
+///
+pub fn decode_state<T: PersistedState>(bytes: &[u8]) -> Result<T, ZkChannelError> {
+    if bytes.starts_with(&MAGIC) {
+        return from_binary(bytes);
+    }
+    from_json_str(str::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channels_mpc::BitcoinNetworkType;
+
+    #[test]
+    fn test_persisted_state_encodings() {
+        let channel_state = ChannelMPCState::new(
+            String::from("Channel A <-> B"),
+            1487,
+            546,
+            546,
+            1000,
+            false,
+            BitcoinNetworkType::Regtest,
+        );
+
+        let json = to_json_string(&channel_state).unwrap();
+        let binary = to_binary(&channel_state).unwrap();
+        assert!(binary.starts_with(&MAGIC));
+        assert_eq!(binary, to_binary(&channel_state).unwrap());
+
+        let from_json: ChannelMPCState = decode_state(json.as_bytes()).unwrap();
+        let from_binary: ChannelMPCState = decode_state(&binary).unwrap();
+        assert_eq!(from_json, channel_state);
+        assert_eq!(from_binary, channel_state);
+
+        // legacy json without an envelope is still loaded
+        let legacy = serde_json::to_string(&channel_state).unwrap();
+        let from_legacy: ChannelMPCState = from_json_str(&legacy).unwrap();
+        assert_eq!(from_legacy, channel_state);
+
+        // a state cannot be loaded as another kind of state
+        assert!(from_json_str::<ChannelMPCToken>(&json).is_err());
+        assert!(from_binary::<ChannelMPCToken>(&binary).is_err());
+
+        // states from a newer version of the library are rejected
+        let mut newer = binary.clone();
+        newer[4..8].copy_from_slice(&(get_schema_version() + 1).to_be_bytes());
+        assert!(decode_state::<ChannelMPCState>(&newer).is_err());
+        assert!(decode_state::<ChannelMPCState>(&binary[..binary.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_binary_nesting_is_limited() {
+        let nested = |depth: usize| {
+            let mut bytes = Vec::new();
+            for _ in 0..depth {
+                bytes.push(TAG_ARRAY);
+                encode_len(1, &mut bytes);
+            }
+            bytes.push(TAG_NULL);
+            bytes
+        };
+        let read = |bytes: &Vec<u8>| {
+            BinaryReader {
+                buf: bytes,
+                pos: 0,
+                depth: 0,
+            }
+            .read_value()
+        };
+        assert!(read(&nested(MAX_DEPTH - 1)).is_ok());
+        assert!(read(&nested(MAX_DEPTH)).is_err());
+        assert!(read(&nested(100_000)).is_err());
+    }
+
+    #[test]
+    fn test_merchant_channel_maps_are_kept_for_the_db() {
+        let state: Value = serde_json::from_str(
+            "{\"id\": \"Bob\", \"channel_status_map\": {\"00\": \"Open\"}, \"activate_map\": {}}",
+        )
+        .unwrap();
+        let migrated = move_merchant_channel_maps(StateKind::MerchantState, state.clone()).unwrap();
+        assert_eq!(
+            migrated["legacy_channel_maps"]["channel_status_map"]["00"],
+            "Open"
+        );
+        assert!(migrated.get("channel_status_map").is_none());
+        assert!(migrated.get("activate_map").is_none());
+
+        // other kinds of state and merchant states without channels are not changed
+        let cust_state = move_merchant_channel_maps(StateKind::CustomerState, state).unwrap();
+        assert_eq!(cust_state["channel_status_map"]["00"], "Open");
+        let empty: Value = serde_json::from_str("{\"id\": \"Bob\", \"close_tx\": {}}").unwrap();
+        let migrated = move_merchant_channel_maps(StateKind::MerchantState, empty).unwrap();
+        assert_eq!(
+            migrated,
+            serde_json::from_str::<Value>("{\"id\": \"Bob\"}").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_migrations_are_applied_in_order() {
+        fn rename_field(_kind: StateKind, mut state: Value) -> Result<Value, ZkChannelError> {
+            let v = state["old"].take();
+            state["new"] = v;
+            Ok(state)
+        }
+        fn bump_field(_kind: StateKind, mut state: Value) -> Result<Value, ZkChannelError> {
+            let v = state["new"].as_u64().unwrap();
+            state["new"] = Value::Number(Number::from(v + 1));
+            Ok(state)
+        }
+        let migrations: [Migration; 2] = [rename_field, bump_field];
+        let state: Value = serde_json::from_str("{\"old\": 1}").unwrap();
+
+        let migrated =
+            migrate_with(&migrations, StateKind::CustomerState, 1, state.clone()).unwrap();
+        assert_eq!(migrated["new"], 2);
+        let partial = serde_json::from_str("{\"new\": 1}").unwrap();
+        let migrated = migrate_with(&migrations, StateKind::CustomerState, 2, partial).unwrap();
+        assert_eq!(migrated["new"], 2);
+        let current =
+            migrate_with(&migrations, StateKind::CustomerState, 3, state.clone()).unwrap();
+        assert_eq!(current, state);
+        assert!(migrate_with(&migrations, StateKind::CustomerState, 4, state.clone()).is_err());
+        assert!(migrate_with(&migrations, StateKind::CustomerState, 0, state).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use super::*;
+use database::StateDatabase;
+use errors::ZkChannelError;
+#[cfg(feature = "async")]
+use futures::channel::oneshot;
+#[cfg(feature = "async")]
+use futures::future::{self, BoxFuture, FutureExt};
+use std::ops::{Deref, DerefMut};
+#[cfg(feature = "async")]
+use std::panic::{self, AssertUnwindSafe};
+#[cfg(feature = "async")]
+use std::sync::mpsc::{self, Sender};
+#[cfg(feature = "async")]
+use std::sync::Arc;
+use std::sync::{Condvar, Mutex};
+#[cfg(feature = "async")]
+use std::thread;
+
+///
+/// Pool - a fixed set of resources (e.g., database connections) that can be shared between
+/// threads. get() blocks until a resource is free and the resource goes back to the pool when
+/// the returned guard is dropped. A pool is Send + Sync if its resources are Send
+///
+pub struct Pool<T> {
+    free: Mutex<Vec<T>>,
+    available: Condvar,
+    size: usize,
+}
+
+// each thread gets its own database connection from the pool
+pub type DatabasePool<D> = Pool<D>;
+
+impl<T> Pool<T> {
+    pub fn new(resources: Vec<T>) -> Result<Self, ZkChannelError> {
+        if resources.is_empty() {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "a pool needs at least one resource",
+            )));
+        }
+        let size = resources.len();
+        Ok(Pool {
+            free: Mutex::new(resources),
+            available: Condvar::new(),
+            size: size,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    ///
+    /// get() - takes a resource from the pool (and waits for one to be returned if none is free)
+    /// output: guard that returns the resource to the pool when dropped
+    ///
+    pub fn get(&self) -> PoolGuard<T> {
+        // a thread can only panic while holding the lock in Vec::pop/push, so recover the list
+        let mut free = match self.free.lock() {
+            Ok(f) => f,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        loop {
+            if let Some(resource) = free.pop() {
+                return PoolGuard {
+                    pool: self,
+                    resource: Some(resource),
+                };
+            }
+            free = match self.available.wait(free) {
+                Ok(f) => f,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+        }
+    }
+
+    fn put(&self, resource: T) {
+        let mut free = match self.free.lock() {
+            Ok(f) => f,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        free.push(resource);
+        self.available.notify_one();
+    }
+}
+
+impl<D: StateDatabase> Pool<D> {
+    ///
+    /// connect() - opens size connections to the database. Only backends where every connection
+    /// sees the same data (e.g., RedisDatabase) can have more than one connection; a FileDatabase
+    /// or HashMapDatabase pool must have size 1, which serializes all access to it
+    /// output: database pool
+    ///
+    pub fn connect(prefix: &str, url: String, size: usize) -> Result<Self, ZkChannelError> {
+        if size > 1 && !D::is_shared() {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "connections to this database do not share their data, so its pool must have size 1 (got {})",
+                size
+            )));
+        }
+        let mut dbs = Vec::new();
+        for _ in 0..size {
+            dbs.push(D::new(prefix, url.clone())?);
+        }
+        Pool::new(dbs)
+    }
+}
+
+pub struct PoolGuard<'a, T: 'a> {
+    pool: &'a Pool<T>,
+    // only None while the guard is dropped
+    resource: Option<T>,
+}
+
+impl<'a, T> Deref for PoolGuard<'a, T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.resource.as_ref().unwrap()
+    }
+}
+
+impl<'a, T> DerefMut for PoolGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.resource.as_mut().unwrap()
+    }
+}
+
+impl<'a, T> Drop for PoolGuard<'a, T> {
+    fn drop(&mut self) {
+        if let Some(resource) = self.resource.take() {
+            self.pool.put(resource);
+        }
+    }
+}
+
+// result of a blocking call that runs on a BlockingPool
+#[cfg(feature = "async")]
+pub type ZkFuture<T> = BoxFuture<'static, Result<T, ZkChannelError>>;
+
+#[cfg(feature = "async")]
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+///
+/// BlockingPool - a fixed set of threads for blocking calls (e.g., the EMP calls of the MPC or
+/// the calls of a StateDatabase), so that an async executor never waits on them. The futures
+/// returned by run() do not depend on a particular executor (e.g., they can be spawned on tokio).
+/// Calls that were queued before the pool is dropped still run
+///
+#[cfg(feature = "async")]
+pub struct BlockingPool {
+    // std::sync::mpsc::Sender is not Sync
+    jobs: Mutex<Sender<Job>>,
+    size: usize,
+}
+
+#[cfg(feature = "async")]
+impl BlockingPool {
+    pub fn new(size: usize) -> Result<Self, ZkChannelError> {
+        if size == 0 {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "a pool needs at least one thread",
+            )));
+        }
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..size {
+            let receiver = receiver.clone();
+            thread::spawn(move || loop {
+                let job = match receiver.lock() {
+                    Ok(r) => r.recv(),
+                    Err(poisoned) => poisoned.into_inner().recv(),
+                };
+                match job {
+                    Ok(job) => job(),
+                    // the pool was dropped and all queued calls have run
+                    Err(_) => break,
+                }
+            });
+        }
+        Ok(BlockingPool {
+            jobs: Mutex::new(sender),
+            size: size,
+        })
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    ///
+    /// run() - queues the call on the next free thread of the pool
+    /// output: future that resolves to the result of the call (or an InternalError if the call panicked)
+    ///
+    pub fn run<F, T>(&self, call: F) -> ZkFuture<T>
+    where
+        F: FnOnce() -> Result<T, ZkChannelError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            // a panic must not take down the thread of the pool
+            let result = match panic::catch_unwind(AssertUnwindSafe(call)) {
+                Ok(r) => r,
+                Err(_) => Err(ZkChannelError::InternalError(String::from(
+                    "blocking call panicked",
+                ))),
+            };
+            let _ = sender.send(result);
+        });
+        let queued = match self.jobs.lock() {
+            Ok(jobs) => jobs.send(job),
+            Err(poisoned) => poisoned.into_inner().send(job),
+        };
+        if queued.is_err() {
+            return future::ready(Err(ZkChannelError::InternalError(String::from(
+                "blocking pool has no threads",
+            ))))
+            .boxed();
+        }
+        receiver
+            .map(|result| match result {
+                Ok(r) => r,
+                Err(_) => Err(ZkChannelError::InternalError(String::from(
+                    "blocking call was dropped",
+                ))),
+            })
+            .boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use database::HashMapDatabase;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_pool_shares_resources_between_threads() {
+        assert_send_sync::<DatabasePool<HashMapDatabase>>();
+        assert!(Pool::<u32>::new(Vec::new()).is_err());
+        // every HashMapDatabase has its own maps
+        assert!(DatabasePool::<HashMapDatabase>::connect("pooltest", String::new(), 2).is_err());
+
+        let pool: DatabasePool<HashMapDatabase> =
+            DatabasePool::connect("pooltest", String::new(), 1).unwrap();
+        let pool = Arc::new(pool);
+        let mut handles = Vec::new();
+        for i in 0..8u8 {
+            let pool = pool.clone();
+            handles.push(thread::spawn(move || {
+                let mut db = pool.get();
+                db.update_unlink_set(&hex::encode([i; 16])).unwrap();
+            }));
+        }
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(pool.size(), 1);
+        assert_eq!(pool.get().get_unlink_set().unwrap().len(), 8);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_blocking_pool_runs_calls_in_parallel() {
+        assert_send_sync::<BlockingPool>();
+        assert!(BlockingPool::new(0).is_err());
+
+        let pool = BlockingPool::new(4).unwrap();
+        let running = Arc::new(Mutex::new((0usize, 0usize)));
+        let mut calls = Vec::new();
+        for _ in 0..4 {
+            let running = running.clone();
+            calls.push(pool.run(move || {
+                running.lock().unwrap().0 += 1;
+                // wait for every call to start (at most 5 seconds)
+                for _ in 0..500 {
+                    if running.lock().unwrap().0 == 4 {
+                        break;
+                    }
+                    thread::sleep(std::time::Duration::from_millis(10));
+                }
+                let mut r = running.lock().unwrap();
+                r.1 = std::cmp::max(r.1, r.0);
+                Ok(())
+            }));
+        }
+        let panicked = pool.run(|| -> Result<(), ZkChannelError> { panic!("bad call") });
+
+        let mut rt = tokio::runtime::Runtime::new().unwrap();
+        let results = rt.block_on(future::join_all(calls));
+        assert!(results.iter().all(|r| r.is_ok()));
+        assert_eq!(running.lock().unwrap().1, 4);
+        match rt.block_on(panicked) {
+            Err(ZkChannelError::InternalError(_)) => (),
+            r => panic!("unexpected result of a panicking call: {:?}", r),
+        }
+        // the thread that ran the panicking call is still there
+        assert_eq!(rt.block_on(pool.run(|| Ok(1))).unwrap(), 1);
+    }
+}
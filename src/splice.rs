@@ -0,0 +1,282 @@
+use super::*;
+use dual_funding::{
+    compute_escrow_prevout, compute_sighash, double_sha256, escrow_script, serialize_escrow_output,
+    sign_sighash, verify_sighash, EscrowContribution, FundingParty, TX_LOCKTIME, TX_SEQUENCE,
+    TX_VERSION,
+};
+use errors::ZkChannelError;
+use secp256k1::PublicKey;
+use zkchan_tx::fixed_size_array::FixedSizeArray32;
+
+///
+/// EscrowSpliceTx - tops up a channel by spending the current escrow output (first input) together with
+/// a new customer input (second input) into a new escrow output, followed by the customer's change.
+/// The escrow amount is carried over in full, so the customer's input also pays the fee of the splice.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EscrowSpliceTx {
+    pub cust_pk: PublicKey,
+    pub merch_pk: PublicKey,
+    pub escrow_txid_be: FixedSizeArray32,
+    pub escrow_sats: i64,
+    pub top_up: EscrowContribution,
+}
+
+impl EscrowSpliceTx {
+    pub fn new(
+        cust_pk: PublicKey,
+        merch_pk: PublicKey,
+        escrow_txid_be: [u8; 32],
+        escrow_sats: i64,
+        top_up: EscrowContribution,
+    ) -> Result<Self, ZkChannelError> {
+        if escrow_sats <= 0 {
+            return Err(ZkChannelError::InvalidInput(format!(
+                "invalid amount for the current escrow: {}",
+                escrow_sats
+            )));
+        }
+        top_up.validate(FundingParty::Customer)?;
+        Ok(EscrowSpliceTx {
+            cust_pk,
+            merch_pk,
+            escrow_txid_be: FixedSizeArray32(escrow_txid_be),
+            escrow_sats,
+            top_up,
+        })
+    }
+
+    pub fn get_new_escrow_sats(&self) -> i64 {
+        self.escrow_sats + self.top_up.escrow_sats
+    }
+
+    fn serialize_escrow_outpoint(&self) -> Vec<u8> {
+        let mut outpoint = self.escrow_txid_be.0.to_vec();
+        outpoint.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        outpoint
+    }
+
+    fn serialize_inputs(&self) -> Vec<u8> {
+        let mut inputs = vec![0x02];
+        // the escrow is a P2WSH output, so its scriptSig is empty
+        inputs.extend(self.serialize_escrow_outpoint());
+        inputs.push(0x00);
+        inputs.extend_from_slice(&TX_SEQUENCE);
+        inputs.extend(self.top_up.input.serialize_input());
+        inputs
+    }
+
+    fn serialize_outputs(&self) -> Vec<u8> {
+        let change_output = self.top_up.serialize_change_output();
+        let mut outputs = Vec::new();
+        outputs.push(if change_output.is_empty() { 1 } else { 2 });
+        outputs.extend(serialize_escrow_output(
+            self.get_new_escrow_sats(),
+            &self.cust_pk,
+            &self.merch_pk,
+        ));
+        outputs.extend(change_output);
+        outputs
+    }
+
+    fn get_outpoints(&self) -> Vec<Vec<u8>> {
+        vec![
+            self.serialize_escrow_outpoint(),
+            self.top_up.input.serialize_outpoint(),
+        ]
+    }
+
+    ///
+    /// get_escrow_sighash() - BIP143 digest that both parties sign to spend the current escrow
+    /// output: sighash
+    ///
+    pub fn get_escrow_sighash(&self) -> [u8; 32] {
+        let script = escrow_script(&self.cust_pk, &self.merch_pk);
+        let mut script_code = vec![script.len() as u8];
+        script_code.extend(script);
+        compute_sighash(
+            &self.get_outpoints(),
+            &self.serialize_escrow_outpoint(),
+            &script_code,
+            self.escrow_sats,
+            &self.serialize_outputs()[1..].to_vec(),
+        )
+    }
+
+    ///
+    /// get_top_up_sighash() - BIP143 digest that the customer signs to spend the top-up input
+    /// output: sighash
+    ///
+    pub fn get_top_up_sighash(&self) -> [u8; 32] {
+        compute_sighash(
+            &self.get_outpoints(),
+            &self.top_up.input.serialize_outpoint(),
+            &self.top_up.input.script_code(),
+            self.top_up.input.input_sats,
+            &self.serialize_outputs()[1..].to_vec(),
+        )
+    }
+
+    ///
+    /// get_txid() - computes the txid of the splice-tx, which is the escrow txid of the topped up channel
+    /// output: new escrow txid (big-endian and little-endian)
+    ///
+    pub fn get_txid(&self) -> ([u8; 32], [u8; 32]) {
+        let mut tx = TX_VERSION.to_vec();
+        tx.extend(self.serialize_inputs());
+        tx.extend(self.serialize_outputs());
+        tx.extend_from_slice(&TX_LOCKTIME);
+        let txid_be = double_sha256(&tx);
+        let mut txid_le = txid_be.clone();
+        txid_le.reverse();
+        (txid_be, txid_le)
+    }
+
+    pub fn get_escrow_prevout(&self) -> [u8; 32] {
+        let (txid_be, _) = self.get_txid();
+        compute_escrow_prevout(&txid_be)
+    }
+
+    fn get_escrow_pk(&self, party: FundingParty) -> &PublicKey {
+        match party {
+            FundingParty::Customer => &self.cust_pk,
+            FundingParty::Merchant => &self.merch_pk,
+        }
+    }
+
+    ///
+    /// sign_escrow() - the given party signs the escrow input with its escrow secret key (sk_c or sk_m)
+    /// output: DER-encoded signature followed by the sighash flag
+    ///
+    pub fn sign_escrow(
+        &self,
+        party: FundingParty,
+        sk: &[u8; 32],
+    ) -> Result<Vec<u8>, ZkChannelError> {
+        sign_sighash(&self.get_escrow_sighash(), sk, self.get_escrow_pk(party))
+    }
+
+    pub fn verify_escrow(&self, party: FundingParty, sig: &Vec<u8>) -> bool {
+        verify_sighash(&self.get_escrow_sighash(), sig, self.get_escrow_pk(party))
+    }
+
+    ///
+    /// sign_top_up() - the customer signs the top-up input with the secret key of its funding outpoint
+    /// output: DER-encoded signature followed by the sighash flag
+    ///
+    pub fn sign_top_up(&self, sk: &[u8; 32]) -> Result<Vec<u8>, ZkChannelError> {
+        sign_sighash(&self.get_top_up_sighash(), sk, &self.top_up.input.pk)
+    }
+
+    pub fn verify_top_up(&self, sig: &Vec<u8>) -> bool {
+        verify_sighash(&self.get_top_up_sighash(), sig, &self.top_up.input.pk)
+    }
+
+    ///
+    /// finalize() - verifies all signatures and assembles the signed splice-tx
+    /// output: signed splice-tx that can be broadcast
+    ///
+    pub fn finalize(
+        &self,
+        cust_escrow_sig: &Vec<u8>,
+        merch_escrow_sig: &Vec<u8>,
+        top_up_sig: &Vec<u8>,
+    ) -> Result<Vec<u8>, ZkChannelError> {
+        if !self.verify_escrow(FundingParty::Customer, cust_escrow_sig) {
+            return Err(ZkChannelError::TransactionError(String::from(
+                "invalid customer signature on the escrow input of the splice-tx",
+            )));
+        }
+        if !self.verify_escrow(FundingParty::Merchant, merch_escrow_sig) {
+            return Err(ZkChannelError::TransactionError(String::from(
+                "invalid merchant signature on the escrow input of the splice-tx",
+            )));
+        }
+        if !self.verify_top_up(top_up_sig) {
+            return Err(ZkChannelError::TransactionError(String::from(
+                "invalid customer signature on the top-up input of the splice-tx",
+            )));
+        }
+        // the signatures have to be in the order of the keys in the escrow script (merchant first)
+        let script = escrow_script(&self.cust_pk, &self.merch_pk);
+        let mut escrow_witness = vec![0x04, 0x00];
+        escrow_witness.push(merch_escrow_sig.len() as u8);
+        escrow_witness.extend_from_slice(merch_escrow_sig);
+        escrow_witness.push(cust_escrow_sig.len() as u8);
+        escrow_witness.extend_from_slice(cust_escrow_sig);
+        escrow_witness.push(script.len() as u8);
+        escrow_witness.extend(script);
+
+        let mut tx = TX_VERSION.to_vec();
+        tx.extend_from_slice(&[0x00, 0x01]); // segwit marker and flag
+        tx.extend(self.serialize_inputs());
+        tx.extend(self.serialize_outputs());
+        tx.extend(escrow_witness);
+        tx.extend(self.top_up.input.serialize_witness(top_up_sig));
+        tx.extend_from_slice(&TX_LOCKTIME);
+        Ok(tx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dual_funding::EscrowFundingInput;
+    use secp256k1::SecretKey;
+
+    fn keypair(byte: u8) -> ([u8; 32], PublicKey) {
+        let secp = secp256k1::Secp256k1::new();
+        let sk = [byte; 32];
+        let pk = PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&sk).unwrap());
+        (sk, pk)
+    }
+
+    #[test]
+    fn test_escrow_splice_sigs() {
+        let (cust_sk, cust_pk) = keypair(0x31);
+        let (merch_sk, merch_pk) = keypair(0x32);
+        let (funding_sk, funding_pk) = keypair(0x11);
+        let (_, change_pk) = keypair(0x12);
+        let top_up = EscrowContribution {
+            input: EscrowFundingInput {
+                txid_le: FixedSizeArray32([0x11; 32]),
+                index: 1,
+                input_sats: 60000,
+                pk: funding_pk,
+            },
+            escrow_sats: 50000,
+            change_sats: 9000,
+            change_pk: Some(change_pk),
+        };
+        let splice_tx = EscrowSpliceTx::new(cust_pk, merch_pk, [0x22; 32], 20000, top_up).unwrap();
+        assert_eq!(splice_tx.get_new_escrow_sats(), 70000);
+
+        let cust_escrow_sig = splice_tx
+            .sign_escrow(FundingParty::Customer, &cust_sk)
+            .unwrap();
+        let merch_escrow_sig = splice_tx
+            .sign_escrow(FundingParty::Merchant, &merch_sk)
+            .unwrap();
+        let top_up_sig = splice_tx.sign_top_up(&funding_sk).unwrap();
+        assert!(splice_tx.verify_escrow(FundingParty::Customer, &cust_escrow_sig));
+        assert!(!splice_tx.verify_escrow(FundingParty::Merchant, &cust_escrow_sig));
+        assert!(!splice_tx.verify_top_up(&cust_escrow_sig));
+        assert!(splice_tx
+            .sign_escrow(FundingParty::Merchant, &cust_sk)
+            .is_err());
+        assert!(splice_tx
+            .finalize(&merch_escrow_sig, &cust_escrow_sig, &top_up_sig)
+            .is_err());
+
+        let signed_tx = splice_tx
+            .finalize(&cust_escrow_sig, &merch_escrow_sig, &top_up_sig)
+            .unwrap();
+        assert_eq!(signed_tx[4..6], [0x00, 0x01]);
+
+        // the new escrow depends on the escrow being spent
+        let mut other_tx = splice_tx.clone();
+        other_tx.escrow_txid_be = FixedSizeArray32([0x23; 32]);
+        assert_ne!(other_tx.get_txid(), splice_tx.get_txid());
+        assert!(!other_tx.verify_escrow(FundingParty::Merchant, &merch_escrow_sig));
+    }
+}
@@ -12,7 +12,13 @@ macro_rules! handle_error_util {
     ($e:expr) => {
         match $e {
             Ok(v) => v,
-            Err(err) => return Err(err.to_string()),
+            Err(err) => return Err(err.into()),
+        }
+    };
+    ($e:expr, $variant:path) => {
+        match $e {
+            Ok(v) => v,
+            Err(err) => return Err($variant(err.to_string())),
         }
     };
 }
@@ -181,6 +187,14 @@ pub fn hmac_sign(key: Vec<u8>, message: &Vec<u8>) -> [u8; 32] {
     return hash;
 }
 
+// current unix time in seconds
+pub fn get_current_time() -> u64 {
+    match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -0,0 +1,588 @@
+use super::*;
+use channels_mpc::{parse_cust_close_transaction, CustCloseTxInfo};
+use errors::ZkChannelError;
+use secp256k1::{PublicKey, SecretKey};
+use std::mem;
+use util::hash_to_slice;
+use zkchan_tx::fixed_size_array::FixedSizeArray32;
+
+///
+/// BlockSource - the blocks that the watchtower scans for revoked cust-close-txs (e.g., a bitcoind rpc client).
+/// Blocks are identified by their height, starting at 0.
+///
+pub trait BlockSource {
+    fn get_block_count(&mut self) -> Result<u64, ZkChannelError>;
+    fn get_block_transactions(&mut self, height: u64) -> Result<Vec<Vec<u8>>, ZkChannelError>;
+}
+
+///
+/// TxPublisher - broadcasts the dispute transactions signed by the watchtower
+///
+pub trait TxPublisher {
+    fn publish_transaction(&mut self, raw_tx: &Vec<u8>) -> Result<(), ZkChannelError>;
+}
+
+///
+/// MockChain - in-process chain for testing the watchtower. Blocks are mined explicitly
+/// and published transactions are kept in the mempool until they are mined.
+///
+#[derive(Clone, Debug, Default)]
+pub struct MockChain {
+    pub blocks: Vec<Vec<Vec<u8>>>,
+    pub mempool: Vec<Vec<u8>>,
+}
+
+impl MockChain {
+    pub fn new() -> Self {
+        MockChain::default()
+    }
+
+    ///
+    /// mine_block() - appends a block with the given transactions
+    /// output: height of the new block
+    ///
+    pub fn mine_block(&mut self, txs: Vec<Vec<u8>>) -> u64 {
+        self.blocks.push(txs);
+        self.blocks.len() as u64 - 1
+    }
+
+    ///
+    /// mine_mempool() - appends a block with all transactions in the mempool
+    /// output: height of the new block
+    ///
+    pub fn mine_mempool(&mut self) -> u64 {
+        let txs = self.mempool.drain(..).collect();
+        self.mine_block(txs)
+    }
+}
+
+impl BlockSource for MockChain {
+    fn get_block_count(&mut self) -> Result<u64, ZkChannelError> {
+        Ok(self.blocks.len() as u64)
+    }
+
+    fn get_block_transactions(&mut self, height: u64) -> Result<Vec<Vec<u8>>, ZkChannelError> {
+        match self.blocks.get(height as usize) {
+            Some(txs) => Ok(txs.clone()),
+            None => Err(ZkChannelError::NotFound(format!(
+                "no block at height: {}",
+                height
+            ))),
+        }
+    }
+}
+
+impl TxPublisher for MockChain {
+    fn publish_transaction(&mut self, raw_tx: &Vec<u8>) -> Result<(), ZkChannelError> {
+        self.mempool.push(raw_tx.clone());
+        Ok(())
+    }
+}
+
+///
+/// DisputeMaterial - rev_secret of a revoked state, keyed by its rev_lock. The merchant
+/// hands it to the watchtower once the state is added to the rev_lock map.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DisputeMaterial {
+    pub rev_lock: FixedSizeArray32,
+    pub rev_secret: FixedSizeArray32,
+}
+
+impl DisputeMaterial {
+    pub fn new(rev_lock: [u8; 32], rev_secret: [u8; 32]) -> Result<Self, ZkChannelError> {
+        if hash_to_slice(&rev_secret.to_vec()) != rev_lock {
+            return Err(ZkChannelError::InvalidCommitmentOpening(format!(
+                "rev_secret does not open rev_lock {}",
+                hex::encode(&rev_lock)
+            )));
+        }
+        Ok(DisputeMaterial {
+            rev_lock: FixedSizeArray32(rev_lock),
+            rev_secret: FixedSizeArray32(rev_secret),
+        })
+    }
+}
+
+///
+/// WatchedChannel - outpoints of a channel that a cust-close-tx can spend from (escrow-tx or
+/// merch-close-tx) together with the parameters of the merch-close-tx that the dispute depends on
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WatchedChannel {
+    pub escrow_txid_be: FixedSizeArray32,
+    pub merch_txid_be: FixedSizeArray32,
+    pub to_self_delay: [u8; 2],
+    pub fee_mc: i64,
+}
+
+///
+/// DisputeAuthorization - pre-authorizes the holder to sign dispute transactions on behalf of the merchant.
+/// It only carries the dispute key (not the escrow or payout keys) and every dispute tx pays to the
+/// merchant's payout-pk. The dispute key is never serialized: it has to be loaded again with
+/// load_dispute_key() after the authorization (or a watchtower that holds it) is deserialized.
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DisputeAuthorization {
+    pub payout_pk: PublicKey,
+    pub dispute_pk: PublicKey,
+    #[serde(skip)]
+    dispute_sk: Option<FixedSizeArray32>,
+}
+
+impl DisputeAuthorization {
+    pub fn new(payout_pk: PublicKey, dispute_sk: [u8; 32]) -> Result<Self, ZkChannelError> {
+        let secp = secp256k1::Secp256k1::new();
+        let sk = handle_error_util!(
+            SecretKey::from_slice(&dispute_sk),
+            ZkChannelError::InvalidInput
+        );
+        Ok(DisputeAuthorization {
+            payout_pk,
+            dispute_pk: PublicKey::from_secret_key(&secp, &sk),
+            dispute_sk: Some(FixedSizeArray32(dispute_sk)),
+        })
+    }
+
+    ///
+    /// load_dispute_key() - sets the dispute key of a deserialized authorization
+    /// output: error if the key does not match the dispute-pk
+    ///
+    pub fn load_dispute_key(&mut self, dispute_sk: [u8; 32]) -> Result<(), ZkChannelError> {
+        let secp = secp256k1::Secp256k1::new();
+        let sk = handle_error_util!(
+            SecretKey::from_slice(&dispute_sk),
+            ZkChannelError::InvalidInput
+        );
+        if PublicKey::from_secret_key(&secp, &sk) != self.dispute_pk {
+            return Err(ZkChannelError::InvalidInput(String::from(
+                "dispute key does not match the dispute-pk of the authorization",
+            )));
+        }
+        self.dispute_sk = Some(FixedSizeArray32(dispute_sk));
+        Ok(())
+    }
+
+    pub fn has_dispute_key(&self) -> bool {
+        self.dispute_sk.is_some()
+    }
+
+    ///
+    /// sign_dispute_transaction() - signs a dispute tx that claims the to_customer output of a revoked cust-close-tx
+    /// output: merch-dispute-signed-tx
+    ///
+    pub fn sign_dispute_transaction(
+        &self,
+        close_tx: &CustCloseTxInfo,
+        rev_secret: &[u8; 32],
+        to_self_delay: [u8; 2],
+        fee_mc: i64,
+    ) -> Result<Vec<u8>, ZkChannelError> {
+        let dispute_sk = match &self.dispute_sk {
+            Some(sk) => sk.0,
+            None => {
+                return Err(ZkChannelError::NotFound(String::from(
+                    "dispute key has not been loaded",
+                )))
+            }
+        };
+        if hash_to_slice(&rev_secret.to_vec()) != close_tx.rev_lock {
+            return Err(ZkChannelError::InvalidCommitmentOpening(format!(
+                "rev_secret does not open rev_lock {}",
+                hex::encode(&close_tx.rev_lock)
+            )));
+        }
+        if close_tx.to_customer_amount <= fee_mc {
+            return Err(ZkChannelError::TransactionError(format!(
+                "to_customer output ({}) does not cover the dispute fee ({})",
+                close_tx.to_customer_amount, fee_mc
+            )));
+        }
+        let signed_tx = handle_error_util!(
+            zkchan_tx::txutil::merchant_sign_merch_dispute_transaction(
+                close_tx.txid_le.to_vec(),
+                close_tx.to_customer_index,
+                close_tx.to_customer_amount,
+                close_tx.to_customer_amount - fee_mc,
+                to_self_delay,
+                self.payout_pk.serialize().to_vec(),
+                close_tx.rev_lock.to_vec(),
+                rev_secret.to_vec(),
+                close_tx.cust_close_pk.clone(),
+                self.dispute_pk.serialize().to_vec(),
+                dispute_sk.to_vec()
+            ),
+            ZkChannelError::TransactionError
+        );
+        Ok(signed_tx)
+    }
+}
+
+///
+/// WatchtowerDispute - a dispute tx that the watchtower published for a revoked cust-close-tx
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WatchtowerDispute {
+    pub escrow_txid_be: FixedSizeArray32,
+    pub close_txid_le: FixedSizeArray32,
+    pub rev_lock: FixedSizeArray32,
+    pub height: u64,
+    pub signed_tx: Vec<u8>,
+}
+
+///
+/// SkippedTx - a revoked cust-close-tx of a watched channel that could not be disputed (e.g., its
+/// to_customer output does not cover the dispute fee)
+///
+#[derive(Clone, Debug, PartialEq)]
+pub struct SkippedTx {
+    pub height: u64,
+    pub raw_tx: Vec<u8>,
+    pub error: ZkChannelError,
+}
+
+///
+/// WatchtowerReport - outcome of a scan with process_new_blocks()
+///
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WatchtowerReport {
+    // disputes that were published
+    pub published: Vec<WatchtowerDispute>,
+    // txs that could not be disputed (the scan goes on past them)
+    pub skipped: Vec<SkippedTx>,
+    // disputes that failed to publish (kept and retried on the next scan) with the error
+    pub failed: Vec<(WatchtowerDispute, ZkChannelError)>,
+}
+
+///
+/// Watchtower - watches a block source for cust-close-txs of registered channels that commit to a
+/// revoked rev_lock and publishes the corresponding dispute tx before the to_self_delay expires
+///
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Watchtower {
+    authorization: DisputeAuthorization,
+    // prevout txid (escrow-txid or merch-txid) => channel
+    channels: HashMap<String, WatchedChannel>,
+    // rev_lock => rev_secret
+    dispute_material: HashMap<String, FixedSizeArray32>,
+    // txids of the cust-close-txs that were already disputed
+    disputed: HashSet<String>,
+    // disputes that could not be published yet (retried by process_new_blocks())
+    #[serde(default)]
+    pending: Vec<WatchtowerDispute>,
+    next_height: u64,
+}
+
+impl Watchtower {
+    pub fn new(authorization: DisputeAuthorization, start_height: u64) -> Self {
+        Watchtower {
+            authorization,
+            channels: HashMap::new(),
+            dispute_material: HashMap::new(),
+            disputed: HashSet::new(),
+            pending: Vec::new(),
+            next_height: start_height,
+        }
+    }
+
+    pub fn get_next_height(&self) -> u64 {
+        self.next_height
+    }
+
+    pub fn get_pending_disputes(&self) -> &Vec<WatchtowerDispute> {
+        &self.pending
+    }
+
+    ///
+    /// load_dispute_key() - sets the dispute key after the watchtower is deserialized (see DisputeAuthorization)
+    ///
+    pub fn load_dispute_key(&mut self, dispute_sk: [u8; 32]) -> Result<(), ZkChannelError> {
+        self.authorization.load_dispute_key(dispute_sk)
+    }
+
+    pub fn add_channel(&mut self, channel: WatchedChannel) {
+        self.channels
+            .insert(hex::encode(&channel.escrow_txid_be.0), channel.clone());
+        self.channels
+            .insert(hex::encode(&channel.merch_txid_be.0), channel);
+    }
+
+    pub fn add_dispute_material(
+        &mut self,
+        material: DisputeMaterial,
+    ) -> Result<(), ZkChannelError> {
+        // re-check the opening since the material may come from an untrusted channel
+        let material = DisputeMaterial::new(material.rev_lock.0, material.rev_secret.0)?;
+        self.dispute_material
+            .insert(hex::encode(&material.rev_lock.0), material.rev_secret);
+        Ok(())
+    }
+
+    pub fn has_dispute_material(&self, rev_lock: &[u8; 32]) -> bool {
+        self.dispute_material.contains_key(&hex::encode(rev_lock))
+    }
+
+    ///
+    /// check_transaction() - checks whether a transaction is a revoked cust-close-tx of a watched channel
+    /// output: the signed dispute tx and the escrow-txid of the channel (None if the tx is not disputable)
+    ///
+    pub fn check_transaction(
+        &self,
+        raw_tx: &Vec<u8>,
+    ) -> Result<Option<(CustCloseTxInfo, FixedSizeArray32, Vec<u8>)>, ZkChannelError> {
+        // most transactions on chain are not cust-close-txs
+        let close_tx = match parse_cust_close_transaction(raw_tx) {
+            Ok(t) => t,
+            Err(_) => return Ok(None),
+        };
+        if self.disputed.contains(&hex::encode(&close_tx.txid_le)) {
+            return Ok(None);
+        }
+        let channel = match self.channels.get(&hex::encode(&close_tx.prevout_txid_be)) {
+            Some(c) => c,
+            None => return Ok(None),
+        };
+        let rev_secret = match self.dispute_material.get(&hex::encode(&close_tx.rev_lock)) {
+            Some(s) => s,
+            None => return Ok(None),
+        };
+        let signed_tx = self.authorization.sign_dispute_transaction(
+            &close_tx,
+            &rev_secret.0,
+            channel.to_self_delay,
+            channel.fee_mc,
+        )?;
+        Ok(Some((close_tx, channel.escrow_txid_be, signed_tx)))
+    }
+
+    ///
+    /// process_new_blocks() - scans the blocks since the last call and publishes a dispute tx for every
+    /// revoked cust-close-tx. A tx that cannot be disputed (e.g., its to_customer output does not cover
+    /// the dispute fee) is skipped, so it never stalls the scan. A dispute that fails to publish is kept
+    /// and retried on the next call. Fails without scanning if the dispute key has not been loaded.
+    /// output: the published disputes, the skipped txs and the disputes that failed to publish
+    ///
+    pub fn process_new_blocks(
+        &mut self,
+        source: &mut dyn BlockSource,
+        publisher: &mut dyn TxPublisher,
+    ) -> Result<WatchtowerReport, ZkChannelError> {
+        if !self.authorization.has_dispute_key() {
+            return Err(ZkChannelError::NotFound(String::from(
+                "dispute key has not been loaded",
+            )));
+        }
+        let mut report = WatchtowerReport::default();
+        for dispute in mem::replace(&mut self.pending, Vec::new()) {
+            self.publish_dispute(publisher, dispute, &mut report);
+        }
+        let block_count = source.get_block_count()?;
+        while self.next_height < block_count {
+            let height = self.next_height;
+            for raw_tx in source.get_block_transactions(height)?.iter() {
+                let (close_tx, escrow_txid_be, signed_tx) = match self.check_transaction(raw_tx) {
+                    Ok(Some(d)) => d,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        report.skipped.push(SkippedTx {
+                            height,
+                            raw_tx: raw_tx.clone(),
+                            error: e,
+                        });
+                        continue;
+                    }
+                };
+                self.disputed.insert(hex::encode(&close_tx.txid_le));
+                let dispute = WatchtowerDispute {
+                    escrow_txid_be,
+                    close_txid_le: FixedSizeArray32(close_tx.txid_le),
+                    rev_lock: FixedSizeArray32(close_tx.rev_lock),
+                    height,
+                    signed_tx,
+                };
+                self.publish_dispute(publisher, dispute, &mut report);
+            }
+            self.next_height += 1;
+        }
+        Ok(report)
+    }
+
+    fn publish_dispute(
+        &mut self,
+        publisher: &mut dyn TxPublisher,
+        dispute: WatchtowerDispute,
+        report: &mut WatchtowerReport,
+    ) {
+        match publisher.publish_transaction(&dispute.signed_tx) {
+            Ok(_) => report.published.push(dispute),
+            Err(e) => {
+                self.pending.push(dispute.clone());
+                report.failed.push((dispute, e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channels_mpc::parse_transaction;
+
+    #[test]
+    fn test_watchtower_ignores_unknown_transactions() {
+        let secp = secp256k1::Secp256k1::new();
+        let payout_pk =
+            PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[0x21; 32]).unwrap());
+        let auth = DisputeAuthorization::new(payout_pk, [0x22; 32]).unwrap();
+        assert!(DisputeAuthorization::new(payout_pk, [0u8; 32]).is_err());
+
+        let rev_secret = [0x33; 32];
+        let rev_lock = hash_to_slice(&rev_secret.to_vec());
+        assert!(DisputeMaterial::new(rev_lock, [0x34; 32]).is_err());
+
+        let mut tower = Watchtower::new(auth, 1);
+        tower
+            .add_dispute_material(DisputeMaterial::new(rev_lock, rev_secret).unwrap())
+            .unwrap();
+        assert!(tower.has_dispute_material(&rev_lock));
+
+        let mut chain = MockChain::new();
+        chain.mine_block(vec![vec![0x01, 0x02]]);
+        chain.mine_block(vec![vec![0x02, 0x00, 0x00, 0x00]]);
+        chain.mine_block(Vec::new());
+
+        let mut publisher = MockChain::new();
+        let report = tower
+            .process_new_blocks(&mut chain, &mut publisher)
+            .unwrap();
+        assert_eq!(report, WatchtowerReport::default());
+        assert!(publisher.mempool.is_empty());
+        assert_eq!(tower.get_next_height(), 3);
+        assert!(chain.get_block_transactions(3).is_err());
+    }
+
+    struct OfflinePublisher;
+
+    impl TxPublisher for OfflinePublisher {
+        fn publish_transaction(&mut self, _raw_tx: &Vec<u8>) -> Result<(), ZkChannelError> {
+            Err(ZkChannelError::InternalError(String::from(
+                "node is offline",
+            )))
+        }
+    }
+
+    // non-segwit cust-close-tx with a to_customer (P2WSH) output and the OP_RETURN output
+    fn build_cust_close_tx(
+        prevout_txid_be: [u8; 32],
+        to_customer_amount: i64,
+        rev_lock: [u8; 32],
+        cust_close_pk: &PublicKey,
+    ) -> Vec<u8> {
+        let mut tx = vec![0x02, 0x00, 0x00, 0x00, 0x01];
+        tx.extend_from_slice(&prevout_txid_be);
+        tx.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0x02]);
+        tx.extend_from_slice(&(to_customer_amount as u64).to_le_bytes());
+        tx.extend_from_slice(&[0x22, 0x00, 0x20]);
+        tx.extend_from_slice(&[0x44; 32]);
+        tx.extend_from_slice(&0u64.to_le_bytes());
+        tx.extend_from_slice(&[0x43, 0x6a, 0x41]);
+        tx.extend_from_slice(&rev_lock);
+        tx.extend_from_slice(&cust_close_pk.serialize());
+        tx.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        tx
+    }
+
+    #[test]
+    fn test_watchtower_disputes_revoked_close_tx() {
+        let secp = secp256k1::Secp256k1::new();
+        let payout_pk =
+            PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[0x21; 32]).unwrap());
+        let cust_close_pk =
+            PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&[0x23; 32]).unwrap());
+        let auth = DisputeAuthorization::new(payout_pk, [0x22; 32]).unwrap();
+
+        let rev_secret = [0x33; 32];
+        let rev_lock = hash_to_slice(&rev_secret.to_vec());
+        let channel = WatchedChannel {
+            escrow_txid_be: FixedSizeArray32([0x11; 32]),
+            merch_txid_be: FixedSizeArray32([0x12; 32]),
+            to_self_delay: [0x05, 0xcf],
+            fee_mc: 1000,
+        };
+        let mut tower = Watchtower::new(auth.clone(), 0);
+        tower.add_channel(channel.clone());
+        tower
+            .add_dispute_material(DisputeMaterial::new(rev_lock, rev_secret).unwrap())
+            .unwrap();
+
+        // the dispute key is not stored with the watchtower
+        let ser_tower = serde_json::to_string(&tower).unwrap();
+        assert!(!ser_tower.contains(&hex::encode([0x22; 32])));
+        let mut tower: Watchtower = serde_json::from_str(&ser_tower).unwrap();
+        let mut chain = MockChain::new();
+        let mut publisher = MockChain::new();
+        assert!(tower
+            .process_new_blocks(&mut chain, &mut publisher)
+            .is_err());
+        assert!(tower.load_dispute_key([0x24; 32]).is_err());
+        tower.load_dispute_key([0x22; 32]).unwrap();
+
+        // a revoked cust-close-tx from the merch-close-tx whose to_customer output cannot pay the fee
+        let cheap_tx = build_cust_close_tx(channel.merch_txid_be.0, 900, rev_lock, &cust_close_pk);
+        // a revoked cust-close-tx from the escrow-tx
+        let close_tx =
+            build_cust_close_tx(channel.escrow_txid_be.0, 10000, rev_lock, &cust_close_pk);
+        let close_tx_info = parse_cust_close_transaction(&close_tx).unwrap();
+        chain.mine_block(vec![cheap_tx.clone(), close_tx.clone()]);
+
+        // the node is offline: the dispute is reported and kept for the next scan
+        let report = tower
+            .process_new_blocks(&mut chain, &mut OfflinePublisher)
+            .unwrap();
+        assert!(report.published.is_empty());
+        assert_eq!(report.skipped.len(), 1);
+        assert_eq!(report.skipped[0].height, 0);
+        assert_eq!(report.skipped[0].raw_tx, cheap_tx);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(tower.get_pending_disputes().len(), 1);
+
+        let report = tower
+            .process_new_blocks(&mut chain, &mut publisher)
+            .unwrap();
+        assert!(report.skipped.is_empty() && report.failed.is_empty());
+        assert_eq!(report.published.len(), 1);
+        let dispute = &report.published[0];
+        assert_eq!(dispute.escrow_txid_be, channel.escrow_txid_be);
+        assert_eq!(dispute.close_txid_le.0, close_tx_info.txid_le);
+        assert_eq!(dispute.rev_lock.0, rev_lock);
+        assert_eq!(publisher.mempool, vec![dispute.signed_tx.clone()]);
+        assert!(tower.get_pending_disputes().is_empty());
+
+        // the dispute tx spends the to_customer output of the close tx
+        let signed_tx = auth
+            .sign_dispute_transaction(
+                &close_tx_info,
+                &rev_secret,
+                channel.to_self_delay,
+                channel.fee_mc,
+            )
+            .unwrap();
+        assert_eq!(dispute.signed_tx, signed_tx);
+        let dispute_tx = parse_transaction(&signed_tx).unwrap();
+        let mut close_txid_be = close_tx_info.txid_le;
+        close_txid_be.reverse();
+        assert_eq!(
+            dispute_tx.outpoints,
+            vec![(close_txid_be, close_tx_info.to_customer_index)]
+        );
+        assert_eq!(dispute_tx.outputs[0].0, 10000 - channel.fee_mc);
+
+        // the close tx is not disputed again
+        chain.mine_block(vec![close_tx]);
+        let report = tower
+            .process_new_blocks(&mut chain, &mut publisher)
+            .unwrap();
+        assert_eq!(report, WatchtowerReport::default());
+        assert_eq!(tower.get_next_height(), 2);
+    }
+}